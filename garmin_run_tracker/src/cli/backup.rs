@@ -0,0 +1,109 @@
+//! Define the backup subcommand
+use crate::db::{backup_database, latest_schema_version, open_db_connection, schema_version};
+use crate::Error;
+use rusqlite::{params, Connection, OpenFlags};
+use std::fs::{remove_file, rename};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Copy the database to another file, it is safe to run while the database is in use
+#[derive(Debug, StructOpt)]
+pub struct BackupOpts {
+    /// Path to write the backup to, an existing file is only replaced once the backup has been
+    /// written and verified
+    #[structopt(short, long, parse(from_os_str))]
+    output: PathBuf,
+}
+
+/// Implementation of the `backup` subcommand
+pub fn backup_command(opts: BackupOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = open_db_connection()?;
+    write_backup(&conn, &opts.output)?;
+    summary!("Backed up the database to {:?}", opts.output);
+
+    Ok(())
+}
+
+/// Copy the database to the output path, an existing file is only replaced once the copy has
+/// been written and verified
+fn write_backup(conn: &Connection, output: &Path) -> Result<(), Error> {
+    // write next to the destination first so a failed backup never replaces a good one
+    let mut partial_name = output
+        .file_name()
+        .ok_or_else(|| Error::Other(format!("{:?} is not a valid backup file name", output)))?
+        .to_os_string();
+    partial_name.push(".partial");
+    let partial = output.with_file_name(partial_name);
+    if partial.exists() {
+        remove_file(&partial)?;
+    }
+
+    let result = backup_database(conn, &partial)
+        .map_err(Error::from)
+        .and_then(|_| verify_backup(&partial));
+    if let Err(e) = result {
+        if partial.exists() {
+            remove_file(&partial)?;
+        }
+        return Err(e);
+    }
+    rename(&partial, output)?;
+
+    Ok(())
+}
+
+/// Check that the backup opens, passes SQLite's consistency check and has every migration
+/// applied
+fn verify_backup(path: &Path) -> Result<(), Error> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version = schema_version(&conn)?;
+    if version != latest_schema_version() {
+        return Err(Error::Other(format!(
+            "Backup has schema version {} instead of {}",
+            version,
+            latest_schema_version()
+        )));
+    }
+    let check: String = conn.query_row("pragma quick_check", params![], |r| r.get(0))?;
+    if check != "ok" {
+        return Err(Error::Other(format!("Backup failed its check: {}", check)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{init_database, open_db_connection_at};
+
+    #[test]
+    fn backup_leaves_only_the_backup_file() {
+        let dir = std::env::temp_dir().join(format!("grt-backup-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // the source uses the write-ahead log like the application database
+        let source = std::env::temp_dir().join(format!("grt-backup-src-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&source);
+        let mut conn = open_db_connection_at(&source).unwrap();
+        init_database(&mut conn).unwrap();
+
+        let output = dir.join("bk.db");
+        write_backup(&conn, &output).unwrap();
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["bk.db"]);
+
+        let backup = Connection::open(&output).unwrap();
+        let mode: String = backup
+            .query_row("pragma journal_mode", params![], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "delete");
+        drop(backup);
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(&source);
+    }
+}
@@ -1,17 +1,20 @@
 //! Define the download-epo subcommand
 //! Original source of code: https://github.com/scrapper/postrunner/blob/master/lib/postrunner/EPO_Downloader.rb
+use super::OutputSink;
 use crate::config::Config;
+use crate::services::http_client;
 use crate::Error;
 use chrono::{Duration, Local, TimeZone, Utc};
-use log::{debug, error, info, warn};
-use reqwest::blocking::Client;
+use log::{error, info, warn};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
-use std::fs::File;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 static URI: &str = "https://omt.garmin.com/Rce/ProtobufApi/EphemerisService/GetEphemerisData";
+/// Size of the data returned by the server, 28 sets of 2307 bytes
+const EPO_DOWNLOAD_SIZE: usize = 28 * 2307;
 // This is the payload of the POST request. It was taken from
 // http://www.kluenter.de/garmin-ephemeris-files-and-linux/. It may contain
 // a product ID or serial number.
@@ -28,6 +31,10 @@ pub struct DownloadEpoOpts {
     /// will be ignored. If "-" is used we will write to stdout.
     #[structopt(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
+    /// Rename existing EPO files to EPO.BIN.bak (using each file's own name) before writing the
+    /// new data, the backup is restored if writing or validating the new file fails
+    #[structopt(long)]
+    backup: bool,
 }
 
 /// Download Extended Prediction Orbit (EPO) data for one or more garmin devices
@@ -42,17 +49,20 @@ pub fn download_epo_command(
 
     // output the EPO data to a single file or the config defined locations
     if let Some(path) = opts.output {
-        if path.to_string_lossy() == "-" {
-            debug!("Writing EPO data to STDOUT");
-            write_to_stdout(&epo_data)?
-        } else {
-            debug!("Writing EPO data to {:?}", path);
-            let mut fp = File::create(path)?;
-            fp.write_all(&epo_data)?
+        let output = OutputSink::new(Some(path));
+        match output.path() {
+            Some(path) if opts.backup => write_with_backup(path, &epo_data)?,
+            _ => output.write(&epo_data)?,
         }
     } else {
         for path in config.epo_data_paths().iter().map(PathBuf::from) {
             info!("Writing EPO data to {:?}", path);
+            if opts.backup {
+                if let Err(e) = write_with_backup(&path, &epo_data) {
+                    warn!("Could not write data to {:?} - {}", path, e);
+                }
+                continue;
+            }
             match File::create(&path) {
                 Ok(mut fp) => fp.write_all(&epo_data)?,
                 Err(e) => {
@@ -67,6 +77,33 @@ pub fn download_epo_command(
     Ok(())
 }
 
+/// Write the EPO data after moving any existing file to a backup next to it. The written file is
+/// read back and validated, if anything fails the backup is moved back into place so a device
+/// never ends up with worse data than it had before.
+fn write_with_backup(path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+    backup_name.push(".bak");
+    let backup = path.with_file_name(backup_name);
+    let backed_up = path.exists();
+    if backed_up {
+        info!("Backing up existing EPO data to {:?}", backup);
+        fs::rename(path, &backup)?;
+    }
+
+    let result = fs::write(path, data)
+        .map_err(Error::from)
+        .and_then(|_| validate_epo_data(&fs::read(path)?));
+    if let Err(e) = result {
+        if backed_up {
+            warn!("Restoring EPO data in {:?} from {:?}", path, backup);
+            fs::rename(&backup, path)?;
+        }
+        return Err(Box::new(e));
+    }
+
+    Ok(())
+}
+
 /// Request EPO data from garmin server using the extracted credentials
 fn download_epo_data() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     // setup headers
@@ -84,14 +121,22 @@ fn download_epo_data() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         HeaderValue::from_str(&format!("{}", POST_DATA.len()))?,
     );
 
-    let client = Client::new();
+    let client = http_client()?;
     let resp = client.post(URI).headers(headers).body(POST_DATA).send()?;
     if resp.status().is_success() {
-        // return EPO data
-        match resp.bytes() {
-            Ok(data) => Ok(data.into_iter().collect()),
-            Err(e) => Err(Box::new(e)),
+        // a misbehaving server shouldn't be able to make us read an arbitrarily large body
+        if let Some(length) = resp.content_length() {
+            if length > EPO_DOWNLOAD_SIZE as u64 {
+                return Err(Box::new(oversized_download_error()));
+            }
         }
+        let mut data = Vec::with_capacity(EPO_DOWNLOAD_SIZE);
+        resp.take(EPO_DOWNLOAD_SIZE as u64 + 1)
+            .read_to_end(&mut data)?;
+        if data.len() > EPO_DOWNLOAD_SIZE {
+            return Err(Box::new(oversized_download_error()));
+        }
+        Ok(data)
     } else {
         let code = resp.status();
         Err(Box::new(Error::RequestError(
@@ -101,6 +146,15 @@ fn download_epo_data() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     }
 }
 
+fn oversized_download_error() -> Error {
+    let msg = format!(
+        "EPO download from {} is larger than the expected {} bytes, aborting",
+        URI, EPO_DOWNLOAD_SIZE
+    );
+    error!("{}", &msg);
+    Error::Other(msg)
+}
+
 /// The downloaded data contains Extended Prediction Orbit data for 6 hour
 /// windows for 7 days. Each EPO set is 2307 bytes long, but the first 3
 /// bytes must be removed for the FR620 to understand it.
@@ -108,11 +162,11 @@ fn download_epo_data() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
 /// The 2304 bytes consist of 32 sets of 72 byte GPS satellite data.
 /// http://www.vis-plus.ee/pdf/SIM28_SIM68R_SIM68V_EPO-II_Protocol_V1.00.pdf
 fn strip_leading_bytes(data: Vec<u8>) -> Result<Vec<u8>, Error> {
-    if data.len() != 28 * 2307 {
+    if data.len() != EPO_DOWNLOAD_SIZE {
         let msg = format!(
             "EPO data has unexpected length of {} bytes instead of {}",
             data.len(),
-            28 * 2307
+            EPO_DOWNLOAD_SIZE
         );
         error!("{}", &msg);
         return Err(Error::Other(msg));
@@ -186,9 +240,3 @@ fn validate_epo_data(data: &[u8]) -> Result<(), Error> {
 
     Ok(())
 }
-
-fn write_to_stdout(data: &[u8]) -> io::Result<()> {
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
-    handle.write_all(&data)
-}
@@ -0,0 +1,43 @@
+//! Define the dump subcommand
+use crate::validate_fit_file;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Print every message and field found in a FIT file without importing it, useful for finding
+/// out why a field didn't show up after an import
+#[derive(Debug, StructOpt)]
+pub struct DumpOpts {
+    /// FIT file to dump
+    #[structopt(name = "PATH", parse(from_os_str))]
+    path: PathBuf,
+    /// Only print messages of this kind (e.g. record, lap, session), can be repeated
+    #[structopt(short, long, number_of_values = 1)]
+    kind: Vec<String>,
+}
+
+pub fn dump_command(opts: DumpOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut data = Vec::new();
+    File::open(&opts.path)?.read_to_end(&mut data)?;
+    validate_fit_file(&data)?;
+    let messages = fitparser::from_bytes(&data)?;
+
+    for (index, mesg) in messages.iter().enumerate() {
+        let kind = mesg.kind().to_string();
+        if !opts.kind.is_empty() && !opts.kind.iter().any(|k| k.eq_ignore_ascii_case(&kind)) {
+            continue;
+        }
+        // the index matches the message_index stored with developer fields
+        println!("#{} {}", index, kind);
+        for field in mesg.fields() {
+            if field.units().is_empty() {
+                println!("    {} = {}", field.name(), field.value());
+            } else {
+                println!("    {} = {} {}", field.name(), field.value(), field.units());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! Define geojson subcommand
+use super::route_image::{hide_privacy_zone, load_route, LabelMode};
+use super::{export_dir, OutputSink};
+use crate::config::Config;
+use crate::db::{all_files, find_file_by_uuid, open_db_connection};
+use crate::gps::Location;
+use crate::services::visualization::route::Marker;
+use crate::{DistanceUnit, ElevationSource, Error, FileInfo};
+use log::{info, warn};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Export the route as a GeoJSON FeatureCollection for use with web mapping libraries
+#[derive(Debug, StructOpt)]
+pub struct GeoJsonOpts {
+    /// Full or partial UUID of file we want to export the route of (use list-files command
+    /// to see UUIDs). The special identifier :last will return the most recent file import.
+    #[structopt(name = "FILE_UUID", required_unless = "all")]
+    uuid: Option<String>,
+    /// Export every file in the database that has GPS data to the output directory
+    #[structopt(long, conflicts_with_all = &["FILE_UUID", "output"])]
+    all: bool,
+    /// name of file to output GeoJSON to, if not provided or "-" is used data is written to stdout.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Write to a file named by the export_file_name template within this directory, --all
+    /// defaults to the export_dir config value
+    #[structopt(long, parse(from_os_str), conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+    /// Only export the route line without the start, finish and lap markers
+    #[structopt(long)]
+    no_markers: bool,
+    /// How to label lap markers: lap (lap number) or distance (cumulative distance)
+    #[structopt(long, default_value = "lap")]
+    label_mode: LabelMode,
+    /// Units used for distance labels: mi or km
+    #[structopt(long, default_value = "mi")]
+    units: DistanceUnit,
+    /// Elevation to use: api (from the elevation service) or device (recorded by the watch),
+    /// defaults to the elevation_source config value
+    #[structopt(long)]
+    elevation_source: Option<ElevationSource>,
+    /// Hide the part of the route within this many meters of the start and finish, e.g. to not
+    /// reveal where you live when sharing it
+    #[structopt(long)]
+    privacy_radius: Option<f64>,
+    /// Decimal places of the route coordinates, defaults to the coordinate_precision config
+    /// value
+    #[structopt(long)]
+    coordinate_precision: Option<usize>,
+}
+
+pub fn geojson_command(
+    config: Config,
+    opts: GeoJsonOpts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = open_db_connection()?;
+    let output_dir = export_dir(opts.output_dir.as_deref(), opts.all, &config)?;
+    let files = match &opts.uuid {
+        Some(uuid) if !opts.all => vec![find_file_by_uuid(&conn, uuid)?],
+        _ => all_files(&conn)?,
+    };
+
+    let elevation_source = opts
+        .elevation_source
+        .unwrap_or_else(|| config.elevation_source());
+    let precision = opts
+        .coordinate_precision
+        .unwrap_or_else(|| config.coordinate_precision());
+    for info in &files {
+        let collection = match route_collection(&conn, info, &opts, elevation_source, precision) {
+            Ok(collection) => collection,
+            // not every file has GPS data, e.g. treadmill runs
+            Err(e) if opts.all => {
+                warn!("Skipping file UUID={}: {}", info.uuid(), e);
+                continue;
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut data = serde_json::to_vec(&collection)?;
+        data.push(b'\n');
+        let output = match output_dir {
+            Some(dir) => OutputSink::export(dir, info, config.export_file_name(), "geojson")?,
+            None => OutputSink::new(opts.output.clone()),
+        };
+        output.write(&data)?;
+        if let Some(path) = output.path() {
+            info!("Exported file UUID={} to {:?}", info.uuid(), path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the FeatureCollection of a file's route, only a single requested file is exported
+/// without any GPS data
+fn route_collection(
+    conn: &Connection,
+    info: &FileInfo,
+    opts: &GeoJsonOpts,
+    elevation_source: ElevationSource,
+    precision: usize,
+) -> Result<Value, Error> {
+    let (trace, markers) =
+        load_route(conn, info, opts.label_mode, opts.units, 1, elevation_source)?;
+    if opts.all && trace.is_empty() {
+        return Err(Error::Other("no GPS data to export".to_string()));
+    }
+    let (trace, markers) = match opts.privacy_radius {
+        Some(radius) => hide_privacy_zone(trace, markers, radius, info)?,
+        None => (trace, markers),
+    };
+    let markers = if opts.no_markers { &[] } else { &markers[..] };
+    Ok(feature_collection(info.uuid(), &trace, markers, precision))
+}
+
+/// Build a FeatureCollection with the trace as a LineString followed by a Point for each marker,
+/// coordinates are rounded to `precision` decimal places
+fn feature_collection(
+    uuid: &str,
+    trace: &[Location],
+    markers: &[Marker],
+    precision: usize,
+) -> Value {
+    let mut features = vec![json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": trace.iter().map(|loc| position(loc, precision)).collect::<Vec<_>>(),
+        },
+        "properties": { "uuid": uuid },
+    })];
+    features.extend(markers.iter().map(|marker| {
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "Point",
+                "coordinates": position(marker.location(), precision),
+            },
+            "properties": { "label": marker.label() },
+        })
+    }));
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+/// GeoJSON positions are longitude, latitude with elevation as an optional third value
+fn position(loc: &Location, precision: usize) -> Value {
+    let (lon, lat) = (
+        round(loc.longitude(), precision),
+        round(loc.latitude(), precision),
+    );
+    match loc.elevation() {
+        Some(elevation) => json!([lon, lat, round(elevation, 1)]),
+        None => json!([lon, lat]),
+    }
+}
+
+/// Round a value to the given number of decimals, this avoids writing out the noise from
+/// widening our single precision coordinates into JSON's doubles
+fn round(value: f32, decimals: usize) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    (value as f64 * factor).round() / factor
+}
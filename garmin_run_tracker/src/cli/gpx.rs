@@ -0,0 +1,364 @@
+//! Define gpx subcommand
+use super::{export_dir, OutputSink};
+use crate::config::Config;
+use crate::db::{all_files, find_file_by_uuid, open_db_connection};
+use crate::gps::{privacy_trim, semicircles_to_degrees, Location};
+use crate::{ElevationSource, Error, FileInfo};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rusqlite::{params, Connection, Result};
+use std::fmt::Write;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Export the GPS track of a file as GPX for use with other running and mapping tools
+#[derive(Debug, StructOpt)]
+pub struct GpxOpts {
+    /// Full or partial UUID of file we want to export the track of (use list-files command
+    /// to see UUIDs). The special identifier :last will return the most recent file import.
+    #[structopt(name = "FILE_UUID", required_unless = "all")]
+    uuid: Option<String>,
+    /// Export every file in the database that has GPS data to the output directory
+    #[structopt(long, conflicts_with_all = &["FILE_UUID", "output"])]
+    all: bool,
+    /// name of file to output GPX to, if not provided or "-" is used data is written to stdout.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Write to a file named by the export_file_name template within this directory, --all
+    /// defaults to the export_dir config value
+    #[structopt(long, parse(from_os_str), conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+    /// Start a new track segment at each lap so every lap can be viewed on its own
+    #[structopt(long)]
+    segment_by_lap: bool,
+    /// Elevation to use: api (from the elevation service) or device (recorded by the watch),
+    /// defaults to the elevation_source config value
+    #[structopt(long)]
+    elevation_source: Option<ElevationSource>,
+    /// Hide the part of the track within this many meters of the start and finish, e.g. to not
+    /// reveal where you live when sharing it
+    #[structopt(long)]
+    privacy_radius: Option<f64>,
+    /// Decimal places of the track point coordinates, defaults to the coordinate_precision
+    /// config value
+    #[structopt(long)]
+    coordinate_precision: Option<usize>,
+}
+
+/// A single track point of the GPX output
+struct TrackPoint {
+    timestamp: DateTime<Utc>,
+    latitude: f64,
+    longitude: f64,
+    elevation: Option<f64>,
+    heart_rate: Option<i64>,
+}
+
+pub fn gpx_command(config: Config, opts: GpxOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = open_db_connection()?;
+    let output_dir = export_dir(opts.output_dir.as_deref(), opts.all, &config)?;
+    let files = match &opts.uuid {
+        Some(uuid) if !opts.all => vec![find_file_by_uuid(&conn, uuid)?],
+        _ => all_files(&conn)?,
+    };
+
+    let elevation_source = opts
+        .elevation_source
+        .unwrap_or_else(|| config.elevation_source());
+    let precision = opts
+        .coordinate_precision
+        .unwrap_or_else(|| config.coordinate_precision());
+    for info in &files {
+        let gpx = match gpx_document(
+            &conn,
+            info,
+            opts.segment_by_lap,
+            elevation_source,
+            opts.privacy_radius,
+            precision,
+        ) {
+            Ok(gpx) => gpx,
+            // not every file has GPS data, e.g. treadmill runs
+            Err(e) if opts.all => {
+                warn!("Skipping file UUID={}: {}", info.uuid(), e);
+                continue;
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        let output = match output_dir {
+            Some(dir) => OutputSink::export(dir, info, config.export_file_name(), "gpx")?,
+            None => OutputSink::new(opts.output.clone()),
+        };
+        output.write(gpx.as_bytes())?;
+        if let Some(path) = output.path() {
+            info!("Exported file UUID={} to {:?}", info.uuid(), path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a GPX document of the file's records, split into a track segment per lap if requested.
+/// Points within `privacy_radius` meters of the start and finish are left out and coordinates
+/// are written with `precision` decimal places.
+fn gpx_document(
+    conn: &Connection,
+    info: &FileInfo,
+    segment_by_lap: bool,
+    elevation_source: ElevationSource,
+    privacy_radius: Option<f64>,
+    precision: usize,
+) -> Result<String, Error> {
+    let mut stmt = conn.prepare(&format!(
+        "select timestamp, position_lat, position_long, {}, heart_rate
+         from record_messages
+         where file_id = ? and position_lat is not null and position_long is not null
+         order by timestamp",
+        elevation_source.column()
+    ))?;
+    let mut points = stmt
+        .query_map(params![info.id()], |r| {
+            Ok(TrackPoint {
+                timestamp: r.get(0)?,
+                latitude: semicircles_to_degrees(r.get(1)?),
+                longitude: semicircles_to_degrees(r.get(2)?),
+                elevation: r.get(3)?,
+                heart_rate: r.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<TrackPoint>>>()?;
+    if points.is_empty() {
+        return Err(Error::Other(format!(
+            "File with UUID={} has no GPS data to export",
+            info.uuid()
+        )));
+    }
+    if let Some(radius) = privacy_radius {
+        let trace: Vec<Location> = points
+            .iter()
+            .map(|p| Location::new(p.latitude as f32, p.longitude as f32))
+            .collect();
+        let range = privacy_trim(&trace, radius);
+        if range.is_empty() {
+            return Err(Error::Other(format!(
+                "No GPS points of file UUID={} are outside of the {}m privacy radius",
+                info.uuid(),
+                radius
+            )));
+        }
+        points.truncate(range.end);
+        points.drain(..range.start);
+    }
+
+    let boundaries = if segment_by_lap {
+        lap_boundaries(conn, info.id())?
+    } else {
+        Vec::new()
+    };
+
+    // writing into a String can't fail so the results are ignored
+    let mut gpx = String::new();
+    gpx.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    gpx.push('\n');
+    gpx.push_str(
+        r#"<gpx version="1.1" creator="garmin-run-tracker" xmlns="http://www.topografix.com/GPX/1/1" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">"#,
+    );
+    gpx.push('\n');
+    let _ = writeln!(
+        gpx,
+        "<metadata><time>{}</time></metadata>",
+        format_time(&points[0].timestamp)
+    );
+    let _ = writeln!(
+        gpx,
+        "<trk><name>{}</name><type>running</type>",
+        info.format_timestamp("%Y-%m-%d %H:%M %:z", false)
+    );
+    gpx.push_str("<trkseg>\n");
+    let mut next_boundary = boundaries.iter().peekable();
+    for (i, point) in points.iter().enumerate() {
+        // close the segment once we pass the start of the next lap, skipping laps without
+        // any records so no empty segments are written
+        let mut new_segment = false;
+        while next_boundary
+            .peek()
+            .is_some_and(|start| point.timestamp >= **start)
+        {
+            next_boundary.next();
+            new_segment = true;
+        }
+        if new_segment && i > 0 {
+            gpx.push_str("</trkseg>\n<trkseg>\n");
+        }
+        write_track_point(&mut gpx, point, precision);
+    }
+    gpx.push_str("</trkseg>\n</trk>\n</gpx>\n");
+
+    Ok(gpx)
+}
+
+/// Start times of every lap after the first, records before the first lap's start time are
+/// kept with the first lap
+fn lap_boundaries(conn: &Connection, file_id: Option<u32>) -> Result<Vec<DateTime<Utc>>, Error> {
+    let mut stmt =
+        conn.prepare("select start_time from lap_messages where file_id = ? order by start_time")?;
+    let starts = stmt
+        .query_map(params![file_id], |r| r.get::<usize, DateTime<Utc>>(0))?
+        .skip(1)
+        .collect::<Result<Vec<DateTime<Utc>>>>()?;
+    Ok(starts)
+}
+
+fn write_track_point(gpx: &mut String, point: &TrackPoint, precision: usize) {
+    let _ = write!(
+        gpx,
+        r#"<trkpt lat="{:.*}" lon="{:.*}">"#,
+        precision, point.latitude, precision, point.longitude
+    );
+    if let Some(elevation) = point.elevation {
+        let _ = write!(gpx, "<ele>{:.1}</ele>", elevation);
+    }
+    let _ = write!(gpx, "<time>{}</time>", format_time(&point.timestamp));
+    if let Some(heart_rate) = point.heart_rate {
+        let _ = write!(
+            gpx,
+            "<extensions><gpxtpx:TrackPointExtension><gpxtpx:hr>{}</gpxtpx:hr></gpxtpx:TrackPointExtension></extensions>",
+            heart_rate
+        );
+    }
+    gpx.push_str("</trkpt>\n");
+}
+
+fn format_time(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{import, memory_db, FitFileBuilder, START};
+
+    fn two_lap_run() -> Vec<u8> {
+        FitFileBuilder::new()
+            .file_id(1234, START)
+            .record(START, (39.46, -80.14), 0.0, 3.0, 120)
+            .record(START + 10, (39.4601, -80.14), 30.0, 3.0, 130)
+            .record(START + 20, (39.4602, -80.14), 60.0, 3.0, 140)
+            .record(START + 30, (39.4603, -80.14), 90.0, 3.0, 150)
+            .lap(START, START + 20, 60.0, 3.0, 12)
+            .lap(START + 20, START + 30, 30.0, 3.0, 7)
+            .activity(START + 30, 0)
+            .build()
+    }
+
+    #[test]
+    fn gpx_document_splits_segments_at_lap_starts() {
+        let mut conn = memory_db();
+        let data = two_lap_run();
+        let info = import(&mut conn, &data);
+
+        let gpx = gpx_document(&conn, &info, false, ElevationSource::Api, None, 7).unwrap();
+        assert_eq!(gpx.matches("<trkseg>").count(), 1);
+        assert_eq!(gpx.matches("<trkpt ").count(), 4);
+        assert!(gpx.contains(r#"<trkpt lat="39.4600000" lon="-80.1400000">"#));
+        assert!(gpx.contains("<time>2021-06-01T12:00:00Z</time>"));
+        assert!(gpx.contains("<gpxtpx:hr>150</gpxtpx:hr>"));
+
+        // the record at the second lap's start time begins the second segment
+        let gpx = gpx_document(&conn, &info, true, ElevationSource::Api, None, 7).unwrap();
+        let segments: Vec<&str> = gpx.split("<trkseg>").skip(1).collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].matches("<trkpt ").count(), 2);
+        assert_eq!(segments[1].matches("<trkpt ").count(), 2);
+        assert!(segments[1].contains("<time>2021-06-01T12:00:20Z</time>"));
+
+        let gpx = gpx_document(&conn, &info, false, ElevationSource::Api, None, 4).unwrap();
+        assert!(gpx.contains(r#"<trkpt lat="39.4600" lon="-80.1400">"#));
+    }
+
+    #[test]
+    fn gpx_document_hides_points_near_the_ends() {
+        let mut conn = memory_db();
+        let data = two_lap_run();
+        let info = import(&mut conn, &data);
+
+        // records are about 11m apart so only the first and last fall inside the radius
+        let gpx = gpx_document(&conn, &info, false, ElevationSource::Api, Some(5.0), 7).unwrap();
+        assert_eq!(gpx.matches("<trkpt ").count(), 2);
+        assert!(!gpx.contains(r#"lat="39.4600000""#));
+        assert!(!gpx.contains(r#"lat="39.4603000""#));
+        assert!(gpx_document(&conn, &info, false, ElevationSource::Api, Some(50.0), 7).is_err());
+    }
+
+    #[test]
+    fn gpx_document_uses_the_requested_elevation() {
+        let mut conn = memory_db();
+        let data = two_lap_run();
+        let info = import(&mut conn, &data);
+        conn.execute(
+            "update record_messages set elevation = 250.0, device_elevation = 262.5",
+            params![],
+        )
+        .unwrap();
+
+        let gpx = gpx_document(&conn, &info, false, ElevationSource::Api, None, 7).unwrap();
+        assert_eq!(gpx.matches("<ele>250.0</ele>").count(), 4);
+        let gpx = gpx_document(&conn, &info, false, ElevationSource::Device, None, 7).unwrap();
+        assert_eq!(gpx.matches("<ele>262.5</ele>").count(), 4);
+    }
+
+    #[cfg(feature = "gpx")]
+    fn records(conn: &Connection, file_id: Option<u32>) -> Vec<(i64, i64, Option<i64>, String)> {
+        let mut stmt = conn
+            .prepare(
+                "select position_lat, position_long, heart_rate, timestamp from record_messages
+                 where file_id = ? order by timestamp",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(params![file_id], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows
+    }
+
+    #[cfg(feature = "gpx")]
+    #[test]
+    fn gpx_export_imports_back_into_the_same_records() {
+        use crate::import_gpx_data;
+        use crate::test_support::simple_run;
+
+        let mut conn = memory_db();
+        let data = simple_run(START);
+        let fit = import(&mut conn, &data);
+        let gpx = gpx_document(&conn, &fit, false, ElevationSource::Api, None, 7).unwrap();
+
+        let tx = conn.transaction().unwrap();
+        let info = import_gpx_data(gpx.as_bytes(), &tx).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(info.manufacturer(), "gpx");
+        assert_eq!(info.timestamp().timestamp(), START as i64);
+        assert_eq!(records(&conn, info.id()), records(&conn, fit.id()));
+
+        // distance is derived from the positions since GPX doesn't store it
+        let (distance, laps): (f64, i64) = conn
+            .query_row(
+                "select max(distance), (select count(*) from lap_messages where file_id = ?1)
+                 from record_messages where file_id = ?1",
+                params![info.id()],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert!((distance - 22.2).abs() < 0.5, "distance = {}", distance);
+        assert_eq!(laps, 1);
+
+        let tx = conn.transaction().unwrap();
+        assert!(matches!(
+            import_gpx_data(gpx.as_bytes(), &tx),
+            Err(Error::DuplicateFileError(_))
+        ));
+    }
+}
@@ -1,11 +1,23 @@
 //! Define FIT file import command
 use crate::config::Config;
-use crate::services::update_elevation_data;
-use crate::{devices_dir, import_fit_data, open_db_connection, Error, FileInfo};
+use crate::db::new_file_info_query;
+use crate::gps::{estimate_utc_offset, Location};
+use crate::services::{update_elevation_data, ElevationDataSource};
+use crate::validation::{check_records, filter_gps_jumps, ValidationThresholds};
+use crate::{
+    devices_dir, generate_uuid, import_activity_file, is_ephemeral, is_track_file, DbWriter, Error,
+    FileInfo, StoredFields,
+};
+use chrono::{DateTime, Duration, Utc};
 use log::{debug, error, info, trace, warn};
-use rusqlite::Connection;
+use rusqlite::{params, Connection, Transaction};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
 use std::fs::{copy as copy_file, create_dir_all, read_dir, File};
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
 use structopt::StructOpt;
 
@@ -21,6 +33,10 @@ pub struct ImportOpts {
     /// Do not copy imported FIT files into the devices directory
     #[structopt(long)]
     no_copy: bool,
+    /// Copy imported FIT files into the devices directory even if persist_imported_files is
+    /// disabled in the config
+    #[structopt(long, conflicts_with = "no-copy")]
+    copy: bool,
     /// Do not search the import paths defined in the application config
     #[structopt(long)]
     skip_config_paths: bool,
@@ -30,6 +46,43 @@ pub struct ImportOpts {
     /// How to respond to import eerrors
     #[structopt(long, default_value = "warn")]
     import_errors: ImportErrorBehavior,
+    /// Check every file found while scanning directories, by default files that haven't been
+    /// modified since the last import are skipped
+    #[structopt(long)]
+    full: bool,
+    /// Only import the activity with this index (starting from 0) from a file that contains
+    /// several, e.g. a monitoring file. Activities are defined by the file's session messages.
+    /// Only a single file can be imported this way.
+    #[structopt(long)]
+    activity_index: Option<usize>,
+    /// Check records for implausible heart rate, speed and elevation changes and log a warning
+    /// for each file that fails. Use --validate=strict to reject those files instead. The limits
+    /// are set in the validation section of the config.
+    #[structopt(long, require_equals = true)]
+    validate: Option<Option<ValidationMode>>,
+    /// Warn about activities that start and end within --overlap-tolerance of a file already
+    /// imported from any device, e.g. when wearing two watches. Use --dedupe-across-devices=skip
+    /// to skip importing them instead.
+    #[structopt(long, require_equals = true)]
+    dedupe_across_devices: Option<Option<DedupeMode>>,
+    /// Seconds the start and end times of two activities can differ by to be considered the same
+    #[structopt(long, default_value = "120")]
+    overlap_tolerance: i64,
+    /// Exit with code 2 after importing everything else if any file failed to import, errors are
+    /// still reported according to --import-errors
+    #[structopt(long)]
+    fail_on_error: bool,
+    /// Fail the import if the after_import hook from the config can't be run or exits with an
+    /// error, by default only a warning is logged
+    #[structopt(long)]
+    strict_hooks: bool,
+}
+
+impl ImportOpts {
+    /// Return true if files that failed to import should fail the command
+    pub(super) fn fail_on_error(&self) -> bool {
+        self.fail_on_error
+    }
 }
 
 /// How we should handle dupes during imports
@@ -40,6 +93,88 @@ enum DuplicateFileBehavior {
     Suppress,
 }
 
+/// How files that fail the data sanity checks are handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ValidationMode {
+    Warn,
+    Strict,
+}
+
+impl FromStr for ValidationMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warn" => Ok(ValidationMode::Warn),
+            "strict" => Ok(ValidationMode::Strict),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: warn, strict"
+            ))),
+        }
+    }
+}
+
+/// How activities overlapping an existing file are handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DedupeMode {
+    Warn,
+    Skip,
+}
+
+impl FromStr for DedupeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warn" => Ok(DedupeMode::Warn),
+            "skip" => Ok(DedupeMode::Skip),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: warn, skip"
+            ))),
+        }
+    }
+}
+
+/// Options applied to each file as it is imported
+struct FileImportOptions<'a> {
+    /// Path template for the copy kept in the devices directory, None skips the copy
+    persist_file: Option<&'a str>,
+    activity_index: Option<usize>,
+    validation: Option<(ValidationMode, &'a ValidationThresholds)>,
+    /// Drop record positions that jump faster than this speed in meters per second
+    max_gps_jump_speed: Option<f64>,
+    infer_timezone: bool,
+    dedupe: Option<(DedupeMode, Duration)>,
+    /// How failures other than duplicates are reported
+    import_errors: ImportErrorBehavior,
+    stored_fields: &'a StoredFields,
+}
+
+/// Tally of what happened to each file an import looked at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    imported: usize,
+    duplicates: usize,
+    errors: usize,
+}
+
+impl ImportSummary {
+    /// Number of files that failed to import
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Imported {} file(s), skipped {} duplicate(s), {} error(s)",
+            self.imported, self.duplicates, self.errors
+        )
+    }
+}
+
 /// How we should handle dupes during imports
 #[derive(Clone, Copy, Debug)]
 enum ImportErrorBehavior {
@@ -52,19 +187,22 @@ impl FromStr for ImportErrorBehavior {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        return match s.to_ascii_lowercase().as_str() {
+        match s.to_ascii_lowercase().as_str() {
             "error" => Ok(ImportErrorBehavior::Error),
             "warn" => Ok(ImportErrorBehavior::Warn),
             "suppress" => Ok(ImportErrorBehavior::Suppress),
             _ => Err(Error::InvalidConfigurationValue(format!(
                 "Unknown value {s}: expected: error, warn, suppress"
             ))),
-        };
+        }
     }
 }
 
 /// Implementation of the `import` subcommand
-pub fn import_command(config: Config, opts: ImportOpts) -> Result<(), Box<dyn std::error::Error>> {
+pub fn import_command(
+    config: Config,
+    opts: ImportOpts,
+) -> Result<ImportSummary, Box<dyn std::error::Error>> {
     // fetch elecation service from config
     let elevation_hdl = if !opts.no_elevation {
         match config.get_elevation_handler() {
@@ -92,6 +230,14 @@ pub fn import_command(config: Config, opts: ImportOpts) -> Result<(), Box<dyn st
             "No import paths provided".to_string(),
         )));
     }
+    // an activity index only identifies an activity within a single file
+    if opts.activity_index.is_some() && (import_paths.len() > 1 || import_paths[0].is_dir()) {
+        return Err(Box::new(Error::Other(
+            "--activity-index requires a single file to import, use --skip-config-paths to \
+             leave out the configured import paths"
+                .to_string(),
+        )));
+    }
 
     // Import FIT files from the defined paths
     let dupe_err = if import_paths.len() == 1 {
@@ -100,45 +246,149 @@ pub fn import_command(config: Config, opts: ImportOpts) -> Result<(), Box<dyn st
     } else {
         DuplicateFileBehavior::Warn
     };
-    let mut conn = open_db_connection()?;
-    let imported_files = import_files(
-        &mut conn,
-        &import_paths,
-        opts.recursive,
-        dupe_err,
-        opts.import_errors,
-        !opts.no_copy,
-    )?;
+    let writer = DbWriter::open()?;
+    let started_at = Utc::now();
+    let file_opts = FileImportOptions {
+        persist_file: if opts.no_copy
+            || (!opts.copy && !config.persist_imported_files())
+            || is_ephemeral()
+        {
+            None
+        } else {
+            Some(config.persisted_file_name())
+        },
+        activity_index: opts.activity_index,
+        validation: opts
+            .validate
+            .map(|mode| (mode.unwrap_or(ValidationMode::Warn), config.validation())),
+        max_gps_jump_speed: config.max_gps_jump_speed(),
+        infer_timezone: config.infer_timezone(),
+        dedupe: opts.dedupe_across_devices.map(|mode| {
+            (
+                mode.unwrap_or(DedupeMode::Warn),
+                Duration::seconds(opts.overlap_tolerance),
+            )
+        }),
+        import_errors: opts.import_errors,
+        stored_fields: config.stored_fields(),
+    };
+    let mut summary = ImportSummary::default();
+    let mut imported_files = Vec::new();
+    for path in &import_paths {
+        // each path is compared against its own last import so paths that are new or only
+        // imported now and then are scanned in full
+        let scan = if opts.full {
+            IncrementalScan::full()
+        } else {
+            writer.read(|conn| IncrementalScan::load(conn, path))?
+        };
+        imported_files.extend(import_files(
+            &writer,
+            std::slice::from_ref(path),
+            opts.recursive,
+            dupe_err,
+            &file_opts,
+            &scan,
+            &mut summary,
+        )?);
+    }
+    for path in import_paths.iter().filter(|p| p.is_dir()) {
+        writer.write(|tx| record_import_run(tx, path, started_at))?;
+    }
 
     // add elevation data after importing all the files
+    let uuids: Vec<String> = imported_files
+        .iter()
+        .map(|f| f.uuid().to_string())
+        .collect();
     if let Some(hdl) = elevation_hdl {
-        // we overwrite here on the assumption that API provides more accurate values than the
-        // device, if the device provided any at all
-        for file_info in imported_files {
-            if file_info.id().is_none() {
-                error!(
-                    "Imported file with UUID={} has no file_id cannot update elevation data.",
+        add_elevation_data(&writer, hdl.as_ref(), imported_files)?;
+    }
+
+    match config.after_import() {
+        Some(command) if !uuids.is_empty() => {
+            if let Err(e) = run_after_import_hook(command, &uuids) {
+                if opts.strict_hooks {
+                    return Err(Box::new(e));
+                }
+                warn!("{}", e);
+            }
+        }
+        Some(_) => debug!("Skipping after_import hook since no files were imported"),
+        None => {}
+    }
+
+    Ok(summary)
+}
+
+/// Run the after_import command through the shell, the UUIDs of the imported files are passed
+/// as positional arguments and in the GARMIN_RUN_TRACKER_UUIDS environment variable.
+fn run_after_import_hook(command: &str, uuids: &[String]) -> Result<(), Error> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = process::Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        // the first argument after the script becomes $0
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c").arg(command).arg("after_import");
+        cmd
+    };
+    debug!("Running after_import hook: {}", command);
+    let status = cmd
+        .args(uuids)
+        .env("GARMIN_RUN_TRACKER_UUIDS", uuids.join(" "))
+        .status()
+        .map_err(|e| Error::Other(format!("Could not run after_import hook - {}", e)))?;
+    if status.success() {
+        info!("after_import hook finished with {}", status);
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "after_import hook failed with {}",
+            status
+        )))
+    }
+}
+
+/// Fetch elevation data for newly imported files, failures are logged and don't stop the
+/// remaining files from being updated.
+pub(super) fn add_elevation_data(
+    writer: &DbWriter,
+    hdl: &dyn ElevationDataSource,
+    files: Vec<FileInfo>,
+) -> Result<(), Error> {
+    // we overwrite here on the assumption that API provides more accurate values than the
+    // device, if the device provided any at all
+    for file_info in files {
+        if file_info.id().is_none() {
+            error!(
+                "Imported file with UUID={} has no file_id cannot update elevation data.",
+                file_info.uuid()
+            );
+            continue;
+        }
+        match writer.write(|tx| Ok(update_elevation_data(tx, hdl, file_info.id(), true)?)) {
+            Ok(_) => {
+                info!(
+                    "Successfully imported elevation for FIT file '{}'",
                     file_info.uuid()
                 );
-                continue;
             }
-            let tx = conn.transaction()?;
-            match update_elevation_data(&tx, hdl.as_ref(), file_info.id(), true) {
-                Ok(_) => {
-                    tx.commit()?;
-                    info!(
-                        "Successfully imported elevation for FIT file '{}'",
-                        file_info.uuid()
-                    );
-                }
-                Err(e) => {
-                    tx.rollback()?;
-                    error!(
-                        "Could not import elevation data from the API for FIT file '{}'",
-                        file_info.uuid()
-                    );
-                    error!("{}", e);
-                }
+            Err(e) => {
+                error!(
+                    "Could not import elevation data from the API for FIT file '{}'",
+                    file_info.uuid()
+                );
+                error!("{}", e);
+                // flag the file so update-elevation --fix-missing retries it later
+                writer.write(|tx| {
+                    tx.execute(
+                        "update files set elevation_pending = 1 where id = ?",
+                        params![file_info.id()],
+                    )?;
+                    Ok(())
+                })?;
             }
         }
     }
@@ -148,12 +398,13 @@ pub fn import_command(config: Config, opts: ImportOpts) -> Result<(), Box<dyn st
 
 /// import multiple files into the database as well as handle recursive directory searches
 fn import_files(
-    conn: &mut Connection,
+    writer: &DbWriter,
     paths: &[PathBuf],
     recursive: bool,
     dupe_err: DuplicateFileBehavior,
-    import_err: ImportErrorBehavior,
-    persist_file: bool,
+    file_opts: &FileImportOptions,
+    scan: &IncrementalScan,
+    summary: &mut ImportSummary,
 ) -> Result<Vec<FileInfo>, Error> {
     let mut file_infos = Vec::new();
     for path in paths {
@@ -162,38 +413,35 @@ fn import_files(
             continue;
         }
         if path.is_dir() {
-            debug!("Scanning contents of: {:?} for FIT files", path);
-            // collect files with the "FIT" extension from the directory and if we are processing
-            // directories recursively incldue them in the import call.
-            let new_paths = read_dir(path)?;
-            let new_paths: Vec<PathBuf> = new_paths
-                .filter_map(|d| d.ok())
-                .map(|d| d.path())
-                .filter(|p| {
-                    p.is_dir() && recursive
-                        || p.extension()
-                            .map_or(false, |e| e.to_string_lossy().to_ascii_lowercase() == "fit")
-                })
-                .collect();
+            let new_paths = scan_directory(path, recursive, Some(scan))?;
             // call function with found paths, suppress dupe errors since we're recursing
             import_files(
-                conn,
+                writer,
                 &new_paths,
                 recursive,
                 DuplicateFileBehavior::Suppress,
-                import_err,
-                persist_file,
+                file_opts,
+                scan,
+                summary,
             )
             .map(|v| file_infos.extend(v))?;
         } else {
             let fname = path
                 .file_name()
-                .map(|v| v.to_str())
-                .flatten()
+                .and_then(|v| v.to_str())
                 .unwrap_or("UNKOWN");
-            match import_file(conn, path, persist_file) {
-                Ok(file_info) => file_infos.push(file_info),
+            match import_file(writer, path, file_opts) {
+                Ok(file_info) => {
+                    summary.imported += 1;
+                    file_infos.push(file_info)
+                }
                 Err(e) => {
+                    match &e {
+                        Error::DuplicateFileError(_) | Error::OverlappingFileError(..) => {
+                            summary.duplicates += 1
+                        }
+                        _ => summary.errors += 1,
+                    }
                     // handle dupe errors
                     match &e {
                         Error::DuplicateFileError(_) => match dupe_err {
@@ -210,7 +458,12 @@ fn import_files(
                                 continue;
                             }
                         },
-                        _ => match import_err {
+                        // skipped overlaps are always reported so they aren't lost in a scan
+                        Error::OverlappingFileError(..) => {
+                            warn!("File {:?}: {}", fname, e);
+                            continue;
+                        }
+                        _ => match file_opts.import_errors {
                             ImportErrorBehavior::Error => {
                                 error!("File {:?}: {}", fname, e);
                                 return Err(e);
@@ -233,43 +486,410 @@ fn import_files(
     Ok(file_infos)
 }
 
+/// Collect FIT files from the directory and if we are processing directories recursively include
+/// any sub directories as well. Files without the "FIT" extension are included if their header
+/// identifies them as FIT data. Files the incremental scan doesn't need are left out.
+pub(super) fn scan_directory(
+    path: &Path,
+    recursive: bool,
+    scan: Option<&IncrementalScan>,
+) -> Result<Vec<PathBuf>, Error> {
+    debug!("Scanning contents of: {:?} for FIT files", path);
+    Ok(read_dir(path)?
+        .filter_map(|d| d.ok())
+        .map(|d| d.path())
+        .filter(|p| {
+            if p.is_dir() {
+                recursive
+            } else if !scan.is_none_or(|s| s.includes(p)) {
+                // checked first so unchanged files aren't opened again on every import
+                false
+            } else if has_fit_extension(p) || is_track_file(p) {
+                true
+            } else if sniff_fit_header(p) {
+                warn!("Found FIT data in file without a FIT extension: {:?}", p);
+                true
+            } else {
+                false
+            }
+        })
+        .collect())
+}
+
+/// Check if the file has a FIT extension, ignoring case
+fn has_fit_extension(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.to_string_lossy().to_ascii_lowercase() == "fit")
+}
+
+/// Check for the ".FIT" signature at byte offset 8 of the file header, some devices store
+/// activity files without an extension.
+fn sniff_fit_header(path: &Path) -> bool {
+    let mut header = [0u8; 12];
+    File::open(path)
+        .and_then(|mut fp| fp.read_exact(&mut header))
+        .is_ok()
+        && &header[8..12] == b".FIT"
+}
+
+/// Decides which of the files found under an import path are checked. Files that haven't been
+/// modified since the path was last imported are skipped, unless they failed to import.
+#[derive(Debug, Default)]
+pub(super) struct IncrementalScan {
+    modified_after: Option<DateTime<Utc>>,
+    /// Canonical paths of the files that failed to import and haven't been imported since
+    failed: HashSet<PathBuf>,
+}
+
+impl IncrementalScan {
+    /// Check every file
+    pub fn full() -> Self {
+        IncrementalScan::default()
+    }
+
+    /// Skip the files under the path that haven't changed since its most recent import run
+    pub fn load(conn: &Connection, path: &Path) -> Result<Self, Error> {
+        let modified_after: Option<DateTime<Utc>> = conn.query_row(
+            "select max(started_at) from import_history where path = ?",
+            params![import_path_key(path)],
+            |r| r.get(0),
+        )?;
+        if let Some(time) = modified_after {
+            debug!("Skipping files in {:?} not modified since {}", path, time);
+        }
+        let mut stmt = conn.prepare("select distinct path from import_errors")?;
+        let failed = stmt
+            .query_map(params![], |r| r.get::<usize, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter_map(|p| Path::new(&p).canonicalize().ok())
+            .collect();
+        Ok(IncrementalScan {
+            modified_after,
+            failed,
+        })
+    }
+
+    /// Return true if the file needs to be checked, files are always checked if their
+    /// modification time can't be read
+    pub fn includes(&self, path: &Path) -> bool {
+        let time = match self.modified_after {
+            Some(time) => time,
+            None => return true,
+        };
+        match path.metadata().and_then(|m| m.modified()) {
+            Ok(modified) if DateTime::<Utc>::from(modified) >= time => true,
+            Ok(_) if path.canonicalize().is_ok_and(|p| self.failed.contains(&p)) => {
+                trace!("Retrying file that failed to import: {:?}", path);
+                true
+            }
+            Ok(_) => {
+                trace!("Skipping file not modified since last import: {:?}", path);
+                false
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+/// Remember when the path was imported so the next import only checks files modified since
+fn record_import_run(
+    conn: &Connection,
+    path: &Path,
+    started_at: DateTime<Utc>,
+) -> Result<(), Error> {
+    conn.execute(
+        "insert into import_history (started_at, path) values (?, ?)",
+        params![started_at, import_path_key(path)],
+    )?;
+    Ok(())
+}
+
+/// Import paths are tracked by their canonical form so e.g. "./runs" and "runs" match
+fn import_path_key(path: &Path) -> String {
+    path.canonicalize()
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
 /// Import a FIT files into the database, optionally fetching elevation data from an external service
 fn import_file(
-    conn: &mut Connection,
+    writer: &DbWriter,
     file: &PathBuf,
-    persist_file: bool,
+    file_opts: &FileImportOptions,
 ) -> Result<FileInfo, Error> {
     trace!("Importing FIT file: {:?}", file);
-    let tx = conn.transaction()?;
-    let mut fp = File::open(&file)?;
-    let file_info = import_fit_data(&mut fp, &tx)?;
+    let mut data = Vec::new();
+    File::open(file)?.read_to_end(&mut data)?;
+    let (file_info, dest) = match writer.write(|tx| store_file(tx, file, &data, file_opts)) {
+        Ok(stored) => stored,
+        Err(e) => {
+            // duplicates, skipped overlaps and a bad template in the config aren't problems
+            // with the file itself
+            if !matches!(
+                e,
+                Error::DuplicateFileError(_)
+                    | Error::OverlappingFileError(..)
+                    | Error::InvalidConfigurationValue(_)
+            ) {
+                writer.write(|tx| record_import_error(tx, &generate_uuid(&data), file, &e))?;
+            }
+            return Err(e);
+        }
+    };
     info!(
         "Successfully imported FIT file: {:?} (UUID={})",
         &file,
         file_info.uuid()
     );
-    tx.commit()?;
 
     // copy FIT file to a local storage location since the device itself will delete the
     // file when it needs space.
-    if persist_file {
-        let sub_dir_name = format!(
-            "{}-{}-{}",
-            file_info.manufacturer(),
-            file_info.product(),
-            file_info.serial_number()
-        );
-        let mut dest = devices_dir().join(&sub_dir_name);
-        if !dest.exists() {
-            create_dir_all(&dest)?;
+    if let Some(dest) = dest {
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent)?;
         }
-        match file.file_name() {
-            Some(name) => dest.push(name),
-            None => dest.push(&format!("{}.fit", file_info.uuid())),
-        };
-        copy_file(&file, &dest)?;
+        copy_file(file, &dest)?;
         info!("Successfully copied FIT file {:?} to {:?}", &file, &dest);
     }
 
     Ok(file_info)
 }
+
+/// Store the file's data and run the configured checks on it, an error rolls back the whole
+/// import. Returns where the copy of the file should be kept, if anywhere.
+fn store_file(
+    tx: &Transaction,
+    file: &Path,
+    data: &[u8],
+    file_opts: &FileImportOptions,
+) -> Result<(FileInfo, Option<PathBuf>), Error> {
+    let file_info = import_activity_file(
+        file,
+        data,
+        tx,
+        file_opts.activity_index,
+        file_opts.stored_fields,
+    )?;
+    if let Some(max_speed) = file_opts.max_gps_jump_speed {
+        remove_gps_jumps(tx, &file_info, max_speed)?;
+    }
+    if file_opts.infer_timezone {
+        infer_timezone(tx, &file_info)?;
+    }
+    if let Some((mode, thresholds)) = file_opts.validation {
+        let issues = check_records(tx, file_info.id(), thresholds)?;
+        for issue in &issues {
+            warn!("File {:?} (UUID={}): {}", file, file_info.uuid(), issue);
+        }
+        if mode == ValidationMode::Strict && !issues.is_empty() {
+            return Err(Error::InvalidFitFile(format!(
+                "Failed validation with {} problem(s): {}",
+                issues.len(),
+                issues.join(", ")
+            )));
+        }
+    }
+    if let Some((mode, tolerance)) = file_opts.dedupe {
+        if let Some(other) = find_overlapping_file(tx, file_info.id(), tolerance)? {
+            let e =
+                Error::OverlappingFileError(file_info.uuid().to_string(), other.uuid().to_string());
+            if mode == DedupeMode::Skip {
+                return Err(e);
+            }
+            warn!(
+                "File {:?}: {} recorded by {}-{}",
+                file,
+                e,
+                other.manufacturer(),
+                other.product()
+            );
+        }
+    }
+    // a bad template in the config shouldn't leave the file imported without its copy
+    let dest = match file_opts.persist_file {
+        Some(template) => {
+            Some(devices_dir().join(file_info.persisted_path(template, file.file_name())?))
+        }
+        None => None,
+    };
+    tx.execute(
+        "delete from import_errors where uuid = ?",
+        params![generate_uuid(data)],
+    )?;
+
+    Ok((file_info, dest))
+}
+
+/// Drop record positions that jump faster than the speed allows, the rest of the record is kept
+pub(super) fn remove_gps_jumps(
+    conn: &Connection,
+    file_info: &FileInfo,
+    max_speed: f64,
+) -> Result<(), Error> {
+    let removed = filter_gps_jumps(conn, file_info.id(), max_speed)?;
+    if removed > 0 {
+        info!(
+            "Removed {} GPS positions jumping faster than {} m/s from UUID={}",
+            removed,
+            max_speed,
+            file_info.uuid()
+        );
+    }
+    Ok(())
+}
+
+/// Store a timezone offset estimated from the first GPS position for files that didn't record
+/// one, files without any positions keep using the local timezone
+pub(super) fn infer_timezone(conn: &Connection, file_info: &FileInfo) -> Result<(), Error> {
+    if file_info.timezone_offset().is_some() {
+        return Ok(());
+    }
+    let position = conn.query_row(
+        "select position_lat, position_long from record_messages
+         where file_id = ? and position_lat is not null and position_long is not null
+         order by timestamp limit 1",
+        params![file_info.id()],
+        |r| Ok(Location::from_fit_coordinates(r.get(0)?, r.get(1)?)),
+    );
+    let location = match position {
+        Ok(location) => location,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            debug!(
+                "No GPS positions to infer the timezone of UUID={}",
+                file_info.uuid()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let offset = estimate_utc_offset(location.longitude());
+    conn.execute(
+        "update files set timezone_offset = ? where id = ?",
+        params![offset, file_info.id()],
+    )?;
+    info!(
+        "Inferred a UTC offset of {:+} hours for UUID={} from its GPS position",
+        offset / 3600,
+        file_info.uuid()
+    );
+    Ok(())
+}
+
+/// Find a file from any device whose records start and end within the tolerance of the given
+/// file's records, e.g. the same run recorded by two watches
+fn find_overlapping_file(
+    conn: &Connection,
+    file_id: Option<u32>,
+    tolerance: Duration,
+) -> Result<Option<FileInfo>, Error> {
+    let (start, end) = match record_time_range(conn, file_id)? {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+
+    // only files created around the same time are candidates so every record isn't scanned,
+    // the window is generous since devices set time_created differently
+    let window = Duration::days(1);
+    let mut query = new_file_info_query();
+    query.and_where("id != ?");
+    query.and_where("time_created >= ?");
+    query.and_where("time_created <= ?");
+    let mut stmt = conn.prepare(&query.to_string())?;
+    let candidates = stmt
+        .query_map(params![file_id, start - window, end + window], |r| {
+            FileInfo::try_from(r)
+        })?
+        .collect::<Result<Vec<FileInfo>, rusqlite::Error>>()?;
+    for other in candidates {
+        if let Some((other_start, other_end)) = record_time_range(conn, other.id())? {
+            if (other_start - start).abs() <= tolerance && (other_end - end).abs() <= tolerance {
+                return Ok(Some(other));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Start and end time of a file's records
+type TimeRange = (DateTime<Utc>, DateTime<Utc>);
+
+fn record_time_range(conn: &Connection, file_id: Option<u32>) -> Result<Option<TimeRange>, Error> {
+    let range = conn.query_row(
+        "select min(timestamp), max(timestamp) from record_messages where file_id = ?",
+        params![file_id],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    match range {
+        (Some(start), Some(end)) => Ok(Some((start, end))),
+        _ => Ok(None),
+    }
+}
+
+/// Store the reason a file failed to import so it can be reported on later
+fn record_import_error(
+    conn: &Connection,
+    uuid: &str,
+    file: &Path,
+    err: &Error,
+) -> Result<(), Error> {
+    conn.execute(
+        "insert into import_errors (uuid, path, message, occurred_at) values (?, ?, ?, ?)",
+        params![uuid, file.to_string_lossy(), err.to_string(), Utc::now()],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{memory_db, simple_run, START};
+
+    /// Create a directory in the temp directory holding a single FIT file
+    fn import_dir(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("grt-{}-{}", name, process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let file = dir.join("run.fit");
+        std::fs::write(&file, simple_run(START)).unwrap();
+        (dir, file)
+    }
+
+    #[test]
+    fn incremental_scan_tracks_each_import_path() {
+        let conn = memory_db();
+        let (dir, file) = import_dir("scan-paths");
+        let (other_dir, _) = import_dir("scan-paths-other");
+        let later = Utc::now() + Duration::hours(1);
+
+        // importing another path doesn't skip the files of one that was never imported
+        record_import_run(&conn, &other_dir, later).unwrap();
+        assert!(IncrementalScan::load(&conn, &dir).unwrap().includes(&file));
+
+        record_import_run(&conn, &dir, later).unwrap();
+        assert!(!IncrementalScan::load(&conn, &dir).unwrap().includes(&file));
+        assert!(IncrementalScan::full().includes(&file));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other_dir).unwrap();
+    }
+
+    #[test]
+    fn incremental_scan_retries_failed_files() {
+        let conn = memory_db();
+        let (dir, file) = import_dir("scan-errors");
+        record_import_run(&conn, &dir, Utc::now() + Duration::hours(1)).unwrap();
+        assert!(!IncrementalScan::load(&conn, &dir).unwrap().includes(&file));
+
+        conn.execute(
+            "insert into import_errors (uuid, path, message, occurred_at) values (?, ?, ?, ?)",
+            params!["uuid", file.to_string_lossy(), "bad file", Utc::now()],
+        )
+        .unwrap();
+        assert!(IncrementalScan::load(&conn, &dir).unwrap().includes(&file));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
 //! Define the list-files subcommand
 use super::parse_date;
-use crate::db::{new_file_info_query, open_db_connection};
-use crate::FileInfo;
+use crate::config::Config;
+use crate::db::{climb_totals_query, new_file_info_query, open_db_connection};
+use crate::{
+    format_pace, DistanceUnit, ElevationSource, ElevationUnit, FileInfo, RestLapThresholds,
+};
 use chrono::{DateTime, Local, NaiveDate};
 use rusqlite::types::Value;
 use rusqlite::{params, params_from_iter, Connection, Result};
@@ -16,10 +19,10 @@ pub struct ListFilesOpts {
     /// Hide per file statistics and only show date, device and UUID of entries
     #[structopt(short, long)]
     short: bool,
-    /// List files after the specified date (YYYY-MM-DD format)
+    /// List files after the specified date (YYYY-MM-DD format or today, yesterday, 7d, 2w, 1m)
     #[structopt(short="-S", long, parse(try_from_str = parse_date))]
     since: Option<NaiveDate>,
-    /// List files before the specified date (YYYY-MM-DD format)
+    /// List files before the specified date (YYYY-MM-DD format or today, yesterday, 7d, 2w, 1m)
     #[structopt(short="-U", long, parse(try_from_str = parse_date))]
     until: Option<NaiveDate>,
     /// Reverse file ordering to be old -> new
@@ -28,9 +31,15 @@ pub struct ListFilesOpts {
     /// Limit results returned to the last "N" entries, use 0 to list all results
     #[structopt(short, long, default_value = "5")]
     number: usize,
+    /// Display times in UTC instead of the timezone the activity was recorded in
+    #[structopt(long)]
+    utc: bool,
 }
 
-pub fn list_files_command(opts: ListFilesOpts) -> Result<(), Box<dyn std::error::Error>> {
+pub fn list_files_command(
+    config: Config,
+    opts: ListFilesOpts,
+) -> Result<(), Box<dyn std::error::Error>> {
     let conn = open_db_connection()?;
 
     // collect all the files we are interested in
@@ -74,36 +83,61 @@ pub fn list_files_command(opts: ListFilesOpts) -> Result<(), Box<dyn std::error:
     // grab aggregrate and lap stats
     if opts.short {
         let agg_data = collect_aggregate_stats(&conn, Rc::clone(&values))?;
-        short_output(&files, agg_data);
+        short_output(&config, &files, agg_data, opts.utc);
     } else {
-        let agg_data = collect_aggregate_stats(&conn, Rc::clone(&values))?;
-        let lap_data = collect_lap_stats(&conn, Rc::clone(&values))?;
-        long_output(&files, agg_data, lap_data);
+        let mut agg_data = collect_aggregate_stats(&conn, Rc::clone(&values))?;
+        let lap_data = collect_lap_stats(&conn, Rc::clone(&values), config.rest_laps())?;
+        for (file_id, timer_time) in collect_timer_times(&conn, Rc::clone(&values))? {
+            if let Some(data) = agg_data.get_mut(&file_id) {
+                data.insert("timer_time", timer_time);
+            }
+        }
+        for (file_id, calories) in collect_calories(&conn, Rc::clone(&values))? {
+            if let Some(data) = agg_data.get_mut(&file_id) {
+                data.insert("calories", calories);
+            }
+        }
+        let elevation_source = config.elevation_source();
+        for (file_id, ascent) in collect_ascents(&conn, Rc::clone(&values), elevation_source)? {
+            if let Some(data) = agg_data.get_mut(&file_id) {
+                data.insert("ascent", ascent);
+            }
+        }
+        let threshold = config.moving_speed_threshold();
+        for (file_id, moving_time) in collect_moving_times(&conn, Rc::clone(&values), threshold)? {
+            if let Some(data) = agg_data.get_mut(&file_id) {
+                data.insert("moving_time", moving_time);
+            }
+        }
+        long_output(&config, &files, agg_data, lap_data, opts.utc);
     };
 
     Ok(())
 }
 
-fn short_output(files: &[FileInfo], agg_data: HashMap<u32, HashMap<&'static str, f64>>) {
-    println!("Date\tDistance[mi]\tPace[mi/min]\tUUID");
+fn short_output(
+    config: &Config,
+    files: &[FileInfo],
+    agg_data: HashMap<u32, HashMap<&'static str, f64>>,
+    utc: bool,
+) {
+    summary!("Date\tDistance[mi]\tPace[mi/min]\tUUID");
     for file in files {
-        match file.id.map(|id| agg_data.get(&id)).flatten() {
+        match file.id.and_then(|id| agg_data.get(&id)) {
             Some(data) => {
                 println!(
-                    "{:10}\t{:0.2}\t{:2}:{:02.0}\t({})",
-                    file.timestamp.format("%Y-%m-%d"),
+                    "{:10}\t{:0.2}\t{:>5}\t({})",
+                    file.format_timestamp("%Y-%m-%d", utc),
                     data["total_distance"],
-                    data["avg_pace"] as i32,
-                    (data["avg_pace"] - data["avg_pace"].floor()) * 60.0,
+                    format_pace(data.get("avg_speed").copied(), DistanceUnit::Mile),
                     file.uuid
                 );
             }
             None => {
                 println!(
-                    "{} {}-{} ({})",
-                    file.timestamp.format("%Y-%m-%d %H:%M"),
-                    file.manufacturer,
-                    file.product,
+                    "{} {} ({})",
+                    file.format_timestamp("%Y-%m-%d %H:%M", utc),
+                    config.device_name(&file.manufacturer, &file.product),
                     file.uuid
                 );
             }
@@ -112,17 +146,19 @@ fn short_output(files: &[FileInfo], agg_data: HashMap<u32, HashMap<&'static str,
 }
 
 fn long_output(
+    config: &Config,
     files: &[FileInfo],
     agg_data: HashMap<u32, HashMap<&'static str, f64>>,
     lap_data: HashMap<u32, Vec<HashMap<&'static str, f64>>>,
+    utc: bool,
 ) {
-    println!("Date, Device, UUID");
+    let elevation_unit = config.units().elevation_unit();
+    summary!("Date, Device, UUID");
     for file in files {
         println!(
-            "{} ({}-{} {})",
-            file.timestamp.format("%Y-%m-%d %H:%M"),
-            file.manufacturer,
-            file.product,
+            "{} ({} {})",
+            file.format_timestamp("%Y-%m-%d %H:%M %:z", utc),
+            config.device_name(&file.manufacturer, &file.product),
             file.uuid
         );
         let file_id = if let Some(val) = file.id {
@@ -131,32 +167,95 @@ fn long_output(
             continue;
         };
         if let Some(data) = agg_data.get(&file_id) {
+            // timer time is only known when the file recorded timer events
+            let other_times: Vec<String> = [("Timer", "timer_time"), ("Moving", "moving_time")]
+                .iter()
+                .filter_map(|(label, key)| {
+                    let v = data.get(key)?;
+                    Some(format!("{}: {}", label, format_minutes(*v)))
+                })
+                .collect();
+            let other_times = if other_times.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", other_times.join(", "))
+            };
             println!(
-                "\t Distance: {:0.2} miles, Time: {:3}:{:02.0}, \
-                     Pace: {:2}:{:02.0}, Heart Rate: {:0.0}bpm",
+                "\t Distance: {:0.2} miles, Time: {:3}:{:02.0}{}, \
+                     Pace: {:>5}, Heart Rate: {:0.0}bpm{}{}",
                 data["total_distance"],
                 data["total_time"] as i32,
                 (data["total_time"] - data["total_time"].floor()) * 60.0,
-                data["avg_pace"] as i32,
-                (data["avg_pace"] - data["avg_pace"].floor()) * 60.0,
-                data["avg_heart_rate"]
+                other_times,
+                format_pace(data.get("avg_speed").copied(), DistanceUnit::Mile),
+                data["avg_heart_rate"],
+                format_ascent(data.get("ascent"), elevation_unit),
+                format_calories(data.get("calories"))
             );
         }
         if let Some(data) = lap_data.get(&file_id) {
+            // consecutive rest laps are collapsed into a single line, running laps keep the
+            // number the device gave them
+            let mut rest: Option<(f64, f64)> = None;
             for (i, lap) in data.iter().enumerate() {
+                if lap.contains_key("rest") {
+                    let (distance, time) = rest.unwrap_or((0.0, 0.0));
+                    rest = Some((distance + lap["total_distance"], time + lap["total_time"]));
+                    continue;
+                }
+                if let Some((distance, time)) = rest.take() {
+                    print_rest(distance, time);
+                }
                 println!(
-                    "\t * Lap {:02} - {:0.2} miles, Time: {:3}:{:02.0}, Heart Rate: {:0.0}bpm",
+                    "\t * Lap {:02} - {:0.2} miles, Time: {:3}:{:02.0}, Heart Rate: {:0.0}bpm{}",
                     i + 1,
                     lap["total_distance"],
                     lap["total_time"] as i32,
                     (lap["total_time"] - lap["total_time"].floor()) * 60.0,
-                    lap["avg_heart_rate"]
+                    lap["avg_heart_rate"],
+                    format_calories(lap.get("calories"))
                 );
             }
+            if let Some((distance, time)) = rest {
+                print_rest(distance, time);
+            }
         }
     }
 }
 
+/// Print the total distance (in miles) and time (in minutes) of one or more consecutive rest laps
+fn print_rest(distance: f64, time: f64) {
+    println!(
+        "\t * Rest    - {:0.2} miles, Time: {:3}:{:02.0}",
+        distance,
+        time as i32,
+        (time - time.floor()) * 60.0
+    );
+}
+
+/// Format the total ascent (in meters) for appending to a line of stats, files without
+/// elevation data leave it out
+fn format_ascent(ascent: Option<&f64>, unit: ElevationUnit) -> String {
+    ascent.map_or_else(String::new, |a| {
+        format!(
+            ", Ascent: {:0.0}{}",
+            unit.from_meters(*a),
+            unit.abbreviation()
+        )
+    })
+}
+
+/// Format a time in minutes as M:SS, rounded to the nearest second so e.g. 4.9999 becomes 5:00
+fn format_minutes(minutes: f64) -> String {
+    let secs = (minutes * 60.0).round() as i64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Format calories for appending to a line of stats, missing values are left out entirely
+fn format_calories(calories: Option<&f64>) -> String {
+    calories.map_or_else(String::new, |c| format!(", Calories: {:0.0}kcal", c))
+}
+
 /// Query the record_messages table to get various values averaged across the entire run
 fn collect_aggregate_stats(
     conn: &Connection,
@@ -184,10 +283,9 @@ fn collect_aggregate_stats(
             row.get::<&str, f64>("tot_dist")? * 0.00062137,
         );
         file_stats.insert("total_time", total_time.num_seconds() as f64 / 60.0);
-        file_stats.insert(
-            "avg_pace",
-            1.0 / (row.get::<&str, f64>("avg_speed")? * 0.00062137 * 60.0),
-        );
+        if let Some(speed) = row.get::<&str, Option<f64>>("avg_speed")? {
+            file_stats.insert("avg_speed", speed);
+        }
         file_stats.insert("avg_heart_rate", row.get("avg_hr").unwrap_or(0.0));
         agg_data.insert(row.get("file_id")?, file_stats);
     }
@@ -195,14 +293,117 @@ fn collect_aggregate_stats(
     Ok(agg_data)
 }
 
-/// Query the record_messages table to get various values averaged across the entire run
+/// Query the events table to sum the time in minutes between timer starts and stops, files that
+/// didn't record any timer events are left out.
+fn collect_timer_times(conn: &Connection, file_ids: Rc<Vec<Value>>) -> Result<HashMap<u32, f64>> {
+    let mut timer_times: HashMap<u32, f64> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "select event_type, timestamp, file_id
+                from events
+                where event = 'timer' and file_id in (select value from rarray(?))
+                order by file_id, timestamp",
+    )?;
+    let mut rows = stmt.query(params![file_ids])?;
+
+    // the timer is started once at the beginning, auto-pause and manual pauses show up as a
+    // stop followed by another start
+    let mut started: Option<(u32, DateTime<Local>)> = None;
+    while let Some(row) = rows.next()? {
+        let event_type: String = row.get(0)?;
+        let timestamp: DateTime<Local> = row.get(1)?;
+        let file_id: u32 = row.get(2)?;
+        let total = timer_times.entry(file_id).or_insert(0.0);
+        match (event_type.as_str(), started) {
+            ("start", Some((id, _))) if id == file_id => {}
+            ("start", _) => started = Some((file_id, timestamp)),
+            (_, Some((id, start))) if id == file_id => {
+                *total += (timestamp - start).num_seconds() as f64 / 60.0;
+                started = None;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(timer_times)
+}
+
+/// Query the lap_messages table to sum the calories burned in each file, files where no lap
+/// recorded calories are left out.
+fn collect_calories(conn: &Connection, file_ids: Rc<Vec<Value>>) -> Result<HashMap<u32, f64>> {
+    let mut stmt = conn.prepare(
+        "select file_id, sum(total_calories)
+                from lap_messages
+                where total_calories is not null and file_id in (select value from rarray(?))
+                group by file_id",
+    )?;
+    let calories = stmt
+        .query_map(params![file_ids], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect();
+    calories
+}
+
+/// Query the total ascent in meters of each file, files without elevation data are left out
+fn collect_ascents(
+    conn: &Connection,
+    file_ids: Rc<Vec<Value>>,
+    elevation_source: ElevationSource,
+) -> Result<HashMap<u32, f64>> {
+    let mut stmt = conn.prepare(&format!(
+        "select file_id, gain from ({})",
+        climb_totals_query(elevation_source, "select value from rarray(?1)")
+    ))?;
+    let ascents = stmt
+        .query_map(params![file_ids], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect();
+    ascents
+}
+
+/// Query the record_messages table to sum the time in minutes between records where the speed
+/// was above the threshold (in m/s), this excludes time spent stopped even if the timer was
+/// left running, similar to the moving time reported by Garmin Connect.
+fn collect_moving_times(
+    conn: &Connection,
+    file_ids: Rc<Vec<Value>>,
+    threshold: f64,
+) -> Result<HashMap<u32, f64>> {
+    let mut moving_times: HashMap<u32, f64> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "select speed, timestamp, file_id
+                from record_messages
+                where file_id in (select value from rarray(?))
+                order by file_id, timestamp",
+    )?;
+    let mut rows = stmt.query(params![file_ids])?;
+
+    // each interval is attributed to the speed recorded at its end
+    let mut previous: Option<(u32, DateTime<Local>)> = None;
+    while let Some(row) = rows.next()? {
+        let speed: Option<f64> = row.get(0)?;
+        let timestamp: DateTime<Local> = row.get(1)?;
+        let file_id: u32 = row.get(2)?;
+        let total = moving_times.entry(file_id).or_insert(0.0);
+        match previous {
+            Some((id, prev)) if id == file_id && speed.is_some_and(|s| s > threshold) => {
+                *total += (timestamp - prev).num_seconds() as f64 / 60.0;
+            }
+            _ => {}
+        }
+        previous = Some((file_id, timestamp));
+    }
+
+    Ok(moving_times)
+}
+
+/// Query the lap_messages table to get the stats of every lap, laps below the rest thresholds
+/// are flagged with a "rest" entry
 fn collect_lap_stats(
     conn: &Connection,
     file_ids: Rc<Vec<Value>>,
+    rest_laps: &RestLapThresholds,
 ) -> Result<HashMap<u32, Vec<HashMap<&'static str, f64>>>> {
     let mut lap_data: HashMap<u32, Vec<HashMap<&'static str, f64>>> = HashMap::new();
     let mut stmt = conn.prepare(
-        "select average_speed, average_heart_rate, total_distance,
+        "select average_speed, average_heart_rate, total_distance, total_calories,
                     start_time, timestamp as end_time, file_id
                 from lap_messages
                 where file_id in (select value from rarray(?))
@@ -219,19 +420,22 @@ fn collect_lap_stats(
         let total_time = row.get::<&str, DateTime<Local>>("end_time")?
             - row.get::<&str, DateTime<Local>>("start_time")?;
         let file_id: u32 = row.get("file_id")?;
-        lap_stats.insert(
-            "total_distance",
-            row.get::<&str, f64>("total_distance")? * 0.00062137,
-        );
+        let distance: f64 = row.get("total_distance")?;
+        if rest_laps.is_rest(Some(distance), total_time.num_seconds() as f64) {
+            lap_stats.insert("rest", 1.0);
+        }
+        lap_stats.insert("total_distance", distance * 0.00062137);
         lap_stats.insert("total_time", total_time.num_seconds() as f64 / 60.0);
-        lap_stats.insert(
-            "avg_pace",
-            1.0 / (row.get::<&str, f64>("average_speed")? * 0.00062137 * 60.0),
-        );
+        if let Some(speed) = row.get::<&str, Option<f64>>("average_speed")? {
+            lap_stats.insert("avg_speed", speed);
+        }
         lap_stats.insert(
             "avg_heart_rate",
             row.get("average_heart_rate").unwrap_or(0.0),
         );
+        if let Some(calories) = row.get::<&str, Option<f64>>("total_calories")? {
+            lap_stats.insert("calories", calories);
+        }
 
         // create new lap vector when file_id changes
         if curr_id != file_id {
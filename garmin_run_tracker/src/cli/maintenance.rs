@@ -0,0 +1,73 @@
+//! Define the maintenance subcommand
+use crate::db::open_db_connection;
+use log::info;
+use rusqlite::{params, Connection, Result};
+use structopt::StructOpt;
+
+/// Tables holding per-message data that reference a row in the files table
+static CHILD_TABLES: &[&str] = &[
+    "record_messages",
+    "lap_messages",
+    "events",
+    "developer_fields",
+];
+
+/// Compact the database and refresh the statistics used by the query planner
+#[derive(Debug, StructOpt)]
+pub struct MaintenanceOpts {
+    /// Delete message rows that reference a file no longer in the database
+    #[structopt(long)]
+    prune_orphans: bool,
+}
+
+/// Implementation of the `maintenance` subcommand
+pub fn maintenance_command(opts: MaintenanceOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = open_db_connection()?;
+    let size_before = database_size(&conn)?;
+
+    if opts.prune_orphans {
+        for table in CHILD_TABLES {
+            let deleted = conn.execute(
+                &format!(
+                    "delete from {} where file_id not in (select id from files)",
+                    table
+                ),
+                params![],
+            )?;
+            summary!("Removed {} orphaned rows from {}", deleted, table);
+        }
+    }
+
+    info!("Running VACUUM and ANALYZE on the database");
+    conn.execute_batch("vacuum; analyze")?;
+
+    let size_after = database_size(&conn)?;
+    summary!(
+        "Database size: {} -> {}",
+        format_size(size_before),
+        format_size(size_after)
+    );
+
+    Ok(())
+}
+
+/// Size of the database in bytes, computed from the page count so it also works in memory
+fn database_size(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        "select page_count * page_size from pragma_page_count(), pragma_page_size()",
+        params![],
+        |r| r.get(0),
+    )
+}
+
+/// Format a number of bytes using binary prefixes
+fn format_size(bytes: i64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} GiB", size)
+}
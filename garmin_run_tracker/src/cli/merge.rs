@@ -0,0 +1,275 @@
+//! Define the merge subcommand
+use crate::db::{find_file_by_uuid, open_db_connection};
+use crate::{generate_uuid, Error, FileInfo};
+use log::info;
+use rusqlite::types::Value;
+use rusqlite::{params, Transaction};
+use std::rc::Rc;
+use structopt::StructOpt;
+
+/// Combine files that belong to the same activity (e.g. a run split by restarting the watch)
+/// into a single new file
+#[derive(Debug, StructOpt)]
+pub struct MergeOpts {
+    /// Full or partial UUIDs of the files to merge, at least two are required
+    #[structopt(name = "FILE_UUIDS", required = true, min_values = 2)]
+    uuids: Vec<String>,
+    /// Delete the original files after merging them
+    #[structopt(long)]
+    remove_originals: bool,
+}
+
+/// Implementation of the `merge` subcommand
+pub fn merge_command(opts: MergeOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = open_db_connection()?;
+    let mut files = opts
+        .uuids
+        .iter()
+        .map(|uuid| find_file_by_uuid(&conn, uuid))
+        .collect::<Result<Vec<FileInfo>, Error>>()?;
+    files.sort_by(|a, b| a.timestamp().cmp(b.timestamp()));
+    files.dedup_by(|a, b| a.uuid() == b.uuid());
+    if files.len() < 2 {
+        return Err(Box::new(Error::Other(
+            "At least two different files are required to merge".to_string(),
+        )));
+    }
+
+    let tx = conn.transaction()?;
+    let merged_uuid = merge_files(&tx, &files)?;
+    if opts.remove_originals {
+        // message rows are removed along with the files by the foreign key cascade
+        for file in &files {
+            tx.execute("delete from files where id = ?", params![file.id()])?;
+        }
+    }
+    tx.commit()?;
+    info!(
+        "Merged {} files into a new file (UUID={})",
+        files.len(),
+        merged_uuid
+    );
+    println!("{}", merged_uuid);
+
+    Ok(())
+}
+
+/// Columns that restart from zero in every file, the merged values continue from the largest
+/// value of the files before plus the step, e.g. message indexes must stay unique
+static CONTINUED_COLUMNS: &[(&str, &str, f64)] = &[
+    ("record_messages", "distance", 0.0),
+    ("developer_fields", "message_index", 1.0),
+];
+
+/// Copy every row belonging to the files, in the order of the files, into a new file entry and
+/// return its UUID. The distance of each record is offset by the final distance of the files
+/// before it since every file starts counting from zero.
+fn merge_files(tx: &Transaction, files: &[FileInfo]) -> Result<String, Error> {
+    // the merged UUID is derived from its sources so merging the same files twice is detected
+    // as a duplicate
+    let sources: Vec<&str> = files.iter().map(|f| f.uuid()).collect();
+    let uuid = generate_uuid(format!("merge:{}", sources.join(",")).as_bytes());
+    if find_file_by_uuid(tx, &uuid).is_ok() {
+        return Err(Error::DuplicateFileError(uuid));
+    }
+
+    // the first file describes the merged one, elevation data is still pending if it is for
+    // any of the sources. The merged file doesn't come from a file on disk.
+    let columns: Vec<String> = table_columns(tx, "files", "uuid")?
+        .into_iter()
+        .filter(|c| {
+            !matches!(
+                c.as_str(),
+                "elevation_pending" | "source_uuid" | "activity_index"
+            )
+        })
+        .collect();
+    let ids = Rc::new(
+        files
+            .iter()
+            .map(|f| Value::from(f.id()))
+            .collect::<Vec<_>>(),
+    );
+    tx.execute(
+        &format!(
+            "insert into files ({c}, elevation_pending, uuid)
+             select {c}, (select max(elevation_pending) from files
+                          where id in (select value from rarray(?1))), ?2
+             from files where id = ?3",
+            c = columns.join(", ")
+        ),
+        params![ids, uuid, files[0].id()],
+    )?;
+    let merged_id = tx.last_insert_rowid();
+
+    for (table, file_column) in child_tables(tx)? {
+        let columns = table_columns(tx, &table, &file_column)?;
+        let continued: Vec<(&str, f64)> = CONTINUED_COLUMNS
+            .iter()
+            .filter(|(t, c, _)| *t == table && columns.iter().any(|col| col == c))
+            .map(|(_, c, step)| (*c, *step))
+            .collect();
+        let mut offsets = vec![0.0; continued.len()];
+        for file in files {
+            let values: Vec<String> = columns
+                .iter()
+                .map(|c| match continued.iter().position(|(col, _)| col == c) {
+                    Some(i) => format!("{} + {}", c, offsets[i]),
+                    None => c.clone(),
+                })
+                .collect();
+            tx.execute(
+                &format!(
+                    "insert into {t} ({c}, {f}) select {v}, ? from {t} where {f} = ? order by id",
+                    t = table,
+                    c = columns.join(", "),
+                    f = file_column,
+                    v = values.join(", ")
+                ),
+                params![merged_id, file.id()],
+            )?;
+            for ((column, step), offset) in continued.iter().zip(offsets.iter_mut()) {
+                let last: Option<f64> = tx.query_row(
+                    &format!(
+                        "select max({}) from {} where {} = ?",
+                        column, table, file_column
+                    ),
+                    params![file.id()],
+                    |r| r.get(0),
+                )?;
+                if let Some(last) = last {
+                    *offset += last + step;
+                }
+            }
+        }
+    }
+
+    Ok(uuid)
+}
+
+/// Tables with rows that belong to a file and the column referencing it, found through their
+/// foreign key so tables added by later migrations are merged as well
+pub(super) fn child_tables(tx: &Transaction) -> Result<Vec<(String, String)>, Error> {
+    let mut stmt = tx.prepare(
+        "select m.name, k.\"from\" from sqlite_master m, pragma_foreign_key_list(m.name) k
+         where m.type = 'table' and k.\"table\" = 'files' order by m.name",
+    )?;
+    let tables = stmt
+        .query_map(params![], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(tables)
+}
+
+/// Columns of the table other than its primary key and the excluded column
+fn table_columns(tx: &Transaction, table: &str, exclude: &str) -> Result<Vec<String>, Error> {
+    let mut stmt =
+        tx.prepare("select name from pragma_table_info(?) where pk = 0 and name != ?")?;
+    let columns = stmt
+        .query_map(params![table, exclude], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        fit_time, import, memory_db, simple_run, Field, FitFileBuilder, RECORD, START,
+    };
+    use rusqlite::Connection;
+
+    fn column<T: rusqlite::types::FromSql>(conn: &Connection, sql: &str, file_id: u32) -> Vec<T> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt
+            .query_map(params![file_id], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows
+    }
+
+    #[test]
+    fn merge_continues_the_distance_across_files() {
+        let mut conn = memory_db();
+        let first = import(&mut conn, &simple_run(START));
+        // the watch was restarted, the second file counts its distance from zero again
+        let second = FitFileBuilder::new()
+            .file_id(1234, START + 60)
+            .record(START + 60, (39.4603, -80.14), 0.0, 3.0, 140)
+            .record(START + 70, (39.4604, -80.14), 30.0, 3.0, 150)
+            .message(
+                RECORD,
+                &[
+                    Field::UInt32(253, fit_time(START + 80)),
+                    Field::UInt8(4, 88), // cadence
+                ],
+            )
+            .build();
+        let second = import(&mut conn, &second);
+        conn.execute(
+            "update files set elevation_pending = 1 where id = ?",
+            params![second.id()],
+        )
+        .unwrap();
+
+        let files = [first, second];
+        let tx = conn.transaction().unwrap();
+        let uuid = merge_files(&tx, &files).unwrap();
+        tx.commit().unwrap();
+        let id: u32 = conn
+            .query_row("select id from files where uuid = ?", params![uuid], |r| {
+                r.get(0)
+            })
+            .unwrap();
+
+        let distances: Vec<Option<f64>> = column(
+            &conn,
+            "select distance from record_messages where file_id = ? order by timestamp",
+            id,
+        );
+        assert_eq!(
+            distances,
+            [
+                Some(0.0),
+                Some(30.0),
+                Some(60.0),
+                Some(60.0),
+                Some(90.0),
+                None
+            ]
+        );
+        let laps: Vec<f64> = column(
+            &conn,
+            "select total_distance from lap_messages where file_id = ?",
+            id,
+        );
+        assert_eq!(laps, [60.0]);
+
+        // extra fields keep unique message indexes and the pending elevation flag carries over
+        let fields: Vec<String> = column(
+            &conn,
+            "select field_name from developer_fields where file_id = ? and message_type = 'record'",
+            id,
+        );
+        assert_eq!(fields, ["cadence"]);
+        let indexes: Vec<i64> = column(
+            &conn,
+            "select message_index from developer_fields where file_id = ? order by id",
+            id,
+        );
+        let mut unique = indexes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), indexes.len());
+        let pending: Vec<bool> = column(
+            &conn,
+            "select elevation_pending from files where id = ?",
+            id,
+        );
+        assert_eq!(pending, [true]);
+
+        // merging the same files again is a duplicate
+        let tx = conn.transaction().unwrap();
+        assert!(merge_files(&tx, &files).is_err());
+    }
+}
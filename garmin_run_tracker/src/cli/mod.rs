@@ -1,19 +1,65 @@
 //! Define the application's command line interface
 use crate::config::Config;
-use chrono::NaiveDate;
+use crate::logging::LogFormat;
+use crate::Error;
+use chrono::{Days, Local, Months, NaiveDate};
 use simplelog::LevelFilter;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
+/// Print human readable headers and summaries to stdout, these are hidden along with info
+/// logging by `--quiet` so scripts only receive the data they asked for.
+macro_rules! summary {
+    ($($arg:tt)*) => {
+        if log::max_level() >= log::LevelFilter::Info {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod backup;
+use backup::{backup_command, BackupOpts};
 mod download_epo;
 use download_epo::{download_epo_command, DownloadEpoOpts};
+mod dump;
+use dump::{dump_command, DumpOpts};
+mod geojson;
+use geojson::{geojson_command, GeoJsonOpts};
+mod gpx;
+use gpx::{gpx_command, GpxOpts};
 mod import;
 use import::{import_command, ImportOpts};
 mod list_files;
 use list_files::{list_files_command, ListFilesOpts};
+mod maintenance;
+use maintenance::{maintenance_command, MaintenanceOpts};
+mod merge;
+use merge::{merge_command, MergeOpts};
+mod output;
+use output::{export_dir, OutputSink};
+mod prune;
+use prune::{prune_command, PruneOpts};
+mod records;
+use records::{records_command, RecordsOpts};
+mod reimport;
+use reimport::{reimport_command, ReimportOpts};
+mod report;
+use report::{report_command, ReportOpts};
 mod route_image;
 use route_image::{route_image_command, RouteImageOpts};
+mod routes;
+use routes::{routes_command, RoutesOpts};
+mod selftest;
+use selftest::{selftest_command, SelftestOpts};
 mod show;
 use show::{show_command, ShowOpts};
+mod splits;
+use splits::{splits_command, SplitsOpts};
+mod status;
+use status::{status_command, StatusOpts};
 mod update_elevation;
 use update_elevation::{update_elevation_command, UpdateElevationOpts};
 
@@ -23,15 +69,43 @@ pub struct Cli {
     /// Set logging level to debug, use a second time (e.g. -vv) to set logging to trace
     #[structopt(short, long, parse(from_occurrences))]
     verbose: i32,
-    /// Suppress info logging messages use a second time (e.g. -qq) to hide warnings
+    /// Suppress info logging messages and output summaries, use a second time (e.g. -qq) to hide
+    /// warnings
     #[structopt(short, long, parse(from_occurrences))]
     quiet: i32,
+    /// Use a temporary in-memory database that is discarded on exit, imported FIT files are also
+    /// not copied into the devices directory
+    #[structopt(long)]
+    ephemeral: bool,
+    /// Load the configuration from this file instead of the one in the data directory
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Format of log messages: text or json (one object per line on stderr)
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
     /// Additional commands beyond importing data
     #[structopt(subcommand)]
     cmd: Command,
 }
 
+/// Global options that take a value, needed to find the first positional argument
+static VALUE_OPTIONS: &[&str] = &["--config", "--log-format"];
+
 impl Cli {
+    /// Parse the command line arguments, when no subcommand is given and the first positional
+    /// argument is an existing path (or a FIT file) it is treated as `import <paths>`. This lets
+    /// `garmin_run_tracker activity.fit` work without typing the subcommand.
+    pub fn from_args_with_default_import() -> Self {
+        let args: Vec<OsString> = env::args_os().collect();
+        match Cli::from_iter_safe(&args) {
+            Ok(cli) => cli,
+            Err(e) => match insert_import_subcommand(&args) {
+                Some(args) => Cli::from_iter(args),
+                None => e.exit(),
+            },
+        }
+    }
+
     /// Return the verbose flag counts as a log level filter
     pub fn verbosity(&self, default: LevelFilter) -> LevelFilter {
         if self.quiet == 1 {
@@ -47,6 +121,21 @@ impl Cli {
         }
     }
 
+    /// Return the alternate configuration file if one was provided
+    pub fn config_path(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    /// Return the requested format for log messages
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    /// Return true if the session shouldn't persist anything to the application database
+    pub fn ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
     /// Consume options struct and return the result of subcommand execution
     pub fn execute_subcommand(self, config: Config) -> Result<(), Box<dyn std::error::Error>> {
         self.cmd.execute(config)
@@ -55,21 +144,63 @@ impl Cli {
 
 #[derive(Debug, StructOpt)]
 pub enum Command {
+    /// Copy the database to another file, safe to run while it is in use
+    #[structopt(name = "backup")]
+    Backup(BackupOpts),
     /// Update the Extended Prediction Orbit (EPO) data for one or more garmin devices
     #[structopt(name = "download-epo")]
     DownloadEpo(DownloadEpoOpts),
+    /// Print every message and field of a FIT file without importing it
+    #[structopt(name = "dump")]
+    Dump(DumpOpts),
+    /// Export the GPS trace and lap markers of a file as GeoJSON
+    #[structopt(name = "geojson")]
+    GeoJson(GeoJsonOpts),
+    /// Export the GPS track of a file as GPX
+    #[structopt(name = "gpx")]
+    Gpx(GpxOpts),
     /// Import new FIT files into the application
     #[structopt(name = "import")]
     Import(ImportOpts),
     /// List files stored in the database
     #[structopt(name = "list-files")]
     Listfiles(ListFilesOpts),
+    /// Vacuum and analyze the database, optionally removing orphaned message rows
+    #[structopt(name = "maintenance")]
+    Maintenance(MaintenanceOpts),
+    /// Merge files that were split from a single activity into a new file
+    #[structopt(name = "merge")]
+    Merge(MergeOpts),
+    /// Remove files recorded before a cutoff date
+    #[structopt(name = "prune")]
+    Prune(PruneOpts),
+    /// Rank runs by total elevation gain, descent or distance
+    #[structopt(name = "records")]
+    Records(RecordsOpts),
+    /// Reimport stored copies of FIT files to fill in newly supported fields
+    #[structopt(name = "reimport")]
+    Reimport(ReimportOpts),
+    /// Create an SVG summary of a week of runs
+    #[structopt(name = "report")]
+    Report(ReportOpts),
     /// Create a route image from the GPS trace
     #[structopt(name = "route-image")]
     RouteImage(RouteImageOpts),
+    /// Group runs that follow the same path into routes
+    #[structopt(name = "routes")]
+    Routes(RoutesOpts),
+    /// Check that configured services return reasonable data
+    #[structopt(name = "selftest")]
+    Selftest(SelftestOpts),
     /// Show file statistics and plot running data
     #[structopt(name = "show")]
     Show(ShowOpts),
+    /// Break a run into fixed distance or time splits
+    #[structopt(name = "splits")]
+    Splits(SplitsOpts),
+    /// Report which files in the import paths have already been imported
+    #[structopt(name = "status")]
+    Status(StatusOpts),
     /// Update elevation data in the database for one or more files
     #[structopt(name = "update-elevation")]
     UpdateElevation(UpdateElevationOpts),
@@ -79,16 +210,155 @@ impl Command {
     /// Consume enum variant and return the result of the command's execution
     fn execute(self, config: Config) -> Result<(), Box<dyn std::error::Error>> {
         match self {
+            Command::Backup(opts) => backup_command(opts),
             Command::DownloadEpo(opts) => download_epo_command(config, opts),
-            Command::Import(opts) => import_command(config, opts),
-            Command::Listfiles(opts) => list_files_command(opts),
+            Command::Dump(opts) => dump_command(opts),
+            Command::GeoJson(opts) => geojson_command(config, opts),
+            Command::Gpx(opts) => gpx_command(config, opts),
+            Command::Import(opts) => {
+                let fail_on_error = opts.fail_on_error();
+                let summary = import_command(config, opts)?;
+                summary!("{}", summary);
+                if fail_on_error && summary.errors() > 0 {
+                    return Err(Box::new(Error::ImportFailures(summary.errors())));
+                }
+                Ok(())
+            }
+            Command::Listfiles(opts) => list_files_command(config, opts),
+            Command::Maintenance(opts) => maintenance_command(opts),
+            Command::Merge(opts) => merge_command(opts),
+            Command::Prune(opts) => prune_command(config, opts),
+            Command::Records(opts) => records_command(config, opts),
+            Command::Reimport(opts) => reimport_command(config, opts),
+            Command::Report(opts) => report_command(config, opts),
             Command::RouteImage(opts) => route_image_command(config, opts),
+            Command::Routes(opts) => routes_command(opts),
+            Command::Selftest(opts) => selftest_command(config, opts),
             Command::Show(opts) => show_command(config, opts),
+            Command::Splits(opts) => splits_command(opts),
+            Command::Status(opts) => status_command(config, opts),
             Command::UpdateElevation(opts) => update_elevation_command(config, opts),
         }
     }
 }
 
-fn parse_date(src: &str) -> Result<NaiveDate, chrono::format::ParseError> {
-    NaiveDate::parse_from_str(src, "%Y-%m-%d")
+/// Insert the import subcommand before the first positional argument if it looks like a path
+fn insert_import_subcommand(args: &[OsString]) -> Option<Vec<OsString>> {
+    let mut index = None;
+    let mut skip_value = false;
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        let text = arg.to_string_lossy();
+        if skip_value {
+            skip_value = false;
+        } else if VALUE_OPTIONS.contains(&text.as_ref()) {
+            skip_value = true;
+        } else if !text.starts_with('-') {
+            index = Some(i);
+            break;
+        }
+    }
+    let index = index?;
+    let path = Path::new(&args[index]);
+    let is_fit = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fit"));
+    if !path.exists() && !is_fit {
+        return None;
+    }
+
+    let mut args = args.to_vec();
+    args.insert(index, OsString::from("import"));
+    Some(args)
+}
+
+/// Read newline separated UUIDs from stdin, blank lines are skipped and surrounding parentheses
+/// are removed so the UUID column of list-files output can be piped in directly
+fn read_uuids_from_stdin() -> Result<Vec<String>, Error> {
+    let mut uuids = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let uuid = line
+            .trim()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .trim();
+        if !uuid.is_empty() {
+            uuids.push(uuid.to_string());
+        }
+    }
+    Ok(uuids)
+}
+
+fn parse_date(src: &str) -> Result<NaiveDate, Error> {
+    resolve_date(src, Local::now().date_naive())
+}
+
+/// Parse a YYYY-MM-DD date or one of the relative keywords "today" and "yesterday", or an
+/// offset of days, weeks or months before `today` such as 7d, 2w and 1m
+fn resolve_date(src: &str, today: NaiveDate) -> Result<NaiveDate, Error> {
+    let invalid = || {
+        Error::Other(format!(
+            "Invalid date {}: expected YYYY-MM-DD, today, yesterday or an offset like 7d, 2w or 1m",
+            src
+        ))
+    };
+    let keyword = src.trim().to_ascii_lowercase();
+    match keyword.as_str() {
+        "today" => return Ok(today),
+        "yesterday" => return today.pred_opt().ok_or_else(invalid),
+        _ => {}
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(&keyword, "%Y-%m-%d") {
+        return Ok(date);
+    }
+
+    // relative offsets are a count followed by a single unit character
+    let (count, unit) = match keyword.char_indices().last() {
+        Some((i, unit)) => (&keyword[..i], unit),
+        None => return Err(invalid()),
+    };
+    let count: u32 = count.parse().map_err(|_| invalid())?;
+    match unit {
+        'd' => today.checked_sub_days(Days::new(count as u64)),
+        'w' => today.checked_sub_days(Days::new(count as u64 * 7)),
+        'm' => today.checked_sub_months(Months::new(count)),
+        _ => None,
+    }
+    .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn resolve_date_accepts_keywords_and_offsets() {
+        let today = date(2021, 3, 31);
+        assert_eq!(resolve_date("today", today).unwrap(), today);
+        assert_eq!(resolve_date("Yesterday", today).unwrap(), date(2021, 3, 30));
+        assert_eq!(resolve_date("7d", today).unwrap(), date(2021, 3, 24));
+        assert_eq!(resolve_date("2w", today).unwrap(), date(2021, 3, 17));
+        // months are calendar months, clamped to the end of shorter months
+        assert_eq!(resolve_date("1m", today).unwrap(), date(2021, 2, 28));
+        assert_eq!(
+            resolve_date("2020-12-01", today).unwrap(),
+            date(2020, 12, 1)
+        );
+    }
+
+    #[test]
+    fn resolve_date_rejects_unknown_values() {
+        let today = date(2021, 3, 31);
+        for value in ["", "d", "7x", "-7d", "tomorrow", "2021-13-01", "7日"] {
+            assert!(
+                resolve_date(value, today).is_err(),
+                "{:?} was accepted",
+                value
+            );
+        }
+    }
 }
@@ -0,0 +1,108 @@
+//! Shared handling of where commands write the files they generate
+use crate::config::Config;
+use crate::{Error, FileInfo};
+use log::{debug, warn};
+use std::fs::{create_dir_all, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Destination for data generated by a command, either a file or stdout
+#[derive(Debug)]
+pub enum OutputSink {
+    Stdout,
+    File(PathBuf),
+}
+
+impl OutputSink {
+    /// Interpret an optional output path, no path or "-" refers to stdout
+    pub fn new(path: Option<PathBuf>) -> Self {
+        match path {
+            Some(path) if path.to_string_lossy() != "-" => OutputSink::File(path),
+            _ => OutputSink::Stdout,
+        }
+    }
+
+    /// Write an exported file within a directory, it is named by expanding the export_file_name
+    /// template and any directories it needs are created
+    pub fn export(
+        dir: &Path,
+        info: &FileInfo,
+        template: &str,
+        extension: &str,
+    ) -> Result<Self, Error> {
+        let path = dir.join(info.export_path(template, extension)?);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        Ok(OutputSink::File(path))
+    }
+
+    /// Return the file path if output isn't going to stdout
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            OutputSink::Stdout => None,
+            OutputSink::File(path) => Some(path),
+        }
+    }
+
+    /// Write all of the data to the destination, replacing any existing file
+    pub fn write(&self, data: &[u8]) -> io::Result<()> {
+        match self {
+            OutputSink::Stdout => {
+                debug!("Writing {} bytes to STDOUT", data.len());
+                io::stdout().lock().write_all(data)
+            }
+            OutputSink::File(path) => {
+                debug!("Writing {} bytes to {:?}", data.len(), path);
+                File::create(path)?.write_all(data)
+            }
+        }
+    }
+
+    /// Launch the system's default application for the written file, failures only produce a
+    /// warning since the data itself was written successfully.
+    pub fn open(&self) {
+        match self {
+            OutputSink::Stdout => warn!("Ignoring --open since the output was written to stdout"),
+            OutputSink::File(path) => {
+                if let Err(e) = open_in_default_viewer(path) {
+                    warn!("Could not open {:?} in the default viewer - {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+/// Directory an export writes its files to, exporting every file needs one so it falls back to
+/// the export_dir config value. None writes a single file to --output or stdout.
+pub fn export_dir<'a>(
+    output_dir: Option<&'a Path>,
+    all: bool,
+    config: &'a Config,
+) -> Result<Option<&'a Path>, Error> {
+    match output_dir {
+        Some(dir) => Ok(Some(dir)),
+        None if all => config.export_dir().map(Some).ok_or_else(|| {
+            Error::Other(
+                "--all needs --output-dir or the export_dir config value to write files to"
+                    .to_string(),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Launch the system's default application for a file without waiting for it to exit
+fn open_in_default_viewer(path: &Path) -> io::Result<()> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        process::Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        let mut cmd = process::Command::new("cmd");
+        cmd.args(["/C", "start", ""]);
+        cmd
+    } else {
+        process::Command::new("xdg-open")
+    };
+    cmd.arg(path).spawn().map(|_| ())
+}
@@ -0,0 +1,103 @@
+//! Define the prune subcommand
+use super::parse_date;
+use super::reimport::stored_files;
+use crate::config::Config;
+use crate::db::{new_file_info_query, open_db_connection};
+use crate::{Error, FileInfo};
+use chrono::NaiveDate;
+use log::{info, warn};
+use rusqlite::params;
+use std::convert::TryFrom;
+use std::fs::remove_file;
+use structopt::StructOpt;
+
+/// Remove files recorded before a cutoff date to keep the database limited to recent activities
+#[derive(Debug, StructOpt)]
+pub struct PruneOpts {
+    /// Remove files recorded before this date (YYYY-MM-DD format or today, yesterday, 7d, 2w, 1m)
+    #[structopt(long, parse(try_from_str = parse_date))]
+    older_than: NaiveDate,
+    /// Also delete the stored copies of the removed files from the devices directory
+    #[structopt(long)]
+    remove_copies: bool,
+    /// Only list the files that would be removed
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+    /// Confirm the removal, nothing is deleted without it
+    #[structopt(short, long, conflicts_with = "dry-run")]
+    yes: bool,
+}
+
+/// Implementation of the `prune` subcommand
+pub fn prune_command(config: Config, opts: PruneOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = open_db_connection()?;
+    let mut query = new_file_info_query();
+    query.and_where("time_created < ?");
+    query.order_by("time_created ASC");
+    let files = conn
+        .prepare(&query.to_string())?
+        .query_map(params![opts.older_than], |r| FileInfo::try_from(r))?
+        .collect::<Result<Vec<FileInfo>, _>>()?;
+    if files.is_empty() {
+        summary!("No files recorded before {}", opts.older_than);
+        return Ok(());
+    }
+
+    // stored copies are named by a configurable template so they're found by their hash instead
+    let stored_files = if opts.remove_copies {
+        stored_files()?
+    } else {
+        Default::default()
+    };
+    for file in &files {
+        println!(
+            "{} {} ({})",
+            file.format_timestamp("%Y-%m-%d %H:%M", false),
+            config.device_name(&file.manufacturer, &file.product),
+            file.uuid
+        );
+        if let Some(path) = stored_files.get(file.uuid()) {
+            println!("    {}", path.display());
+        }
+    }
+
+    if opts.dry_run {
+        summary!(
+            "Dry run, {} files recorded before {} would be removed",
+            files.len(),
+            opts.older_than
+        );
+        return Ok(());
+    } else if !opts.yes {
+        return Err(Box::new(Error::Other(format!(
+            "Refusing to remove {} files without --yes, use --dry-run to preview the removal",
+            files.len()
+        ))));
+    }
+
+    // message rows are removed along with the files by the foreign key cascade
+    let tx = conn.transaction()?;
+    for file in &files {
+        tx.execute("delete from files where id = ?", params![file.id()])?;
+    }
+    tx.commit()?;
+    info!("Removed {} files from the database", files.len());
+
+    for file in &files {
+        if let Some(path) = stored_files.get(file.uuid()) {
+            match remove_file(path) {
+                Ok(_) => info!("Removed stored copy {:?}", path),
+                Err(e) => warn!("Could not remove stored copy {:?}: {}", path, e),
+            }
+        } else if opts.remove_copies {
+            warn!("No stored copy of file UUID={} found", file.uuid());
+        }
+    }
+    summary!(
+        "Removed {} files recorded before {}",
+        files.len(),
+        opts.older_than
+    );
+
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! Define the records subcommand
+use crate::config::Config;
+use crate::db::{climb_totals_query, new_file_info_query, open_db_connection};
+use crate::{ElevationSource, Error, FileInfo};
+use rusqlite::{params, Connection};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Rank runs by their total elevation gain, descent or distance
+#[derive(Debug, StructOpt)]
+pub struct RecordsOpts {
+    /// Total to rank runs by: gain, descent or distance
+    #[structopt(long, default_value = "gain")]
+    by: RecordMetric,
+    /// Number of runs to list, use 0 to list every run
+    #[structopt(short, long, default_value = "10")]
+    number: usize,
+    /// Elevation to use: api (from the elevation service) or device (recorded by the watch),
+    /// defaults to the elevation_source config value
+    #[structopt(long)]
+    elevation_source: Option<ElevationSource>,
+    /// Display dates in UTC instead of the timezone the activity was recorded in
+    #[structopt(long)]
+    utc: bool,
+}
+
+/// Per file total that runs can be ranked by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RecordMetric {
+    Gain,
+    Descent,
+    Distance,
+}
+
+impl RecordMetric {
+    /// Column of the totals query holding this metric
+    fn column(&self) -> &'static str {
+        match self {
+            RecordMetric::Gain => "gain",
+            RecordMetric::Descent => "descent",
+            RecordMetric::Distance => "distance",
+        }
+    }
+}
+
+impl FromStr for RecordMetric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gain" => Ok(RecordMetric::Gain),
+            "descent" => Ok(RecordMetric::Descent),
+            "distance" => Ok(RecordMetric::Distance),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: gain, descent, distance"
+            ))),
+        }
+    }
+}
+
+/// Distance and elevation totals of a single file, elevations are None if the file has no
+/// elevation data
+#[derive(Debug)]
+struct FileTotals {
+    info: FileInfo,
+    /// Total distance in meters
+    distance: Option<f64>,
+    /// Total ascent in meters, measured by the device if its laps reported it and otherwise the
+    /// sum of every climb between consecutive records
+    gain: Option<f64>,
+    /// Total descent in meters, measured by the device if its laps reported it and otherwise the
+    /// sum of every drop between consecutive records
+    descent: Option<f64>,
+}
+
+/// Implementation of the `records` subcommand
+pub fn records_command(
+    config: Config,
+    opts: RecordsOpts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = open_db_connection()?;
+    let elevation_source = opts
+        .elevation_source
+        .unwrap_or_else(|| config.elevation_source());
+    let distance_unit = config.units().distance_unit();
+    let elevation_unit = config.units().elevation_unit();
+    let totals = ranked_files(&conn, opts.by, elevation_source, opts.number)?;
+
+    summary!(
+        "Rank\tDate\tDistance[{}]\tGain[{e}]\tDescent[{e}]\tUUID",
+        distance_unit.abbreviation(),
+        e = elevation_unit.abbreviation()
+    );
+    let format_elevation = |value: Option<f64>| match value {
+        Some(meters) => format!("{:0.0}", elevation_unit.from_meters(meters)),
+        None => "-".to_string(),
+    };
+    for (i, file) in totals.iter().enumerate() {
+        println!(
+            "{}\t{}\t{:0.2}\t{}\t{}\t({})",
+            i + 1,
+            file.info.format_timestamp("%Y-%m-%d", opts.utc),
+            file.distance.unwrap_or(0.0) / distance_unit.meters(),
+            format_elevation(file.gain),
+            format_elevation(file.descent),
+            file.info.uuid()
+        );
+    }
+
+    Ok(())
+}
+
+/// Return the files with the largest totals of the metric, largest first. Files without any
+/// data for the metric are left out and a limit of 0 returns every file.
+fn ranked_files(
+    conn: &Connection,
+    metric: RecordMetric,
+    elevation_source: ElevationSource,
+    limit: usize,
+) -> Result<Vec<FileTotals>, Error> {
+    // gain and descent are the same totals shown by show and the route-image caption
+    let mut stmt = conn.prepare(&format!(
+        "select id, distance, gain, descent from (
+             select files.id, files.time_created, d.distance, c.gain, c.descent
+             from files
+             left join (select file_id, max(distance) as distance from record_messages
+                        group by file_id) d on d.file_id = files.id
+             left join ({climb}) c on c.file_id = files.id
+         )
+         where {m} is not null
+         order by {m} desc, time_created desc
+         limit ?",
+        climb = climb_totals_query(elevation_source, "select id from files"),
+        m = metric.column()
+    ))?;
+    // sqlite treats a negative limit as no limit
+    let limit = if limit == 0 { -1 } else { limit as i64 };
+    let rows = stmt
+        .query_map(params![limit], |r| {
+            Ok((r.get::<usize, u32>(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })?
+        .collect::<Result<Vec<(u32, Option<f64>, Option<f64>, Option<f64>)>, _>>()?;
+
+    let mut query = new_file_info_query();
+    query.and_where("id = ?");
+    let mut info_stmt = conn.prepare(&query.to_string())?;
+    rows.into_iter()
+        .map(|(id, distance, gain, descent)| {
+            Ok(FileTotals {
+                info: info_stmt.query_row(params![id], |r| FileInfo::try_from(r))?,
+                distance,
+                gain,
+                descent,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fit_time, import, memory_db, Field, FitFileBuilder, LAP, START};
+
+    /// Import a short run and give its records the elevations, in order
+    fn import_run(conn: &mut Connection, start: u32, elevations: &[f64]) -> FileInfo {
+        let mut builder = FitFileBuilder::new().file_id(start, start);
+        for (i, _) in elevations.iter().enumerate() {
+            let i = i as u32;
+            builder = builder.record(
+                start + i * 10,
+                (39.46 + i as f64 * 0.0001, -80.14),
+                i as f64 * 30.0,
+                3.0,
+                140,
+            );
+        }
+        let data = builder.activity(start + 100, 0).build();
+        let info = import(conn, &data);
+
+        let mut stmt = conn
+            .prepare(
+                "update record_messages set elevation = ?1 where id = (
+                    select id from record_messages where file_id = ?2
+                    order by timestamp limit 1 offset ?3)",
+            )
+            .unwrap();
+        for (i, elevation) in elevations.iter().enumerate() {
+            stmt.execute(params![elevation, info.id(), i]).unwrap();
+        }
+        info
+    }
+
+    #[test]
+    fn ranked_files_orders_runs_by_elevation_totals() {
+        let mut conn = memory_db();
+        let hilly = import_run(&mut conn, START, &[100.0, 120.0, 110.0, 140.0]);
+        let downhill = import_run(&mut conn, START + 3600, &[200.0, 150.0, 155.0, 100.0, 90.0]);
+
+        let by_gain = ranked_files(&conn, RecordMetric::Gain, ElevationSource::Api, 0).unwrap();
+        assert_eq!(by_gain.len(), 2);
+        assert_eq!(by_gain[0].info.uuid(), hilly.uuid());
+        assert_eq!(by_gain[0].gain, Some(50.0));
+        assert_eq!(by_gain[0].descent, Some(10.0));
+        assert_eq!(by_gain[1].gain, Some(5.0));
+
+        let by_descent =
+            ranked_files(&conn, RecordMetric::Descent, ElevationSource::Api, 1).unwrap();
+        assert_eq!(by_descent.len(), 1);
+        assert_eq!(by_descent[0].info.uuid(), downhill.uuid());
+        assert_eq!(by_descent[0].descent, Some(115.0));
+
+        let by_distance =
+            ranked_files(&conn, RecordMetric::Distance, ElevationSource::Api, 0).unwrap();
+        assert_eq!(by_distance[0].distance, Some(120.0));
+
+        // no device elevation was recorded so nothing can be ranked by it
+        let device = ranked_files(&conn, RecordMetric::Gain, ElevationSource::Device, 0).unwrap();
+        assert!(device.is_empty());
+    }
+
+    #[test]
+    fn ranked_files_prefers_the_device_lap_totals() {
+        let mut conn = memory_db();
+        let recomputed = import_run(&mut conn, START, &[100.0, 120.0, 110.0, 140.0]);
+        let data = FitFileBuilder::new()
+            .file_id(1, START + 3600)
+            .record(START + 3600, (39.46, -80.14), 0.0, 3.0, 140)
+            .message(
+                LAP,
+                &[
+                    Field::UInt32(253, fit_time(START + 3700)),
+                    Field::UInt32(2, fit_time(START + 3600)),
+                    Field::UInt16(21, 35), // total_ascent
+                    Field::UInt16(22, 12), // total_descent
+                ],
+            )
+            .activity(START + 3700, 0)
+            .build();
+        let device = import(&mut conn, &data);
+
+        // the lap totals are used even though the file has no record elevations
+        let by_gain = ranked_files(&conn, RecordMetric::Gain, ElevationSource::Api, 0).unwrap();
+        assert_eq!(by_gain.len(), 2);
+        assert_eq!(by_gain[0].info.uuid(), recomputed.uuid());
+        assert_eq!(by_gain[1].info.uuid(), device.uuid());
+        assert_eq!(by_gain[1].gain, Some(35.0));
+        assert_eq!(by_gain[1].descent, Some(12.0));
+    }
+}
@@ -0,0 +1,336 @@
+//! Define the reimport subcommand
+use super::import::{add_elevation_data, infer_timezone, remove_gps_jumps};
+use super::merge::child_tables;
+use crate::config::Config;
+use crate::db::{all_files, find_file_by_uuid};
+use crate::{
+    devices_dir, generate_uuid, import_activity_file, DbWriter, Error, FileInfo, StoredFields,
+};
+use log::{error, info, warn};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, Transaction};
+use std::collections::HashMap;
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Parse the stored copies of imported FIT files again so fields added to the database since
+/// they were first imported get filled in
+#[derive(Debug, StructOpt)]
+pub struct ReimportOpts {
+    /// Full or partial UUIDs of the files to reimport (use list-files command to see UUIDs). The
+    /// special identifier :last will return the most recent file import.
+    #[structopt(name = "FILE_UUIDS")]
+    uuids: Vec<String>,
+    /// Reimport every file in the database
+    #[structopt(long, conflicts_with = "FILE_UUIDS")]
+    all: bool,
+    /// Query the elevation service again instead of keeping the elevations the files already
+    /// have from it
+    #[structopt(long)]
+    refetch_elevation: bool,
+}
+
+/// Implementation of the `reimport` subcommand
+pub fn reimport_command(
+    config: Config,
+    opts: ReimportOpts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let elevation_hdl = if opts.refetch_elevation {
+        match config.get_elevation_handler() {
+            Ok(hdl) => Some(hdl),
+            Err(e) => {
+                error!("Could not initialize the elevation service {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let writer = DbWriter::open()?;
+    let files = if opts.all {
+        writer.read(all_files)?
+    } else {
+        writer.read(|conn| {
+            opts.uuids
+                .iter()
+                .map(|uuid| find_file_by_uuid(conn, uuid))
+                .collect::<Result<Vec<FileInfo>, Error>>()
+        })?
+    };
+    if files.is_empty() {
+        return Err(Box::new(Error::Other(
+            "No files to reimport, provide one or more UUIDs or use --all".to_string(),
+        )));
+    }
+
+    let stored_files = stored_files()?;
+    let mut reimported_files = Vec::new();
+    for file in files {
+        let uuid = file.uuid();
+        // activities imported on their own are found by the hash of the whole file
+        let (source_uuid, activity_index) = writer.read(|conn| file_source(conn, file.id()))?;
+        let path = match stored_files.get(source_uuid.as_deref().unwrap_or(uuid)) {
+            Some(path) => path,
+            None => {
+                warn!(
+                    "No stored copy of FIT file UUID={} found in {:?}, skipping",
+                    uuid,
+                    devices_dir()
+                );
+                continue;
+            }
+        };
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        // the existing rows are only replaced if the new import succeeds
+        let imported = writer.write(|tx| {
+            let file_info = reimport_file(
+                tx,
+                &file,
+                path,
+                &data,
+                activity_index,
+                config.stored_fields(),
+                !opts.refetch_elevation,
+            )?;
+            if let Some(max_speed) = config.max_gps_jump_speed() {
+                remove_gps_jumps(tx, &file_info, max_speed)?;
+            }
+            if config.infer_timezone() {
+                infer_timezone(tx, &file_info)?;
+            }
+            Ok(file_info)
+        });
+        match imported {
+            Ok(file_info) => {
+                info!(
+                    "Successfully reimported FIT file: {:?} (UUID={})",
+                    path, uuid
+                );
+                reimported_files.push(file_info);
+            }
+            Err(e) => {
+                error!("Could not reimport FIT file {:?}: {}", path, e);
+            }
+        }
+    }
+
+    if let Some(hdl) = elevation_hdl {
+        add_elevation_data(&writer, hdl.as_ref(), reimported_files)?;
+    }
+
+    Ok(())
+}
+
+/// Replace the stored data of a file by importing it again. The file keeps its id and, if
+/// `keep_elevation` is set, the elevations the elevation service filled in since those aren't
+/// part of the FIT file.
+fn reimport_file(
+    tx: &Transaction,
+    file: &FileInfo,
+    path: &Path,
+    data: &[u8],
+    activity_index: Option<usize>,
+    stored_fields: &StoredFields,
+    keep_elevation: bool,
+) -> Result<FileInfo, Error> {
+    let elevations = if keep_elevation {
+        Some(ServiceElevations::load(tx, file.id())?)
+    } else {
+        None
+    };
+
+    // message rows are removed along with the file by the foreign key cascade
+    tx.execute("delete from files where id = ?", params![file.id()])?;
+    let mut file_info = import_activity_file(path, data, tx, activity_index, stored_fields)?;
+    move_file(tx, file_info.id(), file.id())?;
+    file_info.id = file.id();
+
+    if let Some(elevations) = elevations {
+        elevations.restore(tx, file.id())?;
+    }
+    Ok(file_info)
+}
+
+/// Return the UUID of the file an activity was imported from and the activity's index, both are
+/// None for files that were imported whole
+fn file_source(
+    conn: &Connection,
+    file_id: Option<u32>,
+) -> Result<(Option<String>, Option<usize>), Error> {
+    let source = conn.query_row(
+        "select source_uuid, activity_index from files where id = ?",
+        params![file_id],
+        |r| {
+            Ok((
+                r.get(0)?,
+                r.get::<usize, Option<i64>>(1)?.map(|i| i as usize),
+            ))
+        },
+    )?;
+    Ok(source)
+}
+
+/// Give a file the id it had before, along with every row that belongs to it
+fn move_file(tx: &Transaction, from: Option<u32>, to: Option<u32>) -> Result<(), Error> {
+    // the foreign keys are checked at commit instead so the rows can be moved one table at a time
+    tx.execute_batch("pragma defer_foreign_keys = on")?;
+    tx.execute("update files set id = ? where id = ?", params![to, from])?;
+    for (table, file_column) in child_tables(tx)? {
+        tx.execute(
+            &format!(
+                "update {t} set {f} = ? where {f} = ?",
+                t = table,
+                f = file_column
+            ),
+            params![to, from],
+        )?;
+    }
+    Ok(())
+}
+
+/// Elevations of a file's records and laps that came from the elevation service, keyed by the
+/// timestamps of the messages. The timestamps are kept as stored so they compare equal to the
+/// reimported ones.
+struct ServiceElevations {
+    records: Vec<(Value, f64)>,
+    laps: Vec<(Value, Option<f64>, Option<f64>)>,
+    pending: bool,
+}
+
+impl ServiceElevations {
+    fn load(tx: &Transaction, file_id: Option<u32>) -> Result<Self, Error> {
+        let mut stmt = tx.prepare(
+            "select timestamp, elevation from record_messages
+             where file_id = ? and elevation is not null",
+        )?;
+        let records = stmt
+            .query_map(params![file_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let mut stmt = tx.prepare(
+            "select start_time, start_elevation, end_elevation from lap_messages
+             where file_id = ? and (start_elevation is not null or end_elevation is not null)",
+        )?;
+        let laps = stmt
+            .query_map(params![file_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        let pending = tx.query_row(
+            "select elevation_pending from files where id = ?",
+            params![file_id],
+            |r| r.get(0),
+        )?;
+        Ok(ServiceElevations {
+            records,
+            laps,
+            pending,
+        })
+    }
+
+    /// Set the elevations on the messages of the reimported file with matching timestamps
+    fn restore(&self, tx: &Transaction, file_id: Option<u32>) -> Result<(), Error> {
+        let mut stmt = tx.prepare(
+            "update record_messages set elevation = ? where file_id = ? and timestamp = ?",
+        )?;
+        for (timestamp, elevation) in &self.records {
+            stmt.execute(params![elevation, file_id, timestamp])?;
+        }
+        let mut stmt = tx.prepare(
+            "update lap_messages set start_elevation = ?, end_elevation = ?
+             where file_id = ? and start_time = ?",
+        )?;
+        for (start_time, start_elevation, end_elevation) in &self.laps {
+            stmt.execute(params![start_elevation, end_elevation, file_id, start_time])?;
+        }
+        tx.execute(
+            "update files set elevation_pending = ? where id = ?",
+            params![self.pending, file_id],
+        )?;
+        Ok(())
+    }
+}
+
+/// Hash every file stored in the devices directory so they can be looked up by UUID
+pub(super) fn stored_files() -> Result<HashMap<String, PathBuf>, Error> {
+    let mut files = HashMap::new();
+    let mut dirs = vec![devices_dir()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let mut data = Vec::new();
+                File::open(&path)?.read_to_end(&mut data)?;
+                files.insert(generate_uuid(&data), path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import_fit_data;
+    use crate::test_support::{memory_db, simple_run, START};
+
+    #[test]
+    fn reimport_keeps_the_file_id_and_service_elevations() {
+        let mut conn = memory_db();
+        let data = simple_run(START);
+        let tx = conn.transaction().unwrap();
+        import_fit_data(&mut simple_run(START - 86400).as_slice(), &tx).unwrap();
+        let file = import_fit_data(&mut data.as_slice(), &tx).unwrap();
+        tx.execute(
+            "update record_messages set elevation = 100.0 + id where file_id = ?",
+            params![file.id()],
+        )
+        .unwrap();
+        tx.execute(
+            "update lap_messages set start_elevation = 101.0, end_elevation = 103.0
+             where file_id = ?",
+            params![file.id()],
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        let elevations = |conn: &Connection| -> Vec<Option<f64>> {
+            let mut stmt = conn
+                .prepare(
+                    "select elevation from record_messages where file_id = ?
+                     union all
+                     select start_elevation from lap_messages where file_id = ?
+                     union all
+                     select end_elevation from lap_messages where file_id = ?",
+                )
+                .unwrap();
+            stmt.query_map(params![file.id(), file.id(), file.id()], |r| r.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let before = elevations(&conn);
+
+        let tx = conn.transaction().unwrap();
+        let path = Path::new("run.fit");
+        let stored_fields = StoredFields::default();
+        let reimported =
+            reimport_file(&tx, &file, path, &data, None, &stored_fields, true).unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(reimported.id(), file.id());
+        assert_eq!(reimported.uuid(), file.uuid());
+        assert_eq!(elevations(&conn), before);
+        let nrecords: i64 = conn
+            .query_row(
+                "select count(*) from record_messages where file_id = ?",
+                params![file.id()],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(nrecords, 3);
+    }
+}
@@ -0,0 +1,272 @@
+//! Define the report subcommand
+use super::parse_date;
+use super::route_image::{load_route, LabelMode};
+use super::OutputSink;
+use crate::config::Config;
+use crate::db::{new_file_info_query, open_db_connection};
+use crate::services::visualization::route::{ImageFormat, RouteDrawingService};
+use crate::{DistanceUnit, ElevationSource, FileInfo};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{Datelike, Duration, NaiveDate};
+use log::warn;
+use rusqlite::{params, Connection, Result};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+// layout of the generated SVG in pixels
+static REPORT_WIDTH: u32 = 780;
+static CHART_HEIGHT: u32 = 200;
+static THUMBNAIL_SIZE: u32 = 240;
+static THUMBNAILS_PER_ROW: u32 = 3;
+static MARGIN: u32 = 20;
+
+/// Create a shareable SVG summary of a week of runs with a mileage chart and route thumbnails
+#[derive(Debug, StructOpt)]
+pub struct ReportOpts {
+    /// Any date within the week (Monday through Sunday) to report on (YYYY-MM-DD format)
+    #[structopt(short, long, parse(try_from_str = parse_date))]
+    week: NaiveDate,
+    /// name of file to output the SVG to, if not provided or "-" is used data is written to stdout
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Skip requesting route images from the route visualization service
+    #[structopt(long)]
+    no_routes: bool,
+    /// Open the report in the system's default viewer after writing it
+    #[structopt(long)]
+    open: bool,
+}
+
+/// A single run included in the report
+struct Run {
+    file: FileInfo,
+    distance: f64,
+    calories: Option<f64>,
+    route: Option<(ImageFormat, Vec<u8>)>,
+}
+
+/// Implementation of the `report` subcommand
+pub fn report_command(config: Config, opts: ReportOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let start = opts.week - Duration::days(opts.week.weekday().num_days_from_monday() as i64);
+    let end = start + Duration::days(7);
+    let mut route_drawer = if opts.no_routes {
+        None
+    } else {
+        match config.get_route_visualization_handler() {
+            Ok(hdl) => Some(hdl),
+            Err(e) => {
+                warn!("Route thumbnails will not be included - {}", e);
+                None
+            }
+        }
+    };
+    if let Some(hdl) = route_drawer.as_mut() {
+        hdl.set_image_format(ImageFormat::Png);
+    }
+
+    let conn = open_db_connection()?;
+    let mut query = new_file_info_query();
+    query.and_where("time_created >= ?");
+    query.and_where("time_created < ?");
+    query.order_by("time_created ASC");
+    let mut stmt = conn.prepare(&query.to_string())?;
+    let files = stmt
+        .query_map(params![start, end], |r| FileInfo::try_from(r))?
+        .collect::<Result<Vec<FileInfo>>>()?;
+
+    let elevation_source = config.elevation_source();
+    let mut runs = Vec::with_capacity(files.len());
+    for file in files {
+        let distance = total_distance(&conn, file.id())?;
+        let calories = total_calories(&conn, file.id())?;
+        let route = route_drawer
+            .as_deref()
+            .and_then(|hdl| route_thumbnail(&conn, hdl, &file, elevation_source));
+        runs.push(Run {
+            file,
+            distance,
+            calories,
+            route,
+        });
+    }
+
+    let output = OutputSink::new(opts.output);
+    output.write(render_report(start, &runs).as_bytes())?;
+    if opts.open {
+        output.open();
+    }
+
+    Ok(())
+}
+
+/// Total distance of a file in miles
+fn total_distance(conn: &Connection, file_id: Option<u32>) -> Result<f64> {
+    conn.query_row(
+        "select coalesce(max(distance), 0) from record_messages where file_id = ?",
+        params![file_id],
+        |r| r.get::<usize, f64>(0),
+    )
+    .map(|d| d * 0.0006213712)
+}
+
+/// Total calories of a file summed across its laps, None when no lap recorded them
+pub(super) fn total_calories(conn: &Connection, file_id: Option<u32>) -> Result<Option<f64>> {
+    conn.query_row(
+        "select sum(total_calories) from lap_messages where file_id = ?",
+        params![file_id],
+        |r| r.get(0),
+    )
+}
+
+/// Request a route image for the file, failures only produce a warning since the rest of the
+/// report is still useful without it
+fn route_thumbnail(
+    conn: &Connection,
+    hdl: &dyn RouteDrawingService,
+    file: &FileInfo,
+    elevation_source: ElevationSource,
+) -> Option<(ImageFormat, Vec<u8>)> {
+    let image = load_route(
+        conn,
+        file,
+        LabelMode::Lap,
+        DistanceUnit::Mile,
+        1,
+        elevation_source,
+    )
+    .map_err(|e| e.to_string())
+    .and_then(|(trace, markers)| hdl.draw_route(&trace, &markers).map_err(|e| e.to_string()));
+    match image {
+        Ok(data) => match ImageFormat::detect(&data) {
+            Some(format) => Some((format, data)),
+            None => {
+                warn!("Unrecognized route image format for UUID={}", file.uuid());
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Could not draw route for UUID={} - {}", file.uuid(), e);
+            None
+        }
+    }
+}
+
+/// Lay out the weekly totals, a bar chart of daily mileage and the route thumbnails as an SVG
+fn render_report(start: NaiveDate, runs: &[Run]) -> String {
+    let mut daily = [0.0f64; 7];
+    for run in runs {
+        let day = run
+            .file
+            .activity_timestamp()
+            .weekday()
+            .num_days_from_monday();
+        daily[day as usize] += run.distance;
+    }
+    let total: f64 = daily.iter().sum();
+    // runs without calories are left out of the total rather than counted as zero
+    let calories: Option<f64> = runs.iter().filter_map(|r| r.calories).reduce(|a, b| a + b);
+    let max_daily = daily.iter().cloned().fold(0.0, f64::max);
+
+    let thumbnails: Vec<&Run> = runs.iter().filter(|r| r.route.is_some()).collect();
+    let rows = (thumbnails.len() as u32).div_ceil(THUMBNAILS_PER_ROW);
+    let chart_top = 90;
+    let thumbs_top = chart_top + CHART_HEIGHT + 50;
+    let height = thumbs_top + rows * (THUMBNAIL_SIZE + 30) + MARGIN;
+
+    // writing into a String can't fail so the results are ignored
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif">"#,
+        w = REPORT_WIDTH,
+        h = height
+    );
+    let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="40" font-size="24" font-weight="bold">Week of {}</text>"#,
+        MARGIN,
+        start.format("%Y-%m-%d")
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="70" font-size="16">{:.2} miles in {} runs{}</text>"#,
+        MARGIN,
+        total,
+        runs.len(),
+        calories.map_or_else(String::new, |c| format!(", {:.0} kcal", c))
+    );
+
+    // daily mileage bar chart
+    let slot = (REPORT_WIDTH - 2 * MARGIN) / 7;
+    for (i, (miles, name)) in daily
+        .iter()
+        .zip(["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"])
+        .enumerate()
+    {
+        let x = MARGIN + i as u32 * slot;
+        let bar = if max_daily > 0.0 {
+            (miles / max_daily * (CHART_HEIGHT - 20) as f64).round() as u32
+        } else {
+            0
+        };
+        let baseline = chart_top + CHART_HEIGHT;
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="steelblue"/>"#,
+            x + 10,
+            baseline - bar,
+            slot - 20,
+            bar
+        );
+        if *miles > 0.0 {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{}" y="{}" font-size="12" text-anchor="middle">{:.1}</text>"#,
+                x + slot / 2,
+                baseline - bar - 4,
+                miles
+            );
+        }
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" font-size="14" text-anchor="middle">{}</text>"#,
+            x + slot / 2,
+            baseline + 20,
+            name
+        );
+    }
+
+    // route thumbnail collage
+    for (i, run) in thumbnails.iter().enumerate() {
+        let (format, data) = match &run.route {
+            Some(route) => route,
+            None => continue,
+        };
+        let x = MARGIN + (i as u32 % THUMBNAILS_PER_ROW) * (THUMBNAIL_SIZE + 10);
+        let y = thumbs_top + (i as u32 / THUMBNAILS_PER_ROW) * (THUMBNAIL_SIZE + 30);
+        let _ = writeln!(
+            svg,
+            r#"<image x="{}" y="{}" width="{s}" height="{s}" preserveAspectRatio="xMidYMid slice" href="data:{};base64,{}"/>"#,
+            x,
+            y,
+            format.mime_type(),
+            BASE64.encode(data),
+            s = THUMBNAIL_SIZE
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" font-size="12">{} - {:.2} mi</text>"#,
+            x,
+            y + THUMBNAIL_SIZE + 16,
+            run.file.activity_timestamp().format("%a %H:%M"),
+            run.distance
+        );
+    }
+    svg.push_str("</svg>\n");
+
+    svg
+}
@@ -1,12 +1,22 @@
 //! Define route image subcommand
+use super::OutputSink;
 use crate::config::Config;
-use crate::db::{find_file_by_uuid, open_db_connection};
-use crate::gps::Location;
-use crate::services::visualization::route::Marker;
-use rusqlite::{params, Result};
-use std::fs::File;
-use std::io::{self, Write};
+use crate::db::{find_file_by_uuid, open_db_connection, total_climb};
+use crate::gps::{downsample, privacy_trim, Location};
+use crate::services::visualization::plotting::escape;
+use crate::services::visualization::route::{ImageFormat, Marker};
+use crate::{
+    generate_uuid, route_image_cache_dir, DistanceUnit, ElevationSource, ElevationUnit, Error,
+    FileInfo,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use rusqlite::{params, Connection, Result};
+use std::fs::{self, create_dir_all};
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 /// Generate an image of the running route based on the file's waypoints
@@ -14,83 +24,530 @@ use structopt::StructOpt;
 pub struct RouteImageOpts {
     /// Full or partial UUID of file we want to generate route image for (use list-files command
     /// to see UUIDs). The special identifier :last will return the most recent file import.
-    #[structopt(name = "FILE_UUID")]
-    uuid: String,
-    /// name of file to output image data to, if not provided or "-" is used data is written to stdout
+    #[structopt(name = "FILE_UUID", required_unless = "uuids")]
+    uuid: Option<String>,
+    /// Comma separated UUIDs of several files whose traces are drawn on the same map, e.g. to
+    /// compare every run of a route. Markers aren't drawn for multiple files.
+    #[structopt(long, use_delimiter = true, conflicts_with = "FILE_UUID")]
+    uuids: Vec<String>,
+    /// name of file to output image data to, if not provided or "-" is used data is written to stdout.
+    /// The image format is chosen based on the file extension (png, jpg or webp) when the service
+    /// allows it.
     #[structopt(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
+    /// Open the image in the system's default viewer after writing it
+    #[structopt(long)]
+    open: bool,
+    /// Only draw the route line without the start, finish and lap markers
+    #[structopt(long)]
+    no_markers: bool,
+    /// How to label lap markers: lap (lap number) or distance (cumulative distance)
+    #[structopt(long, default_value = "lap")]
+    label_mode: LabelMode,
+    /// Units used for distance labels: mi or km
+    #[structopt(long, default_value = "mi")]
+    units: DistanceUnit,
+    /// Add a caption with the run's distance, time and elevation gain below the route. The
+    /// image is wrapped in an SVG to do this, so the output file must use the svg extension.
+    #[structopt(long)]
+    caption: bool,
+    /// Draw at most this many points of the route, very long activities are evenly downsampled
+    /// to keep requests to the drawing service small
+    #[structopt(long)]
+    max_points: Option<usize>,
+    /// Only draw every Nth GPS point (the finish is always kept), a lighter alternative to
+    /// --max-points for activities with very dense recording. The default of 1 draws every point.
+    #[structopt(long, default_value = "1")]
+    thin: usize,
+    /// Elevation to use: api (from the elevation service) or device (recorded by the watch),
+    /// defaults to the elevation_source config value
+    #[structopt(long)]
+    elevation_source: Option<ElevationSource>,
+    /// Request a new image from the route drawing service even if a cached one exists, the
+    /// cache is updated with the new image
+    #[structopt(long)]
+    no_cache: bool,
+    /// Print the URL the route drawing service would be sent, with credentials masked, instead
+    /// of requesting the image
+    #[structopt(long)]
+    print_url: bool,
+    /// Hide the part of the route within this many meters of the start and finish, e.g. to not
+    /// reveal where you live when sharing it
+    #[structopt(long)]
+    privacy_radius: Option<f64>,
+}
+
+/// How lap markers are labeled on the route
+#[derive(Clone, Copy, Debug)]
+pub(super) enum LabelMode {
+    Lap,
+    Distance,
+}
+
+impl FromStr for LabelMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lap" => Ok(LabelMode::Lap),
+            "distance" => Ok(LabelMode::Distance),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: lap, distance"
+            ))),
+        }
+    }
 }
 
 pub fn route_image_command(
     config: Config,
     opts: RouteImageOpts,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let route_drawer = config.get_route_visualization_handler()?;
+    let mut route_drawer = config.get_route_visualization_handler()?;
+    let elevation_unit = config.units().elevation_unit();
+    let elevation_source = opts
+        .elevation_source
+        .unwrap_or_else(|| config.elevation_source());
+
+    // stdout keeps the service's default format
+    let output = OutputSink::new(opts.output);
+    let format = if opts.caption {
+        if let Some(path) = output.path() {
+            if !path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+            {
+                return Err(Box::new(Error::Other(format!(
+                    "--caption writes an SVG image, {:?} needs an svg extension",
+                    path
+                ))));
+            }
+        }
+        // the embedded image needs known dimensions so ask for a format we can read them from
+        route_drawer.set_image_format(ImageFormat::Png);
+        None
+    } else {
+        output.path().and_then(ImageFormat::from_extension)
+    };
+    match (output.path(), format) {
+        (Some(path), None) if !opts.caption => warn!(
+            "Unknown image extension for {:?}, writing the service's default format",
+            path
+        ),
+        (_, Some(format)) if !route_drawer.set_image_format(format) => warn!(
+            "Route drawing service can't be asked for {} images, the output may not match",
+            format
+        ),
+        _ => {}
+    }
+
     let conn = open_db_connection()?;
 
-    // locate file_id from uuid
-    let file_id = match find_file_by_uuid(&conn, &opts.uuid) {
-        Ok(info) => info.id,
-        Err(e) => return Err(Box::new(e)),
+    // locate file_ids from uuids
+    let files = match opts.uuid.as_ref() {
+        Some(uuid) => vec![find_file_by_uuid(&conn, uuid)?],
+        None => opts
+            .uuids
+            .iter()
+            .map(|uuid| find_file_by_uuid(&conn, uuid))
+            .collect::<Result<Vec<FileInfo>, Error>>()?,
     };
+    if files.len() > 1 && opts.caption {
+        return Err(Box::new(Error::Other(
+            "--caption describes a single run and can't be used with multiple files".to_string(),
+        )));
+    }
 
-    // TODO throw an eror if the trace is empty
+    if opts.thin == 0 {
+        return Err(Box::new(Error::Other(
+            "--thin must be at least 1 to draw a route".to_string(),
+        )));
+    }
+    if opts.max_points.is_some_and(|max_points| max_points < 2) {
+        return Err(Box::new(Error::Other(
+            "--max-points must be at least 2 to draw a route".to_string(),
+        )));
+    }
+    let mut traces = Vec::new();
+    let mut markers = Vec::new();
+    for file_info in &files {
+        let (trace, file_markers) = load_route(
+            &conn,
+            file_info,
+            opts.label_mode,
+            opts.units,
+            opts.thin,
+            elevation_source,
+        )?;
+        let (mut trace, file_markers) = match opts.privacy_radius {
+            Some(radius) => hide_privacy_zone(trace, file_markers, radius, file_info)?,
+            None => (trace, file_markers),
+        };
+        if let Some(max_points) = opts.max_points {
+            trace = downsample(&trace, max_points);
+        }
+        traces.push(trace);
+        // markers would overlap each other so they're only drawn for a single file
+        if files.len() == 1 && !opts.no_markers {
+            markers = file_markers;
+        }
+    }
+    let traces: Vec<&[Location]> = traces.iter().map(Vec::as_slice).collect();
+    if opts.print_url {
+        println!("{}", route_drawer.preview_url(&traces, &markers)?);
+        return Ok(());
+    }
+    // the request URL covers the service, style, dimensions and every drawn point so changes to
+    // a file's records lead to a new cache entry
+    let cache_key = if config.cache_route_images() {
+        match route_drawer.preview_url(&traces, &markers) {
+            Ok(url) => Some(CacheKey::new(route_image_cache_dir(), &files, &url)),
+            Err(e) => {
+                debug!("Route images can't be cached: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut image_data = cached_image(cache_key.as_ref(), opts.no_cache, || {
+        route_drawer.draw_routes(&traces, &markers)
+    })?;
+    if opts.caption {
+        let caption = route_caption(
+            &conn,
+            files[0].id(),
+            opts.units,
+            elevation_unit,
+            elevation_source,
+        )?;
+        image_data = captioned_image(&image_data, &caption)?.into_bytes();
+    }
+    if let (Some(expected), Some(actual)) = (format, ImageFormat::detect(&image_data)) {
+        if expected != actual {
+            return Err(Box::new(Error::Other(format!(
+                "Route drawing service returned {} data which doesn't match the requested {} output",
+                actual, expected
+            ))));
+        }
+    }
+    output.write(&image_data)?;
+    if opts.open {
+        output.open();
+    }
 
-    // fetch all waypoints from record_messages and convert them into a GPS location trace for
-    // map plotting
-    let mut stmt = conn.prepare(
-        "select position_lat, position_long from record_messages where
-                                 file_id = ? and
-                                 position_lat is not null and
-                                 position_long is not null
-                                 order by timestamp",
+    Ok(())
+}
+
+/// Return the cached image unless `no_cache` is set, otherwise the image is drawn and stored in
+/// the cache for the next request
+fn cached_image<F>(
+    cache_key: Option<&CacheKey>,
+    no_cache: bool,
+    draw: F,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<Vec<u8>, Box<dyn std::error::Error>>,
+{
+    let cached = match cache_key {
+        Some(key) if !no_cache => key.read(),
+        _ => None,
+    };
+    if let Some(data) = cached {
+        return Ok(data);
+    }
+    let data = draw()?;
+    if let Some(key) = cache_key {
+        if let Err(e) = key.store(&data) {
+            warn!("Could not cache the route image: {}", e);
+        }
+    }
+    Ok(data)
+}
+
+/// Names the cache entry of a route image, entries of the same files share a prefix so stale
+/// images are removed when a new one is stored
+struct CacheKey {
+    dir: PathBuf,
+    files: String,
+    request: String,
+}
+
+impl CacheKey {
+    fn new(dir: PathBuf, files: &[FileInfo], request_url: &str) -> Self {
+        let uuids: Vec<&str> = files.iter().map(FileInfo::uuid).collect();
+        CacheKey {
+            dir,
+            files: generate_uuid(uuids.join(",").as_bytes()),
+            request: generate_uuid(request_url.as_bytes()),
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}-{}", self.files, self.request))
+    }
+
+    /// Return the cached image if there is one
+    fn read(&self) -> Option<Vec<u8>> {
+        let data = fs::read(self.path()).ok()?;
+        debug!("Using cached route image {:?}", self.path());
+        Some(data)
+    }
+
+    /// Cache the image, replacing any images of the same files drawn from other requests
+    fn store(&self, data: &[u8]) -> Result<(), Error> {
+        create_dir_all(&self.dir)?;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let stale = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&format!("{}-", self.files)));
+            if stale {
+                fs::remove_file(&path)?;
+            }
+        }
+        fs::write(self.path(), data)?;
+        Ok(())
+    }
+}
+
+/// Summarize the distance, elapsed time and elevation gain of a file in a single line
+fn route_caption(
+    conn: &Connection,
+    file_id: Option<u32>,
+    units: DistanceUnit,
+    elevation_unit: ElevationUnit,
+    elevation_source: ElevationSource,
+) -> Result<String, Error> {
+    let (distance, start, end): (
+        Option<f64>,
+        Option<DateTime<Local>>,
+        Option<DateTime<Local>>,
+    ) = conn.query_row(
+        "select max(distance), min(timestamp), max(timestamp) from record_messages
+             where file_id = ?",
+        params![file_id],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
     )?;
-    let mut rows = stmt.query(params![file_id])?;
+    let mut parts = vec![format!(
+        "{:0.2} {}",
+        distance.unwrap_or(0.0) / units.meters(),
+        units.abbreviation()
+    )];
+    if let (Some(start), Some(end)) = (start, end) {
+        let secs = (end - start).num_seconds();
+        parts.push(format!(
+            "{}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        ));
+    }
+
+    if let Some((gain, _)) = total_climb(conn, file_id, elevation_source)? {
+        parts.push(format!(
+            "{:0.0} {} gain",
+            elevation_unit.from_meters(gain),
+            elevation_unit.abbreviation()
+        ));
+    }
+
+    Ok(parts.join("  |  "))
+}
+
+/// Wrap the image in an SVG with the caption written on a bar below it
+fn captioned_image(data: &[u8], caption: &str) -> Result<String, Error> {
+    let format = ImageFormat::detect(data).ok_or_else(|| {
+        Error::Other("Route drawing service returned an unknown image format".to_string())
+    })?;
+    let (width, height) = ImageFormat::dimensions(data).ok_or_else(|| {
+        Error::Other(format!(
+            "Could not read the size of the {} route image to add a caption",
+            format
+        ))
+    })?;
+    let bar = (height / 12).max(40);
+    Ok(format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif">
+<image x="0" y="0" width="{w}" height="{ih}" href="data:{mime};base64,{data}"/>
+<rect x="0" y="{ih}" width="{w}" height="{bar}" fill="white"/>
+<text x="{cx}" y="{ty}" font-size="{fs}" text-anchor="middle" dominant-baseline="middle">{caption}</text>
+</svg>
+"#,
+        w = width,
+        h = height + bar,
+        ih = height,
+        mime = format.mime_type(),
+        data = BASE64.encode(data),
+        bar = bar,
+        cx = width / 2,
+        ty = height + bar / 2,
+        fs = bar / 2,
+        caption = escape(caption),
+    ))
+}
+
+/// Load the GPS trace of a file along with start, finish and lap markers, only every `thin`-th
+/// point of the trace is kept along with the last one
+pub(super) fn load_route(
+    conn: &Connection,
+    file_info: &FileInfo,
+    label_mode: LabelMode,
+    units: DistanceUnit,
+    thin: usize,
+    elevation_source: ElevationSource,
+) -> Result<(Vec<Location>, Vec<Marker>), Error> {
+    // fetch the waypoints from record_messages and convert them into a GPS location trace for
+    // map plotting
+    let mut stmt = conn.prepare(&format!(
+        "select position_lat, position_long, elevation from (
+             select position_lat, position_long, {} as elevation, timestamp,
+                    row_number() over (order by timestamp) - 1 as n,
+                    count(*) over () - 1 as last
+             from record_messages where
+                 file_id = ?1 and
+                 position_lat is not null and
+                 position_long is not null
+         ) where n % ?2 = 0 or n = last
+         order by timestamp",
+        elevation_source.column()
+    ))?;
+    let mut rows = stmt.query(params![file_info.id(), thin as i64])?;
     let mut trace: Vec<Location> = Vec::new();
     while let Some(row) = rows.next()? {
-        trace.push(Location::from_fit_coordinates(row.get(0)?, row.get(1)?));
+        let mut loc = Location::from_fit_coordinates(row.get(0)?, row.get(1)?);
+        loc.set_elevation(row.get(2)?);
+        trace.push(loc);
     }
 
     // fetch all waypoints from lap_messages and convert them into a GPS location markers for
     // map plotting
     let mut stmt = conn.prepare(
-        "select end_position_lat, end_position_long from lap_messages where
-                                 file_id = ? and
-                                 end_position_lat is not null and
-                                 end_position_long is not null
+        "select end_position_lat, end_position_long, total_distance from lap_messages where
+                                 file_id = ?
                                  order by timestamp",
     )?;
-    let mut rows = stmt.query(params![file_id])?;
-    let mut markers: Vec<Marker> = vec![Marker::new(trace[0], "S".to_string())];
-    let mut mile = 1;
+    let mut rows = stmt.query(params![file_info.id()])?;
+    let mut markers: Vec<Marker> = match trace.first() {
+        Some(loc) => vec![Marker::new(*loc, "S".to_string())],
+        None => return Err(Error::NoRecordsFound(file_info.uuid().to_string())),
+    };
+    let mut lap = 0;
+    let mut distance = 0.0;
     while let Some(row) = rows.next()? {
-        markers.push(Marker::new(
-            Location::from_fit_coordinates(row.get(0)?, row.get(1)?),
-            format!("{}", mile),
-        ));
-        mile += 1;
+        lap += 1;
+        distance += row.get::<usize, Option<f64>>(2)?.unwrap_or(0.0);
+        // laps without a position still count towards the lap number and distance
+        let (lat, lon) = match (row.get(0)?, row.get(1)?) {
+            (Some(lat), Some(lon)) => (lat, lon),
+            _ => continue,
+        };
+        // some services only accept short numeric labels so distances are rounded
+        let label = match label_mode {
+            LabelMode::Lap => lap.to_string(),
+            LabelMode::Distance => format!("{:.0}", distance / units.meters()),
+        };
+        markers.push(Marker::new(Location::from_fit_coordinates(lat, lon), label));
     }
     if let Some(loc) = trace.last() {
         markers.push(Marker::new(*loc, "F".to_string()));
     }
 
-    let image_data = route_drawer.draw_route(&trace, &markers)?;
-    if let Some(path) = opts.output {
-        if path.to_string_lossy() == "-" {
-            write_to_stdout(&image_data)?
-        } else {
-            let mut fp = File::create(path)?;
-            fp.write_all(&image_data)?
-        }
-    } else {
-        write_to_stdout(&image_data)?
+    Ok((trace, markers))
+}
+
+/// Drop the part of the trace within `radius` meters of its start and finish. The start and
+/// finish markers are moved to the ends of the remaining trace and lap markers inside either zone
+/// are dropped.
+pub(super) fn hide_privacy_zone(
+    trace: Vec<Location>,
+    markers: Vec<Marker>,
+    radius: f64,
+    file_info: &FileInfo,
+) -> Result<(Vec<Location>, Vec<Marker>), Error> {
+    let range = privacy_trim(&trace, radius);
+    if range.is_empty() {
+        return Err(Error::Other(format!(
+            "No GPS points of file UUID={} are outside of the {}m privacy radius",
+            file_info.uuid(),
+            radius
+        )));
     }
+    let (start, finish) = (trace[0], trace[trace.len() - 1]);
+    let trimmed = trace[range].to_vec();
 
-    Ok(())
+    // load_route always starts the markers with the start and ends them with the finish
+    let last = markers.len() - 1;
+    let markers = markers
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, marker)| {
+            if i == 0 {
+                Some(Marker::new(trimmed[0], marker.label().to_string()))
+            } else if i == last {
+                Some(Marker::new(
+                    trimmed[trimmed.len() - 1],
+                    marker.label().to_string(),
+                ))
+            } else if marker.location().distance_to(&start) <= radius
+                || marker.location().distance_to(&finish) <= radius
+            {
+                None
+            } else {
+                Some(marker)
+            }
+        })
+        .collect();
+
+    Ok((trimmed, markers))
 }
 
-fn write_to_stdout(data: &[u8]) -> io::Result<()> {
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
-    handle.write_all(&data)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::visualization::route::{MapBox, RouteDrawingService};
+    use crate::test_support::{import, memory_db, simple_run, START};
+    use std::cell::Cell;
+
+    #[test]
+    fn cached_image_is_reused_until_the_trace_changes() {
+        let mut conn = memory_db();
+        let file = import(&mut conn, &simple_run(START));
+        let dir = std::env::temp_dir().join(format!("grt-route-cache-{}", std::process::id()));
+        let drawer = MapBox::default();
+        let cache_key = |trace: &[Location]| {
+            let url = drawer.preview_url(&[trace], &[]).unwrap();
+            CacheKey::new(dir.clone(), std::slice::from_ref(&file), &url)
+        };
+        let draws = Cell::new(0);
+        let draw = |image: &str| {
+            draws.set(draws.get() + 1);
+            Ok(image.as_bytes().to_vec())
+        };
+        let mut trace = vec![Location::new(39.46, -80.14), Location::new(39.47, -80.13)];
+
+        let key = cache_key(&trace);
+        let image = cached_image(Some(&key), false, || draw("first")).unwrap();
+        assert_eq!((image, draws.get()), (b"first".to_vec(), 1));
+        // an identical request is served from the cache
+        let key = cache_key(&trace);
+        let image = cached_image(Some(&key), false, || draw("second")).unwrap();
+        assert_eq!((image, draws.get()), (b"first".to_vec(), 1));
+
+        // --no-cache draws the image again and replaces the cached one
+        let image = cached_image(Some(&key), true, || draw("refreshed")).unwrap();
+        assert_eq!((image, draws.get()), (b"refreshed".to_vec(), 2));
+        let image = cached_image(Some(&key), false, || draw("second")).unwrap();
+        assert_eq!((image, draws.get()), (b"refreshed".to_vec(), 2));
+
+        // moving a point changes the request so the image is drawn again, the stale entry of
+        // the file is removed
+        trace[1] = Location::new(39.48, -80.12);
+        let key = cache_key(&trace);
+        let image = cached_image(Some(&key), false, || draw("moved")).unwrap();
+        assert_eq!((image, draws.get()), (b"moved".to_vec(), 3));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,171 @@
+//! Define routes subcommand
+use crate::db::{new_file_info_query, open_db_connection};
+use crate::gps::{simplify, Location};
+use crate::FileInfo;
+use rusqlite::{params, Connection, Result};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use structopt::StructOpt;
+
+/// Tolerance in meters used to simplify traces before comparing them
+static SIMPLIFY_TOLERANCE: f64 = 10.0;
+
+/// Group runs that follow the same path into routes and list how often each one was run
+#[derive(Debug, StructOpt)]
+pub struct RoutesOpts {
+    /// Maximum average distance in meters between two traces (and their start points) for the
+    /// runs to be considered the same route
+    #[structopt(long, default_value = "100")]
+    radius: f64,
+    /// Maximum difference in total distance between runs of the same route in percent
+    #[structopt(long, default_value = "10")]
+    distance_tolerance: f64,
+    /// Only list routes that were run at least this many times
+    #[structopt(long, default_value = "2")]
+    min_runs: usize,
+    /// List the date and UUID of every run below each route
+    #[structopt(long)]
+    files: bool,
+    /// Display dates in UTC instead of the timezone the activity was recorded in
+    #[structopt(long)]
+    utc: bool,
+}
+
+/// A single run reduced to the data needed to compare it with others
+struct Run {
+    info: FileInfo,
+    distance: f64,
+    trace: Vec<Location>,
+}
+
+pub fn routes_command(opts: RoutesOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = open_db_connection()?;
+    let runs = load_runs(&conn)?;
+
+    // greedily assign each run to the first route whose initial run it matches, runs are
+    // visited oldest first so a route is represented by the first time it was run
+    let mut routes: Vec<Vec<Run>> = Vec::new();
+    for run in runs {
+        match routes
+            .iter_mut()
+            .find(|route| same_route(&route[0], &run, &opts))
+        {
+            Some(route) => route.push(run),
+            None => routes.push(vec![run]),
+        }
+    }
+    // most frequently run routes first, ties go to the most recently run
+    routes.retain(|route| route.len() >= opts.min_runs);
+    routes.sort_by(|a, b| {
+        b.len()
+            .cmp(&a.len())
+            .then_with(|| last_run(b).timestamp().cmp(last_run(a).timestamp()))
+    });
+
+    summary!("Route\tRuns\tDistance[mi]\tLast Run\tUUID");
+    for (i, route) in routes.iter().enumerate() {
+        let avg_distance = route.iter().map(|r| r.distance).sum::<f64>() / route.len() as f64;
+        let last = last_run(route);
+        println!(
+            "{}\t{}\t{:0.2}\t{}\t({})",
+            i + 1,
+            route.len(),
+            avg_distance * 0.00062137,
+            last.format_timestamp("%Y-%m-%d", opts.utc),
+            last.uuid()
+        );
+        if opts.files {
+            for run in route {
+                println!(
+                    "\t * {} {:0.2} miles ({})",
+                    run.info.format_timestamp("%Y-%m-%d %H:%M", opts.utc),
+                    run.distance * 0.00062137,
+                    run.info.uuid()
+                );
+            }
+        }
+    }
+    summary!(
+        "Found {} route(s) run at least {} time(s)",
+        routes.len(),
+        opts.min_runs
+    );
+
+    Ok(())
+}
+
+/// Load every file with GPS data along with its simplified trace, oldest first
+fn load_runs(conn: &Connection) -> Result<Vec<Run>, Box<dyn std::error::Error>> {
+    let mut query = new_file_info_query();
+    query.order_by("time_created ASC");
+    let mut stmt = conn.prepare(&query.to_string())?;
+    let files = stmt
+        .query_map([], |r| FileInfo::try_from(r))?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut stmt = conn.prepare(
+        "select position_lat, position_long, distance, file_id from record_messages where
+                                 position_lat is not null and
+                                 position_long is not null
+                                 order by file_id, timestamp",
+    )?;
+    let mut rows = stmt.query(params![])?;
+    let mut traces: HashMap<u32, (f64, Vec<Location>)> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let entry = traces.entry(row.get(3)?).or_default();
+        entry.0 = entry
+            .0
+            .max(row.get::<usize, Option<f64>>(2)?.unwrap_or(0.0));
+        entry
+            .1
+            .push(Location::from_fit_coordinates(row.get(0)?, row.get(1)?));
+    }
+
+    Ok(files
+        .into_iter()
+        .filter_map(|info| {
+            let (distance, trace) = traces.remove(&info.id()?)?;
+            Some(Run {
+                info,
+                distance,
+                trace: simplify(&trace, SIMPLIFY_TOLERANCE),
+            })
+        })
+        .collect())
+}
+
+/// Compare the cheap summary values first and only then the full traces in both directions,
+/// checking both ensures an out and back doesn't match a run that turned around early.
+fn same_route(a: &Run, b: &Run, opts: &RoutesOpts) -> bool {
+    let max_distance = a.distance.max(b.distance);
+    if (a.distance - b.distance).abs() > max_distance * opts.distance_tolerance / 100.0 {
+        return false;
+    }
+    if a.trace[0].distance_to(&b.trace[0]) > opts.radius {
+        return false;
+    }
+    mean_deviation(&a.trace, &b.trace) <= opts.radius
+        && mean_deviation(&b.trace, &a.trace) <= opts.radius
+}
+
+/// Average distance in meters from each point of a trace to the closest segment of another
+fn mean_deviation(trace: &[Location], other: &[Location]) -> f64 {
+    let total: f64 = trace
+        .iter()
+        .map(|loc| {
+            if other.len() < 2 {
+                return loc.distance_to(&other[0]);
+            }
+            other
+                .windows(2)
+                .map(|seg| loc.distance_to_segment(&seg[0], &seg[1]))
+                .fold(f64::INFINITY, f64::min)
+        })
+        .sum();
+    total / trace.len() as f64
+}
+
+/// Return the most recent run of a route
+fn last_run(route: &[Run]) -> &FileInfo {
+    &route[route.len() - 1].info
+}
@@ -0,0 +1,88 @@
+//! Define the selftest subcommand
+use crate::config::Config;
+use crate::gps::Location;
+use crate::Error;
+use log::error;
+use structopt::StructOpt;
+
+/// Points with well known elevations in meters used to check the elevation service
+static ELEVATION_CHECKS: [(&str, f32, f32, f32); 5] = [
+    ("Miami Beach, FL", 25.7907, -80.1300, 1.0),
+    ("Badwater Basin, CA", 36.2302, -116.767, -86.0),
+    ("Colorado State Capitol", 39.7393, -104.9848, 1609.0),
+    ("Pikes Peak summit, CO", 38.8405, -105.0442, 4302.0),
+    ("Mont Blanc summit", 45.8326, 6.8652, 4806.0),
+];
+
+/// Check that configured services return reasonable data, the database is not used
+#[derive(Debug, StructOpt)]
+pub enum SelftestOpts {
+    /// Request the elevation of a few well known points and compare them to their expected values
+    #[structopt(name = "elevation")]
+    Elevation {
+        /// Differences in meters larger than this are flagged. Elevation datasets smooth out
+        /// summits so expect the mountain checks to come in a bit low.
+        #[structopt(short, long, default_value = "75")]
+        tolerance: f32,
+    },
+}
+
+/// Implementation of the `selftest` subcommand
+pub fn selftest_command(
+    config: Config,
+    opts: SelftestOpts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match opts {
+        SelftestOpts::Elevation { tolerance } => selftest_elevation(config, tolerance),
+    }
+}
+
+fn selftest_elevation(config: Config, tolerance: f32) -> Result<(), Box<dyn std::error::Error>> {
+    let elevation_hdl = match config.get_elevation_handler() {
+        Ok(hdl) => hdl,
+        Err(e) => {
+            error!("Could not initialize the elevation service {}", e);
+            return Err(Box::new(e));
+        }
+    };
+
+    let mut locations: Vec<Location> = ELEVATION_CHECKS
+        .iter()
+        .map(|(_, lat, lon, _)| Location::new(*lat, *lon))
+        .collect();
+    elevation_hdl.request_elevation_data(&mut locations)?;
+
+    let mut failures = 0;
+    summary!("Status\tExpected[m]\tReturned[m]\tDifference[m]\tLocation");
+    for ((name, _, _, expected), loc) in ELEVATION_CHECKS.iter().zip(&locations) {
+        match loc.elevation() {
+            Some(elevation) => {
+                let diff = elevation - expected;
+                let status = if diff.abs() > tolerance {
+                    failures += 1;
+                    "CHECK"
+                } else {
+                    "ok"
+                };
+                println!(
+                    "{}\t{:0.0}\t{:0.0}\t{:+0.0}\t{}",
+                    status, expected, elevation, diff, name
+                );
+            }
+            None => {
+                failures += 1;
+                println!("MISSING\t{:0.0}\t-\t-\t{}", expected, name);
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(Box::new(Error::Other(format!(
+            "{} of {} elevation checks were missing or off by more than {} m",
+            failures,
+            ELEVATION_CHECKS.len(),
+            tolerance
+        ))));
+    }
+    Ok(())
+}
@@ -1,11 +1,24 @@
 //! Define show subcommand
+use super::report::total_calories;
+use super::OutputSink;
 use crate::config::Config;
-use crate::db::{find_file_by_uuid, open_db_connection};
-use crate::services::visualization::plotting::{DataSeries, Plot};
-use rusqlite::{params, Result};
-//use std::fs::File;
-//use std::io::{self, Write};
-//use std::path::PathBuf;
+use crate::db::{find_file_by_uuid, open_db_connection, total_climb};
+use crate::gps::Location;
+use crate::services::visualization::plotting::{
+    escape, DataPlottingService, DataSeries, Plot, SvgPlotter, PLOT_SIZE_RANGE,
+};
+use crate::{
+    format_pace, heart_rate_recovery, pace_from_speed, DistanceUnit, ElevationSource,
+    ElevationUnit, Error, FileInfo, HeartRateRecovery,
+};
+use chrono::{DateTime, Duration, Local, Utc};
+use log::warn;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, Result};
+use serde_json::json;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 /// Show file stats and plot running data
@@ -15,96 +28,874 @@ pub struct ShowOpts {
     /// to see UUIDs). The special identifier :last will return the most recent file import.
     #[structopt(name = "FILE_UUID", default_value = ":last")]
     uuid: String,
+    /// Print the developer/custom fields stored for the file instead of plotting data
+    #[structopt(long)]
+    developer_fields: bool,
+    /// Display times in UTC instead of the timezone the activity was recorded in
+    #[structopt(long)]
+    utc: bool,
+    /// Number of points in the moving average used to smooth elevation data, both the elevation
+    /// plot and the grade computed from it show the smoothed values so GPS noise doesn't dominate
+    /// them. Use 1 to disable smoothing.
+    #[structopt(long, default_value = "5")]
+    smoothing: usize,
+    /// Elevation to use: api (from the elevation service) or device (recorded by the watch),
+    /// defaults to the elevation_source config value
+    #[structopt(long)]
+    elevation_source: Option<ElevationSource>,
+    /// Value to plot the record data against: distance or time (minutes since the start), e.g.
+    /// for treadmill runs without reliable distance
+    #[structopt(long, default_value = "distance")]
+    x_axis: XAxis,
+    /// Draw a vertical line at each lap boundary on the plots of record data
+    #[structopt(long)]
+    show_laps: bool,
+    /// Plot running dynamics (vertical oscillation, ground contact time and step length)
+    #[structopt(long)]
+    dynamics: bool,
+    /// List gaps between consecutive records (e.g. from GPS dropouts) instead of plotting data
+    #[structopt(long)]
+    report_gaps: bool,
+    /// Minimum number of seconds between records to report as a gap
+    #[structopt(long, default_value = "30")]
+    gap_threshold: i64,
+    /// Write the plotted series to stdout as JSON instead of drawing them
+    #[structopt(long)]
+    json: bool,
+    /// Write a self-contained HTML page with the charts and run statistics to this file instead
+    /// of plotting on the terminal, "-" writes the page to stdout
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Open the HTML page in the system's default viewer after writing it
+    #[structopt(long)]
+    open: bool,
+    /// Width in pixels of the plots drawn by image based plotting services and the HTML page,
+    /// overrides the configured value
+    #[structopt(long, parse(try_from_str = parse_plot_size))]
+    width: Option<u32>,
+    /// Height in pixels of each plot drawn by image based plotting services and the HTML page,
+    /// overrides the configured value
+    #[structopt(long, parse(try_from_str = parse_plot_size))]
+    height: Option<u32>,
+}
+
+/// Value the record data is plotted against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum XAxis {
+    Distance,
+    Time,
+}
+
+impl XAxis {
+    fn label(&self) -> String {
+        match self {
+            XAxis::Distance => "Distance [mi]".to_string(),
+            XAxis::Time => "Time [min]".to_string(),
+        }
+    }
+
+    /// Position of a record along the axis, None if the record has no distance to plot it at
+    fn value(
+        &self,
+        distance: Option<f64>,
+        timestamp: DateTime<Utc>,
+        start: DateTime<Utc>,
+    ) -> Option<f64> {
+        match self {
+            XAxis::Distance => distance.map(|d| d * 0.0006213712),
+            XAxis::Time => Some((timestamp - start).num_milliseconds() as f64 / 60000.0),
+        }
+    }
+
+    /// Spacing of the tick labels for an axis ending at xmax, a label per mile or a round
+    /// number of minutes that keeps the labels readable
+    fn tick_step(&self, xmax: f64) -> f64 {
+        match self {
+            XAxis::Distance => 1.0,
+            XAxis::Time => [1.0, 2.0, 5.0, 10.0, 15.0, 30.0]
+                .into_iter()
+                .find(|step| xmax / step <= 12.0)
+                .unwrap_or(60.0),
+        }
+    }
+}
+
+impl FromStr for XAxis {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "distance" => Ok(XAxis::Distance),
+            "time" => Ok(XAxis::Time),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: distance, time"
+            ))),
+        }
+    }
+}
+
+fn parse_plot_size(src: &str) -> Result<u32, Error> {
+    match src.parse::<u32>() {
+        Ok(size) if PLOT_SIZE_RANGE.contains(&size) => Ok(size),
+        _ => Err(Error::Other(format!(
+            "expected a size between {} and {} pixels",
+            PLOT_SIZE_RANGE.start(),
+            PLOT_SIZE_RANGE.end()
+        ))),
+    }
 }
 
 pub fn show_command(config: Config, opts: ShowOpts) -> Result<(), Box<dyn std::error::Error>> {
-    let plotter = config.get_plotting_visualization_handler()?;
+    let mut plotter = config.get_plotting_visualization_handler()?;
+    let elevation_unit = config.units().elevation_unit();
+    let elevation_source = opts
+        .elevation_source
+        .unwrap_or_else(|| config.elevation_source());
+    let resized = opts.width.is_some() || opts.height.is_some();
+    if resized && !plotter.set_size(opts.width, opts.height) && opts.output.is_none() {
+        warn!("The plotting service doesn't draw images, --width and --height are ignored");
+    }
     let conn = open_db_connection()?;
 
     // locate file_id from uuid
-    let file_id = match find_file_by_uuid(&conn, &opts.uuid) {
-        Ok(info) => info.id,
+    let file_info = match find_file_by_uuid(&conn, &opts.uuid) {
+        Ok(info) => info,
         Err(e) => return Err(Box::new(e)),
     };
+    let file_id = file_info.id();
+    if opts.developer_fields {
+        return print_developer_fields(&conn, file_id);
+    }
+    if opts.report_gaps {
+        return print_gaps(&conn, &file_info, opts.gap_threshold, opts.utc);
+    }
+    if opts.dynamics {
+        return plot_dynamics(
+            &conn,
+            plotter.as_ref(),
+            &file_info,
+            &opts,
+            elevation_source,
+            &config,
+        );
+    }
 
     // fetch per-record values from messages for plotting
-    let mut stmt = conn.prepare(
-        "select distance, speed, elevation, heart_rate from record_messages where
+    let start = first_record_time(&conn, file_id)?;
+    let mut stmt = conn.prepare(&format!(
+        "select distance, speed, {}, heart_rate, timestamp from record_messages where
                                  file_id = ?
                                  order by timestamp",
-    )?;
+        elevation_source.column()
+    ))?;
     let mut rows = stmt.query(params![file_id])?;
-    let mut distance: Vec<f64> = Vec::new();
-    let mut speed: Vec<f64> = Vec::new();
-    let mut elevation: Vec<f64> = Vec::new();
-    let mut heart_rate: Vec<f64> = Vec::new();
+    let mut xs: Vec<f64> = Vec::new();
+    let mut series1_data: Vec<(f64, f64)> = Vec::new();
+    // (x, distance [mi], elevation) of each record, the distance is None for indoor runs
+    let mut elevation: Vec<(f64, Option<f64>, f64)> = Vec::new();
+    let mut series3_data: Vec<(f64, f64)> = Vec::new();
     while let Some(row) = rows.next()? {
-        distance.push(row.get::<usize, f64>(0)? * 0.0006213712);
-        if let Ok(v) = row.get::<usize, f64>(1) {
-            if v != 0.0 {
-                speed.push(1.0 / (row.get::<usize, f64>(1)? * 0.0006213712 * 60.0));
-            } else {
-                speed.push(0.0); // ideally this would just be a gap in the graph
-            }
+        let dist: Option<f64> = row.get(0)?;
+        // records without a distance can't be placed on a distance axis
+        let x = match opts.x_axis.value(dist, row.get(4)?, start) {
+            Some(x) => x,
+            None => continue,
+        };
+        xs.push(x);
+        // points where we were stopped are left out instead of spiking the plot
+        if let Some(p) = row
+            .get::<usize, f64>(1)
+            .ok()
+            .and_then(|v| pace_from_speed(v, DistanceUnit::Mile))
+        {
+            series1_data.push((x, p.num_milliseconds() as f64 / 60000.0));
+        }
+        // these two may or may not have data available, e.g. before the GPS has a fix
+        if let Some(v) = row.get::<usize, Option<f64>>(2)? {
+            elevation.push((
+                x,
+                dist.map(|d| d * 0.0006213712),
+                elevation_unit.from_meters(v),
+            ));
+        }
+        if let Some(v) = row.get::<usize, Option<f64>>(3)? {
+            series3_data.push((x, v));
         }
-        // these two may or may not have data available
-        row.get::<usize, f64>(2)
-            .into_iter()
-            .for_each(|v| elevation.push(v * 3.28084));
-        row.get::<usize, f64>(3)
-            .into_iter()
-            .for_each(|v| heart_rate.push(v));
     }
 
+    let tick_step = opts
+        .x_axis
+        .tick_step(xs.iter().copied().fold(0.0, f64::max));
+    let laps = if opts.show_laps {
+        lap_boundaries(&conn, file_id, opts.x_axis, start)?
+    } else {
+        Vec::new()
+    };
     let mut pace_plot = Plot::new(
-        "".to_string(),
-        "Distance [mi]".to_string(),
+        file_info.format_timestamp("%Y-%m-%d %H:%M %:z", opts.utc),
+        opts.x_axis.label(),
         "Pace [min/mile]".to_string(),
     );
-    let series1_data: Vec<(f64, f64)> = distance
-        .iter()
-        .zip(speed.into_iter())
-        .map(|(d, s)| (*d, s))
-        .collect();
+    pace_plot.x_tick_step = tick_step;
     pace_plot.add_series(DataSeries::new("Pace", &series1_data));
+    for band in plotter.pace_zones() {
+        pace_plot.add_band(band);
+    }
 
     let mut elev_plot = Plot::new(
         "".to_string(),
-        "Distance [mi]".to_string(),
-        "Elevation [ft]".to_string(),
+        opts.x_axis.label(),
+        format!("Elevation [{}]", elevation_unit.abbreviation()),
     );
-    let series2_data: Vec<(f64, f64)> = distance
+    let values: Vec<f64> = elevation.iter().map(|(_, _, e)| *e).collect();
+    let series2_data: Vec<(f64, f64)> = elevation
         .iter()
-        .zip(elevation.into_iter())
-        .map(|(d, s)| (*d, s))
+        .zip(moving_average(&values, opts.smoothing))
+        .map(|((x, _, _), s)| (*x, s))
         .collect();
+    elev_plot.x_tick_step = tick_step;
     elev_plot.show_y_zero = false;
     elev_plot.add_series(DataSeries::new("Elevation", &series2_data));
 
+    let mut grade_plot = Plot::new("".to_string(), opts.x_axis.label(), "Grade [%]".to_string());
+    // grade is always computed over distance, records without one have no grade
+    let profile: Vec<(f64, f64, f64)> = elevation
+        .iter()
+        .filter_map(|(x, d, e)| d.map(|d| (*x, d, *e)))
+        .collect();
+    let values: Vec<f64> = profile.iter().map(|(_, _, e)| *e).collect();
+    let profile: Vec<(f64, f64, f64)> = profile
+        .iter()
+        .zip(moving_average(&values, opts.smoothing))
+        .map(|((x, d, _), s)| (*x, *d, s))
+        .collect();
+    let series4_data = grade(&profile, elevation_unit);
+    grade_plot.x_tick_step = tick_step;
+    grade_plot.show_y_zero = false;
+    grade_plot.add_series(DataSeries::new("Grade", &series4_data));
+
     let mut hr_plot = Plot::new(
         "".to_string(),
-        "Distance [mi]".to_string(),
+        opts.x_axis.label(),
         "Heart Rate [bpm]".to_string(),
     );
-    let series3_data: Vec<(f64, f64)> = distance
-        .iter()
-        .zip(heart_rate.into_iter())
-        .map(|(d, s)| (*d, s))
-        .collect();
+    hr_plot.x_tick_step = tick_step;
     hr_plot.add_series(DataSeries::new("Heart Rate", &series3_data));
 
+    for plot in [
+        &mut pace_plot,
+        &mut elev_plot,
+        &mut grade_plot,
+        &mut hr_plot,
+    ] {
+        for &x in &laps {
+            plot.add_marker(x);
+        }
+    }
+
+    // discrete per lap values are drawn as bars next to the continuous record data, rest laps
+    // keep their number but their pace is left out
+    let mut stmt = conn.prepare(
+        "select average_speed, average_heart_rate, total_distance, start_time, timestamp
+         from lap_messages where file_id = ?
+         order by start_time",
+    )?;
+    let mut rows = stmt.query(params![file_id])?;
+    let mut lap_pace: Vec<(f64, f64)> = Vec::new();
+    let mut lap_heart_rate: Vec<(f64, f64)> = Vec::new();
+    let mut lap = 0.0;
+    while let Some(row) = rows.next()? {
+        lap += 1.0;
+        let duration = row.get::<usize, DateTime<Utc>>(4)? - row.get::<usize, DateTime<Utc>>(3)?;
+        let rest = config
+            .rest_laps()
+            .is_rest(row.get(2)?, duration.num_seconds() as f64);
+        if let Some(p) = row
+            .get::<usize, Option<f64>>(0)?
+            .filter(|_| !rest)
+            .and_then(|v| pace_from_speed(v, DistanceUnit::Mile))
+        {
+            lap_pace.push((lap, p.num_milliseconds() as f64 / 60000.0));
+        }
+        if let Some(hr) = row.get::<usize, Option<f64>>(1)? {
+            lap_heart_rate.push((lap, hr));
+        }
+    }
+
+    let mut lap_pace_plot = Plot::new(
+        "".to_string(),
+        "Lap".to_string(),
+        "Lap Pace [min/mile]".to_string(),
+    );
+    lap_pace_plot.add_series(DataSeries::bars("Lap Pace", &lap_pace));
+    for band in plotter.pace_zones() {
+        lap_pace_plot.add_band(band);
+    }
+
+    let mut lap_hr_plot = Plot::new(
+        "".to_string(),
+        "Lap".to_string(),
+        "Lap Heart Rate [bpm]".to_string(),
+    );
+    lap_hr_plot.add_series(DataSeries::bars("Lap Heart Rate", &lap_heart_rate));
+
     // only plot if we have data
-    let mut all_plots = Vec::with_capacity(3);
+    let mut all_plots = Vec::with_capacity(6);
     if !series1_data.is_empty() {
         all_plots.push(&pace_plot);
     }
     if !series2_data.is_empty() {
         all_plots.push(&elev_plot);
     }
+    if !series4_data.is_empty() {
+        all_plots.push(&grade_plot);
+    }
     if !series3_data.is_empty() {
         all_plots.push(&hr_plot);
     }
-    plotter.plot(&all_plots)?;
+    if !lap_pace.is_empty() {
+        all_plots.push(&lap_pace_plot);
+    }
+    if !lap_heart_rate.is_empty() {
+        all_plots.push(&lap_hr_plot);
+    }
+    if all_plots.is_empty() {
+        return Err(Box::new(Error::NoRecordsFound(
+            file_info.uuid().to_string(),
+        )));
+    }
+
+    output_plots(
+        &conn,
+        plotter.as_ref(),
+        &file_info,
+        &all_plots,
+        &opts,
+        elevation_source,
+        &config,
+    )
+}
+
+/// Plot the running dynamics recorded by a dynamics pod or compatible watch
+fn plot_dynamics(
+    conn: &Connection,
+    plotter: &dyn DataPlottingService,
+    file_info: &FileInfo,
+    opts: &ShowOpts,
+    elevation_source: ElevationSource,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start = first_record_time(conn, file_info.id())?;
+    let mut stmt = conn.prepare(
+        "select distance, vertical_oscillation, stance_time, step_length, timestamp
+                                 from record_messages
+                                 where file_id = ? and
+                                 (vertical_oscillation is not null or
+                                  stance_time is not null or
+                                  step_length is not null)
+                                 order by timestamp",
+    )?;
+    let mut rows = stmt.query(params![file_info.id()])?;
+    let mut oscillation: Vec<(f64, f64)> = Vec::new();
+    let mut stance_time: Vec<(f64, f64)> = Vec::new();
+    let mut step_length: Vec<(f64, f64)> = Vec::new();
+    let mut xmax: f64 = 0.0;
+    while let Some(row) = rows.next()? {
+        let distance = match opts.x_axis.value(row.get(0)?, row.get(4)?, start) {
+            Some(x) => x,
+            None => continue,
+        };
+        xmax = xmax.max(distance);
+        if let Some(v) = row.get::<usize, Option<f64>>(1)? {
+            oscillation.push((distance, v / 10.0));
+        }
+        if let Some(v) = row.get::<usize, Option<f64>>(2)? {
+            stance_time.push((distance, v));
+        }
+        if let Some(v) = row.get::<usize, Option<f64>>(3)? {
+            step_length.push((distance, v / 1000.0));
+        }
+    }
+    if oscillation.is_empty() && stance_time.is_empty() && step_length.is_empty() {
+        println!("No dynamics data for FIT file UUID={}", file_info.uuid());
+        return Ok(());
+    }
+
+    let mut plots = [
+        Plot::new(
+            file_info.format_timestamp("%Y-%m-%d %H:%M %:z", opts.utc),
+            opts.x_axis.label(),
+            "Vertical Oscillation [cm]".to_string(),
+        ),
+        Plot::new(
+            "".to_string(),
+            opts.x_axis.label(),
+            "Ground Contact [ms]".to_string(),
+        ),
+        Plot::new(
+            "".to_string(),
+            opts.x_axis.label(),
+            "Step Length [m]".to_string(),
+        ),
+    ];
+    let series = [
+        ("Vertical Oscillation", &oscillation),
+        ("Ground Contact", &stance_time),
+        ("Step Length", &step_length),
+    ];
+    let laps = if opts.show_laps {
+        lap_boundaries(conn, file_info.id(), opts.x_axis, start)?
+    } else {
+        Vec::new()
+    };
+    for (plot, (name, data)) in plots.iter_mut().zip(series) {
+        plot.x_tick_step = opts.x_axis.tick_step(xmax);
+        plot.show_y_zero = false;
+        plot.add_series(DataSeries::new(name, data));
+        for &x in &laps {
+            plot.add_marker(x);
+        }
+    }
+
+    // only plot if we have data
+    let all_plots: Vec<&Plot> = plots
+        .iter()
+        .zip(series)
+        .filter(|(_, (_, data))| !data.is_empty())
+        .map(|(plot, _)| plot)
+        .collect();
+
+    output_plots(
+        conn,
+        plotter,
+        file_info,
+        &all_plots,
+        opts,
+        elevation_source,
+        config,
+    )
+}
+
+/// Send the finished plots to the destination requested by the options
+fn output_plots(
+    conn: &Connection,
+    plotter: &dyn DataPlottingService,
+    file_info: &FileInfo,
+    plots: &[&Plot],
+    opts: &ShowOpts,
+    elevation_source: ElevationSource,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if opts.json {
+        print_json(conn, file_info, plots, opts.utc)
+    } else if opts.output.is_some() {
+        let mut svg_plotter = SvgPlotter::default();
+        svg_plotter.set_size(opts.width, opts.height);
+        let html = render_html(
+            conn,
+            file_info,
+            &svg_plotter,
+            plots,
+            elevation_source,
+            config,
+            opts.utc,
+        )?;
+        let output = OutputSink::new(opts.output.clone());
+        output.write(html.as_bytes())?;
+        if opts.open {
+            output.open();
+        }
+        Ok(())
+    } else {
+        // image based plotters return their data, the terminal plotter draws directly
+        let data = plotter.plot(plots)?;
+        if !data.is_empty() {
+            OutputSink::new(None).write(&data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build a self-contained HTML page with a table of run and lap statistics followed by the
+/// plots drawn as an inline SVG, nothing is loaded from the network so it works offline.
+fn render_html(
+    conn: &Connection,
+    file_info: &FileInfo,
+    svg_plotter: &SvgPlotter,
+    plots: &[&Plot],
+    elevation_source: ElevationSource,
+    config: &Config,
+    utc: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let title = file_info.format_timestamp("%Y-%m-%d %H:%M %:z", utc);
+    let charts = String::from_utf8(svg_plotter.plot(plots)?)?;
+
+    // overall statistics
+    let stats = conn.query_row(
+        "select max(distance), min(timestamp), max(timestamp), avg(speed), avg(heart_rate)
+         from record_messages where file_id = ?",
+        params![file_info.id()],
+        |r| {
+            let distance: Option<f64> = r.get(0)?;
+            let start: Option<DateTime<Local>> = r.get(1)?;
+            let end: Option<DateTime<Local>> = r.get(2)?;
+            let heart_rate: Option<f64> = r.get(4)?;
+            Ok([
+                ("UUID", file_info.uuid().to_string()),
+                (
+                    "Device",
+                    format!("{}-{}", file_info.manufacturer(), file_info.product()),
+                ),
+                (
+                    "Distance",
+                    distance.map_or_else(String::new, |d| format!("{:0.2} mi", d * 0.0006213712)),
+                ),
+                (
+                    "Time",
+                    match (start, end) {
+                        (Some(start), Some(end)) => format_duration(end - start),
+                        _ => String::new(),
+                    },
+                ),
+                (
+                    "Pace",
+                    format!("{} /mi", format_pace(r.get(3)?, DistanceUnit::Mile)),
+                ),
+                (
+                    "Heart Rate",
+                    heart_rate.map_or_else(String::new, |hr| format!("{:0.0} bpm", hr)),
+                ),
+            ])
+        },
+    )?;
+
+    // calories are only shown when the laps recorded them, and the climb when there's any
+    // elevation data
+    let calories = total_calories(conn, file_info.id())?;
+    let climb = total_climb(conn, file_info.id(), elevation_source)?;
+    let recovery = file_heart_rate_recovery(conn, file_info.id())?;
+    let elevation_unit = config.units().elevation_unit();
+    let elevation = |meters: f64| {
+        format!(
+            "{:0.0} {}",
+            elevation_unit.from_meters(meters),
+            elevation_unit.abbreviation()
+        )
+    };
+    let stats = stats
+        .into_iter()
+        .chain(climb.map(|(ascent, _)| ("Ascent", elevation(ascent))))
+        .chain(climb.map(|(_, descent)| ("Descent", elevation(descent))))
+        .chain(calories.map(|c| ("Calories", format!("{:0.0} kcal", c))))
+        .chain(recovery.map(|r| {
+            (
+                "HR Recovery",
+                format!(
+                    "{:0.0} bpm (from a peak of {:0.0} bpm)",
+                    r.delta(),
+                    r.peak()
+                ),
+            )
+        }));
+
+    // writing into a String can't fail so the results are ignored
+    let mut html = String::new();
+    let _ = writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Run {}</title>",
+        escape(&title)
+    );
+    html.push_str(
+        "<style>
+body { font-family: sans-serif; max-width: 800px; margin: 20px auto; }
+table { border-collapse: collapse; margin-bottom: 20px; }
+th, td { padding: 4px 12px; border-bottom: 1px solid #ddd; text-align: left; }
+</style>\n</head>\n<body>\n",
+    );
+    let _ = writeln!(html, "<h1>Run {}</h1>\n<table>", escape(&title));
+    for (name, value) in stats {
+        let _ = writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape(&value)
+        );
+    }
+    html.push_str("</table>\n");
+
+    // per lap statistics
+    let mut stmt = conn.prepare(
+        "select total_distance, start_time, timestamp, average_speed, average_heart_rate,
+            total_calories
+         from lap_messages where file_id = ? order by start_time",
+    )?;
+    let mut rows = stmt.query(params![file_info.id()])?;
+    let mut laps = String::new();
+    let mut lap = 0;
+    while let Some(row) = rows.next()? {
+        lap += 1;
+        let distance: Option<f64> = row.get(0)?;
+        let time = row.get::<usize, DateTime<Local>>(2)? - row.get::<usize, DateTime<Local>>(1)?;
+        let heart_rate: Option<f64> = row.get(4)?;
+        let lap_calories: Option<f64> = row.get(5)?;
+        // a rest lap's pace is meaningless so it's labeled instead
+        let pace = if config
+            .rest_laps()
+            .is_rest(distance, time.num_seconds() as f64)
+        {
+            "Rest".to_string()
+        } else {
+            format!("{} /mi", format_pace(row.get(3)?, DistanceUnit::Mile))
+        };
+        let _ = writeln!(
+            laps,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>{}</tr>",
+            lap,
+            distance.map_or_else(String::new, |d| format!("{:0.2} mi", d * 0.0006213712)),
+            format_duration(time),
+            pace,
+            heart_rate.map_or_else(String::new, |hr| format!("{:0.0} bpm", hr)),
+            match (calories, lap_calories) {
+                (None, _) => String::new(),
+                (Some(_), c) => format!(
+                    "<td>{}</td>",
+                    c.map_or_else(String::new, |c| format!("{:0.0} kcal", c))
+                ),
+            },
+        );
+    }
+    if !laps.is_empty() {
+        html.push_str(
+            "<table>\n<tr><th>Lap</th><th>Distance</th><th>Time</th><th>Pace</th><th>Heart Rate</th>",
+        );
+        if calories.is_some() {
+            html.push_str("<th>Calories</th>");
+        }
+        html.push_str("</tr>\n");
+        html.push_str(&laps);
+        html.push_str("</table>\n");
+    }
+
+    html.push_str(&charts);
+    html.push_str("</body>\n</html>\n");
+
+    Ok(html)
+}
+
+/// Format a duration as hours, minutes and seconds
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Write every series of the plots to stdout as a single JSON object, each series keeps its
+/// axis labels so the units are known without reading the source.
+fn print_json(
+    conn: &Connection,
+    file_info: &FileInfo,
+    plots: &[&Plot],
+    utc: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let series: Vec<serde_json::Value> = plots
+        .iter()
+        .flat_map(|plot| {
+            plot.series().iter().map(move |s| {
+                json!({
+                    "name": s.name(),
+                    "x": plot.x(),
+                    "y": plot.y(),
+                    "data": s.data(),
+                })
+            })
+        })
+        .collect();
+    let output = json!({
+        "uuid": file_info.uuid(),
+        "timestamp": file_info.format_timestamp("%Y-%m-%dT%H:%M:%S%:z", utc),
+        "calories": total_calories(conn, file_info.id())?,
+        "heart_rate_recovery": file_heart_rate_recovery(conn, file_info.id())?.map(|r| json!({
+            "peak": r.peak(),
+            "recovered": r.recovered(),
+            "delta": r.delta(),
+        })),
+        "series": series,
+    });
+    println!("{}", serde_json::to_string(&output)?);
+
+    Ok(())
+}
+
+/// Heart rate recovery over the minute after the file's peak heart rate
+fn file_heart_rate_recovery(
+    conn: &Connection,
+    file_id: Option<u32>,
+) -> Result<Option<HeartRateRecovery>> {
+    let mut stmt = conn.prepare(
+        "select timestamp, heart_rate from record_messages where file_id = ? order by timestamp",
+    )?;
+    let samples = stmt
+        .query_map(params![file_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<Vec<(DateTime<Utc>, Option<f64>)>>>()?;
+    Ok(heart_rate_recovery(&samples))
+}
+
+/// Time of the first record of a file, elapsed times are measured from it. Files without
+/// records have nothing to measure so any time will do.
+fn first_record_time(conn: &Connection, file_id: Option<u32>) -> Result<DateTime<Utc>> {
+    conn.query_row(
+        "select min(timestamp) from record_messages where file_id = ?",
+        params![file_id],
+        |r| r.get::<usize, Option<DateTime<Utc>>>(0),
+    )
+    .map(Option::unwrap_or_default)
+}
+
+/// Position along the x axis of the start of every lap after the first. A lap starts at the
+/// distance of the first record at or after its start time, so the boundaries line up with the
+/// plotted records.
+fn lap_boundaries(
+    conn: &Connection,
+    file_id: Option<u32>,
+    x_axis: XAxis,
+    start: DateTime<Utc>,
+) -> Result<Vec<f64>> {
+    let mut stmt = conn.prepare(
+        "select l.start_time,
+                (select r.distance from record_messages r
+                 where r.file_id = l.file_id and r.timestamp >= l.start_time and
+                       r.distance is not null
+                 order by r.timestamp limit 1)
+         from lap_messages l where l.file_id = ? order by l.start_time",
+    )?;
+    let laps = stmt
+        .query_map(params![file_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<Vec<(DateTime<Utc>, Option<f64>)>>>()?;
+    Ok(laps
+        .into_iter()
+        .skip(1)
+        .filter_map(|(time, distance)| x_axis.value(distance, time, start))
+        .collect())
+}
+
+/// Smooth values using a centered moving average over the given number of points, the window
+/// shrinks near the ends of the data.
+fn moving_average(values: &[f64], window: usize) -> Vec<f64> {
+    let half = window.max(1) / 2;
+    (0..values.len())
+        .map(|i| {
+            let window = &values[i.saturating_sub(half)..(i + half + 1).min(values.len())];
+            window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect()
+}
+
+/// Compute the grade in percent between consecutive (x, distance [mi], elevation) points, points
+/// where the distance doesn't advance are skipped to avoid dividing by zero. Each grade is placed
+/// at the x axis value of the second point of the pair.
+fn grade(profile: &[(f64, f64, f64)], elevation_unit: ElevationUnit) -> Vec<(f64, f64)> {
+    let per_mile = elevation_unit.from_meters(DistanceUnit::Mile.meters());
+    profile
+        .windows(2)
+        .filter_map(|pair| {
+            let (_, d0, e0) = pair[0];
+            let (x, d1, e1) = pair[1];
+            let run = (d1 - d0) * per_mile;
+            if run > 0.0 {
+                Some((x, 100.0 * (e1 - e0) / run))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// List intervals between consecutive records longer than the threshold (in seconds) along with
+/// the straight line distance between the records on either side of the gap
+fn print_gaps(
+    conn: &Connection,
+    file_info: &FileInfo,
+    threshold: i64,
+    utc: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "select timestamp, position_lat, position_long from record_messages
+                                 where file_id = ?
+                                 order by timestamp",
+    )?;
+    let records = stmt
+        .query_map(params![file_info.id()], |r| {
+            let position = match (r.get::<usize, Option<i32>>(1)?, r.get(2)?) {
+                (Some(lat), Some(lon)) => Some(Location::from_fit_coordinates(lat, lon)),
+                _ => None,
+            };
+            Ok((r.get::<usize, DateTime<Utc>>(0)?, position))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let offset = *file_info.activity_timestamp().offset();
+    let format = |t: &DateTime<Utc>| {
+        if utc {
+            t.format("%H:%M:%S").to_string()
+        } else {
+            t.with_timezone(&offset).format("%H:%M:%S").to_string()
+        }
+    };
+    summary!("Start\tEnd\tDuration[s]\tDistance[mi]");
+    let mut ngaps = 0;
+    for pair in records.windows(2) {
+        let (start, start_pos) = &pair[0];
+        let (end, end_pos) = &pair[1];
+        let duration = (*end - *start).num_seconds();
+        if duration < threshold {
+            continue;
+        }
+        ngaps += 1;
+        let distance = match (start_pos, end_pos) {
+            (Some(a), Some(b)) => format!("{:0.2}", a.distance_to(b) * 0.0006213712),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}",
+            format(start),
+            format(end),
+            duration,
+            distance
+        );
+    }
+    summary!("{} gaps of at least {} seconds found", ngaps, threshold);
+
+    Ok(())
+}
+
+/// Output all fields stored in the developer_fields table for a file
+fn print_developer_fields(
+    conn: &Connection,
+    file_id: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "select message_type, message_index, field_name, value, units from developer_fields
+                                 where file_id = ?
+                                 order by message_index, id",
+    )?;
+    let mut rows = stmt.query(params![file_id])?;
+    summary!("Message\tIndex\tField\tValue");
+    while let Some(row) = rows.next()? {
+        let value = match row.get::<usize, Value>(3)? {
+            Value::Null => String::new(),
+            Value::Integer(v) => v.to_string(),
+            Value::Real(v) => v.to_string(),
+            Value::Text(v) => v,
+            Value::Blob(v) => format!("{:?}", v),
+        };
+        println!(
+            "{}\t{}\t{}\t{} {}",
+            row.get::<usize, String>(0)?,
+            row.get::<usize, i64>(1)?,
+            row.get::<usize, String>(2)?,
+            value,
+            row.get::<usize, Option<String>>(4)?.unwrap_or_default()
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,154 @@
+//! Define the splits subcommand
+use crate::db::{find_file_by_uuid, open_db_connection};
+use crate::{format_pace, DistanceUnit, Error};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection};
+use structopt::StructOpt;
+
+/// Break a run into fixed distance or time splits, independent of the laps recorded by the device
+#[derive(Debug, StructOpt)]
+pub struct SplitsOpts {
+    /// Full or partial UUID of file we want to split (use list-files command to see UUIDs).
+    /// The special identifier :last will return the most recent file import.
+    #[structopt(name = "FILE_UUID", default_value = ":last")]
+    uuid: String,
+    /// Length of each split in --units, used unless --time is given
+    #[structopt(short, long, default_value = "1")]
+    distance: f64,
+    /// Length of each split in minutes instead of distance
+    #[structopt(short, long, conflicts_with = "distance")]
+    time: Option<f64>,
+    /// Units used for split distances and paces: mi or km
+    #[structopt(long, default_value = "mi")]
+    units: DistanceUnit,
+}
+
+/// How the record stream is divided
+#[derive(Clone, Copy, Debug)]
+enum SplitInterval {
+    /// length of each split in meters
+    Distance(f64),
+    /// length of each split in seconds
+    Time(f64),
+}
+
+/// A record with the fields needed to compute splits
+struct SplitRecord {
+    timestamp: DateTime<Utc>,
+    distance: f64,
+    heart_rate: Option<f64>,
+}
+
+/// Totals for a single split
+struct Split {
+    distance: f64,
+    duration: Duration,
+    heart_rate: Option<f64>,
+}
+
+pub fn splits_command(opts: SplitsOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let interval = match opts.time {
+        Some(minutes) if minutes > 0.0 => SplitInterval::Time(minutes * 60.0),
+        None if opts.distance > 0.0 => SplitInterval::Distance(opts.distance * opts.units.meters()),
+        _ => {
+            return Err(Box::new(Error::Other(
+                "The split length must be greater than zero".to_string(),
+            )))
+        }
+    };
+
+    let conn = open_db_connection()?;
+    let info = find_file_by_uuid(&conn, &opts.uuid)?;
+    let records = load_records(&conn, info.id())?;
+    if records.len() < 2 {
+        println!("No distance data for FIT file UUID={}", info.uuid());
+        return Ok(());
+    }
+
+    summary!(
+        "Split\tDistance[{u}]\tTime\tPace[min/{u}]\tHeart Rate[bpm]",
+        u = opts.units.abbreviation()
+    );
+    for (i, split) in compute_splits(&records, interval).iter().enumerate() {
+        let secs = split.duration.num_milliseconds() as f64 / 1000.0;
+        let speed = if secs > 0.0 {
+            Some(split.distance / secs)
+        } else {
+            None
+        };
+        println!(
+            "{}\t{:0.2}\t{}\t{}\t{}",
+            i + 1,
+            split.distance / opts.units.meters(),
+            format_minutes(split.duration.num_seconds()),
+            format_pace(speed, opts.units),
+            split
+                .heart_rate
+                .map_or_else(|| "-".to_string(), |hr| format!("{:0.0}", hr)),
+        );
+    }
+
+    Ok(())
+}
+
+fn load_records(conn: &Connection, file_id: Option<u32>) -> Result<Vec<SplitRecord>, Error> {
+    let mut stmt = conn.prepare(
+        "select timestamp, distance, heart_rate from record_messages
+         where file_id = ? and distance is not null
+         order by timestamp",
+    )?;
+    let records = stmt
+        .query_map(params![file_id], |r| {
+            Ok(SplitRecord {
+                timestamp: r.get(0)?,
+                distance: r.get(1)?,
+                heart_rate: r.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<SplitRecord>, rusqlite::Error>>()?;
+    Ok(records)
+}
+
+/// Divide the records into splits, each split ends at the first record that reaches its
+/// boundary and the next split starts from that record so no distance or time is lost between
+/// them. The last split holds whatever is left over and is usually shorter.
+fn compute_splits(records: &[SplitRecord], interval: SplitInterval) -> Vec<Split> {
+    let first = &records[0];
+    let position = |rec: &SplitRecord| match interval {
+        SplitInterval::Distance(meters) => (rec.distance - first.distance) / meters,
+        SplitInterval::Time(secs) => {
+            (rec.timestamp - first.timestamp).num_milliseconds() as f64 / 1000.0 / secs
+        }
+    };
+
+    let mut splits = Vec::new();
+    let mut start = first;
+    let mut heart_rates: Vec<f64> = first.heart_rate.into_iter().collect();
+    let mut boundary = 1.0;
+    for (i, rec) in records.iter().enumerate().skip(1) {
+        heart_rates.extend(rec.heart_rate);
+        if position(rec) >= boundary || i == records.len() - 1 {
+            let heart_rate = if heart_rates.is_empty() {
+                None
+            } else {
+                Some(heart_rates.iter().sum::<f64>() / heart_rates.len() as f64)
+            };
+            splits.push(Split {
+                distance: rec.distance - start.distance,
+                duration: rec.timestamp - start.timestamp,
+                heart_rate,
+            });
+            heart_rates.clear();
+            start = rec;
+            // a single gap in the records can cover more than one boundary
+            boundary = position(rec).floor() + 1.0;
+        }
+    }
+
+    splits
+}
+
+/// Format a number of seconds as minutes and seconds
+fn format_minutes(secs: i64) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
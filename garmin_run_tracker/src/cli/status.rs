@@ -0,0 +1,146 @@
+//! Define the status subcommand
+use super::import::scan_directory;
+use crate::config::Config;
+use crate::db::open_db_connection;
+use crate::{generate_uuid, Error};
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Report which files in the import paths have been imported without modifying anything
+#[derive(Debug, StructOpt)]
+pub struct StatusOpts {
+    /// Additional FIT files or directories to check
+    #[structopt(name = "PATHS", parse(from_os_str))]
+    paths: Vec<PathBuf>,
+    /// Search directory paths recursively
+    #[structopt(short, long)]
+    recursive: bool,
+    /// Do not check the import paths defined in the application config
+    #[structopt(long)]
+    skip_config_paths: bool,
+    /// Only list files that haven't been imported yet
+    #[structopt(short, long)]
+    new_only: bool,
+}
+
+/// Import state of a single file on disk
+enum FileStatus {
+    Imported,
+    New,
+    Errored(String),
+}
+
+/// Implementation of the `status` subcommand
+pub fn status_command(config: Config, opts: StatusOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths: Vec<PathBuf> = if opts.skip_config_paths {
+        Vec::new()
+    } else {
+        config.import_paths().iter().map(PathBuf::from).collect()
+    };
+    paths.extend(opts.paths);
+    if paths.is_empty() {
+        return Err(Box::new(Error::Other(
+            "No import paths provided".to_string(),
+        )));
+    }
+
+    // expand directories into the FIT files they contain
+    let mut files = Vec::new();
+    while let Some(path) = paths.pop() {
+        if !path.exists() {
+            warn!("Path does not exist: {:?}", path);
+        } else if path.is_dir() {
+            paths.extend(scan_directory(&path, opts.recursive, None)?);
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let conn = open_db_connection()?;
+    let mut counts = (0, 0, 0);
+    summary!("Status\tUUID\tPath");
+    for path in files {
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+        let uuid = generate_uuid(&data);
+        let status = file_status(&conn, &uuid)?;
+        match &status {
+            FileStatus::Imported => counts.0 += 1,
+            FileStatus::New => counts.1 += 1,
+            FileStatus::Errored(_) => counts.2 += 1,
+        }
+        match status {
+            FileStatus::Imported if opts.new_only => {}
+            FileStatus::Imported => println!("imported\t{}\t{:?}", uuid, path),
+            FileStatus::New => println!("new\t{}\t{:?}", uuid, path),
+            FileStatus::Errored(msg) => println!("error\t{}\t{:?} - {}", uuid, path, msg),
+        }
+    }
+    summary!(
+        "{} imported, {} new and {} previously errored files",
+        counts.0,
+        counts.1,
+        counts.2
+    );
+
+    Ok(())
+}
+
+/// Determine if a file is already in the database, either whole or as one of its activities, or
+/// failed to import previously
+fn file_status(conn: &Connection, uuid: &str) -> Result<FileStatus, Error> {
+    let imported = conn
+        .query_row(
+            "select id from files where uuid = ?1 or source_uuid = ?1 limit 1",
+            params![uuid],
+            |r| r.get::<usize, i64>(0),
+        )
+        .optional()?;
+    if imported.is_some() {
+        return Ok(FileStatus::Imported);
+    }
+
+    let message = conn
+        .query_row(
+            "select message from import_errors where uuid = ? order by occurred_at desc",
+            params![uuid],
+            |r| r.get::<usize, String>(0),
+        )
+        .optional()?;
+    Ok(message.map_or(FileStatus::New, FileStatus::Errored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import_fit_activity;
+    use crate::test_support::{memory_db, FitFileBuilder, START};
+    use crate::StoredFields;
+
+    #[test]
+    fn file_status_finds_files_imported_one_activity_at_a_time() {
+        let mut conn = memory_db();
+        let data = FitFileBuilder::new()
+            .file_id(1234, START)
+            .record(START, (39.46, -80.14), 0.0, 3.0, 120)
+            .session(START, START + 10)
+            .record(START + 600, (39.47, -80.14), 0.0, 3.0, 120)
+            .session(START + 600, START + 610)
+            .build();
+        let uuid = generate_uuid(&data);
+        assert!(matches!(file_status(&conn, &uuid), Ok(FileStatus::New)));
+
+        let tx = conn.transaction().unwrap();
+        import_fit_activity(&mut data.as_slice(), &tx, Some(1), &StoredFields::default()).unwrap();
+        tx.commit().unwrap();
+        assert!(matches!(
+            file_status(&conn, &uuid),
+            Ok(FileStatus::Imported)
+        ));
+    }
+}
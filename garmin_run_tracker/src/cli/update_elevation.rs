@@ -1,10 +1,17 @@
 //! Define FIT file update-elevation command
+use super::{parse_date, read_uuids_from_stdin};
 use crate::config::Config;
-use crate::db::{find_file_by_uuid, open_db_connection};
-use crate::services::{update_elevation_data, ElevationDataSource};
+use crate::db::{find_file_by_uuid, new_file_info_query, QueryStringBuilder};
+use crate::services::{
+    update_elevation_data, update_missing_elevation_data, ElevationDataSource,
+    ElevationUpdateReport,
+};
+use crate::{DbWriter, FileInfo};
+use chrono::NaiveDate;
 use log::{error, info};
-use rusqlite::{params, Connection};
+use rusqlite::{params, params_from_iter, Connection};
 use std::collections::HashSet;
+use std::convert::TryFrom;
 use structopt::StructOpt;
 
 /// Update elevation data for one or more FIT files, all entries with missing elevation data
@@ -18,12 +25,28 @@ pub struct UpdateElevationOpts {
     /// List files with missing elevation data, do not update anything
     #[structopt(short = "-l", long)]
     list_missing: bool,
-    /// Update all records with missing elevation data
+    /// Update all records with missing elevation data, files whose elevation data couldn't be
+    /// fetched during import are updated in full
     #[structopt(short = "-a", long)]
     fix_missing: bool,
+    /// Only fix files recorded on or after the specified date with --fix-missing (YYYY-MM-DD
+    /// format or today, yesterday, 7d, 2w, 1m)
+    #[structopt(short = "-S", long, requires = "fix-missing", parse(try_from_str = parse_date))]
+    since: Option<NaiveDate>,
+    /// Only fix files recorded before the specified date with --fix-missing (YYYY-MM-DD format
+    /// or today, yesterday, 7d, 2w, 1m)
+    #[structopt(short = "-U", long, requires = "fix-missing", parse(try_from_str = parse_date))]
+    until: Option<NaiveDate>,
     /// Overwrite elevation data for the specified files, e.g. if you have a more accurate data source.
     #[structopt(short = "-f", long)]
     overwrite: bool,
+    /// Also read newline separated UUIDs from stdin, e.g. from list-files output
+    #[structopt(long)]
+    stdin: bool,
+    /// Print the fraction of points of each updated file that were given an elevation, e.g. to
+    /// find runs outside of the elevation source's coverage area
+    #[structopt(long, conflicts_with = "list-missing")]
+    report: bool,
 }
 
 /// Implementation of the `update-elevation` subcommand
@@ -31,11 +54,11 @@ pub fn update_elevation_command(
     config: Config,
     opts: UpdateElevationOpts,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut conn = open_db_connection()?;
+    let writer = DbWriter::open()?;
 
     // return UUIDs of files with missing elevation data but valid lat/long points
     if opts.list_missing {
-        return list_missing(&conn);
+        return writer.read(list_missing);
     }
 
     // fetch elevation service from config
@@ -50,17 +73,78 @@ pub fn update_elevation_command(
     // update elevation data for specified files, we handle each file in it's own transaction
     // so that not everything gets rolled back if it fails. API calls may not be free so we don't
     // want to waste them if possible.
-    for uuid in opts.uuids {
-        update_file(&mut conn, elevation_hdl.as_ref(), &uuid, opts.overwrite)?;
+    let mut uuids = opts.uuids;
+    if opts.stdin {
+        uuids.extend(read_uuids_from_stdin()?);
+    }
+    let mut report = ElevationUpdateReport::default();
+    for uuid in uuids {
+        update_file(
+            &writer,
+            elevation_hdl.as_ref(),
+            &uuid,
+            opts.overwrite,
+            &mut report,
+        )?;
     }
 
     // update missing elevation data in database
     if opts.fix_missing {
+        // device values are kept for files the service failed on at import so those are
+        // overwritten individually before filling in the remaining gaps
+        for uuid in writer.read(|conn| pending_files(conn, opts.since, opts.until))? {
+            update_file(&writer, elevation_hdl.as_ref(), &uuid, true, &mut report)?;
+        }
         info!("Attempting to update elevation data for all database records with missing values");
-        let tx = conn.transaction()?;
-        update_elevation_data(&tx, elevation_hdl.as_ref(), None, false)?;
-        tx.commit()?;
+        report.merge(writer.write(|tx| {
+            Ok(update_missing_elevation_data(
+                tx,
+                elevation_hdl.as_ref(),
+                opts.since,
+                opts.until,
+            )?)
+        })?);
+    }
+
+    if opts.report {
+        writer.read(|conn| print_report(conn, &report))?;
+    }
+
+    Ok(())
+}
+
+/// Print the per file coverage of the elevation update
+fn print_report(
+    conn: &Connection,
+    report: &ElevationUpdateReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if report.files().next().is_none() {
+        summary!("No points needed elevation data.");
+        return Ok(());
+    }
+
+    let mut query = new_file_info_query();
+    query.and_where("id = ?");
+    let mut stmt = conn.prepare(&query.to_string())?;
+    summary!("Date\tFilled/Points\tCoverage\tUUID");
+    for (file_id, coverage) in report.files() {
+        let info = stmt.query_row(params![file_id], |r| FileInfo::try_from(r))?;
+        println!(
+            "{}\t{}/{}\t{:0.1}%\t({})",
+            info.format_timestamp("%Y-%m-%d %H:%M", false),
+            coverage.filled(),
+            coverage.points(),
+            coverage.fraction().unwrap_or(0.0) * 100.0,
+            info.uuid()
+        );
     }
+    let total = report.total();
+    summary!(
+        "Filled {}/{} points ({:0.1}%)",
+        total.filled(),
+        total.points(),
+        total.fraction().unwrap_or(0.0) * 100.0
+    );
 
     Ok(())
 }
@@ -82,7 +166,7 @@ fn list_missing(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
             start_position_long is not null and
             start_elevation is null
     )";
-    let mut uuids = HashSet::new();
+    let mut uuids: HashSet<String> = pending_files(conn, None, None)?.into_iter().collect();
     let mut stmt = conn.prepare(rec_query)?;
     for uuid in stmt.query_map(params![], |row| row.get::<usize, String>(0))? {
         uuids.insert(uuid?);
@@ -93,9 +177,9 @@ fn list_missing(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
     }
 
     if uuids.is_empty() {
-        println!("No files have missing elevation data.");
+        summary!("No files have missing elevation data.");
     } else {
-        println!(
+        summary!(
             "The following {:?} files have missing elevation data:",
             uuids.len()
         );
@@ -107,16 +191,44 @@ fn list_missing(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// UUIDs of files the elevation service failed on during import, optionally limited to the
+/// files recorded within a date range
+fn pending_files(
+    conn: &Connection,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+) -> Result<Vec<String>, rusqlite::Error> {
+    let mut params: Vec<&dyn rusqlite::ToSql> = Vec::new();
+    let mut query = QueryStringBuilder::new("select uuid from files");
+    query.and_where("elevation_pending = 1");
+    if let Some(start_date) = since.as_ref() {
+        query.and_where("time_created >= ?");
+        params.push(start_date as &dyn rusqlite::ToSql);
+    }
+    if let Some(end_date) = until.as_ref() {
+        query.and_where("time_created < ?");
+        params.push(end_date as &dyn rusqlite::ToSql);
+    }
+    let mut stmt = conn.prepare(&query.to_string())?;
+    let uuids = stmt
+        .query_map(params_from_iter(params.iter()), |row| {
+            row.get::<usize, String>(0)
+        })?
+        .collect();
+    uuids
+}
+
 /// Update the elevation data for a file, this suppresses all non-fatal errors and instead
 /// emits out logging messages for them.
 fn update_file<T: ElevationDataSource + ?Sized>(
-    conn: &mut Connection,
+    writer: &DbWriter,
     elevation_hdl: &T,
     uuid: &str,
     overwrite: bool,
+    report: &mut ElevationUpdateReport,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // locate file_id from uuid
-    let file_info = match find_file_by_uuid(&conn, &uuid) {
+    let file_info = match writer.read(|conn| find_file_by_uuid(conn, uuid)) {
         Ok(info) => info,
         Err(e) => return Err(Box::new(e)),
     };
@@ -128,17 +240,22 @@ fn update_file<T: ElevationDataSource + ?Sized>(
         return Ok(());
     }
 
-    let tx = conn.transaction()?;
-    match update_elevation_data(&tx, elevation_hdl, file_info.id(), overwrite) {
-        Ok(_) => {
-            tx.commit()?;
+    match writer.write(|tx| {
+        Ok(update_elevation_data(
+            tx,
+            elevation_hdl,
+            file_info.id(),
+            overwrite,
+        )?)
+    }) {
+        Ok(file_report) => {
+            report.merge(file_report);
             info!(
                 "Successfully updated elevation for FIT file '{}'",
                 file_info.uuid()
             );
         }
         Err(e) => {
-            tx.rollback()?;
             error!(
                 "Could not import elevation data from the API for FIT file '{}'",
                 file_info.uuid()
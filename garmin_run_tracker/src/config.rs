@@ -1,15 +1,22 @@
 //! Store application configuration that gets read from disk
+use crate::services::visualization::plotting::{FallbackPlotter, TerminalPlotter};
 use crate::services::{
     new_elevation_handler, new_plotting_visualization_handler, new_route_visualization_handler,
-    DataPlottingService, ElevationDataSource, RouteDrawingService,
+    DataPlottingService, ElevationDataSource, RouteDrawingService, DEFAULT_HTTP_TIMEOUT,
+    DEFAULT_USER_AGENT,
 };
-use crate::Error;
+use crate::validation::ValidationThresholds;
+use crate::DEFAULT_BUSY_TIMEOUT;
+use crate::{ElevationSource, Error, RestLapThresholds, StoredFields, UnitSystem};
+use log::warn;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_yaml::Value;
 use simplelog::LevelFilter;
 use std::collections::HashMap;
+use std::fmt;
 use std::io::prelude::*;
 use std::iter::Iterator;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 /// Defines the allowed keys under the services map
@@ -24,6 +31,85 @@ pub enum ServiceType {
 /// Type alias for clarity
 pub type ServiceParameters = HashMap<String, Value>;
 
+/// Parameter names containing any of these are treated as credentials and hidden from logs
+static SECRET_PARAMETERS: &[&str] = &["auth", "token", "key", "password"];
+
+/// Return true if the parameter name looks like it holds a credential
+pub(crate) fn is_secret_parameter(key: &str) -> bool {
+    let lower = key.to_ascii_lowercase();
+    SECRET_PARAMETERS.iter().any(|s| lower.contains(s))
+}
+
+/// A configuration value such as an API token that is hidden when printed for debugging
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Return the actual value, only use this when building a request
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"****\"")
+        }
+    }
+}
+
+/// Proxy that every outbound HTTP request is sent through
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// URL of the proxy server, e.g. "http://proxy.example.com:3128"
+    url: String,
+    /// Username sent to the proxy with basic authentication, the proxy isn't authenticated if
+    /// this is empty
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: Secret,
+}
+
+impl ProxyConfig {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &Secret {
+        &self.password
+    }
+}
+
 /// Configuration options for a single service of any type
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ServiceConfig {
@@ -41,14 +127,27 @@ impl ServiceConfig {
         &self.handler
     }
 
+    /// Return the parameter names in sorted order so anything built from them, such as a
+    /// request's query string, is the same on every run
     pub fn parameters(&self) -> impl Iterator<Item = &String> + '_ {
-        self.configuration.keys()
+        let mut keys: Vec<&String> = self.configuration.keys().collect();
+        keys.sort();
+        keys.into_iter()
     }
 
     pub fn get_parameter(&self, key: &str) -> Option<&Value> {
         self.configuration.get(key)
     }
 
+    /// Format a parameter's value for log messages, credentials are masked
+    pub fn display_parameter(&self, key: &str) -> String {
+        match self.configuration.get(key) {
+            Some(_) if is_secret_parameter(key) => "****".to_string(),
+            Some(value) => format!("{:?}", value),
+            None => String::new(),
+        }
+    }
+
     pub fn get_parameter_as_string(&self, key: &str) -> Option<Result<String, Error>> {
         if let Some(value) = self.configuration.get(key) {
             let value = value
@@ -80,6 +179,55 @@ impl ServiceConfig {
         }
     }
 
+    /// Parse a scalar parameter with the type's `FromStr` implementation, e.g. for values given
+    /// by name
+    pub fn get_parameter_parsed<T>(&self, key: &str) -> Option<Result<T, Error>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.configuration.get(key).map(|value| {
+            let text = match value {
+                Value::String(s) => s.clone(),
+                Value::Number(n) => n.to_string(),
+                _ => {
+                    return Err(Error::InvalidConfigurationValue(format!(
+                        "invalid value for {}.{}, expected a string: {:?}",
+                        &self.handler, key, value
+                    )))
+                }
+            };
+            text.parse::<T>().map_err(|e| {
+                Error::InvalidConfigurationValue(format!(
+                    "invalid value for {}.{}: {:?} ({})",
+                    &self.handler, key, text, e
+                ))
+            })
+        })
+    }
+
+    /// Return a scalar parameter, or each element of a list of scalars, formatted as strings
+    pub fn get_parameter_as_strings(&self, key: &str) -> Option<Result<Vec<String>, Error>> {
+        let scalar = |value: &Value| match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        };
+        self.configuration.get(key).map(|value| {
+            let values = match value {
+                Value::Sequence(items) => items.iter().map(scalar).collect(),
+                _ => scalar(value).map(|v| vec![v]),
+            };
+            values.ok_or_else(|| {
+                Error::InvalidConfigurationValue(format!(
+                    "invalid value for {}.{}, expected a scalar or list of scalars: {:?}",
+                    &self.handler, key, value
+                ))
+            })
+        })
+    }
+
     pub fn get_parameter_as_f64(&self, key: &str) -> Option<Result<f64, Error>> {
         if let Some(value) = self.configuration.get(key) {
             let value = value.as_f64().ok_or_else(|| {
@@ -106,6 +254,74 @@ pub struct Config {
         default = "default_level_filter"
     )]
     log_level: LevelFilter,
+    /// Speed in meters per second above which time between records counts as moving time
+    #[serde(default = "default_moving_speed_threshold")]
+    moving_speed_threshold: f64,
+    /// Fall back to the terminal plotter if the configured plotting service fails
+    #[serde(default = "default_plotting_fallback")]
+    plotting_fallback: bool,
+    /// Shell command run after an import that added new files
+    #[serde(default)]
+    after_import: Option<String>,
+    /// Limits used by import --validate to flag implausible record data
+    #[serde(default)]
+    validation: ValidationThresholds,
+    /// Limits below which a lap is shown as a rest instead of a running lap
+    #[serde(default)]
+    rest_laps: RestLapThresholds,
+    /// Fields without a dedicated column to keep for each message type, every field is kept
+    /// for message types that aren't listed
+    #[serde(default)]
+    stored_fields: StoredFields,
+    /// Record positions jumping faster than this many meters per second are dropped at import
+    #[serde(default)]
+    max_gps_jump_speed: Option<f64>,
+    /// Estimate the timezone from the first GPS position of files that didn't record one
+    #[serde(default)]
+    infer_timezone: bool,
+    /// Seconds an outbound HTTP request may take before it is abandoned
+    #[serde(default = "default_http_timeout")]
+    http_timeout: u64,
+    /// Keep route images returned by the drawing service and reuse them for identical requests
+    #[serde(default)]
+    cache_route_images: bool,
+    /// Milliseconds to wait for another connection or program to release the database lock
+    #[serde(default = "default_busy_timeout")]
+    busy_timeout: u64,
+    /// Use SQLite's write-ahead log instead of its rollback journal
+    #[serde(default = "default_wal_journal")]
+    wal_journal: bool,
+    /// Proxy for every outbound HTTP request, the proxy settings of the environment (e.g.
+    /// HTTPS_PROXY) are used if this isn't set
+    #[serde(default)]
+    proxy: Option<ProxyConfig>,
+    /// User-Agent header sent with every outbound HTTP request
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+    /// Copy imported files into the devices directory unless overridden on the command line
+    #[serde(default = "default_persist_imported_files")]
+    persist_imported_files: bool,
+    /// Path template for copies of imported files, relative to the devices directory
+    #[serde(default = "default_persisted_file_name")]
+    persisted_file_name: String,
+    /// Directory the exports write to when exporting every file without --output-dir
+    #[serde(default)]
+    export_dir: Option<PathBuf>,
+    /// File name template for exported files, the extension is added by the export
+    #[serde(default = "default_export_file_name")]
+    export_file_name: String,
+    /// Decimal places of the coordinates written by the gpx and geojson exports
+    #[serde(default = "default_coordinate_precision")]
+    coordinate_precision: usize,
+    /// Elevation read by show and the exports unless overridden on the command line
+    #[serde(default)]
+    elevation_source: ElevationSource,
+    /// Units used to display values, the database always stores metric values
+    #[serde(default)]
+    units: UnitSystem,
+    /// Display names for devices keyed by "manufacturer-product"
+    #[serde(default)]
+    device_aliases: HashMap<String, String>,
     services: HashMap<ServiceType, ServiceConfig>,
 }
 
@@ -126,6 +342,99 @@ impl Config {
         self.log_level
     }
 
+    pub fn moving_speed_threshold(&self) -> f64 {
+        self.moving_speed_threshold
+    }
+
+    pub fn plotting_fallback(&self) -> bool {
+        self.plotting_fallback
+    }
+
+    pub fn after_import(&self) -> Option<&str> {
+        self.after_import.as_deref()
+    }
+
+    pub fn max_gps_jump_speed(&self) -> Option<f64> {
+        self.max_gps_jump_speed
+    }
+
+    pub fn infer_timezone(&self) -> bool {
+        self.infer_timezone
+    }
+
+    pub fn http_timeout(&self) -> u64 {
+        self.http_timeout
+    }
+
+    pub fn cache_route_images(&self) -> bool {
+        self.cache_route_images
+    }
+
+    pub fn busy_timeout(&self) -> u64 {
+        self.busy_timeout
+    }
+
+    pub fn wal_journal(&self) -> bool {
+        self.wal_journal
+    }
+
+    pub fn proxy(&self) -> Option<&ProxyConfig> {
+        self.proxy.as_ref()
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn persist_imported_files(&self) -> bool {
+        self.persist_imported_files
+    }
+
+    pub fn persisted_file_name(&self) -> &str {
+        &self.persisted_file_name
+    }
+
+    pub fn export_dir(&self) -> Option<&Path> {
+        self.export_dir.as_deref()
+    }
+
+    pub fn export_file_name(&self) -> &str {
+        &self.export_file_name
+    }
+
+    pub fn coordinate_precision(&self) -> usize {
+        self.coordinate_precision
+    }
+
+    pub fn elevation_source(&self) -> ElevationSource {
+        self.elevation_source
+    }
+
+    pub fn units(&self) -> UnitSystem {
+        self.units
+    }
+
+    pub fn validation(&self) -> &ValidationThresholds {
+        &self.validation
+    }
+
+    pub fn rest_laps(&self) -> &RestLapThresholds {
+        &self.rest_laps
+    }
+
+    pub fn stored_fields(&self) -> &StoredFields {
+        &self.stored_fields
+    }
+
+    /// Name to display for a device, unmapped devices use "manufacturer-product"
+    pub fn device_name(&self, manufacturer: &str, product: &str) -> String {
+        let device = format!("{}-{}", manufacturer, product);
+        match self.device_aliases.get(&device) {
+            Some(alias) => alias.clone(),
+            None => device,
+        }
+    }
+
     pub fn get_elevation_handler(&self) -> Result<Box<dyn ElevationDataSource>, Error> {
         match self.services.get(&ServiceType::Elevation) {
             Some(cfg) => new_elevation_handler(cfg),
@@ -148,6 +457,19 @@ impl Config {
         &self,
     ) -> Result<Box<dyn DataPlottingService>, Error> {
         match self.services.get(&ServiceType::DataPlotting) {
+            // there is nothing to fall back to when the terminal plotter is configured
+            Some(cfg) if self.plotting_fallback && cfg.handler() != "ratatui" => {
+                match new_plotting_visualization_handler(cfg) {
+                    Ok(hdl) => Ok(Box::new(FallbackPlotter::new(hdl))),
+                    Err(e) => {
+                        warn!(
+                            "Could not initialize the plotting service, using the terminal plotter - {}",
+                            e
+                        );
+                        Ok(Box::new(TerminalPlotter::default()))
+                    }
+                }
+            }
             Some(cfg) => new_plotting_visualization_handler(cfg),
             None => {
                 // use terminal as default plotter since we always have that
@@ -179,3 +501,43 @@ where
 fn default_level_filter() -> LevelFilter {
     LevelFilter::Info
 }
+
+fn default_plotting_fallback() -> bool {
+    true
+}
+
+fn default_http_timeout() -> u64 {
+    DEFAULT_HTTP_TIMEOUT
+}
+
+fn default_busy_timeout() -> u64 {
+    DEFAULT_BUSY_TIMEOUT
+}
+
+fn default_wal_journal() -> bool {
+    true
+}
+
+fn default_user_agent() -> String {
+    DEFAULT_USER_AGENT.to_string()
+}
+
+fn default_persist_imported_files() -> bool {
+    true
+}
+
+fn default_persisted_file_name() -> String {
+    "{manufacturer}-{product}-{serial}/{name}".to_string()
+}
+
+fn default_export_file_name() -> String {
+    "{date}-{time}-{uuid}".to_string()
+}
+
+fn default_coordinate_precision() -> usize {
+    7
+}
+
+fn default_moving_speed_threshold() -> f64 {
+    0.5
+}
@@ -0,0 +1,190 @@
+//! Insert many rows with a single statement, large imports spend most of their time on the
+//! overhead of executing one statement per row
+use rusqlite::types::{ToSqlOutput, Value};
+use rusqlite::{params_from_iter, Connection, Result, ToSql};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of rows inserted by each statement unless changed with `set_insert_batch_size`
+const DEFAULT_INSERT_BATCH_SIZE: usize = 500;
+static INSERT_BATCH_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_INSERT_BATCH_SIZE);
+
+/// Set the number of rows batched into each insert statement created afterwards, 1 inserts every
+/// row on its own
+#[cfg(test)]
+fn set_insert_batch_size(rows: usize) {
+    INSERT_BATCH_SIZE.store(rows.max(1), Ordering::Relaxed);
+}
+
+/// Return the number of rows batched into each insert statement
+fn insert_batch_size() -> usize {
+    INSERT_BATCH_SIZE.load(Ordering::Relaxed)
+}
+
+/// Queues rows for a table and inserts them a batch at a time. Rows aren't written until a batch
+/// fills up so `flush` must be called before they are read back, queued rows are lost if the
+/// inserter is dropped without it.
+pub struct BatchInsert {
+    table: &'static str,
+    columns: &'static [&'static str],
+    batch_size: usize,
+    values: Vec<Value>,
+}
+
+impl BatchInsert {
+    pub fn new(table: &'static str, columns: &'static [&'static str]) -> Self {
+        BatchInsert {
+            table,
+            columns,
+            batch_size: insert_batch_size(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Queue a row with a value for each column in order, a full batch is inserted right away
+    pub fn push(&mut self, conn: &Connection, row: &[&dyn ToSql]) -> Result<()> {
+        debug_assert_eq!(row.len(), self.columns.len());
+        for value in row {
+            // the values have to outlive the message they were read from
+            self.values.push(match value.to_sql()? {
+                ToSqlOutput::Borrowed(v) => v.into(),
+                ToSqlOutput::Owned(v) => v,
+                _ => {
+                    return Err(rusqlite::Error::ToSqlConversionFailure(
+                        format!("unsupported value for a {} column", self.table).into(),
+                    ))
+                }
+            });
+        }
+        if self.values.len() >= self.batch_size * self.columns.len() {
+            self.flush(conn)?;
+        }
+        Ok(())
+    }
+
+    /// Insert every queued row
+    pub fn flush(&mut self, conn: &Connection) -> Result<()> {
+        if self.values.is_empty() {
+            return Ok(());
+        }
+        // full batches share the same statement so it's only prepared once
+        let rows = self.values.len() / self.columns.len();
+        let placeholders = format!("({})", vec!["?"; self.columns.len()].join(", "));
+        let sql = format!(
+            "insert into {} ({}) values {}",
+            self.table,
+            self.columns.join(", "),
+            vec![placeholders; rows].join(", ")
+        );
+        conn.prepare_cached(&sql)?
+            .execute(params_from_iter(self.values.drain(..)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import_fit_data;
+    use crate::test_support::{fit_time, import, memory_db, Field, FitFileBuilder, RECORD, START};
+    use rusqlite::params;
+    use std::time::Instant;
+
+    /// Every row of a table in insertion order
+    fn table_rows(conn: &Connection, table: &str) -> Vec<Vec<Value>> {
+        let mut stmt = conn
+            .prepare(&format!("select * from {} order by id", table))
+            .unwrap();
+        let columns = stmt.column_count();
+        stmt.query_map([], |r| (0..columns).map(|i| r.get(i)).collect())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn batched_inserts_store_the_same_rows() {
+        let mut builder = FitFileBuilder::new().file_id(1234, START);
+        for i in 0..7 {
+            builder = builder.message(
+                RECORD,
+                &[
+                    Field::UInt32(253, fit_time(START + i)),
+                    Field::UInt32(5, i * 300),
+                    Field::UInt16(6, 3000),
+                    // cadence isn't mapped to a column so it's stored as a developer field
+                    Field::UInt8(4, 80 + i as u8),
+                ],
+            );
+        }
+        let data = builder
+            .lap(START, START + 3, 900.0, 3.0, 10)
+            .lap(START + 3, START + 6, 900.0, 3.0, 10)
+            .build();
+
+        // a batch size that doesn't divide the number of rows leaves a partial batch at the end
+        let mut databases = Vec::new();
+        for batch_size in [1, 3] {
+            set_insert_batch_size(batch_size);
+            let mut conn = memory_db();
+            import(&mut conn, &data);
+            databases.push(conn);
+        }
+        set_insert_batch_size(DEFAULT_INSERT_BATCH_SIZE);
+
+        for table in ["record_messages", "lap_messages", "developer_fields"] {
+            let single = table_rows(&databases[0], table);
+            assert!(!single.is_empty(), "no rows in {}", table);
+            assert_eq!(single, table_rows(&databases[1], table), "{}", table);
+        }
+    }
+
+    /// Time the import of a 24 hour ultra recorded every second with single row and batched inserts,
+    /// run it with `cargo test --release --lib benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark_large_file_import() {
+        const RECORDS: u32 = 86_400;
+        let mut builder = FitFileBuilder::new().file_id(1234, START);
+        for i in 0..RECORDS {
+            let offset = i as f64 * 0.00002;
+            builder = builder.record(
+                START + i,
+                (39.46 + offset, -80.14),
+                i as f64 * 2.5,
+                2.5,
+                (120 + i % 60) as u8,
+            );
+        }
+        let data = builder
+            .lap(START, START + RECORDS - 1, RECORDS as f64 * 2.5, 2.5, 200)
+            .build();
+
+        for batch_size in [1, DEFAULT_INSERT_BATCH_SIZE] {
+            set_insert_batch_size(batch_size);
+            let mut conn = memory_db();
+            let tx = conn.transaction().unwrap();
+            let start = Instant::now();
+            let info = import_fit_data(&mut data.as_slice(), &tx).unwrap();
+            let elapsed = start.elapsed();
+            tx.commit().unwrap();
+
+            let count: u32 = conn
+                .query_row(
+                    "select count(*) from record_messages where file_id = ?",
+                    params![info.id()],
+                    |r| r.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, RECORDS);
+            println!(
+                "Imported {} records ({} bytes) {} rows per insert in {:?}, {:.0} records/s",
+                RECORDS,
+                data.len(),
+                batch_size,
+                elapsed,
+                RECORDS as f64 / elapsed.as_secs_f64()
+            );
+        }
+        set_insert_batch_size(DEFAULT_INSERT_BATCH_SIZE);
+    }
+}
@@ -1,19 +1,35 @@
 //! Database utility functions and the schema definition
-use crate::{data_dir, Error, FileInfo};
+use crate::{data_dir, ElevationSource, Error, FileInfo};
 use chrono::Utc;
 use fitparser::Value;
-use log::{debug, error};
+use log::{debug, error, warn};
+use rusqlite::backup::Backup;
 use rusqlite::types::ToSqlOutput;
-use rusqlite::{params_from_iter, Connection, Result, ToSql};
+use rusqlite::{params, params_from_iter, Connection, Result, ToSql};
 use std::convert::TryFrom;
 use std::fmt;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
 
+mod batch;
+pub use batch::BatchInsert;
 mod schema;
-pub use schema::create_database;
+pub use schema::{create_database, init_database, latest_schema_version, schema_version};
+mod writer;
+pub use writer::DbWriter;
 
 static DATABASE_NAME: &str = "garmin-run-tracker.db";
+/// Shared cache URI so every connection opened during an ephemeral session sees the same data
+static EPHEMERAL_DATABASE_URI: &str = "file:garmin-run-tracker?mode=memory&cache=shared";
+static EPHEMERAL: AtomicBool = AtomicBool::new(false);
+/// Milliseconds a connection waits for another one to release the database lock before failing
+/// with "database is locked"
+pub const DEFAULT_BUSY_TIMEOUT: u64 = 5000;
+static BUSY_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static WAL_JOURNAL: AtomicBool = AtomicBool::new(true);
 
 /// Acts as a pointer to a Value variant that can be used in parameterized sql statements
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -21,7 +37,7 @@ pub struct SqlValue<'a>(&'a Value);
 
 impl SqlValue<'_> {
     /// Wrap a reference to a Value parsed from a FIT file
-    pub fn new(value: &Value) -> SqlValue {
+    pub fn new(value: &Value) -> SqlValue<'_> {
         SqlValue(value)
     }
 }
@@ -30,7 +46,7 @@ impl Deref for SqlValue<'_> {
     type Target = Value;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        self.0
     }
 }
 
@@ -140,7 +156,8 @@ impl TryFrom<&'_ rusqlite::Row<'_>> for FileInfo {
     type Error = rusqlite::Error;
 
     fn try_from(row: &rusqlite::Row) -> Result<Self, Self::Error> {
-        let (id, manufacturer, product, serial_number, timestamp, uuid) = TryFrom::try_from(row)?;
+        let (id, manufacturer, product, serial_number, timestamp, timezone_offset, uuid) =
+            TryFrom::try_from(row)?;
 
         Ok(FileInfo {
             id,
@@ -148,6 +165,7 @@ impl TryFrom<&'_ rusqlite::Row<'_>> for FileInfo {
             product,
             serial_number,
             timestamp,
+            timezone_offset,
             uuid,
         })
     }
@@ -158,23 +176,182 @@ pub fn db_path() -> PathBuf {
     data_dir().join(DATABASE_NAME)
 }
 
-/// Open a fresh connection to the application database
+/// Open a fresh connection to the application database, or the in-memory database when an
+/// ephemeral session has been started
 pub fn open_db_connection() -> Result<Connection> {
-    let db = db_path();
-    let conn = Connection::open(&db)?;
-    rusqlite::vtab::array::load_module(&conn)?;
-    debug!("Connected to local database located at: {:?}", db);
+    if is_ephemeral() {
+        open_db_connection_at(EPHEMERAL_DATABASE_URI)
+    } else {
+        open_db_connection_at(db_path())
+    }
+}
+
+/// Open a connection to the database at the given path, SQLite URIs are also accepted
+pub fn open_db_connection_at<P: AsRef<Path>>(path: P) -> Result<Connection> {
+    let path = path.as_ref();
+    let conn = Connection::open(path)?;
+    prepare_connection(&conn)?;
+    debug!("Connected to local database located at: {:?}", path);
+    Ok(conn)
+}
+
+/// Set how long every connection opened afterwards waits for a locked database, only the first
+/// call has any effect
+pub fn set_busy_timeout(timeout: Duration) {
+    let _ = BUSY_TIMEOUT.set(timeout);
+}
+
+/// Return how long connections wait for a locked database before giving up
+pub fn busy_timeout() -> Duration {
+    BUSY_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_millis(DEFAULT_BUSY_TIMEOUT))
+}
+
+/// Choose between SQLite's write-ahead log (the default) and its rollback journal for every
+/// connection opened afterwards. The journal mode is stored in the database file, so the
+/// database is switched over when the next connection is opened.
+pub fn set_wal_journal(enabled: bool) {
+    WAL_JOURNAL.store(enabled, Ordering::SeqCst);
+}
+
+/// Settings that every connection needs, SQLite leaves foreign key enforcement off by default
+fn prepare_connection(conn: &Connection) -> Result<()> {
+    rusqlite::vtab::array::load_module(conn)?;
+    conn.busy_timeout(busy_timeout())?;
+    conn.execute_batch("pragma foreign_keys = on")?;
+    set_journal_mode(conn)
+}
+
+/// Switch the database to the configured journal mode. The write-ahead log speeds up the many
+/// small writes of an import and lets other connections read while it runs, it keeps its data
+/// in the -wal and -shm files next to the database while connections are open.
+fn set_journal_mode(conn: &Connection) -> Result<()> {
+    let wal = WAL_JOURNAL.load(Ordering::SeqCst);
+    let mode = if wal { "wal" } else { "delete" };
+    let current: String = conn.query_row("pragma journal_mode", [], |r| r.get(0))?;
+    // in-memory databases can't use a write-ahead log
+    if current != "memory" && current != mode {
+        // leaving the write-ahead log needs every other connection closed, the database keeps
+        // working in its current mode if that isn't possible
+        match conn.query_row(&format!("pragma journal_mode = {}", mode), [], |r| {
+            r.get::<usize, String>(0)
+        }) {
+            Ok(new) if new == mode => debug!("Switched database journal mode to {}", mode),
+            Ok(new) => warn!(
+                "Could not switch database journal mode to {}, using {}",
+                mode, new
+            ),
+            Err(e) => warn!("Could not switch database journal mode to {} - {}", mode, e),
+        }
+    }
+    // a write-ahead log doesn't need a sync after every transaction to stay consistent
+    if wal {
+        conn.execute_batch("pragma synchronous = normal")?;
+    }
+    Ok(())
+}
+
+/// Copy the database into the file at the given path with SQLite's online backup API, which is
+/// safe while other connections are using the database. Steps that find the database busy are
+/// retried after a short pause.
+pub fn backup_database<P: AsRef<Path>>(conn: &Connection, path: P) -> Result<()> {
+    let mut dest = Connection::open(path)?;
+    Backup::new(conn, &mut dest)?.run_to_completion(100, Duration::from_millis(50), None)?;
+    // the copy takes on the write-ahead log of the source, a rollback journal keeps it a single
+    // self-contained file without -wal and -shm files next to it
+    dest.query_row("pragma journal_mode = delete", [], |_| Ok(()))
+}
+
+/// Open a private in-memory database with the application schema already created
+pub fn open_in_memory_db_connection() -> Result<Connection> {
+    let mut conn = Connection::open_in_memory()?;
+    prepare_connection(&conn)?;
+    init_database(&mut conn)?;
+    debug!("Connected to a new in-memory database");
     Ok(conn)
 }
 
+/// Direct all following calls to `open_db_connection` at a shared in-memory database that is
+/// discarded when the program exits. The returned connection must be kept alive for the rest of
+/// the session since SQLite drops the in-memory database once its last connection is closed.
+pub fn start_ephemeral_session() -> Result<Connection> {
+    EPHEMERAL.store(true, Ordering::SeqCst);
+    let mut conn = open_db_connection()?;
+    init_database(&mut conn)?;
+    Ok(conn)
+}
+
+/// Return true if the application is using an in-memory database for this session
+pub fn is_ephemeral() -> bool {
+    EPHEMERAL.load(Ordering::SeqCst)
+}
+
 /// Return a QueryStringBuilder with the correct columns and column ordering to allow try_from
 /// to be used to convert the rusqlite::Row into a FileInfo via FileInfo::try_from(row)
 pub fn new_file_info_query() -> QueryStringBuilder<'static> {
     QueryStringBuilder::new(
-        "select id, device_manufacturer, device_product, device_serial_number, time_created, uuid from files",
+        "select id, device_manufacturer, device_product, device_serial_number, time_created, timezone_offset, uuid from files",
     )
 }
 
+/// Return every file in the database, oldest first
+pub fn all_files(conn: &Connection) -> Result<Vec<FileInfo>, Error> {
+    let mut query = new_file_info_query();
+    query.order_by("time_created ASC");
+    let files = conn
+        .prepare(&query.to_string())?
+        .query_map([], |r| FileInfo::try_from(r))?
+        .collect::<Result<Vec<FileInfo>, _>>()?;
+    Ok(files)
+}
+
+/// Query of the total ascent and descent in meters of each file, with `file_id`, `gain` and
+/// `descent` columns. `file_ids` is a list or subquery of the ids of the files to include, e.g.
+/// `?1` or `select id from files`. The per lap totals measured by the device (e.g. with a
+/// barometric altimeter) are used when it reported them, otherwise the climbs and drops between
+/// consecutive records with elevation data are summed. Files with neither are left out.
+pub fn climb_totals_query(elevation_source: ElevationSource, file_ids: &str) -> String {
+    format!(
+        "select f.id as file_id,
+                iif(l.ascent is null, e.gain, l.ascent) as gain,
+                iif(l.ascent is null, e.descent, coalesce(l.descent, 0)) as descent
+         from files f
+         left join (select file_id, sum(total_ascent) as ascent, sum(total_descent) as descent
+                    from lap_messages where file_id in ({ids})
+                    group by file_id) l on l.file_id = f.id
+         left join (select file_id, total(max(delta, 0)) as gain,
+                           total(max(-delta, 0)) as descent
+                    from (select file_id,
+                                 {c} - lag({c}) over (partition by file_id order by timestamp)
+                                     as delta
+                          from record_messages where {c} is not null and file_id in ({ids}))
+                    group by file_id) e on e.file_id = f.id
+         where f.id in ({ids}) and (l.ascent is not null or e.file_id is not null)",
+        c = elevation_source.column(),
+        ids = file_ids
+    )
+}
+
+/// Total ascent and descent of a file in meters, see `climb_totals_query`. None if the file has
+/// no elevation data.
+pub fn total_climb(
+    conn: &Connection,
+    file_id: Option<u32>,
+    elevation_source: ElevationSource,
+) -> Result<Option<(f64, f64)>> {
+    let mut stmt = conn.prepare(&format!(
+        "select gain, descent from ({})",
+        climb_totals_query(elevation_source, "?1")
+    ))?;
+    let mut rows = stmt.query(params![file_id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+        None => Ok(None),
+    }
+}
+
 /// Attempt to locate a specific file by it's full or partial UUID, partial UUIDs that aren't
 /// unique return the most recent match (similar to short git commit SHAs).
 pub fn find_file_by_uuid(conn: &Connection, uuid: &str) -> Result<FileInfo, Error> {
@@ -1,19 +1,155 @@
 use super::{db_path, open_db_connection};
 use log::debug;
-use rusqlite::{params, Result};
+use rusqlite::{ffi, params, Connection, Result, Transaction};
+
+/// Schema changes applied on top of the initial tables, the position of each entry (plus one)
+/// is the `user_version` stored in the database once that change has been applied. New entries
+/// must only ever be appended to this list.
+static MIGRATIONS: &[&str] = &[
+    // 1: store any FIT fields that don't map onto a known column
+    "create table if not exists developer_fields (
+        message_index integer not null,
+        message_type  text not null,
+        field_name    text not null,
+        value,
+        units         text,
+        file_id       integer not null,
+        id            integer primary key
+    )",
+    // 2: offset from UTC in seconds of the timezone the activity was recorded in
+    "alter table files add column timezone_offset integer",
+    // 3: track when imports were run so unchanged files can be skipped by later imports
+    "create table if not exists import_history (
+        started_at datetime not null,
+        id         integer primary key
+    )",
+    // 4: keep track of files that failed to import
+    "create table if not exists import_errors (
+        uuid        text not null,
+        path        text not null,
+        message     text not null,
+        occurred_at datetime not null,
+        id          integer primary key
+    )",
+    // 5: nearly every query against the message tables filters by file and orders by time
+    "create index if not exists record_messages_file_id_timestamp
+        on record_messages (file_id, timestamp);
+    create index if not exists lap_messages_file_id_start_time
+        on lap_messages (file_id, start_time)",
+    // 6: deduplication relies on the file hash being unique
+    "create unique index if not exists files_uuid on files (uuid)",
+    // 7: rebuild the message tables with foreign keys so deleting a file removes its data,
+    // rows that are already orphaned are dropped along the way
+    "create table record_messages_new (
+        position_lat  integer,
+        position_long integer,
+        speed         float,
+        distance      float,
+        elevation     float,
+        heart_rate    integer,
+        timestamp     datetime not null,
+        file_id       integer not null references files (id) on delete cascade,
+        id            integer primary key
+    );
+    insert into record_messages_new
+        select * from record_messages where file_id in (select id from files);
+    drop table record_messages;
+    alter table record_messages_new rename to record_messages;
+    create index record_messages_file_id_timestamp on record_messages (file_id, timestamp);
+
+    create table lap_messages_new (
+        start_position_lat  integer,
+        start_position_long integer,
+        start_elevation     float,
+        end_position_lat    integer,
+        end_position_long   integer,
+        end_elevation       float,
+        average_speed       float,
+        average_heart_rate  integer,
+        total_calories      integer,
+        total_distance      float,
+        start_time          datetime not null,
+        timestamp           datetime not null,
+        file_id             integer not null references files (id) on delete cascade,
+        id                  integer primary key
+    );
+    insert into lap_messages_new
+        select * from lap_messages where file_id in (select id from files);
+    drop table lap_messages;
+    alter table lap_messages_new rename to lap_messages;
+    create index lap_messages_file_id_start_time on lap_messages (file_id, start_time);
+
+    create table developer_fields_new (
+        message_index integer not null,
+        message_type  text not null,
+        field_name    text not null,
+        value,
+        units         text,
+        file_id       integer not null references files (id) on delete cascade,
+        id            integer primary key
+    );
+    insert into developer_fields_new
+        select * from developer_fields where file_id in (select id from files);
+    drop table developer_fields;
+    alter table developer_fields_new rename to developer_fields;
+    create index developer_fields_file_id on developer_fields (file_id)",
+    // 8: running dynamics, NULL unless a dynamics pod or compatible watch recorded them
+    "alter table record_messages add column vertical_oscillation float;
+    alter table record_messages add column stance_time float;
+    alter table record_messages add column step_length float",
+    // 9: timer, lap and session events so paused time can be told apart from moving time
+    "create table if not exists events (
+        event       text not null,
+        event_type  text not null,
+        timestamp   datetime not null,
+        file_id     integer not null references files (id) on delete cascade,
+        id          integer primary key
+    );
+    create index if not exists events_file_id_timestamp on events (file_id, timestamp)",
+    // 10: set when fetching elevation data failed at import so update-elevation can retry later
+    "alter table files add column elevation_pending integer not null default 0",
+    // 11: altitude recorded by the device, kept apart from the elevation service's values
+    "alter table record_messages add column device_elevation float",
+    // 12: ascent and descent the device measured over each lap, e.g. with a barometric altimeter
+    "alter table lap_messages add column total_ascent float;
+    alter table lap_messages add column total_descent float",
+    // 13: import runs are tracked for each scanned path, older rows without one are ignored
+    "alter table import_history add column path text",
+    // 14: activities imported on their own from a multi-activity file remember the UUID of the
+    // whole file and their index so the file can still be found on disk
+    "alter table files add column source_uuid text;
+    alter table files add column activity_index integer",
+];
+
+/// Version that adds the unique index on `files.uuid`, existing duplicates must be resolved
+/// by hand before it can be applied.
+const UNIQUE_UUID_VERSION: usize = 6;
 
 /// Create the database and required tables
 pub fn create_database() -> Result<()> {
     let db = db_path();
-    if db.exists() {
+    let exists = db.exists();
+    let mut conn = open_db_connection()?;
+    if exists {
         debug!(
             "Skipping database initialization, pre-existing database found at {:?}",
             db
         );
-        return Ok(());
+        migrate_database(&mut conn)
+    } else {
+        init_database(&mut conn)
     }
+}
 
-    let mut conn = open_db_connection()?;
+/// Create the required tables and apply all migrations using an existing connection, e.g. to
+/// setup an in-memory database.
+pub fn init_database(conn: &mut Connection) -> Result<()> {
+    create_tables(conn)?;
+    migrate_database(conn)
+}
+
+/// Create the initial set of tables, later changes are handled by `migrate_database`
+fn create_tables(conn: &mut Connection) -> Result<()> {
     let tx = conn.transaction()?;
     tx.execute(
         "create table if not exists files (
@@ -67,3 +203,63 @@ pub fn create_database() -> Result<()> {
     debug!("Completed database initialization");
     Ok(())
 }
+
+/// Schema version of a database that has every migration applied
+pub fn latest_schema_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Schema version stored in the database's `user_version`
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    conn.query_row("pragma user_version", params![], |r| r.get(0))
+}
+
+/// Apply any schema changes the database hasn't seen yet based on its `user_version`
+fn migrate_database(conn: &mut Connection) -> Result<()> {
+    let version = schema_version(conn)?;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for (idx, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        debug!("Applying database migration {}", idx + 1);
+        if idx + 1 == UNIQUE_UUID_VERSION {
+            check_unique_uuids(&tx)?;
+        }
+        tx.execute_batch(sql)?;
+    }
+    // pragmas don't accept bound parameters
+    tx.execute_batch(&format!("pragma user_version = {}", MIGRATIONS.len()))?;
+    tx.commit()?;
+    debug!("Database schema is now at version {}", MIGRATIONS.len());
+    Ok(())
+}
+
+/// Report any files sharing a UUID since they would cause the unique index to fail
+fn check_unique_uuids(tx: &Transaction) -> Result<()> {
+    let mut stmt = tx.prepare(
+        "select uuid, group_concat(id, ', ') from files group by uuid having count(*) > 1",
+    )?;
+    let duplicates = stmt
+        .query_map(params![], |r| {
+            Ok((r.get::<usize, String>(0)?, r.get::<usize, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    // the logger isn't initialized when migrations run so the details go into the error itself
+    let details: Vec<String> = duplicates
+        .iter()
+        .map(|(uuid, ids)| format!("UUID={} (file ids: {})", uuid, ids))
+        .collect();
+    Err(rusqlite::Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_CONSTRAINT_UNIQUE),
+        Some(format!(
+            "Database contains duplicated files, remove the extra rows from the files table to continue: {}",
+            details.join(", ")
+        )),
+    ))
+}
@@ -0,0 +1,97 @@
+//! Serialize database writes and retry them while another process holds the lock
+use super::open_db_connection;
+use crate::Error;
+use log::warn;
+use rusqlite::{Connection, ErrorCode, TransactionBehavior};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Number of times a write is attempted before a busy database is reported as an error
+const MAX_WRITE_ATTEMPTS: u32 = 5;
+/// Pause before retrying a write the first time, it doubles with every attempt
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Funnel writes from any number of threads through a single connection so they never compete
+/// for the database lock with each other. Each write runs in its own transaction and is retried
+/// when another process (e.g. a database browser) holds the lock past the busy timeout.
+pub struct DbWriter {
+    conn: Mutex<Connection>,
+}
+
+impl DbWriter {
+    pub fn new(conn: Connection) -> Self {
+        DbWriter {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    /// Create a writer using a new connection to the application database
+    pub fn open() -> Result<Self, Error> {
+        Ok(DbWriter::new(open_db_connection()?))
+    }
+
+    /// Run the closure in a transaction that is committed if it succeeds and rolled back if it
+    /// returns an error. The closure is run again if the database was busy so it must not
+    /// have side effects outside of the transaction.
+    pub fn write<T, F>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&rusqlite::Transaction) -> Result<T, Error>,
+    {
+        // a panic while writing rolls back the transaction so the connection is still usable
+        let mut conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            // an immediate transaction takes the write lock up front instead of failing part
+            // way through when another connection is writing
+            let result = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(Error::from)
+                .and_then(|tx| {
+                    let value = f(&tx)?;
+                    tx.commit()?;
+                    Ok(value)
+                });
+            match result {
+                Err(e) if is_busy(&e) && attempt < MAX_WRITE_ATTEMPTS => {
+                    warn!(
+                        "Database is busy, retrying write in {}ms (attempt {} of {})",
+                        delay.as_millis(),
+                        attempt,
+                        MAX_WRITE_ATTEMPTS
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Run the closure with the connection outside of any transaction, e.g. to read the data
+    /// a following write depends on
+    pub fn read<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Connection) -> T,
+    {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        f(&conn)
+    }
+
+    /// Return the connection, e.g. once every thread has finished writing
+    pub fn into_inner(self) -> Connection {
+        self.conn.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Return true if the error was caused by another connection holding the database lock
+fn is_busy(err: &Error) -> bool {
+    match err {
+        Error::Rusqlite(rusqlite::Error::SqliteFailure(e, _)) => {
+            matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        }
+        _ => false,
+    }
+}
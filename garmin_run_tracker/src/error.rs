@@ -7,10 +7,15 @@ use std::fmt;
 pub enum Error {
     ArrayConversionError,
     DuplicateFileError(String),
+    OverlappingFileError(String, String),
+    ImportFailures(usize),
     RequestError(reqwest::StatusCode, String),
     FileDoesNotExistError(String),
     FileIdMessageNotFound(String),
     FitParser(fitparser::ErrorKind),
+    InvalidFitFile(String),
+    InvalidTrackFile(String),
+    NoRecordsFound(String),
     Io(std::io::Error),
     Other(String),
     Rusqlite(rusqlite::Error),
@@ -49,6 +54,19 @@ impl convert::From<serde_yaml::Error> for Error {
     }
 }
 
+impl convert::From<Box<dyn std::error::Error>> for Error {
+    fn from(err: Box<dyn std::error::Error>) -> Error {
+        // database errors are kept intact so e.g. a busy database can still be retried
+        match err.downcast::<Error>() {
+            Ok(err) => *err,
+            Err(err) => match err.downcast::<rusqlite::Error>() {
+                Ok(err) => Error::Rusqlite(*err),
+                Err(err) => Error::Other(err.to_string()),
+            },
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -60,18 +78,29 @@ impl fmt::Display for Error {
                 "Attempted to import a file already in the database, UUID: {}",
                 uuid
             ),
+            Error::OverlappingFileError(uuid, existing) => write!(
+                f,
+                "Activity in file UUID: {} overlaps the file already in the database, UUID: {}",
+                uuid, existing
+            ),
+            Error::ImportFailures(count) => write!(f, "{} file(s) failed to import", count),
             Error::RequestError(code, msg) => {
                 write!(f, "HTTP request failed with code: {} - {}", code, msg)
             }
             Error::FileDoesNotExistError(uuid) => {
                 write!(f, "FIT File with UUID='{}' does not exist", uuid)
             }
+            Error::NoRecordsFound(uuid) => {
+                write!(f, "File with UUID='{}' has no GPS/record data", uuid)
+            }
             Error::FileIdMessageNotFound(uuid) => write!(
                 f,
                 "FIT File with UUID='{}' did not have a File_id message",
                 uuid
             ),
             Error::FitParser(e) => write!(f, "{}", e),
+            Error::InvalidFitFile(msg) => write!(f, "Invalid FIT file: {}", msg),
+            Error::InvalidTrackFile(msg) => write!(f, "Invalid track file: {}", msg),
             Error::Io(e) => write!(f, "{}", e),
             Error::Other(msg) => write!(f, "{}", msg),
             Error::Rusqlite(e) => write!(f, "{}", e),
@@ -82,4 +111,15 @@ impl fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// Exit code for the process when this error ends a command, partial failures get their own
+    /// code so scripts can tell them apart from a command that didn't run at all
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ImportFailures(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
 impl std::error::Error for Error {}
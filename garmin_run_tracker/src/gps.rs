@@ -1,5 +1,15 @@
 //! Module with GPS specific structures
 use std::char;
+use std::ops::Range;
+
+/// Mean radius of the earth in meters
+static EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Convert a FIT coordinate in semicircles units into degrees, exports that need more precision
+/// than a `Location` holds use this directly
+pub fn semicircles_to_degrees(value: i32) -> f64 {
+    value as f64 * 180.0 / 2147483648.0
+}
 
 /// Stores a single geospatial point
 #[derive(Clone, Copy, Debug)]
@@ -13,11 +23,20 @@ pub struct Location {
 }
 
 impl Location {
+    /// Create a location without elevation data from coordinates provided in degrees
+    pub fn new(latitude: f32, longitude: f32) -> Self {
+        Location {
+            latitude,
+            longitude,
+            elevation: None,
+        }
+    }
+
     /// Create a location without elevation data from coordinates provided in semicircles units
     pub fn from_fit_coordinates(latitude: i32, longitude: i32) -> Self {
         Location {
-            latitude: (latitude as f32) * 180.0 / 2147483648.0,
-            longitude: (longitude as f32) * 180.0 / 2147483648.0,
+            latitude: semicircles_to_degrees(latitude) as f32,
+            longitude: semicircles_to_degrees(longitude) as f32,
             elevation: None,
         }
     }
@@ -41,19 +60,146 @@ impl Location {
     pub fn set_elevation(&mut self, elevation: Option<f32>) {
         self.elevation = elevation;
     }
+
+    /// Return the great circle distance in meters to another location (haversine formula)
+    pub fn distance_to(&self, other: &Location) -> f64 {
+        let lat1 = (self.latitude as f64).to_radians();
+        let lat2 = (other.latitude as f64).to_radians();
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude as f64 - self.longitude as f64).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    /// Return the distance in meters to the closest point on the segment between start and end,
+    /// the points are projected onto a local flat plane which is accurate enough at the scale of
+    /// a run.
+    pub fn distance_to_segment(&self, start: &Location, end: &Location) -> f64 {
+        let scale = (start.latitude as f64).to_radians().cos();
+        let project = |loc: &Location| {
+            (
+                (loc.longitude as f64 - start.longitude as f64).to_radians() * scale * EARTH_RADIUS,
+                (loc.latitude as f64 - start.latitude as f64).to_radians() * EARTH_RADIUS,
+            )
+        };
+        let (px, py) = project(self);
+        let (ex, ey) = project(end);
+        let length = ex * ex + ey * ey;
+        if length == 0.0 {
+            return (px * px + py * py).sqrt();
+        }
+        let t = ((px * ex + py * ey) / length).clamp(0.0, 1.0);
+        ((px - t * ex).powi(2) + (py - t * ey).powi(2)).sqrt()
+    }
+}
+
+/// Reduce the number of points in a trace with the Ramer-Douglas-Peucker algorithm, points that
+/// are within `tolerance` meters of the simplified line are dropped. The first and last points
+/// are always kept.
+pub fn simplify(trace: &[Location], tolerance: f64) -> Vec<Location> {
+    if trace.len() < 3 {
+        return trace.to_vec();
+    }
+    let mut keep = vec![false; trace.len()];
+    keep[0] = true;
+    keep[trace.len() - 1] = true;
+
+    // use an explicit stack of index ranges instead of recursing so long traces are safe
+    let mut stack = vec![(0, trace.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let (index, max_dist) = trace[first + 1..last]
+            .iter()
+            .enumerate()
+            .map(|(i, loc)| {
+                (
+                    first + 1 + i,
+                    loc.distance_to_segment(&trace[first], &trace[last]),
+                )
+            })
+            .fold((first, 0.0), |acc, v| if v.1 > acc.1 { v } else { acc });
+        if max_dist > tolerance {
+            keep[index] = true;
+            stack.push((first, index));
+            stack.push((index, last));
+        }
+    }
+
+    trace
+        .iter()
+        .zip(keep)
+        .filter_map(|(loc, keep)| if keep { Some(*loc) } else { None })
+        .collect()
+}
+
+/// Reduce a trace to at most `max_points` evenly spaced points, the first and last points are
+/// always kept.
+pub fn downsample(trace: &[Location], max_points: usize) -> Vec<Location> {
+    if trace.len() <= max_points {
+        return trace.to_vec();
+    }
+    match max_points {
+        0 => Vec::new(),
+        1 => vec![trace[0]],
+        _ => {
+            let step = (trace.len() - 1) as f64 / (max_points - 1) as f64;
+            (0..max_points)
+                .map(|i| trace[(i as f64 * step).round() as usize])
+                .collect()
+        }
+    }
+}
+
+/// Range of the trace left after hiding the points near its start and finish, e.g. so a shared
+/// route doesn't reveal where someone lives. Leading points within `radius` meters of the start
+/// and trailing points within `radius` meters of the finish are dropped, points that pass back
+/// through either zone later on are kept so the trace stays contiguous. The range is empty if no
+/// point ever leaves the zones.
+pub fn privacy_trim(trace: &[Location], radius: f64) -> Range<usize> {
+    let (start, finish) = match (trace.first(), trace.last()) {
+        (Some(start), Some(finish)) => (start, finish),
+        _ => return 0..0,
+    };
+    let first = match trace.iter().position(|loc| loc.distance_to(start) > radius) {
+        Some(index) => index,
+        None => return 0..0,
+    };
+    let last = trace
+        .iter()
+        .rposition(|loc| loc.distance_to(finish) > radius)
+        .unwrap_or(first);
+    if last < first {
+        0..0
+    } else {
+        first..last + 1
+    }
+}
+
+/// Estimate the offset from UTC in seconds of the timezone at a longitude using nautical time
+/// zones, each covering 15 degrees centered on a whole hour. Political borders and daylight
+/// saving time are ignored so the estimate can be an hour or more off the actual local time.
+pub fn estimate_utc_offset(longitude: f32) -> i32 {
+    let hours = (longitude / 15.0).round().clamp(-12.0, 12.0);
+    hours as i32 * 3600
 }
 
-/// Encodes a slice of coordinates into Google Encoded Polyline format.
+/// Encodes a slice of coordinates into Google Encoded Polyline format using the given number of
+/// decimal digits (5 for the standard format or 6 for the higher precision variant). Using a
+/// different precision than the consumer expects silently scales the coordinates by 10x.
 ///
 /// This code was extracted and simplified for our use case from:
 /// https://github.com/georust/polyline
 /// https://developers.google.com/maps/documentation/utilities/polylinealgorithm
-pub fn encode_coordinates(coordinates: &[Location]) -> Result<String, String> {
+pub fn encode_coordinates(coordinates: &[Location], precision: u32) -> Result<String, String> {
+    let factor = match precision {
+        5 => 100_000.0,
+        6 => 1_000_000.0,
+        _ => return Err(format!("Unsupported polyline precision: {}", precision)),
+    };
     let mut output = "".to_string();
     let mut b = (0, 0);
 
     for a in coordinates {
-        let a = (scale(a.latitude), scale(a.longitude));
+        let a = (scale(a.latitude, factor), scale(a.longitude, factor));
         output = output + &encode(a.0, b.0)?;
         output = output + &encode(a.1, b.1)?;
         b = a;
@@ -64,9 +210,8 @@ pub fn encode_coordinates(coordinates: &[Location]) -> Result<String, String> {
 
 /// Scale a floating point value into an integer at the given precision
 #[inline]
-fn scale(n: f32) -> i32 {
-    static FACTOR: f32 = 100_000.0; // use 5 digits of precision
-    (FACTOR * n).round() as i32
+fn scale(n: f32, factor: f64) -> i32 {
+    (factor * n as f64).round() as i32
 }
 
 /// Encode a single latitude or longitude value into the polyline format
@@ -1,26 +1,45 @@
-use chrono::{DateTime, Local, TimeZone};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, TimeZone, Utc};
+use fitparser::de::{FitObject, FitStreamProcessor};
 use fitparser::profile::MesgNum;
 use fitparser::{FitDataRecord, Value};
-use log::trace;
+use log::{debug, trace, warn};
 use rusqlite::{params, Transaction};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::prelude::*;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 
 pub mod cli;
 pub mod config;
 pub use config::Config;
 mod db;
-pub use db::{create_database, open_db_connection};
-use db::{find_file_by_uuid, SqlValue};
+pub use db::{
+    backup_database, busy_timeout, create_database, init_database, is_ephemeral,
+    latest_schema_version, open_db_connection, open_db_connection_at, open_in_memory_db_connection,
+    schema_version, set_busy_timeout, set_wal_journal, start_ephemeral_session, DbWriter,
+    DEFAULT_BUSY_TIMEOUT,
+};
+use db::{find_file_by_uuid, BatchInsert, SqlValue};
 mod error;
 pub use error::Error;
 pub mod gps;
+pub mod logging;
 pub mod services;
+#[cfg(any(feature = "gpx", feature = "tcx"))]
+mod track;
+#[cfg(feature = "gpx")]
+pub use track::import_gpx_data;
+#[cfg(feature = "tcx")]
+pub use track::import_tcx_data;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod validation;
 
 // re-export service config derive macro
 #[macro_use]
@@ -28,6 +47,97 @@ extern crate garmin_run_tracker_derive;
 
 static DIRECTORY_NAME: &str = "garmin-run-tracker";
 
+// Heart rate recovery is measured this long after the peak
+const RECOVERY_WINDOW_SECS: i64 = 60;
+// Longest gap between heart rate samples that is bridged to find the rate at the end of the
+// recovery window, e.g. with smart recording or a dropped strap connection
+const RECOVERY_MAX_GAP_SECS: i64 = 30;
+
+// Progress of a FIT file import is logged every time this many messages have been stored
+const IMPORT_PROGRESS_INTERVAL: usize = 10_000;
+
+// Events that are stored, others such as device power or battery events aren't useful to us
+static STORED_EVENTS: &[&str] = &["timer", "lap", "session"];
+
+// Fields stored in a dedicated column for each message type we import, anything else gets
+// stored in the developer_fields table.
+static FILE_ID_FIELDS: &[&str] = &[
+    "type",
+    "manufacturer",
+    "product",
+    "garmin_product",
+    "serial_number",
+    "time_created",
+];
+static LAP_FIELDS: &[&str] = &[
+    "start_position_lat",
+    "start_position_long",
+    "end_position_lat",
+    "end_position_long",
+    "avg_speed",
+    "enhanced_avg_speed",
+    "avg_heart_rate",
+    "total_calories",
+    "total_distance",
+    "total_ascent",
+    "total_descent",
+    "start_time",
+    "timestamp",
+];
+static EVENT_FIELDS: &[&str] = &["event", "event_type", "timestamp"];
+static RECORD_FIELDS: &[&str] = &[
+    "position_lat",
+    "position_long",
+    "speed",
+    "enhanced_speed",
+    "distance",
+    "heart_rate",
+    "timestamp",
+    "vertical_oscillation",
+    "stance_time",
+    "step_length",
+    "altitude",
+    "enhanced_altitude",
+];
+
+// Columns of the tables filled a batch of rows at a time during an import
+static LAP_COLUMNS: &[&str] = &[
+    "start_position_lat",
+    "start_position_long",
+    "end_position_lat",
+    "end_position_long",
+    "average_speed",
+    "average_heart_rate",
+    "total_calories",
+    "total_distance",
+    "total_ascent",
+    "total_descent",
+    "start_time",
+    "timestamp",
+    "file_id",
+];
+static RECORD_COLUMNS: &[&str] = &[
+    "position_lat",
+    "position_long",
+    "speed",
+    "distance",
+    "heart_rate",
+    "timestamp",
+    "vertical_oscillation",
+    "stance_time",
+    "step_length",
+    "device_elevation",
+    "file_id",
+];
+static DEVELOPER_FIELD_COLUMNS: &[&str] = &[
+    "message_index",
+    "message_type",
+    "field_name",
+    "value",
+    "units",
+    "file_id",
+];
+
 /// Contains basic information about a single FIT file, if the file is chained this struct
 /// will get updated to the last file in the chain.
 #[derive(Debug)]
@@ -37,6 +147,7 @@ pub struct FileInfo {
     product: String,
     serial_number: u32,
     timestamp: DateTime<Local>,
+    timezone_offset: Option<i32>,
     uuid: String,
 }
 
@@ -65,10 +176,388 @@ impl FileInfo {
         &self.timestamp
     }
 
+    /// Return offset from UTC in seconds of the timezone the activity was recorded in if the
+    /// file defined one
+    pub fn timezone_offset(&self) -> Option<i32> {
+        self.timezone_offset
+    }
+
+    /// Return the file's timestamp as wall-clock time where the activity took place, falling
+    /// back to the local timezone if the file didn't define an offset
+    pub fn activity_timestamp(&self) -> DateTime<FixedOffset> {
+        match self.timezone_offset.and_then(FixedOffset::east_opt) {
+            Some(offset) => self.timestamp.with_timezone(&offset),
+            None => self.timestamp.fixed_offset(),
+        }
+    }
+
+    /// Return the file's timestamp formatted in either UTC or the activity's timezone
+    pub fn format_timestamp(&self, fmt: &str, utc: bool) -> String {
+        if utc {
+            self.timestamp.with_timezone(&Utc).format(fmt).to_string()
+        } else {
+            self.activity_timestamp().format(fmt).to_string()
+        }
+    }
+
     /// Return UUID generated from this file's byte stream
     pub fn uuid(&self) -> &str {
         &self.uuid
     }
+
+    /// Expand a template like "{year}/{date}-{uuid}.fit" into a path relative to the devices
+    /// directory. {name} is the file's original name, falling back to "<uuid>.fit" without one.
+    pub fn persisted_path(
+        &self,
+        template: &str,
+        file_name: Option<&OsStr>,
+    ) -> Result<PathBuf, Error> {
+        self.expand_path_template("persisted_file_name", template, file_name)
+    }
+
+    /// Expand an export file name template (e.g. "{date}-{uuid}") and add the extension, the
+    /// path is relative to the export directory and uses the same placeholders as
+    /// `persisted_path`
+    pub fn export_path(&self, template: &str, extension: &str) -> Result<PathBuf, Error> {
+        let mut path = self.expand_path_template("export_file_name", template, None)?;
+        path.as_mut_os_string().push(format!(".{}", extension));
+        Ok(path)
+    }
+
+    /// Fill in the placeholders of a path template, the values are sanitized so they can't add
+    /// directories or characters some platforms don't allow in file names
+    fn expand_path_template(
+        &self,
+        setting: &str,
+        template: &str,
+        file_name: Option<&OsStr>,
+    ) -> Result<PathBuf, Error> {
+        let invalid = |msg: String| {
+            Error::InvalidConfigurationValue(format!("{} '{}': {}", setting, template, msg))
+        };
+        let timestamp = self.activity_timestamp();
+        let mut path = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            path.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed '{'".to_string()))?;
+            let value = match &rest[start + 1..start + end] {
+                "date" => timestamp.format("%Y-%m-%d").to_string(),
+                "year" => timestamp.format("%Y").to_string(),
+                "month" => timestamp.format("%m").to_string(),
+                "day" => timestamp.format("%d").to_string(),
+                "time" => timestamp.format("%H%M").to_string(),
+                "uuid" => self.uuid.clone(),
+                "manufacturer" => self.manufacturer.clone(),
+                "product" => self.product.clone(),
+                "serial" => self.serial_number.to_string(),
+                "name" => file_name.map_or_else(
+                    || format!("{}.fit", self.uuid),
+                    |name| name.to_string_lossy().into_owned(),
+                ),
+                other => return Err(invalid(format!("unknown placeholder {{{}}}", other))),
+            };
+            path.push_str(&sanitize_file_name(&value));
+            rest = &rest[start + end + 1..];
+        }
+        path.push_str(rest);
+
+        // keep copies inside the devices directory
+        let path = PathBuf::from(path);
+        if path.as_os_str().is_empty()
+            || path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(invalid(
+                "must expand to a relative path without '..'".to_string(),
+            ));
+        }
+        Ok(path)
+    }
+}
+
+/// Replace characters that aren't allowed in file names on every platform (e.g. ':' on Windows)
+/// or would create directories with '_'
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Unit used to display distances
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceUnit {
+    Mile,
+    Kilometer,
+}
+
+impl DistanceUnit {
+    /// Number of meters in one unit
+    pub fn meters(&self) -> f64 {
+        match self {
+            DistanceUnit::Mile => 1609.344,
+            DistanceUnit::Kilometer => 1000.0,
+        }
+    }
+
+    /// Abbreviated name of the unit
+    pub fn abbreviation(&self) -> &'static str {
+        match self {
+            DistanceUnit::Mile => "mi",
+            DistanceUnit::Kilometer => "km",
+        }
+    }
+}
+
+impl FromStr for DistanceUnit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mi" | "mile" | "miles" => Ok(DistanceUnit::Mile),
+            "km" | "kilometer" | "kilometers" => Ok(DistanceUnit::Kilometer),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: mi, km"
+            ))),
+        }
+    }
+}
+
+/// Unit used to display elevations, they are always stored in meters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElevationUnit {
+    Foot,
+    Meter,
+}
+
+impl ElevationUnit {
+    /// Number of meters in one unit
+    pub fn meters(&self) -> f64 {
+        match self {
+            ElevationUnit::Foot => 0.3048,
+            ElevationUnit::Meter => 1.0,
+        }
+    }
+
+    /// Abbreviated name of the unit
+    pub fn abbreviation(&self) -> &'static str {
+        match self {
+            ElevationUnit::Foot => "ft",
+            ElevationUnit::Meter => "m",
+        }
+    }
+
+    /// Convert a stored elevation in meters into this unit
+    pub fn from_meters(&self, meters: f64) -> f64 {
+        meters / self.meters()
+    }
+}
+
+/// Set of units values are displayed in, chosen by the `units` configuration value
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitSystem {
+    #[default]
+    Imperial,
+    Metric,
+}
+
+impl UnitSystem {
+    pub fn distance_unit(&self) -> DistanceUnit {
+        match self {
+            UnitSystem::Imperial => DistanceUnit::Mile,
+            UnitSystem::Metric => DistanceUnit::Kilometer,
+        }
+    }
+
+    pub fn elevation_unit(&self) -> ElevationUnit {
+        match self {
+            UnitSystem::Imperial => ElevationUnit::Foot,
+            UnitSystem::Metric => ElevationUnit::Meter,
+        }
+    }
+}
+
+/// Which stored elevation to read, the values from the elevation service or the altitude the
+/// device recorded itself (e.g. from a barometric altimeter)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ElevationSource {
+    #[default]
+    Api,
+    Device,
+}
+
+impl ElevationSource {
+    /// Column of record_messages holding elevations from this source
+    pub fn column(&self) -> &'static str {
+        match self {
+            ElevationSource::Api => "elevation",
+            ElevationSource::Device => "device_elevation",
+        }
+    }
+}
+
+impl FromStr for ElevationSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "api" => Ok(ElevationSource::Api),
+            "device" => Ok(ElevationSource::Device),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: api, device"
+            ))),
+        }
+    }
+}
+
+/// Convert a speed in meters per second into the time needed to cover one unit of distance.
+/// Stopped or invalid speeds return None as do paces slower than an hour per unit, which only
+/// show up as noise from GPS drift while standing still.
+pub fn pace_from_speed(m_per_s: f64, units: DistanceUnit) -> Option<Duration> {
+    if !m_per_s.is_finite() || m_per_s <= 0.0 {
+        return None;
+    }
+    let seconds = units.meters() / m_per_s;
+    if seconds > 3600.0 {
+        return None;
+    }
+    Duration::try_milliseconds((seconds * 1000.0).round() as i64)
+}
+
+/// Format the pace for a speed in meters per second as minutes and seconds per unit, rounded to
+/// the nearest second. Speeds without a meaningful pace (missing, stopped, invalid or slower
+/// than an hour per unit) are shown as `--:--`.
+pub fn format_pace(m_per_s: Option<f64>, units: DistanceUnit) -> String {
+    match m_per_s.and_then(|v| pace_from_speed(v, units)) {
+        Some(pace) => {
+            let secs = (pace.num_milliseconds() + 500) / 1000;
+            format!("{}:{:02}", secs / 60, secs % 60)
+        }
+        None => "--:--".to_string(),
+    }
+}
+
+/// Laps shorter than either limit are considered rest, e.g. the pauses and transitions recorded
+/// by multisport or stop-and-go workouts, instead of running laps with a meaningless pace
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestLapThresholds {
+    /// Distance in meters
+    pub min_distance: f64,
+    /// Duration in seconds
+    pub min_time: f64,
+}
+
+impl Default for RestLapThresholds {
+    fn default() -> Self {
+        RestLapThresholds {
+            min_distance: 50.0,
+            min_time: 10.0,
+        }
+    }
+}
+
+impl RestLapThresholds {
+    /// Return true if a lap covering the distance (in meters) over the duration (in seconds) is
+    /// a rest, laps that didn't record a distance are only judged by their duration
+    pub fn is_rest(&self, distance: Option<f64>, duration: f64) -> bool {
+        distance.is_some_and(|d| d < self.min_distance) || duration < self.min_time
+    }
+}
+
+/// Fields without a dedicated column that are kept in the developer_fields table, listed by
+/// message type (e.g. record or lap). Message types that aren't listed keep every field.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct StoredFields(HashMap<String, Vec<String>>);
+
+impl StoredFields {
+    pub fn new(fields: HashMap<String, Vec<String>>) -> Self {
+        StoredFields(fields)
+    }
+
+    /// Return true if the field of the message type should be stored
+    pub fn stores(&self, message_type: &str, field: &str) -> bool {
+        self.0
+            .get(message_type)
+            .is_none_or(|fields| fields.iter().any(|f| f == field))
+    }
+}
+
+/// Heart rate at the peak of a workout and one minute later, the drop between them is a
+/// common measure of cardiovascular fitness
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeartRateRecovery {
+    peak: f64,
+    recovered: f64,
+}
+
+impl HeartRateRecovery {
+    /// Highest heart rate of the workout in bpm
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
+
+    /// Heart rate one minute after the peak in bpm
+    pub fn recovered(&self) -> f64 {
+        self.recovered
+    }
+
+    /// Drop in bpm over the minute after the peak
+    pub fn delta(&self) -> f64 {
+        self.peak - self.recovered
+    }
+}
+
+/// Find the peak heart rate of a series of (timestamp, heart rate) samples ordered by time and
+/// the rate one minute later. Samples without a heart rate are ignored, if the peak is held the
+/// minute starts from the last sample at the peak. The rate at the end of the minute is
+/// interpolated between the samples around it. None is returned when there is no heart rate
+/// data, the series ends less than a minute after the peak or the samples around the end of
+/// the minute are too far apart.
+pub fn heart_rate_recovery(samples: &[(DateTime<Utc>, Option<f64>)]) -> Option<HeartRateRecovery> {
+    let samples: Vec<(DateTime<Utc>, f64)> = samples
+        .iter()
+        .filter_map(|(time, hr)| hr.map(|hr| (*time, hr)))
+        .collect();
+    let (peak_index, &(peak_time, peak)) = samples
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.1.total_cmp(&b.1))?;
+
+    let target = peak_time + Duration::seconds(RECOVERY_WINDOW_SECS);
+    let after = &samples[peak_index..];
+    let next = after.iter().position(|(time, _)| *time >= target)?;
+    let (next_time, next_hr) = after[next];
+    if next_time == target {
+        return Some(HeartRateRecovery {
+            peak,
+            recovered: next_hr,
+        });
+    }
+
+    // the sample at the peak is before the target so there is always a previous sample
+    let (prev_time, prev_hr) = after[next - 1];
+    let span = (next_time - prev_time).num_milliseconds();
+    if span > RECOVERY_MAX_GAP_SECS * 1000 {
+        return None;
+    }
+    let fraction = (target - prev_time).num_milliseconds() as f64 / span as f64;
+    Some(HeartRateRecovery {
+        peak,
+        recovered: prev_hr + (next_hr - prev_hr) * fraction,
+    })
 }
 
 pub fn data_dir() -> PathBuf {
@@ -79,19 +568,70 @@ pub fn devices_dir() -> PathBuf {
     data_dir().join("devices")
 }
 
+/// Directory holding route images returned by drawing services so they aren't requested again
+pub fn route_image_cache_dir() -> PathBuf {
+    data_dir().join("cache").join("route-images")
+}
+
+/// Platform configuration directory of the application, e.g. `$XDG_CONFIG_HOME` on Linux
+pub fn config_dir() -> PathBuf {
+    dirs::config_dir().unwrap_or_default().join(DIRECTORY_NAME)
+}
+
+/// Return the path of the default configuration file. A config.yml in the configuration
+/// directory takes precedence, otherwise the one stored alongside the database in the data
+/// directory is used so existing setups keep working.
+pub fn config_path() -> PathBuf {
+    let path = config_dir().join("config.yml");
+    if path.exists() {
+        path
+    } else {
+        data_dir().join("config.yml")
+    }
+}
+
+/// Load the default configuration file, see `config_path` for where it is looked for
 pub fn load_config() -> Result<Config, Error> {
-    let file = data_dir().join("config.yml");
-    let mut fp = File::open(&file)?;
+    load_config_from(config_path())
+}
+
+/// Load a configuration file from an arbitrary location
+pub fn load_config_from<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+    let mut fp = File::open(path.as_ref())?;
     Config::load(&mut fp).map_err(Error::from)
 }
 
 /// Import raw fit file data into the local database
 pub fn import_fit_data<T: Read>(fp: &mut T, tx: &Transaction) -> Result<FileInfo, Error> {
+    import_fit_activity(fp, tx, None, &StoredFields::default())
+}
+
+/// Import raw fit file data into the local database, optionally keeping only the records, laps
+/// and events of a single activity. Activities are the time ranges covered by the file's session
+/// messages and are numbered from 0 in the order they appear, e.g. for monitoring files that
+/// contain several short activities. Each activity gets its own UUID so they can all be imported.
+/// Fields without a dedicated column are only kept if `stored_fields` lists them.
+///
+/// Messages are stored as they are decoded, so a file that turns out to be corrupt part way
+/// through leaves the messages before the error in the transaction and it should be rolled back.
+pub fn import_fit_activity<T: Read>(
+    fp: &mut T,
+    tx: &Transaction,
+    activity_index: Option<usize>,
+    stored_fields: &StoredFields,
+) -> Result<FileInfo, Error> {
     let mut data = Vec::new();
     fp.read_to_end(&mut data)?;
 
-    // hash the fit file for deduplication purposes
-    let uuid = generate_uuid(&data);
+    // hash the fit file for deduplication purposes, a single activity keeps the hash of the
+    // whole file as its source
+    let (uuid, source_uuid) = match activity_index {
+        Some(index) => (
+            generate_activity_uuid(&data, index),
+            Some(generate_uuid(&data)),
+        ),
+        None => (generate_uuid(&data), None),
+    };
     trace!("UUID hash of file: {}", uuid);
 
     // connect to database and see if the UUID is aleady present before parsing
@@ -99,47 +639,87 @@ pub fn import_fit_data<T: Read>(fp: &mut T, tx: &Transaction) -> Result<FileInfo
         return Err(Error::DuplicateFileError(uuid));
     }
 
-    // parse the fit file
-    let messages = fitparser::from_bytes(&data)?;
-    trace!("Parsed FIT file and found {} messages", messages.len());
+    // the sessions are found before storing anything since they bound the messages kept
+    validate_fit_file(&data)?;
+    let activity = match activity_index {
+        Some(index) => Some(activity_time_range(&data, index)?),
+        None => None,
+    };
+    // the file is dated by the start of the activity instead of when the file was created
+    let activity_start = activity.map(|(start, _)| Value::Timestamp(start));
+    let activity_start = activity_start.as_ref().map(SqlValue::new);
 
-    // loop over messages, the file_id message starts a new FIT file and any records appearing
-    // before it are disregarded.
+    // loop over messages as they are decoded, the file_id message starts a new FIT file and any
+    // records appearing before it are disregarded.
     let mut file_rec_id = None;
     let mut file_info = None;
-    for mesg in messages {
-        let data = create_fit_data_map(&mesg);
+    let mut inserts = ImportInserts::new(stored_fields);
+    let mut messages = FitMessages::new(&data);
+    while let Some(record) = messages.next() {
+        let mesg = &record?;
+        let index = messages.decoded() - 1;
+        if messages.decoded().is_multiple_of(IMPORT_PROGRESS_INTERVAL) {
+            debug!(
+                "Imported {} messages ({:.0}% of the file)",
+                messages.decoded(),
+                100.0 * messages.progress()
+            );
+        }
+        let data = create_fit_data_map(mesg);
+        if let Some(range) = activity {
+            let time_field = match mesg.kind() {
+                MesgNum::Record | MesgNum::Event => Some("timestamp"),
+                MesgNum::Lap => Some("start_time"),
+                _ => None,
+            };
+            if time_field.is_some_and(|field| !within_time_range(&data, field, range)) {
+                trace!("Skipped {} message outside of the activity", mesg.kind());
+                continue;
+            }
+        }
         match mesg.kind() {
             MesgNum::FileId => {
                 // insert new file record into db and set file_rec_id to the row id
                 // this message must exist before any others since there is a NULL constraint
                 // on the file_id column in the lap and record tables
+                let time_created = activity_start.as_ref().or(data.get("time_created"));
                 let mut stmt = tx.prepare_cached(
                     "insert into files (type,
                                         device_manufacturer,
                                         device_product,
                                         device_serial_number,
                                         time_created,
-                                        uuid)
-                     values (?1, ?2, ?3, ?4, ?5, ?6)",
+                                        uuid,
+                                        source_uuid,
+                                        activity_index)
+                     values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
                 )?;
                 stmt.execute(params![
                     data.get("type"),
                     data.get("manufacturer"),
                     data.get("garmin_product"),
                     data.get("serial_number"),
-                    data.get("time_created"),
+                    time_created,
                     uuid,
-                ])?;
-                let timestamp = data
-                    .get("time_created")
-                    .map_or_else(default_timestamp, |v| {
-                        if let Value::Timestamp(v) = v.deref() {
-                            *v
-                        } else {
-                            default_timestamp()
-                        }
-                    });
+                    source_uuid,
+                    activity_index.map(|i| i as i64),
+                ])
+                .map_err(|e| match e {
+                    // the unique index on uuid catches a duplicate that slipped past the check
+                    rusqlite::Error::SqliteFailure(ref err, _)
+                        if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+                    {
+                        Error::DuplicateFileError(uuid.clone())
+                    }
+                    _ => Error::from(e),
+                })?;
+                let timestamp = time_created.map_or_else(default_timestamp, |v| {
+                    if let Value::Timestamp(v) = v.deref() {
+                        *v
+                    } else {
+                        default_timestamp()
+                    }
+                });
                 let serial_number = data
                     .get("serial_number")
                     .map_or(Ok(-1i64), |v| v.deref().clone().try_into())?;
@@ -154,77 +734,345 @@ pub fn import_fit_data<T: Read>(fp: &mut T, tx: &Transaction) -> Result<FileInfo
                         .map_or(String::new(), |v| v.to_string()),
                     serial_number: serial_number as u32,
                     timestamp,
+                    timezone_offset: None,
                     uuid: uuid.clone(),
                 });
+                store_developer_fields(tx, &mut inserts, file_rec_id, index, mesg, FILE_ID_FIELDS)?;
                 trace!("Processed and stored file_id message with data: {:?}", data)
             }
             MesgNum::Lap => {
                 // store lap mesage
-                let mut stmt = tx.prepare_cached(
-                    "insert into lap_messages
-                     (start_position_lat,
-                      start_position_long,
-                      end_position_lat,
-                      end_position_long,
-                      average_speed,
-                      average_heart_rate,
-                      total_calories,
-                      total_distance,
-                      start_time,
-                      timestamp,
-                      file_id)
-                     values (?1, ?2, ?3, ?4, ?5,?6, ?7, ?8, ?9, ?10, ?11)",
+                inserts.laps.push(
+                    tx,
+                    params![
+                        data.get("start_position_lat"),
+                        data.get("start_position_long"),
+                        data.get("end_position_lat"),
+                        data.get("end_position_long"),
+                        data.get("enhanced_avg_speed"),
+                        data.get("avg_heart_rate"),
+                        data.get("total_calories"),
+                        data.get("total_distance"),
+                        data.get("total_ascent"),
+                        data.get("total_descent"),
+                        data.get("start_time"),
+                        data.get("timestamp"),
+                        file_rec_id
+                    ],
                 )?;
-                stmt.execute(params![
-                    data.get("start_position_lat"),
-                    data.get("start_position_long"),
-                    data.get("end_position_lat"),
-                    data.get("end_position_long"),
-                    data.get("enhanced_avg_speed"),
-                    data.get("avg_heart_rate"),
-                    data.get("total_calories"),
-                    data.get("total_distance"),
-                    data.get("start_time"),
-                    data.get("timestamp"),
-                    file_rec_id
-                ])?;
+                store_developer_fields(tx, &mut inserts, file_rec_id, index, mesg, LAP_FIELDS)?;
                 trace!("Processed and stored lap message with data: {:?}", data)
             }
             MesgNum::Record => {
                 // store record mesage
+                inserts.records.push(
+                    tx,
+                    params![
+                        data.get("position_lat"),
+                        data.get("position_long"),
+                        data.get("enhanced_speed"),
+                        data.get("distance"),
+                        data.get("heart_rate"),
+                        data.get("timestamp"),
+                        data.get("vertical_oscillation"),
+                        data.get("stance_time"),
+                        data.get("step_length"),
+                        data.get("enhanced_altitude").or(data.get("altitude")),
+                        file_rec_id
+                    ],
+                )?;
+                store_developer_fields(tx, &mut inserts, file_rec_id, index, mesg, RECORD_FIELDS)?;
+                trace!("Processed and stored record message with data: {:?}", data)
+            }
+            MesgNum::Event => {
+                let event = data.get("event").map(|v| v.to_string());
+                if !event.is_some_and(|e| STORED_EVENTS.contains(&e.as_str())) {
+                    trace!("Skipped {} message with data: {:?}", mesg.kind(), data);
+                    continue;
+                }
+                // event_type is optional in the FIT profile but the event means nothing without it
+                if !data.contains_key("event_type") {
+                    warn!(
+                        "Skipped {} message without an event_type: {:?}",
+                        mesg.kind(),
+                        data
+                    );
+                    continue;
+                }
+                // store timer start/stop, lap and session events
                 let mut stmt = tx.prepare_cached(
-                    "insert into record_messages
-                     (position_lat,
-                      position_long,
-                      speed,
-                      distance,
-                      heart_rate,
-                      timestamp,
-                      file_id)
-                     values (?1, ?2, ?3, ?4, ?5,?6, ?7)",
+                    "insert into events (event, event_type, timestamp, file_id)
+                     values (?1, ?2, ?3, ?4)",
                 )?;
                 stmt.execute(params![
-                    data.get("position_lat"),
-                    data.get("position_long"),
-                    data.get("enhanced_speed"),
-                    data.get("distance"),
-                    data.get("heart_rate"),
+                    data.get("event"),
+                    data.get("event_type"),
                     data.get("timestamp"),
                     file_rec_id
                 ])?;
-                trace!("Processed and stored record message with data: {:?}", data)
+                store_developer_fields(tx, &mut inserts, file_rec_id, index, mesg, EVENT_FIELDS)?;
+                trace!("Processed and stored event message with data: {:?}", data)
+            }
+            MesgNum::Activity | MesgNum::TimestampCorrelation => {
+                // the difference between the local and UTC timestamps gives us the timezone
+                // the activity was recorded in
+                let offset = match (data.get("timestamp"), data.get("local_timestamp")) {
+                    (Some(utc), Some(local)) => match (utc.deref(), local.deref()) {
+                        (Value::Timestamp(utc), Value::Timestamp(local)) => {
+                            timezone_offset(utc, local)
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let (Some(offset), Some(info)) = (offset, file_info.as_mut()) {
+                    let mut stmt =
+                        tx.prepare_cached("update files set timezone_offset = ? where id = ?")?;
+                    stmt.execute(params![offset, file_rec_id])?;
+                    info.timezone_offset = Some(offset);
+                    trace!("Stored timezone offset of {}s for file", offset);
+                } else {
+                    trace!("Skipped {} message with data: {:?}", mesg.kind(), data)
+                }
             }
             _ => trace!("Skipped {} message with data: {:?}", mesg.kind(), data),
         }
     }
+    inserts.flush(tx)?;
+    debug!("Processed {} messages of the FIT file", messages.decoded());
     file_info.ok_or(Error::FileIdMessageNotFound(uuid))
 }
 
+/// Rows of the tables with a row per message, or more, that are inserted in batches
+struct ImportInserts<'a> {
+    laps: BatchInsert,
+    records: BatchInsert,
+    developer_fields: BatchInsert,
+    stored_fields: &'a StoredFields,
+}
+
+impl<'a> ImportInserts<'a> {
+    fn new(stored_fields: &'a StoredFields) -> Self {
+        ImportInserts {
+            laps: BatchInsert::new("lap_messages", LAP_COLUMNS),
+            records: BatchInsert::new("record_messages", RECORD_COLUMNS),
+            developer_fields: BatchInsert::new("developer_fields", DEVELOPER_FIELD_COLUMNS),
+            stored_fields,
+        }
+    }
+
+    /// Insert the rows still queued
+    fn flush(&mut self, tx: &Transaction) -> Result<(), Error> {
+        self.laps.flush(tx)?;
+        self.records.flush(tx)?;
+        self.developer_fields.flush(tx)?;
+        Ok(())
+    }
+}
+
+/// Decode the data messages of a FIT file one at a time, so the messages of a large file never
+/// need to be held in memory all at once
+struct FitMessages<'a> {
+    processor: FitStreamProcessor,
+    data: &'a [u8],
+    remaining: &'a [u8],
+    count: usize,
+}
+
+impl<'a> FitMessages<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        FitMessages {
+            processor: FitStreamProcessor::new(),
+            data,
+            remaining: data,
+            count: 0,
+        }
+    }
+
+    /// Number of data messages decoded so far
+    fn decoded(&self) -> usize {
+        self.count
+    }
+
+    /// Fraction of the file's bytes consumed so far
+    fn progress(&self) -> f64 {
+        1.0 - self.remaining.len() as f64 / self.data.len().max(1) as f64
+    }
+}
+
+impl Iterator for FitMessages<'_> {
+    type Item = Result<FitDataRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.remaining.is_empty() {
+            let (remaining, object) = match self.processor.deserialize_next(self.remaining) {
+                Ok(value) => value,
+                Err(e) => {
+                    // nothing after a parse error can be trusted so the iteration ends here
+                    self.remaining = &[];
+                    return Some(Err(e.into()));
+                }
+            };
+            self.remaining = remaining;
+            match object {
+                // the CRC ends a FIT file and another one may follow it in the same data
+                FitObject::Crc(_) => self.processor.reset(),
+                FitObject::DataMessage(msg) => {
+                    self.count += 1;
+                    return Some(self.processor.decode_message(msg).map_err(Error::from));
+                }
+                FitObject::Header(_) | FitObject::DefinitionMessage(_) => {}
+            }
+        }
+        None
+    }
+}
+
+/// Import a FIT, GPX or TCX file into the local database, the format is picked by the file
+/// extension and anything unrecognized is parsed as FIT data. The activity index only applies
+/// to FIT files and the stored fields only to FIT files since the others have no extra fields.
+pub fn import_activity_file(
+    path: &Path,
+    data: &[u8],
+    tx: &Transaction,
+    activity_index: Option<usize>,
+    stored_fields: &StoredFields,
+) -> Result<FileInfo, Error> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        #[cfg(feature = "gpx")]
+        Some("gpx") => import_gpx_data(data, tx),
+        #[cfg(feature = "tcx")]
+        Some("tcx") => import_tcx_data(data, tx),
+        _ => import_fit_activity(&mut &data[..], tx, activity_index, stored_fields),
+    }
+}
+
+/// Return true if the file has the extension of a track format other than FIT that this build
+/// can import
+pub fn is_track_file(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        #[cfg(feature = "gpx")]
+        Some("gpx") => true,
+        #[cfg(feature = "tcx")]
+        Some("tcx") => true,
+        _ => false,
+    }
+}
+
+/// Find the start and end time of the session message with the given index
+fn activity_time_range(
+    data: &[u8],
+    index: usize,
+) -> Result<(DateTime<Local>, DateTime<Local>), Error> {
+    let mut sessions = Vec::new();
+    for mesg in FitMessages::new(data) {
+        let mesg = mesg?;
+        if mesg.kind() == MesgNum::Session {
+            sessions.push(mesg);
+        }
+    }
+    let session = sessions.get(index).ok_or_else(|| {
+        Error::Other(format!(
+            "Activity index {} is out of range, the file contains {} activities",
+            index,
+            sessions.len()
+        ))
+    })?;
+    let data = create_fit_data_map(session);
+    match (
+        data.get("start_time").map(|v| v.deref()),
+        data.get("timestamp").map(|v| v.deref()),
+    ) {
+        (Some(Value::Timestamp(start)), Some(Value::Timestamp(end))) => Ok((*start, *end)),
+        _ => Err(Error::InvalidFitFile(format!(
+            "Session message for activity {} is missing its start or end time",
+            index
+        ))),
+    }
+}
+
+/// Check if the timestamp stored in the given field falls within the range, messages without
+/// the field are considered outside of it
+fn within_time_range(
+    data: &HashMap<&str, SqlValue>,
+    field: &str,
+    (start, end): (DateTime<Local>, DateTime<Local>),
+) -> bool {
+    match data.get(field).map(|v| v.deref()) {
+        Some(Value::Timestamp(time)) => *time >= start && *time <= end,
+        _ => false,
+    }
+}
+
+/// Store any fields of the message that aren't in the list of mapped fields, this preserves
+/// data from devices and Connect IQ apps that doesn't have a dedicated column. Fields left out
+/// of the stored fields setting are dropped.
+fn store_developer_fields(
+    tx: &Transaction,
+    inserts: &mut ImportInserts,
+    file_id: Option<u32>,
+    index: usize,
+    mesg: &FitDataRecord,
+    mapped_fields: &[&str],
+) -> Result<(), Error> {
+    let message_type = mesg.kind().to_string();
+    for field in mesg.fields() {
+        if mapped_fields.contains(&field.name()) {
+            continue;
+        }
+        if !inserts.stored_fields.stores(&message_type, field.name()) {
+            trace!(
+                "Skipped {} field not in stored_fields: {}",
+                message_type,
+                field.name()
+            );
+            continue;
+        }
+        if let Value::Array(_) = field.value() {
+            trace!("Skipped array valued field: {}", field.name());
+            continue;
+        }
+        inserts.developer_fields.push(
+            tx,
+            params![
+                index as i64,
+                message_type,
+                field.name(),
+                SqlValue::new(field.value()),
+                field.units(),
+                file_id
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
 /// Create a UUID by taking the SHA256 hash of the data and then converting it to UUID4 format
-fn generate_uuid(data: &[u8]) -> String {
+pub(crate) fn generate_uuid(data: &[u8]) -> String {
     // Create a SHA256 hash from the data
     let mut hasher = Sha256::new();
     hasher.update(data);
+    format_uuid(hasher)
+}
+
+/// Create a UUID for a single activity of a file by hashing the activity index along with the
+/// data, this keeps it distinct from the UUID of the whole file and the file's other activities
+fn generate_activity_uuid(data: &[u8], activity_index: usize) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.update(format!("activity:{}", activity_index).as_bytes());
+    format_uuid(hasher)
+}
+
+/// Convert the hash to UUID4 format
+fn format_uuid(hasher: Sha256) -> String {
     let mut result = hasher.finalize();
 
     // set version and variant bits
@@ -242,17 +1090,112 @@ fn generate_uuid(data: &[u8]) -> String {
     uuid
 }
 
+/// Lookup table for the CRC-16 used by FIT files
+static CRC_TABLE: [u16; 16] = [
+    0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800, 0xB401,
+    0x5000, 0x9C01, 0x8801, 0x4400,
+];
+
+/// Compute the CRC-16 defined by the FIT protocol
+fn fit_crc(data: &[u8]) -> u16 {
+    data.iter().fold(0, |mut crc, byte| {
+        let tmp = CRC_TABLE[(crc & 0xF) as usize];
+        crc = (crc >> 4) & 0x0FFF;
+        crc = crc ^ tmp ^ CRC_TABLE[(byte & 0xF) as usize];
+        let tmp = CRC_TABLE[(crc & 0xF) as usize];
+        crc = (crc >> 4) & 0x0FFF;
+        crc ^ tmp ^ CRC_TABLE[((byte >> 4) & 0xF) as usize]
+    })
+}
+
+/// Check the FIT header and CRCs so obviously invalid files are rejected with a clear message
+/// before attempting a full parse. Only the first FIT file is checked in chained files.
+pub fn validate_fit_file(data: &[u8]) -> Result<(), Error> {
+    if data.len() < 12 {
+        return Err(Error::InvalidFitFile(format!(
+            "file is too short to contain a FIT header ({} bytes)",
+            data.len()
+        )));
+    }
+    let header_size = data[0] as usize;
+    if header_size != 12 && header_size != 14 {
+        return Err(Error::InvalidFitFile(format!(
+            "unexpected header size of {} bytes",
+            header_size
+        )));
+    }
+    if &data[8..12] != b".FIT" {
+        return Err(Error::InvalidFitFile(
+            "missing the \".FIT\" header signature".to_string(),
+        ));
+    }
+    if data.len() < header_size {
+        return Err(Error::InvalidFitFile("truncated file header".to_string()));
+    }
+    // a header CRC of zero means it wasn't computed
+    if header_size == 14 {
+        let crc = u16::from_le_bytes([data[12], data[13]]);
+        if crc != 0 && crc != fit_crc(&data[..12]) {
+            return Err(Error::InvalidFitFile(
+                "header CRC does not match".to_string(),
+            ));
+        }
+    }
+
+    let data_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let end = header_size + data_size;
+    if data.len() < end + 2 {
+        return Err(Error::InvalidFitFile(format!(
+            "header declares {} bytes of data but only {} are present",
+            data_size,
+            data.len().saturating_sub(header_size + 2)
+        )));
+    }
+    let crc = u16::from_le_bytes([data[end], data[end + 1]]);
+    if crc != fit_crc(&data[..end]) {
+        return Err(Error::InvalidFitFile("file CRC does not match".to_string()));
+    }
+
+    Ok(())
+}
+
 /// Build a hash map of field references that can be acessed by field name
-fn create_fit_data_map<'a>(mesg: &'a FitDataRecord) -> HashMap<&'a str, SqlValue> {
+fn create_fit_data_map(mesg: &FitDataRecord) -> HashMap<&str, SqlValue<'_>> {
     mesg.fields()
         .iter()
         .map(|f| (f.name(), SqlValue::new(f.value())))
         .collect()
 }
 
+/// Compute the offset in seconds between a UTC timestamp and the device's local timestamp for
+/// the same instant. The FIT local_date_time type gets interpreted in the timezone of the
+/// machine parsing the file, so we compare the raw seconds since the FIT epoch instead of the
+/// parsed values directly.
+fn timezone_offset(utc: &DateTime<Local>, local: &DateTime<Local>) -> Option<i32> {
+    let epoch = NaiveDate::from_ymd_opt(1989, 12, 31)?.and_hms_opt(0, 0, 0)?;
+    let utc_secs = (*utc - Local.from_utc_datetime(&epoch)).num_seconds();
+    let local_secs = (*local - Local.from_local_datetime(&epoch).single()?).num_seconds();
+    // round to the nearest minute and reject anything that isn't a real timezone
+    let offset = ((local_secs - utc_secs) as f64 / 60.0).round() as i32 * 60;
+    FixedOffset::east_opt(offset).map(|_| offset)
+}
+
 fn default_timestamp() -> DateTime<Local> {
     Local
         .timestamp_opt(0, 0)
         .single()
         .expect("Failed to create instance of UNIX timestamp")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_file_name_replaces_reserved_characters() {
+        assert_eq!(
+            sanitize_file_name("Run 12:30 a/b\\c?"),
+            "Run 12_30 a_b_c_".to_string()
+        );
+    }
+}
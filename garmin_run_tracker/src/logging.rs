@@ -0,0 +1,67 @@
+//! Alternate log output formats to the human readable terminal logger
+use crate::Error;
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Format log messages are written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::InvalidConfigurationValue(format!(
+                "Unknown value {s}: expected: text, json"
+            ))),
+        }
+    }
+}
+
+/// Writes each log record to stderr as a single line JSON object so it can be consumed by log
+/// collectors, stdout is left alone for command output.
+#[derive(Debug)]
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    /// Install a JSON logger as the global logger
+    pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_max_level(level);
+        log::set_boxed_logger(Box::new(JsonLogger { level }))
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        // there is nowhere left to report a failure to write a log message
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
@@ -1,8 +1,13 @@
 use garmin_run_tracker::cli::Cli;
-use garmin_run_tracker::{create_database, devices_dir, load_config};
+use garmin_run_tracker::logging::{JsonLogger, LogFormat};
+use garmin_run_tracker::services::{set_http_proxy, set_http_timeout, set_user_agent};
+use garmin_run_tracker::{
+    create_database, devices_dir, load_config, load_config_from, set_busy_timeout, set_wal_journal,
+    start_ephemeral_session, Error,
+};
 use simplelog::{ColorChoice, Config as LoggerConfig, TermLogger, TerminalMode};
 use std::fs::create_dir_all;
-use structopt::StructOpt;
+use std::time::Duration;
 
 fn run() -> Result<(), Box<dyn std::error::Error>> {
     // create data_dir if needed
@@ -10,21 +15,40 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
         create_dir_all(devices_dir())?;
     }
 
-    // create database if needed
-    create_database()?;
+    let opt = Cli::from_args_with_default_import();
 
-    // load config now so that the other initialization tasks can complete. They aren't currently
-    // dependent on the config file but if that changes we will need to reorder stuff.
-    let config = load_config()?;
+    // load config first since the database connections depend on it
+    let config = match opt.config_path() {
+        Some(path) => load_config_from(path)?,
+        None => load_config()?,
+    };
+    set_busy_timeout(Duration::from_millis(config.busy_timeout()));
+    set_wal_journal(config.wal_journal());
+    set_user_agent(config.user_agent().to_string());
+    set_http_timeout(Duration::from_secs(config.http_timeout()));
+    if let Some(proxy) = config.proxy() {
+        set_http_proxy(proxy.clone());
+    }
+
+    // create database if needed, an ephemeral session holds onto its in-memory database until
+    // the subcommand finishes
+    let _session = if opt.ephemeral() {
+        Some(start_ephemeral_session()?)
+    } else {
+        create_database()?;
+        None
+    };
 
-    let opt = Cli::from_args();
     let log_level = opt.verbosity(config.log_level());
-    TermLogger::init(
-        log_level,
-        LoggerConfig::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )?;
+    match opt.log_format() {
+        LogFormat::Text => TermLogger::init(
+            log_level,
+            LoggerConfig::default(),
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        )?,
+        LogFormat::Json => JsonLogger::init(log_level)?,
+    }
 
     // execute any subcommands
     opt.execute_subcommand(config)
@@ -36,7 +60,7 @@ fn main() {
         Ok(_) => 0,
         Err(err) => {
             eprintln!("Error: {}", err);
-            1
+            err.downcast_ref::<Error>().map_or(1, Error::exit_code)
         }
     });
 }
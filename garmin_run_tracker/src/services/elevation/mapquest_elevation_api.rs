@@ -1,13 +1,19 @@
 //! Import elevation data based on lat, long coordintes using the mapquest open elevation API
-use super::ElevationDataSource;
+use super::{record_batch, ElevationDataSource};
 use crate::{
     config::{FromServiceConfig, ServiceConfig},
     gps::{encode_coordinates, Location},
+    services::http_client,
     Error,
 };
-use reqwest::{blocking::Client, StatusCode, Url};
+use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::time::Instant;
+
+// the "cmp" shape format uses the standard 5 digit polyline precision, "cmp6" would use 6
+static SHAPE_FORMAT: &str = "cmp";
+static SHAPE_PRECISION: u32 = 5;
 
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
@@ -82,7 +88,7 @@ impl MapquestElevationApi {
     fn request_url(&self) -> Result<Url, Box<dyn std::error::Error>> {
         Url::parse_with_params(
             &format!("{}/elevation/{}/profile?", self.base_url, self.api_version),
-            &[("key", self.api_key()), ("shapeFormat", "cmp")],
+            &[("key", self.api_key()), ("shapeFormat", SHAPE_FORMAT)],
         )
         .map_err(|e| e.into())
     }
@@ -109,12 +115,17 @@ impl ElevationDataSource for MapquestElevationApi {
         locations: &mut [Location],
     ) -> Result<(), Box<dyn std::error::Error>> {
         // create client and start fetching data in batches
-        let client = Client::new();
-        for chunk in locations.chunks_mut(self.batch_size) {
+        let client = http_client()?;
+        let nbatches = locations.len().div_ceil(self.batch_size);
+        for (i, chunk) in locations.chunks_mut(self.batch_size).enumerate() {
+            let started = Instant::now();
             let request_url = self.request_url()?;
             let resp = client
                 .get(request_url)
-                .query(&[("latLngCollection", &encode_coordinates(chunk)?)])
+                .query(&[(
+                    "latLngCollection",
+                    &encode_coordinates(chunk, SHAPE_PRECISION)?,
+                )])
                 .send()?;
             if resp.status().is_success() {
                 // parse response and update locations, they seem to use 0 as a success response code
@@ -138,6 +149,7 @@ impl ElevationDataSource for MapquestElevationApi {
                 let code = resp.status();
                 return Err(Box::new(Error::RequestError(code, String::new())));
             }
+            record_batch(i + 1, nbatches, chunk.len(), started.elapsed());
         }
 
         Ok(())
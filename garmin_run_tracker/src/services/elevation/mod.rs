@@ -3,13 +3,19 @@ use crate::config::{FromServiceConfig, ServiceConfig};
 use crate::db::QueryStringBuilder;
 use crate::gps::Location;
 use crate::Error;
-use log::{info, warn};
+use chrono::NaiveDate;
+use log::{debug, info, warn};
 use rusqlite::{params, params_from_iter, Transaction};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 mod opentopodata;
 pub use opentopodata::OpenTopoData;
 mod mapquest_elevation_api;
 pub use mapquest_elevation_api::MapquestElevationApi;
+mod static_source;
+pub use static_source::StaticElevationSource;
 
 /// trait that defines how elevation data should be added for an array of lat, long coordintes
 pub trait ElevationDataSource {
@@ -20,12 +26,134 @@ pub trait ElevationDataSource {
     ) -> Result<(), Box<dyn std::error::Error>>;
 }
 
+thread_local! {
+    // number of batches the sources requested on this thread and the time they took
+    static BATCH_TIMINGS: Cell<(usize, Duration)> = const { Cell::new((0, Duration::ZERO)) };
+}
+
+/// Log the time a source took to fetch one batch of locations and add it to the totals of the
+/// update in progress
+fn record_batch(batch: usize, nbatches: usize, points: usize, elapsed: Duration) {
+    debug!(
+        "Elevation batch {}/{}: {} points in {:.2?}",
+        batch, nbatches, points, elapsed
+    );
+    BATCH_TIMINGS.with(|timings| {
+        let (count, total) = timings.get();
+        timings.set((count + 1, total + elapsed));
+    });
+}
+
+/// Time an elevation update spent waiting on the source, the rest of it was spent reading and
+/// writing the database
+#[derive(Debug, Default)]
+struct RequestTimings {
+    /// Batches requested and the time spent on them
+    batches: usize,
+    batch_time: Duration,
+    /// Everything the source did, including batches, client setup and rate limiting delays
+    elapsed: Duration,
+}
+
+impl RequestTimings {
+    /// Request elevation data for the locations and add the time it took to the totals
+    fn request<T: ElevationDataSource + ?Sized>(
+        &mut self,
+        src: &T,
+        locations: &mut [Location],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let (batches, batch_time) = BATCH_TIMINGS.with(Cell::get);
+        src.request_elevation_data(locations)?;
+        let (new_batches, new_batch_time) = BATCH_TIMINGS.with(Cell::get);
+        self.batches += new_batches - batches;
+        self.batch_time += new_batch_time - batch_time;
+        self.elapsed += started.elapsed();
+        Ok(())
+    }
+}
+
+/// Number of record points an elevation update requested and how many of them were given an
+/// elevation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ElevationCoverage {
+    filled: usize,
+    points: usize,
+}
+
+impl ElevationCoverage {
+    /// Points that were given an elevation
+    pub fn filled(&self) -> usize {
+        self.filled
+    }
+
+    /// Points elevation data was requested for
+    pub fn points(&self) -> usize {
+        self.points
+    }
+
+    /// Fraction of the points that were given an elevation, None if there weren't any points
+    pub fn fraction(&self) -> Option<f64> {
+        if self.points == 0 {
+            None
+        } else {
+            Some(self.filled as f64 / self.points as f64)
+        }
+    }
+}
+
+/// Per file coverage of an elevation update, files without any points to update are left out
+#[derive(Debug, Default)]
+pub struct ElevationUpdateReport {
+    files: BTreeMap<u32, ElevationCoverage>,
+}
+
+impl ElevationUpdateReport {
+    /// Coverage of every updated file by file id
+    pub fn files(&self) -> impl Iterator<Item = (u32, &ElevationCoverage)> {
+        self.files.iter().map(|(id, coverage)| (*id, coverage))
+    }
+
+    /// Coverage of a single file, None if none of its points were updated
+    pub fn file(&self, file_id: u32) -> Option<&ElevationCoverage> {
+        self.files.get(&file_id)
+    }
+
+    /// Coverage summed over every file
+    pub fn total(&self) -> ElevationCoverage {
+        self.files
+            .values()
+            .fold(ElevationCoverage::default(), |acc, c| ElevationCoverage {
+                filled: acc.filled + c.filled,
+                points: acc.points + c.points,
+            })
+    }
+
+    /// Add the counts of another update, e.g. when files are updated one at a time
+    pub fn merge(&mut self, other: ElevationUpdateReport) {
+        for (file_id, coverage) in other.files {
+            let entry = self.files.entry(file_id).or_default();
+            entry.filled += coverage.filled;
+            entry.points += coverage.points;
+        }
+    }
+
+    fn add_point(&mut self, file_id: u32, filled: bool) {
+        let entry = self.files.entry(file_id).or_default();
+        entry.points += 1;
+        if filled {
+            entry.filled += 1;
+        }
+    }
+}
+
 pub fn new_elevation_handler(
     config: &ServiceConfig,
 ) -> Result<Box<dyn ElevationDataSource>, Error> {
     match config.handler() {
         "opentopodata" => Ok(Box::new(OpenTopoData::from_config(config)?)),
         "mapquest" => Ok(Box::new(MapquestElevationApi::from_config(config)?)),
+        "static" => Ok(Box::new(StaticElevationSource::from_config(config)?)),
         _ => Err(Error::UnknownServiceHandler(format!(
             "no elevation handler exists for: {}",
             config.handler()
@@ -39,17 +167,9 @@ pub fn update_elevation_data<T: ElevationDataSource + ?Sized>(
     src: &T,
     file_id: Option<u32>,
     overwrite: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<ElevationUpdateReport, Box<dyn std::error::Error>> {
     // setup base queries
-    let mut rec_query =
-        QueryStringBuilder::new("select position_lat, position_long, id from record_messages");
-    rec_query
-        .and_where("position_lat is not null")
-        .and_where("position_long is not null");
-    let mut lap_query = QueryStringBuilder::new("select start_position_lat, start_position_long, end_position_lat, end_position_long, id from lap_messages");
-    lap_query
-        .and_where("start_position_lat is not null")
-        .and_where("start_position_long is not null");
+    let (mut rec_query, mut lap_query) = elevation_queries();
     if file_id.is_none() || !overwrite {
         rec_query.and_where("elevation is null");
         lap_query.and_where("start_elevation is null");
@@ -66,21 +186,103 @@ pub fn update_elevation_data<T: ElevationDataSource + ?Sized>(
     let params: Vec<&dyn rusqlite::ToSql> = file_id
         .as_ref()
         .map_or(Vec::new(), |v| vec![v as &dyn rusqlite::ToSql]);
+    let report = set_elevation_data(tx, src, &rec_query, &lap_query, &params)?;
+
+    // every value of the file now comes from the service so a retry is no longer needed
+    if overwrite && file_id.is_some() {
+        tx.execute(
+            "update files set elevation_pending = 0 where id = ?",
+            params![file_id],
+        )?;
+    }
+
+    Ok(report)
+}
+
+/// Fill in missing elevation data of the files created within the date range, `until` is
+/// exclusive and either end can be left open
+pub fn update_missing_elevation_data<T: ElevationDataSource + ?Sized>(
+    tx: &Transaction,
+    src: &T,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+) -> Result<ElevationUpdateReport, Box<dyn std::error::Error>> {
+    let (mut rec_query, mut lap_query) = elevation_queries();
+    rec_query.and_where("elevation is null");
+    lap_query.and_where("start_elevation is null");
+    let mut params: Vec<&dyn rusqlite::ToSql> = Vec::new();
+    if let Some(since) = since.as_ref() {
+        rec_query.and_where("file_id in (select id from files where time_created >= ?)");
+        lap_query.and_where("file_id in (select id from files where time_created >= ?)");
+        params.push(since);
+    }
+    if let Some(until) = until.as_ref() {
+        rec_query.and_where("file_id in (select id from files where time_created < ?)");
+        lap_query.and_where("file_id in (select id from files where time_created < ?)");
+        params.push(until);
+    }
+    set_elevation_data(tx, src, &rec_query, &lap_query, &params)
+}
+
+/// Queries selecting the positions of record and lap messages, callers narrow them down to the
+/// rows that need elevation data
+fn elevation_queries() -> (QueryStringBuilder<'static>, QueryStringBuilder<'static>) {
+    let mut rec_query = QueryStringBuilder::new(
+        "select position_lat, position_long, id, file_id from record_messages",
+    );
+    rec_query
+        .and_where("position_lat is not null")
+        .and_where("position_long is not null");
+    let mut lap_query = QueryStringBuilder::new("select start_position_lat, start_position_long, end_position_lat, end_position_long, id from lap_messages");
+    lap_query
+        .and_where("start_position_lat is not null")
+        .and_where("start_position_long is not null");
+    (rec_query, lap_query)
+}
+
+/// Fetch and save elevation data for the record and lap messages the queries select
+fn set_elevation_data<T: ElevationDataSource + ?Sized>(
+    tx: &Transaction,
+    src: &T,
+    rec_query: &QueryStringBuilder,
+    lap_query: &QueryStringBuilder,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<ElevationUpdateReport, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut timings = RequestTimings::default();
     let mut stmt = tx.prepare(&rec_query.to_string())?;
-    let (nset, nrows) = stmt
+    let report = stmt
         .query(params_from_iter(params.iter()))
-        .map(|rows| add_record_elevation_data(src, &tx, rows))??; // we have nested results here
+        .map(|rows| add_record_elevation_data(src, tx, rows, &mut timings))??; // we have nested results here
     stmt.finalize()?; // appease borrow checker
-    info!("Set location data for {}/{} record messages", nset, nrows,);
+    let total = report.total();
+    info!(
+        "Set location data for {}/{} record messages",
+        total.filled(),
+        total.points()
+    );
 
     let mut stmt = tx.prepare(&lap_query.to_string())?;
     let (nset, nrows) = stmt
         .query(params_from_iter(params.iter()))
-        .map(|rows| add_lap_elevation_data(src, &tx, rows))??;
+        .map(|rows| add_lap_elevation_data(src, tx, rows, &mut timings))??;
     stmt.finalize()?; // appease borrow checker
     info!("Set location data for {}/{} lap messages", nset, nrows,);
 
-    Ok(())
+    // sources without requests (e.g. static) only show up in the time outside of batches
+    let elapsed = started.elapsed();
+    debug!(
+        "Elevation update took {:.2?}: {} batch(es) in {:.2?} ({:.2?} per batch), {:.2?} of \
+         other source overhead (client setup, rate limiting) and {:.2?} in the database",
+        elapsed,
+        timings.batches,
+        timings.batch_time,
+        timings.batch_time / timings.batches.max(1) as u32,
+        timings.elapsed.saturating_sub(timings.batch_time),
+        elapsed.saturating_sub(timings.elapsed)
+    );
+
+    Ok(report)
 }
 
 /// Updates a set of rows with elevation data by querying the elevation API and then passing that
@@ -89,24 +291,24 @@ fn add_record_elevation_data<T: ElevationDataSource + ?Sized>(
     src: &T,
     tx: &rusqlite::Transaction,
     mut rows: rusqlite::Rows,
-) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    timings: &mut RequestTimings,
+) -> Result<ElevationUpdateReport, Box<dyn std::error::Error>> {
     let mut locations: Vec<Location> = Vec::new();
-    let mut record_ids: Vec<i32> = Vec::new();
+    let mut record_ids: Vec<(i32, u32)> = Vec::new();
     while let Some(row) = rows.next()? {
         locations.push(Location::from_fit_coordinates(row.get(0)?, row.get(1)?));
-        record_ids.push(row.get(2)?);
+        record_ids.push((row.get(2)?, row.get(3)?));
     }
-    src.request_elevation_data(&mut locations)?;
+    timings.request(src, &mut locations)?;
 
+    let mut report = ElevationUpdateReport::default();
     let mut stmt = tx.prepare_cached("update record_messages set elevation = ? where id = ?")?;
-    for (loc, rec_id) in locations.iter().zip(record_ids) {
+    for (loc, (rec_id, file_id)) in locations.iter().zip(record_ids) {
         stmt.execute(params![loc.elevation().map(|v| v as f64), rec_id])?;
+        report.add_point(file_id, loc.elevation().is_some());
     }
 
-    Ok((
-        locations.iter().filter(|l| l.elevation().is_some()).count(),
-        locations.len(),
-    ))
+    Ok(report)
 }
 
 /// Updates a set of rows with elevation data by querying the elevation API and then passing that
@@ -115,6 +317,7 @@ fn add_lap_elevation_data<T: ElevationDataSource + ?Sized>(
     src: &T,
     tx: &rusqlite::Transaction,
     mut rows: rusqlite::Rows,
+    timings: &mut RequestTimings,
 ) -> Result<(usize, usize), Box<dyn std::error::Error>> {
     let mut st_locations: Vec<Location> = Vec::new();
     let mut en_locations: Vec<Location> = Vec::new();
@@ -124,8 +327,8 @@ fn add_lap_elevation_data<T: ElevationDataSource + ?Sized>(
         en_locations.push(Location::from_fit_coordinates(row.get(2)?, row.get(3)?));
         record_ids.push(row.get(4)?);
     }
-    src.request_elevation_data(&mut st_locations)?;
-    src.request_elevation_data(&mut en_locations)?;
+    timings.request(src, &mut st_locations)?;
+    timings.request(src, &mut en_locations)?;
 
     let mut stmt = tx.prepare_cached(
         "update lap_messages set start_elevation = ?, end_elevation = ? where id = ?",
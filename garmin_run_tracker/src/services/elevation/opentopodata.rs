@@ -1,11 +1,11 @@
 //! Import elevation data based on lat, long coordintes using the opentopodata API
-use super::ElevationDataSource;
+use super::{record_batch, ElevationDataSource};
 use crate::{
-    config::{FromServiceConfig, ServiceConfig},
+    config::{FromServiceConfig, Secret, ServiceConfig},
     gps::Location,
+    services::{http_client, with_authorization},
     Error,
 };
-use reqwest::blocking::Client;
 use serde::Deserialize;
 use std::{thread, time};
 
@@ -33,6 +33,10 @@ pub struct OpenTopoData {
     dataset: String,
     batch_size: usize,
     requests_per_sec: f32,
+    /// Value of the Authorization header sent with requests (e.g. "Basic dXNlcjpwYXNz")
+    auth_header: Secret,
+    /// Token sent as a bearer token in the Authorization header
+    auth_token: Secret,
 }
 
 impl OpenTopoData {
@@ -49,6 +53,7 @@ impl OpenTopoData {
             dataset,
             batch_size,
             requests_per_sec,
+            ..Default::default()
         }
     }
 
@@ -65,6 +70,8 @@ impl Default for OpenTopoData {
             dataset: "ned10m".to_string(), // works well for USA/Canada
             batch_size: 100,
             requests_per_sec: -1.0,
+            auth_header: Secret::default(),
+            auth_token: Secret::default(),
         }
     }
 }
@@ -84,17 +91,19 @@ impl ElevationDataSource for OpenTopoData {
         let delay = time::Duration::from_micros(delay);
 
         // create client and start fetching data in batches
-        let client = Client::new();
-        for chunk in locations.chunks_mut(self.batch_size) {
+        let client = http_client()?;
+        let nbatches = locations.len().div_ceil(self.batch_size);
+        for (i, chunk) in locations.chunks_mut(self.batch_size).enumerate() {
+            let started = time::Instant::now();
             let loc_params: String = chunk
                 .iter()
                 .map(|l| format!("{0:.6},{1:.6}", l.latitude(), l.longitude()))
                 .collect::<Vec<String>>()
                 .join("|");
-            let resp = client
+            let request = client
                 .get(&request_url)
-                .query(&[("locations", &loc_params)])
-                .send()?;
+                .query(&[("locations", &loc_params)]);
+            let resp = with_authorization(request, &self.auth_header, &self.auth_token)?.send()?;
             if resp.status().is_success() {
                 // parse response and update locations
                 let json: SuccessResponse = resp.json()?;
@@ -110,6 +119,7 @@ impl ElevationDataSource for OpenTopoData {
                 let json: ErrorResponse = resp.json()?;
                 return Err(Box::new(Error::RequestError(code, json.error)));
             }
+            record_batch(i + 1, nbatches, chunk.len(), started.elapsed());
             thread::sleep(delay);
         }
 
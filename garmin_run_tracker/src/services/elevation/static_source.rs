@@ -0,0 +1,57 @@
+//! Set elevation data without any external service, for testing and offline demos
+use super::ElevationDataSource;
+use crate::{
+    config::{FromServiceConfig, ServiceConfig},
+    gps::Location,
+    Error,
+};
+
+#[derive(Clone, Debug, Default, FromServiceConfig)]
+/// Sets a fixed elevation on every location, optionally sloped by the coordinates so routes
+/// don't come out completely flat
+pub struct StaticElevationSource {
+    /// Elevation in meters at 0° latitude and longitude
+    elevation: f32,
+    /// Meters added per degree of latitude
+    latitude_slope: f32,
+    /// Meters added per degree of longitude
+    longitude_slope: f32,
+}
+
+impl StaticElevationSource {
+    /// Create a data source that sets the same elevation on every location
+    pub fn new(elevation: f32) -> Self {
+        StaticElevationSource {
+            elevation,
+            ..Default::default()
+        }
+    }
+
+    /// Create a data source where the elevation changes linearly with the coordinates
+    pub fn with_slope(elevation: f32, latitude_slope: f32, longitude_slope: f32) -> Self {
+        StaticElevationSource {
+            elevation,
+            latitude_slope,
+            longitude_slope,
+        }
+    }
+
+    /// Return the elevation this source sets for a location
+    pub fn elevation_at(&self, location: &Location) -> f32 {
+        self.elevation
+            + self.latitude_slope * location.latitude()
+            + self.longitude_slope * location.longitude()
+    }
+}
+
+impl ElevationDataSource for StaticElevationSource {
+    fn request_elevation_data(
+        &self,
+        locations: &mut [Location],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for location in locations.iter_mut() {
+            location.set_elevation(Some(self.elevation_at(location)));
+        }
+        Ok(())
+    }
+}
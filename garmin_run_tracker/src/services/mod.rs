@@ -3,7 +3,126 @@
 pub mod elevation;
 pub mod visualization;
 
+use crate::config::{is_secret_parameter, ProxyConfig, Secret};
+use crate::Error;
+use reqwest::blocking::{Client, Request, RequestBuilder};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::Proxy;
+use std::sync::OnceLock;
+use std::time::Duration;
+
 // rexport some traits and utilty functions
-pub use elevation::{new_elevation_handler, update_elevation_data, ElevationDataSource};
+pub use elevation::{
+    new_elevation_handler, update_elevation_data, update_missing_elevation_data, ElevationCoverage,
+    ElevationDataSource, ElevationUpdateReport,
+};
 pub use visualization::plotting::{new_plotting_visualization_handler, DataPlottingService};
 pub use visualization::route::{new_route_visualization_handler, RouteDrawingService};
+
+/// User-Agent identifying this tool, some public servers rate-limit or block generic clients
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "garmin-run-tracker/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/stadelmanma/garmin-run-tracker)"
+);
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+/// Seconds an HTTP request may take before it is abandoned
+pub const DEFAULT_HTTP_TIMEOUT: u64 = 30;
+static HTTP_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static HTTP_PROXY: OnceLock<ProxyConfig> = OnceLock::new();
+
+/// Set the User-Agent sent by every HTTP client, only the first call has any effect
+pub fn set_user_agent(user_agent: String) {
+    let _ = USER_AGENT.set(user_agent);
+}
+
+/// Return the User-Agent sent with outbound requests
+pub fn user_agent() -> &'static str {
+    USER_AGENT.get().map_or(DEFAULT_USER_AGENT, String::as_str)
+}
+
+/// Set how long every HTTP request may take, only the first call has any effect
+pub fn set_http_timeout(timeout: Duration) {
+    let _ = HTTP_TIMEOUT.set(timeout);
+}
+
+/// Return how long outbound requests may take before they are abandoned
+pub fn http_timeout() -> Duration {
+    HTTP_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_secs(DEFAULT_HTTP_TIMEOUT))
+}
+
+/// Send every HTTP request through the proxy instead of the one found in the environment, only
+/// the first call has any effect
+pub fn set_http_proxy(proxy: ProxyConfig) {
+    let _ = HTTP_PROXY.set(proxy);
+}
+
+/// Create an HTTP client that identifies itself with the configured User-Agent and gives up on
+/// requests that take longer than the configured timeout. Requests go through the configured
+/// proxy, or the proxy set by environment variables such as HTTPS_PROXY if there isn't one.
+pub(crate) fn http_client() -> Result<Client, Error> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent())
+        .timeout(http_timeout());
+    if let Some(config) = HTTP_PROXY.get() {
+        let mut proxy = Proxy::all(config.url()).map_err(|e| {
+            Error::InvalidConfigurationValue(format!("Invalid proxy URL {}: {}", config.url(), e))
+        })?;
+        if !config.username().is_empty() {
+            proxy = proxy.basic_auth(config.username(), config.password().expose());
+        }
+        // adding a proxy turns off the environment based proxy detection
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| Error::Other(format!("Could not create HTTP client - {}", e)))
+}
+
+/// Return the URL of a request with the values of query parameters that hold credentials (e.g.
+/// access_token) replaced by "****" so it can be shown to the user
+pub(crate) fn masked_url(request: &Request) -> String {
+    let mut url = request.url().clone();
+    if url.query_pairs().any(|(key, _)| is_secret_parameter(&key)) {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, value)| {
+                let value = if is_secret_parameter(&key) {
+                    "****".to_string()
+                } else {
+                    value.into_owned()
+                };
+                (key.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+/// Add an Authorization header for servers behind an authenticating proxy. A full header value
+/// (e.g. "Basic dXNlcjpwYXNz") takes precedence over a token which is sent as a bearer token.
+/// The header is marked as sensitive so it isn't included in any logging.
+pub(crate) fn with_authorization(
+    request: RequestBuilder,
+    auth_header: &Secret,
+    auth_token: &Secret,
+) -> Result<RequestBuilder, Error> {
+    let value = if !auth_header.is_empty() {
+        auth_header.expose().to_string()
+    } else if !auth_token.is_empty() {
+        format!("Bearer {}", auth_token.expose())
+    } else {
+        return Ok(request);
+    };
+    let mut value = HeaderValue::from_str(&value).map_err(|_| {
+        Error::InvalidConfigurationValue(
+            "auth_header and auth_token may only contain visible ASCII characters".to_string(),
+        )
+    })?;
+    value.set_sensitive(true);
+    Ok(request.header(AUTHORIZATION, value))
+}
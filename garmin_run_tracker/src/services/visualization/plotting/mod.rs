@@ -3,25 +3,51 @@ use ::ratatui::text::Span;
 
 use crate::config::{FromServiceConfig, ServiceConfig};
 use crate::Error;
+use log::warn;
 mod ratatui;
 pub use self::ratatui::TerminalPlotter;
+mod svg;
+pub(crate) use self::svg::escape;
+pub use self::svg::SvgPlotter;
+
+/// Range of widths and heights in pixels accepted for plot images, the SVG margins around the
+/// axes alone take up 90 pixels across and 75 down so smaller plots leave no room for the data
+pub const PLOT_SIZE_RANGE: std::ops::RangeInclusive<u32> = 200..=10000;
 
 /// A vector of (x, y) coordinate pairs and a name
 #[derive(Debug)]
 pub struct DataSeries<'a> {
     name: &'a str,
     data: &'a [(f64, f64)],
+    bars: bool,
 }
 
 impl<'a> DataSeries<'a> {
     pub fn new(name: &'a str, data: &'a [(f64, f64)]) -> Self {
-        DataSeries { name, data }
+        DataSeries {
+            name,
+            data,
+            bars: false,
+        }
+    }
+
+    /// Create a series drawn as a bar for each point instead of a line, e.g. one value per lap
+    pub fn bars(name: &'a str, data: &'a [(f64, f64)]) -> Self {
+        DataSeries {
+            name,
+            data,
+            bars: true,
+        }
     }
 
     pub fn name(&self) -> &'a str {
         self.name
     }
 
+    pub fn is_bars(&self) -> bool {
+        self.bars
+    }
+
     pub fn data(&self) -> &'a [(f64, f64)] {
         self.data
     }
@@ -50,19 +76,47 @@ impl<'a> Iterator for DataSeriesIter<'a> {
 
     // next() is the only required method
     fn next(&mut self) -> Option<Self::Item> {
-        // Check to see if we've finished counting or not.
+        let item = self.data.get(self.idx).copied();
         self.idx += 1;
-        match self.data.get(self.idx) {
-            Some(v) => {
-                //self.idx += 1;
-                Some(*v)
-            }
-            None => {
-                //self.idx = 0; // reset counter so we can loop over this again
-                None
-            }
+        item
+    }
+}
+
+/// A horizontal region highlighted behind the data of a plot, e.g. a training zone
+#[derive(Clone, Debug)]
+pub struct Band {
+    name: String,
+    ymin: f64,
+    ymax: f64,
+    color: (u8, u8, u8),
+}
+
+impl Band {
+    pub fn new(name: String, ymin: f64, ymax: f64, color: (u8, u8, u8)) -> Self {
+        Band {
+            name,
+            ymin,
+            ymax,
+            color,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ymin(&self) -> f64 {
+        self.ymin
+    }
+
+    pub fn ymax(&self) -> f64 {
+        self.ymax
+    }
+
+    /// RGB color of the band
+    pub fn color(&self) -> (u8, u8, u8) {
+        self.color
+    }
 }
 
 /// Defines the labels applied to the plot
@@ -75,7 +129,11 @@ pub struct Plot<'a> {
     pub show_x_zero: bool,
     /// Ensure 0 is shown on plot y axis, default true
     pub show_y_zero: bool,
+    /// Spacing of the x axis tick labels, default 1
+    pub x_tick_step: f64,
     series: Vec<DataSeries<'a>>,
+    bands: Vec<Band>,
+    markers: Vec<f64>,
     _xmax: f64,
     _ymin: f64,
     _ymax: f64,
@@ -85,8 +143,11 @@ impl<'a> Plot<'a> {
     pub fn new(title: String, x_axis: String, y_axis: String) -> Self {
         Plot {
             series: Vec::new(),
+            bands: Vec::new(),
+            markers: Vec::new(),
             show_x_zero: true,
             show_y_zero: true,
+            x_tick_step: 1.0,
             _xmax: 0.0,
             _ymin: 1e99f64,
             _ymax: 0.0,
@@ -127,6 +188,25 @@ impl<'a> Plot<'a> {
         self.series.push(data);
     }
 
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    /// Highlight a range of y values behind the data, bands don't affect the plot bounds
+    pub fn add_band(&mut self, band: Band) {
+        self.bands.push(band);
+    }
+
+    /// x values marked by a vertical line, e.g. lap boundaries
+    pub fn markers(&self) -> &[f64] {
+        &self.markers
+    }
+
+    /// Mark an x value with a vertical line across the plot, markers don't affect the plot bounds
+    pub fn add_marker(&mut self, x: f64) {
+        self.markers.push(x);
+    }
+
     pub fn xmax(&self) -> f64 {
         self._xmax
     }
@@ -144,15 +224,24 @@ impl<'a> Plot<'a> {
         self._ymax + 0.10 * (self._ymax - self._ymin)
     }
 
-    pub fn xticks(&self) -> Vec<Span> {
-        let mut ticks: Vec<Span> = (0..=(self._xmax.floor() as i32))
-            .map(|v| Span::from(v.to_string()))
+    /// Positions of the x axis tick labels, every multiple of the tick step up to xmax
+    pub fn xtick_values(&self) -> Vec<f64> {
+        (0..=((self._xmax / self.x_tick_step).floor() as i32))
+            .map(|v| v as f64 * self.x_tick_step)
+            .collect()
+    }
+
+    pub fn xticks(&self) -> Vec<Span<'_>> {
+        let mut ticks: Vec<Span> = self
+            .xtick_values()
+            .into_iter()
+            .map(|x| Span::from(x.to_string()))
             .collect();
         ticks.push(Span::from(format!("{:0.1}", self._xmax)));
-        return ticks;
+        ticks
     }
 
-    pub fn yticks(&self, nticks: usize) -> Vec<Span> {
+    pub fn yticks(&self, nticks: usize) -> Vec<Span<'_>> {
         (0..=nticks)
             .map(|n| {
                 Span::from(format!(
@@ -164,10 +253,57 @@ impl<'a> Plot<'a> {
     }
 }
 
+/// Wraps a plotting service so the terminal plotter is used if it fails, the user at least sees
+/// their data when an image based service is unavailable.
+pub struct FallbackPlotter {
+    primary: Box<dyn DataPlottingService>,
+    fallback: TerminalPlotter,
+}
+
+impl FallbackPlotter {
+    pub fn new(primary: Box<dyn DataPlottingService>) -> Self {
+        FallbackPlotter {
+            primary,
+            fallback: TerminalPlotter::default(),
+        }
+    }
+}
+
+impl DataPlottingService for FallbackPlotter {
+    fn plot(&self, plots: &[&Plot]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.primary.plot(plots).or_else(|e| {
+            warn!(
+                "Plotting service failed, falling back to the terminal plotter - {}",
+                e
+            );
+            self.fallback.plot(plots)
+        })
+    }
+
+    fn pace_zones(&self) -> Vec<Band> {
+        self.primary.pace_zones()
+    }
+
+    fn set_size(&mut self, width: Option<u32>, height: Option<u32>) -> bool {
+        self.primary.set_size(width, height)
+    }
+}
+
 /// trait that defines how to plot a set of data series
 pub trait DataPlottingService {
     /// Draw a plot of data to display to the user
     fn plot(&self, plots: &[&Plot]) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+    /// Pace zones in min/mile to shade behind pace plots, none are defined by default
+    fn pace_zones(&self) -> Vec<Band> {
+        Vec::new()
+    }
+
+    /// Override the width and/or height in pixels of each plot, returns false if the service
+    /// doesn't draw images (e.g. the terminal plotter)
+    fn set_size(&mut self, _width: Option<u32>, _height: Option<u32>) -> bool {
+        false
+    }
 }
 
 pub fn new_plotting_visualization_handler(
@@ -175,6 +311,11 @@ pub fn new_plotting_visualization_handler(
 ) -> Result<Box<dyn DataPlottingService>, Error> {
     match config.handler() {
         "ratatui" => Ok(Box::new(TerminalPlotter::from_config(config)?)),
+        "svg" => {
+            let plotter = SvgPlotter::from_config(config)?;
+            plotter.validate()?;
+            Ok(Box::new(plotter))
+        }
         _ => Err(Error::UnknownServiceHandler(format!(
             "no plotting visualization handler exists for: {}",
             config.handler()
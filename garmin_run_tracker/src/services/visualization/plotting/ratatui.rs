@@ -1,79 +1,290 @@
 //! Use the ratatui crate to draw plots directly on the terminal
-use super::{DataPlottingService, Plot};
+use super::{Band, DataPlottingService, Plot};
 use crate::config::{FromServiceConfig, ServiceConfig};
 use crate::Error;
+use crossterm::cursor::{Hide, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
     symbols,
     text::Span,
-    widgets::{Axis, Block, Chart, Dataset, GraphType},
-    Terminal,
+    widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph},
+    Frame, Terminal,
 };
 use std::cmp::max;
-use std::io;
+use std::io::{self, IsTerminal};
+
+/// Resolution of the grid of points used to fill in bands
+static BAND_POINTS_X: usize = 200;
+static BAND_POINTS_Y: usize = 100;
 
-/// Defines parameters to interact with the MapBox API
+/// Plots data on the terminal, pace zones are shaded when both paces (in min/mile) are set
 #[derive(Debug, FromServiceConfig)]
-pub struct TerminalPlotter {}
+pub struct TerminalPlotter {
+    /// Paces slower than this are considered easy
+    easy_pace: f64,
+    /// Paces faster than this are considered at or above threshold
+    threshold_pace: f64,
+    /// Color of the plotted data, given by name (e.g. cyan, light-blue), terminal color index or
+    /// #rrggbb value
+    #[service_config(parse)]
+    data_color: Color,
+    /// Color of the axis lines and tick labels
+    #[service_config(parse)]
+    axis_color: Color,
+    /// Color of the axis titles
+    #[service_config(parse)]
+    title_color: Color,
+}
 
 impl Default for TerminalPlotter {
     fn default() -> Self {
-        TerminalPlotter {}
+        TerminalPlotter {
+            easy_pace: 0.0,
+            threshold_pace: 0.0,
+            data_color: Color::Cyan,
+            axis_color: Color::White,
+            title_color: Color::Red,
+        }
+    }
+}
+
+/// Generate a grid of points covering the visible part of a band, ratatui charts have no fill
+/// so this gives every terminal cell in the band a point to draw
+fn band_points(band: &Band, plot: &Plot) -> Vec<(f64, f64)> {
+    let ymin = band.ymin().max(plot.ymin());
+    let ymax = band.ymax().min(plot.ymax());
+    if ymax <= ymin {
+        return Vec::new();
+    }
+    let dx = plot.xmax() / BAND_POINTS_X as f64;
+    let dy = (plot.ymax() - plot.ymin()) / BAND_POINTS_Y as f64;
+    let mut points = Vec::new();
+    let mut y = ymin;
+    while y <= ymax {
+        points.extend((0..=BAND_POINTS_X).map(|i| (i as f64 * dx, y)));
+        y += dy;
+    }
+    points
+}
+
+/// Vertical line spanning the plot at each marker within the x bounds, one per dataset so the
+/// lines aren't joined to each other
+fn marker_lines(plot: &Plot) -> Vec<[(f64, f64); 2]> {
+    plot.markers()
+        .iter()
+        .filter(|&&x| (0.0..=plot.xmax()).contains(&x))
+        .map(|&x| [(x, plot.ymin()), (x, plot.ymax())])
+        .collect()
+}
+
+/// Trace each bar of a series as a vertical line up from the bottom of the plot, ratatui charts
+/// can only draw lines and points
+fn bar_points(data: &[(f64, f64)], plot: &Plot) -> Vec<(f64, f64)> {
+    data.iter()
+        .flat_map(|&(x, y)| [(x, plot.ymin()), (x, y), (x, plot.ymin())])
+        .collect()
+}
+
+impl TerminalPlotter {
+    /// Draw a single plot over the whole frame with a line at the bottom listing the keys used
+    /// to page between the plots
+    fn draw_page(&self, f: &mut Frame, plots: &[&Plot], page: usize) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(f.size());
+        self.draw(f, chunks[0], &plots[page..=page]);
+        let help = if plots.len() > 1 {
+            format!(
+                " Plot {}/{}  left/right or tab: switch plots  q: quit",
+                page + 1,
+                plots.len()
+            )
+        } else {
+            " q: quit".to_string()
+        };
+        f.render_widget(
+            Paragraph::new(help).style(Style::default().fg(Color::DarkGray)),
+            chunks[1],
+        );
+    }
+
+    /// Draw every plot stacked vertically within the area
+    fn draw(&self, f: &mut Frame, area: Rect, plots: &[&Plot]) {
+        let constraints = vec![Constraint::Ratio(1, plots.len() as u32); plots.len()];
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(1)
+            .constraints(constraints)
+            .split(area);
+        let y_nticks = max(2, 7 - plots.len()); // reduce ticks if less vertical space
+
+        for (&chunk, &plot) in chunks.iter().zip(plots) {
+            // bands and markers are drawn first so the data is drawn on top of them
+            let band_data: Vec<Vec<(f64, f64)>> =
+                plot.bands().iter().map(|b| band_points(b, plot)).collect();
+            let mut datasets: Vec<Dataset> = plot
+                .bands()
+                .iter()
+                .zip(&band_data)
+                .map(|(band, points)| {
+                    let (r, g, b) = band.color();
+                    Dataset::default()
+                        .marker(symbols::Marker::Block)
+                        .graph_type(GraphType::Scatter)
+                        .style(Style::default().fg(Color::Rgb(r, g, b)))
+                        .data(points)
+                })
+                .collect();
+            let marker_data = marker_lines(plot);
+            datasets.extend(marker_data.iter().map(|line| {
+                Dataset::default()
+                    .marker(symbols::Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(Color::DarkGray))
+                    .data(line)
+            }));
+            let bar_data: Vec<Vec<(f64, f64)>> = plot
+                .series()
+                .iter()
+                .map(|s| {
+                    if s.is_bars() {
+                        bar_points(s.data(), plot)
+                    } else {
+                        Vec::new()
+                    }
+                })
+                .collect();
+            datasets.extend(plot.series().iter().zip(&bar_data).map(|(s, bars)| {
+                Dataset::default()
+                    //.name(s.name())
+                    .marker(symbols::Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(self.data_color))
+                    .data(if s.is_bars() { bars } else { s.data() })
+            }));
+            let chart = Chart::new(datasets)
+                .block(Block::default().title(plot.title()))
+                .x_axis(
+                    Axis::default()
+                        .title(Span::styled(
+                            plot.x(),
+                            Style::default().fg(self.title_color),
+                        ))
+                        .style(Style::default().fg(self.axis_color))
+                        .bounds([0.0, plot.xmax()])
+                        .labels(plot.xticks()),
+                )
+                .y_axis(
+                    Axis::default()
+                        .title(Span::styled(
+                            plot.y(),
+                            Style::default().fg(self.title_color),
+                        ))
+                        .style(Style::default().fg(self.axis_color))
+                        .bounds([plot.ymin(), plot.ymax()])
+                        .labels(plot.yticks(y_nticks)),
+                );
+            f.render_widget(chart, chunk);
+        }
+    }
+}
+
+/// Switches the terminal to the alternate screen in raw mode and restores it when dropped, so
+/// the user's terminal is left as it was even if drawing fails
+struct AlternateScreen;
+
+impl AlternateScreen {
+    fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        // from here on the guard exists so a failure below still restores the terminal
+        let guard = AlternateScreen;
+        execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for AlternateScreen {
+    fn drop(&mut self) {
+        // there is nothing else we can do if restoring the terminal fails
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+        let _ = disable_raw_mode();
     }
 }
 
 impl DataPlottingService for TerminalPlotter {
     fn plot(&self, plots: &[&Plot]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if plots.is_empty() {
+            return Ok(Vec::new());
+        }
         let stdout = io::stdout();
-        let backend = CrosstermBackend::new(stdout);
-        let mut terminal = Terminal::new(backend)?;
+        // without a terminal to interact with the plots are drawn once and left in the output
+        if !stdout.is_terminal() {
+            let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+            terminal.draw(|f| self.draw(f, f.size(), plots))?;
+            return Ok(Vec::new());
+        }
 
+        let _screen = AlternateScreen::enter()?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
         terminal.clear()?;
-        terminal.draw(|f| {
-            let constraints = vec![Constraint::Ratio(1, plots.len() as u32); plots.len()];
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .margin(1)
-                .constraints(constraints)
-                .split(f.size());
-            let y_nticks = max(2, 7 - plots.len()); // reduce ticks if less vertical space
-
-            for (&chunk, &plot) in chunks.into_iter().zip(plots) {
-                let datasets = plot
-                    .series()
-                    .iter()
-                    .map(|s| {
-                        Dataset::default()
-                            //.name(s.name())
-                            .marker(symbols::Marker::Braille)
-                            .graph_type(GraphType::Line)
-                            .style(Style::default().fg(Color::Cyan))
-                            .data(s.data())
-                    })
-                    .collect();
-                let chart = Chart::new(datasets)
-                    .block(Block::default().title(plot.title()))
-                    .x_axis(
-                        Axis::default()
-                            .title(Span::styled(plot.x(), Style::default().fg(Color::Red)))
-                            .style(Style::default().fg(Color::White))
-                            .bounds([0.0, plot.xmax()])
-                            .labels(plot.xticks()),
-                    )
-                    .y_axis(
-                        Axis::default()
-                            .title(Span::styled(plot.y(), Style::default().fg(Color::Red)))
-                            .style(Style::default().fg(Color::White))
-                            .bounds([plot.ymin(), plot.ymax()])
-                            .labels(plot.yticks(y_nticks)),
-                    );
-                f.render_widget(chart, chunk);
+        // each plot gets the whole screen, the user pages through them until they quit
+        let mut page = 0;
+        loop {
+            terminal.draw(|f| self.draw_page(f, plots, page))?;
+            // resizing falls through to redraw the page at the new size
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Right | KeyCode::Tab | KeyCode::Char('l') => {
+                        page = (page + 1) % plots.len()
+                    }
+                    KeyCode::Left | KeyCode::BackTab | KeyCode::Char('h') => {
+                        page = (page + plots.len() - 1) % plots.len()
+                    }
+                    _ => {}
+                }
             }
-        })?;
+        }
 
         // we plot to the terminal so there isn't anything to return
         Ok(Vec::new())
     }
+
+    fn pace_zones(&self) -> Vec<Band> {
+        if self.threshold_pace <= 0.0 || self.easy_pace <= self.threshold_pace {
+            return Vec::new();
+        }
+        vec![
+            Band::new(
+                "Threshold".to_string(),
+                0.0,
+                self.threshold_pace,
+                (90, 30, 30),
+            ),
+            Band::new(
+                "Moderate".to_string(),
+                self.threshold_pace,
+                self.easy_pace,
+                (90, 80, 20),
+            ),
+            Band::new(
+                "Easy".to_string(),
+                self.easy_pace,
+                f64::INFINITY,
+                (30, 80, 30),
+            ),
+        ]
+    }
 }
@@ -0,0 +1,247 @@
+//! Draw plots as a standalone SVG image that can be embedded in HTML pages
+use super::{DataPlottingService, Plot, PLOT_SIZE_RANGE};
+use crate::config::{FromServiceConfig, ServiceConfig};
+use crate::Error;
+use std::fmt::Write;
+
+/// Colors used for each series of a plot in order
+static SERIES_COLORS: &[&str] = &["steelblue", "firebrick", "seagreen", "darkorange"];
+/// Space reserved around each plot for the axis labels and title
+static MARGIN_LEFT: u32 = 70;
+static MARGIN_RIGHT: u32 = 20;
+static MARGIN_TOP: u32 = 30;
+static MARGIN_BOTTOM: u32 = 45;
+/// Number of intervals on the y axis
+static Y_TICKS: usize = 5;
+
+/// Plots data as an SVG image with every plot stacked vertically, series include a title
+/// element so browsers show their name when hovering over the line.
+#[derive(Debug, FromServiceConfig)]
+pub struct SvgPlotter {
+    /// Width of the image in pixels
+    width: u32,
+    /// Height of each plot in pixels
+    height: u32,
+    /// Pixels per inch used to give the image a physical size when printed
+    dpi: u32,
+}
+
+impl Default for SvgPlotter {
+    fn default() -> Self {
+        SvgPlotter {
+            width: 780,
+            height: 240,
+            dpi: 96,
+        }
+    }
+}
+
+impl SvgPlotter {
+    pub fn new(width: u32, height: u32, dpi: u32) -> Self {
+        SvgPlotter { width, height, dpi }
+    }
+
+    /// Check the configured size is something that can be reasonably drawn
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, value) in [("width", self.width), ("height", self.height)] {
+            if !PLOT_SIZE_RANGE.contains(&value) {
+                return Err(Error::InvalidConfigurationValue(format!(
+                    "svg plot {} must be between {} and {} pixels, got {}",
+                    name,
+                    PLOT_SIZE_RANGE.start(),
+                    PLOT_SIZE_RANGE.end(),
+                    value
+                )));
+            }
+        }
+        if !(36..=2400).contains(&self.dpi) {
+            return Err(Error::InvalidConfigurationValue(format!(
+                "svg plot dpi must be between 36 and 2400, got {}",
+                self.dpi
+            )));
+        }
+        Ok(())
+    }
+
+    /// Draw a single plot with its top edge at the given offset
+    fn draw_plot(&self, svg: &mut String, plot: &Plot, top: u32) {
+        let left = MARGIN_LEFT as f64;
+        let right = (self.width - MARGIN_RIGHT) as f64;
+        let ptop = (top + MARGIN_TOP) as f64;
+        let bottom = (top + self.height - MARGIN_BOTTOM) as f64;
+        let (xmax, ymin, ymax) = (plot.xmax(), plot.ymin(), plot.ymax());
+        let xscale = if xmax > 0.0 {
+            (right - left) / xmax
+        } else {
+            0.0
+        };
+        let yscale = if ymax > ymin {
+            (bottom - ptop) / (ymax - ymin)
+        } else {
+            0.0
+        };
+        let px = |x: f64| left + x * xscale;
+        let py = |y: f64| bottom - (y - ymin) * yscale;
+
+        // writing into a String can't fail so the results are ignored
+        if !plot.title().is_empty() {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{}" y="{}" font-size="16" font-weight="bold">{}</text>"#,
+                left,
+                top + 20,
+                escape(plot.title())
+            );
+        }
+        for band in plot.bands() {
+            let (y0, y1) = (band.ymin().max(ymin), band.ymax().min(ymax));
+            if y1 <= y0 {
+                continue;
+            }
+            let (r, g, b) = band.color();
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="rgb({},{},{})" fill-opacity="0.25"><title>{}</title></rect>"#,
+                left,
+                py(y1),
+                right - left,
+                py(y0) - py(y1),
+                r,
+                g,
+                b,
+                escape(band.name())
+            );
+        }
+
+        for &x in plot.markers() {
+            if !(0.0..=xmax).contains(&x) {
+                continue;
+            }
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{x:.1}" y1="{:.1}" x2="{x:.1}" y2="{:.1}" stroke="gray" stroke-dasharray="4,3"/>"#,
+                ptop,
+                bottom,
+                x = px(x)
+            );
+        }
+
+        // axes with tick labels
+        let _ = writeln!(
+            svg,
+            r#"<path d="M{l},{t} L{l},{b} L{r},{b}" fill="none" stroke="black"/>"#,
+            l = left,
+            t = ptop,
+            b = bottom,
+            r = right
+        );
+        for n in 0..=Y_TICKS {
+            let y = ymin + (ymax - ymin) * n as f64 / Y_TICKS as f64;
+            let _ = writeln!(
+                svg,
+                r##"<line x1="{:.1}" y1="{y:.1}" x2="{:.1}" y2="{y:.1}" stroke="#ddd"/><text x="{:.1}" y="{:.1}" font-size="11" text-anchor="end">{:.1}</text>"##,
+                left,
+                right,
+                left - 6.0,
+                py(y) + 4.0,
+                y,
+                y = py(y)
+            );
+        }
+        for x in plot.xtick_values() {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{:.1}" font-size="11" text-anchor="middle">{}</text>"#,
+                px(x),
+                bottom + 16.0,
+                x
+            );
+        }
+        let _ = writeln!(
+            svg,
+            r#"<text x="{:.1}" y="{:.1}" font-size="12" text-anchor="middle">{}</text>"#,
+            (left + right) / 2.0,
+            bottom + 36.0,
+            escape(plot.x())
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text transform="translate({:.1},{:.1}) rotate(-90)" font-size="12" text-anchor="middle">{}</text>"#,
+            16.0,
+            (ptop + bottom) / 2.0,
+            escape(plot.y())
+        );
+
+        for (series, color) in plot.series().iter().zip(SERIES_COLORS.iter().cycle()) {
+            if series.is_bars() {
+                // bars are centered on their x value and take up most of one x unit
+                let width = 0.6 * xscale;
+                for (x, y) in series {
+                    let _ = writeln!(
+                        svg,
+                        r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}"><title>{}: {:.2}</title></rect>"#,
+                        px(x) - width / 2.0,
+                        py(y),
+                        width,
+                        (bottom - py(y)).max(0.0),
+                        color,
+                        escape(series.name()),
+                        y
+                    );
+                }
+                continue;
+            }
+            let mut points = String::new();
+            for (x, y) in series {
+                let _ = write!(points, "{:.1},{:.1} ", px(x), py(y));
+            }
+            let _ = writeln!(
+                svg,
+                r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"><title>{}</title></polyline>"#,
+                points.trim_end(),
+                color,
+                escape(series.name())
+            );
+        }
+    }
+}
+
+impl DataPlottingService for SvgPlotter {
+    fn plot(&self, plots: &[&Plot]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let height = self.height * plots.len() as u32;
+        // 96 pixels per inch is what browsers assume so the size is left unitless
+        let (width_attr, height_attr) = if self.dpi == 96 {
+            (self.width.to_string(), height.to_string())
+        } else {
+            (
+                format!("{:.2}in", self.width as f64 / self.dpi as f64),
+                format!("{:.2}in", height as f64 / self.dpi as f64),
+            )
+        };
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}" font-family="sans-serif">"#,
+            width_attr, height_attr, self.width, height
+        );
+        for (i, plot) in plots.iter().enumerate() {
+            self.draw_plot(&mut svg, plot, i as u32 * self.height);
+        }
+        svg.push_str("</svg>\n");
+
+        Ok(svg.into_bytes())
+    }
+
+    fn set_size(&mut self, width: Option<u32>, height: Option<u32>) -> bool {
+        self.width = width.unwrap_or(self.width);
+        self.height = height.unwrap_or(self.height);
+        true
+    }
+}
+
+/// Escape text so it can be placed inside an XML element
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
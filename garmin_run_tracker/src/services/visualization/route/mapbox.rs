@@ -1,10 +1,11 @@
 //! Use an instance of open map tiles to draw a course route
 use super::{Marker, RouteDrawingService};
-use crate::config::{FromServiceConfig, ServiceConfig};
+use crate::config::{FromServiceConfig, Secret, ServiceConfig};
 use crate::gps::{encode_coordinates, Location};
+use crate::services::{http_client, masked_url, with_authorization};
 use crate::Error;
-use log::warn;
-use reqwest::blocking::Client;
+use log::{debug, warn};
+use reqwest::blocking::{Client, RequestBuilder};
 
 /// Defines parameters to interact with the MapBox API
 #[derive(Debug, FromServiceConfig)]
@@ -20,16 +21,33 @@ pub struct MapBox {
     stroke_color: String,
     stroke_width: u32,
     stroke_opacity: f32,
-    access_token: String,
+    access_token: Secret,
+    /// Value of the Authorization header sent with requests, for self-hosted servers behind an
+    /// authenticating proxy (e.g. "Basic dXNlcjpwYXNz")
+    auth_header: Secret,
+    /// Token sent as a bearer token in the Authorization header
+    auth_token: Secret,
 }
 
 impl MapBox {
-    fn request_url(&self, encoded_path: String, markers: &[Marker]) -> String {
+    fn request_url(&self, encoded_paths: &[String], markers: &[Marker]) -> String {
         // hacky way to encode the path, we need to drop the leading '=' sign
         // from the call to form_urlencoded which is meant for key=value pairs
-        let encoded_path = form_urlencoded::Serializer::new(String::new())
-            .append_pair("", &encoded_path)
-            .finish();
+        let paths: Vec<String> = encoded_paths
+            .iter()
+            .map(|encoded_path| {
+                let encoded_path = form_urlencoded::Serializer::new(String::new())
+                    .append_pair("", encoded_path)
+                    .finish();
+                format!(
+                    "path-{}+{}-{}({})",
+                    self.stroke_width,
+                    self.stroke_color,
+                    self.stroke_opacity,
+                    &encoded_path[1..]
+                )
+            })
+            .collect();
         let markers = markers.iter().fold(String::new(), |acc, m| {
             acc + &format!(
                 "pin-{}-{}+{}({},{}),",
@@ -44,16 +62,13 @@ impl MapBox {
             .append_pair("", &markers)
             .finish();
         let url = format!(
-            "{}/styles/{}/{}/{}/static/{}path-{}+{}-{}({})/auto/{}x{}",
+            "{}/styles/{}/{}/{}/static/{}{}/auto/{}x{}",
             self.base_url,
             self.api_version,
             self.username,
             self.style,
             &markers[1..],
-            self.stroke_width,
-            self.stroke_color,
-            self.stroke_opacity,
-            &encoded_path[1..],
+            paths.join(","),
             self.image_width,
             self.image_height,
         );
@@ -66,6 +81,30 @@ impl MapBox {
 
         url
     }
+
+    /// Build the request for an image of the traces and markers, each trace is a separate path
+    /// overlay and the access token is sent in the query string
+    fn build_request(
+        &self,
+        client: &Client,
+        traces: &[&[Location]],
+        markers: &[Marker],
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        // MapBox path overlays expect an encoded polyline with 5 digits of precision
+        let encoded_paths = traces
+            .iter()
+            .map(|trace| encode_coordinates(trace, 5))
+            .collect::<Result<Vec<String>, String>>()?;
+        let request_url = self.request_url(&encoded_paths, markers);
+        let request = client
+            .get(&request_url)
+            .query(&[("access_token", self.access_token.expose())]);
+        Ok(with_authorization(
+            request,
+            &self.auth_header,
+            &self.auth_token,
+        )?)
+    }
 }
 
 impl Default for MapBox {
@@ -82,7 +121,9 @@ impl Default for MapBox {
             stroke_color: "f44".to_string(),
             stroke_width: 5,
             stroke_opacity: 0.75,
-            access_token: String::new(),
+            access_token: Secret::default(),
+            auth_header: Secret::default(),
+            auth_token: Secret::default(),
         }
     }
 }
@@ -92,14 +133,20 @@ impl RouteDrawingService for MapBox {
         &self,
         trace: &[Location],
         markers: &[Marker],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.draw_routes(&[trace], markers)
+    }
+
+    fn draw_routes(
+        &self,
+        traces: &[&[Location]],
+        markers: &[Marker],
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         // request image data
-        let client = Client::new();
-        let request_url = self.request_url(encode_coordinates(trace)?, markers);
-        let resp = client
-            .get(&request_url)
-            .query(&[("access_token", &self.access_token)])
-            .send()?;
+        let client = http_client()?;
+        let request = self.build_request(&client, traces, markers)?.build()?;
+        debug!("Requesting route image from {}", masked_url(&request));
+        let resp = client.execute(request)?;
         if resp.status().is_success() {
             // return image data
             match resp.bytes() {
@@ -114,4 +161,14 @@ impl RouteDrawingService for MapBox {
             )))
         }
     }
+
+    fn preview_url(
+        &self,
+        traces: &[&[Location]],
+        markers: &[Marker],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let client = http_client()?;
+        let request = self.build_request(&client, traces, markers)?.build()?;
+        Ok(masked_url(&request))
+    }
 }
@@ -2,6 +2,8 @@
 use crate::config::{FromServiceConfig, ServiceConfig};
 use crate::gps::Location;
 use crate::Error;
+use std::fmt;
+use std::path::Path;
 mod mapbox;
 pub use mapbox::MapBox;
 mod openmaptiles;
@@ -15,6 +17,132 @@ pub trait RouteDrawingService {
         trace: &[Location],
         markers: &[Marker],
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+    /// Draw several traces on the same map, e.g. every run of a route. Services that can only
+    /// draw a single trace return an error when given more than one.
+    fn draw_routes(
+        &self,
+        traces: &[&[Location]],
+        markers: &[Marker],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match traces {
+            [trace] => self.draw_route(trace, markers),
+            _ => Err(Box::new(Error::Other(
+                "Route drawing service can only draw a single trace".to_string(),
+            ))),
+        }
+    }
+
+    /// Return the URL draw_routes would request, with any credentials masked, without sending
+    /// the request
+    fn preview_url(
+        &self,
+        _traces: &[&[Location]],
+        _markers: &[Marker],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        Err(Box::new(Error::Other(
+            "Route drawing service doesn't request images by URL".to_string(),
+        )))
+    }
+
+    /// Request images in a specific format, returns false if the service doesn't allow the
+    /// format to be chosen or can't produce it.
+    fn set_image_format(&mut self, _format: ImageFormat) -> bool {
+        false
+    }
+}
+
+/// Image formats route drawing services commonly return
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    /// Determine the expected format from a file's extension
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "webp" => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// Identify the format of image data using the magic bytes at the start of it
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else {
+            None
+        }
+    }
+
+    /// Read the width and height in pixels from the image header, only PNG and JPEG data is
+    /// supported
+    pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+        match ImageFormat::detect(data)? {
+            // the IHDR chunk is always first and starts with the width and height
+            ImageFormat::Png if data.len() >= 24 => Some((
+                u32::from_be_bytes([data[16], data[17], data[18], data[19]]),
+                u32::from_be_bytes([data[20], data[21], data[22], data[23]]),
+            )),
+            ImageFormat::Jpeg => {
+                // walk the segments until a start of frame marker which holds the dimensions
+                let mut i = 2;
+                while i + 9 < data.len() {
+                    if data[i] != 0xFF {
+                        return None;
+                    }
+                    let marker = data[i + 1];
+                    let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+                    if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+                        let height = u16::from_be_bytes([data[i + 5], data[i + 6]]);
+                        let width = u16::from_be_bytes([data[i + 7], data[i + 8]]);
+                        return Some((width as u32, height as u32));
+                    }
+                    i += 2 + length;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// File extension commonly used for the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    /// MIME type of the format
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFormat::Png => write!(f, "PNG"),
+            ImageFormat::Jpeg => write!(f, "JPEG"),
+            ImageFormat::Webp => write!(f, "WebP"),
+        }
+    }
 }
 
 /// Defines a marker at a specific GPS location that can be used by some route drawers to
@@ -29,6 +157,10 @@ impl Marker {
         Marker { location, label }
     }
 
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
     pub fn latitude(&self) -> f32 {
         self.location.latitude()
     }
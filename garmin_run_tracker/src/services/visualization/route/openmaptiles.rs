@@ -1,9 +1,11 @@
 //! Use an instance of open map tiles to draw a course route
-use super::{Marker, RouteDrawingService};
-use crate::config::{FromServiceConfig, ServiceConfig};
+use super::{ImageFormat, Marker, RouteDrawingService};
+use crate::config::{FromServiceConfig, Secret, ServiceConfig};
 use crate::gps::Location;
+use crate::services::{http_client, masked_url, with_authorization};
 use crate::Error;
-use reqwest::blocking::Client;
+use log::debug;
+use reqwest::blocking::{Client, RequestBuilder};
 
 /// Defines connection parameters to request course rotes from an OpenMapTiles server
 #[derive(Debug, FromServiceConfig)]
@@ -15,6 +17,16 @@ pub struct OpenMapTiles {
     image_format: String,
     stroke_color: String,
     stroke_width: u32,
+    /// Number of decimal places used for coordinates in the route path
+    coordinate_precision: usize,
+    /// Value of the Authorization header sent with requests (e.g. "Basic dXNlcjpwYXNz")
+    auth_header: Secret,
+    /// Token sent as a bearer token in the Authorization header
+    auth_token: Secret,
+    /// Any other configuration parameters, sent as-is in the query string so newer styling
+    /// options (fill, linecap, extra paths, ...) can be used without code changes
+    #[service_config(extra)]
+    extra_parameters: Vec<(String, String)>,
 }
 
 impl OpenMapTiles {
@@ -58,6 +70,19 @@ impl OpenMapTiles {
         self.stroke_width = width;
     }
 
+    /// Add a query parameter sent with every request, repeated keys are sent multiple times
+    pub fn add_extra_parameter(&mut self, key: String, value: String) {
+        self.extra_parameters.push((key, value));
+    }
+
+    pub fn coordinate_precision(&self) -> usize {
+        self.coordinate_precision
+    }
+
+    pub fn set_coordinate_precision(&mut self, precision: usize) {
+        self.coordinate_precision = precision;
+    }
+
     fn request_url(&self, min_lat: f32, max_lat: f32, min_lon: f32, max_lon: f32) -> String {
         // Ex.: http://localhost:8080/styles/osm-bright/static/-80.1465,39.46,-80.1313,39.4842/1800x1200.png
         format!(
@@ -73,6 +98,57 @@ impl OpenMapTiles {
             self.image_format
         )
     }
+
+    /// Build the request for an image of the traces, each one is sent as a separate path and the
+    /// map covers the bounding box of all of them
+    fn build_request(
+        &self,
+        client: &Client,
+        traces: &[&[Location]],
+    ) -> Result<RequestBuilder, Error> {
+        // build path queries while determining the bounding coordintes
+        let mut min_lat = 90.0;
+        let mut max_lat = -90.0;
+        let mut min_lon = 180.0;
+        let mut max_lon = -180.0;
+        let mut paths = Vec::new();
+        for trace in traces {
+            let mut path = String::new();
+            for location in trace.iter() {
+                if location.latitude() < min_lat {
+                    min_lat = location.latitude()
+                } else if location.latitude() > max_lat {
+                    max_lat = location.latitude()
+                }
+
+                if location.longitude() < min_lon {
+                    min_lon = location.longitude()
+                } else if location.longitude() > max_lon {
+                    max_lon = location.longitude()
+                }
+                path += &format!(
+                    "{:.*},{:.*}|",
+                    self.coordinate_precision,
+                    location.longitude(),
+                    self.coordinate_precision,
+                    location.latitude()
+                );
+            }
+            path.truncate(path.len() - 1); // remove trailing pipe
+            paths.push(path);
+        }
+
+        let request_url = self.request_url(min_lat, max_lat, min_lon, max_lon);
+        let mut request = client
+            .get(&request_url)
+            .query(&[("stroke", self.stroke_color())])
+            .query(&[("width", self.stroke_width())]);
+        for path in &paths {
+            request = request.query(&[("path", path)]);
+        }
+        let request = request.query(&self.extra_parameters);
+        with_authorization(request, &self.auth_header, &self.auth_token)
+    }
 }
 
 impl Default for OpenMapTiles {
@@ -85,6 +161,10 @@ impl Default for OpenMapTiles {
             image_format: "png".to_string(), // other formats are available but the list is short,
             stroke_color: "red".to_string(),
             stroke_width: 3,
+            coordinate_precision: 6,
+            auth_header: Secret::default(),
+            auth_token: Secret::default(),
+            extra_parameters: Vec::new(),
         }
     }
 }
@@ -93,39 +173,21 @@ impl RouteDrawingService for OpenMapTiles {
     fn draw_route(
         &self,
         trace: &[Location],
-        _markers: &[Marker],
+        markers: &[Marker],
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // build path query while determining the bounding coordintes
-        let mut min_lat = 90.0;
-        let mut max_lat = -90.0;
-        let mut min_lon = 180.0;
-        let mut max_lon = -180.0;
-        let mut path = String::new();
-        for location in trace {
-            if location.latitude() < min_lat {
-                min_lat = location.latitude()
-            } else if location.latitude() > max_lat {
-                max_lat = location.latitude()
-            }
-
-            if location.longitude() < min_lon {
-                min_lon = location.longitude()
-            } else if location.longitude() > max_lon {
-                max_lon = location.longitude()
-            }
-            path += &format!("{},{}|", location.longitude(), location.latitude());
-        }
-        path.truncate(path.len() - 1); // remove trailing pipe
+        self.draw_routes(&[trace], markers)
+    }
 
+    fn draw_routes(
+        &self,
+        traces: &[&[Location]],
+        _markers: &[Marker],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         // request image data
-        let client = Client::new();
-        let request_url = self.request_url(min_lat, max_lat, min_lon, max_lon);
-        let resp = client
-            .get(&request_url)
-            .query(&[("stroke", self.stroke_color())])
-            .query(&[("width", self.stroke_width())])
-            .query(&[("path", &path)])
-            .send()?;
+        let client = http_client()?;
+        let request = self.build_request(&client, traces)?.build()?;
+        debug!("Requesting route image from {}", masked_url(&request));
+        let resp = client.execute(request)?;
         if resp.status().is_success() {
             // return image data
             match resp.bytes() {
@@ -140,4 +202,19 @@ impl RouteDrawingService for OpenMapTiles {
             )))
         }
     }
+
+    fn preview_url(
+        &self,
+        traces: &[&[Location]],
+        _markers: &[Marker],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let client = http_client()?;
+        let request = self.build_request(&client, traces)?.build()?;
+        Ok(masked_url(&request))
+    }
+
+    fn set_image_format(&mut self, format: ImageFormat) -> bool {
+        self.image_format = format.extension().to_string();
+        true
+    }
 }
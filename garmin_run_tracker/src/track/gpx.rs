@@ -0,0 +1,56 @@
+//! Read the tracks of GPX files, each track segment becomes a lap
+use super::{
+    child_text, parse_child, parse_descendant, parse_time, store_activity, Activity, Lap,
+    TrackPoint,
+};
+use crate::{Error, FileInfo};
+use roxmltree::{Document, Node};
+use rusqlite::Transaction;
+
+/// Import the tracks of a GPX file into the local database, heart rate is read from the Garmin
+/// TrackPointExtension when present
+pub fn import_gpx_data(data: &[u8], tx: &Transaction) -> Result<FileInfo, Error> {
+    let text = std::str::from_utf8(data)
+        .map_err(|e| Error::InvalidTrackFile(format!("GPX file is not valid UTF-8: {}", e)))?;
+    let doc = Document::parse(text)
+        .map_err(|e| Error::InvalidTrackFile(format!("Could not parse GPX file: {}", e)))?;
+    let root = doc.root_element();
+    if !root.has_tag_name("gpx") {
+        return Err(Error::InvalidTrackFile(format!(
+            "Expected a gpx document, found <{}>",
+            root.tag_name().name()
+        )));
+    }
+
+    let laps = root
+        .descendants()
+        .filter(|n| n.has_tag_name("trkseg"))
+        .map(|segment| Lap {
+            points: segment
+                .children()
+                .filter(|n| n.has_tag_name("trkpt"))
+                .map(track_point)
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+    let activity = Activity {
+        format: "gpx",
+        creator: root.attribute("creator").unwrap_or_default().to_string(),
+        laps,
+    };
+    store_activity(data, tx, activity)
+}
+
+fn track_point(node: Node) -> TrackPoint {
+    let lat = node.attribute("lat").and_then(|v| v.parse().ok());
+    let lon = node.attribute("lon").and_then(|v| v.parse().ok());
+    TrackPoint {
+        timestamp: child_text(node, "time").and_then(parse_time),
+        position: lat.zip(lon),
+        elevation: parse_child(node, "ele"),
+        heart_rate: parse_descendant(node, "hr"),
+        distance: None,
+        speed: parse_descendant(node, "speed"),
+    }
+}
@@ -0,0 +1,260 @@
+//! Import activities recorded as GPX or TCX files into the same tables as FIT data
+use crate::db::find_file_by_uuid;
+use crate::gps::Location;
+use crate::{generate_uuid, Error, FileInfo};
+use chrono::{DateTime, Duration, Local, Utc};
+use log::trace;
+use roxmltree::Node;
+use rusqlite::{params, Transaction};
+use std::str::FromStr;
+
+#[cfg(feature = "gpx")]
+mod gpx;
+#[cfg(feature = "gpx")]
+pub use gpx::import_gpx_data;
+#[cfg(feature = "tcx")]
+mod tcx;
+#[cfg(feature = "tcx")]
+pub use tcx::import_tcx_data;
+
+/// A single point of a track, values the file didn't record are None
+#[derive(Clone, Debug, Default)]
+struct TrackPoint {
+    timestamp: Option<DateTime<Utc>>,
+    /// Latitude and longitude in degrees
+    position: Option<(f64, f64)>,
+    elevation: Option<f64>,
+    heart_rate: Option<i64>,
+    distance: Option<f64>,
+    speed: Option<f64>,
+}
+
+/// A lap of the activity, GPX track segments are treated as laps
+#[derive(Debug, Default)]
+struct Lap {
+    points: Vec<TrackPoint>,
+    start_time: Option<DateTime<Utc>>,
+    total_time: Option<f64>,
+    total_distance: Option<f64>,
+    total_calories: Option<i64>,
+    average_heart_rate: Option<i64>,
+}
+
+/// Activity read from a GPX or TCX file
+#[derive(Debug)]
+struct Activity {
+    /// Stored as the manufacturer since there is no device information to go on
+    format: &'static str,
+    /// Application or device that created the file, stored as the product
+    creator: String,
+    laps: Vec<Lap>,
+}
+
+/// Return the text of the first child element with the given name
+fn child_text<'a>(node: Node<'a, 'a>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|n| n.has_tag_name(name))
+        .and_then(|n| n.text())
+        .map(str::trim)
+}
+
+/// Parse the text of the first child element with the given name
+fn parse_child<T: FromStr>(node: Node, name: &str) -> Option<T> {
+    child_text(node, name).and_then(|v| v.parse().ok())
+}
+
+/// Parse the text of the first element below the node with the given name, extensions are
+/// namespaced differently by each application so they are matched by name only
+fn parse_descendant<T: FromStr>(node: Node, name: &str) -> Option<T> {
+    node.descendants()
+        .find(|n| n.has_tag_name(name))
+        .and_then(|n| n.text())
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Convert degrees into the semicircles FIT files use to store coordinates
+fn semicircles(degrees: f64) -> i32 {
+    (degrees * 2147483648.0 / 180.0).round() as i32
+}
+
+/// Keep a product name usable in paths, e.g. for the persisted_file_name template
+fn product_name(creator: &str) -> String {
+    creator
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Fill in the distance and speed of each point from the positions when the file didn't
+/// record any, GPX files usually only have positions and times
+fn fill_distance_and_speed(laps: &mut [Lap]) {
+    let points = || laps.iter().flat_map(|l| l.points.iter());
+    let has_distance = points().any(|p| p.distance.is_some());
+    let has_speed = points().any(|p| p.speed.is_some());
+    let mut distance = 0.0;
+    let mut previous: Option<(DateTime<Utc>, Location, Option<f64>)> = None;
+    for point in laps.iter_mut().flat_map(|l| l.points.iter_mut()) {
+        let (timestamp, position) = match (point.timestamp, point.position) {
+            (Some(t), Some((lat, lon))) => (t, Location::new(lat as f32, lon as f32)),
+            _ => continue,
+        };
+        if !has_distance {
+            if let Some((_, prev_position, _)) = previous {
+                distance += prev_position.distance_to(&position);
+            }
+            point.distance = Some(distance);
+        }
+        if let (false, Some((prev_time, _, Some(prev_distance))), Some(current)) =
+            (has_speed, previous, point.distance)
+        {
+            let seconds = (timestamp - prev_time).num_milliseconds() as f64 / 1000.0;
+            if seconds > 0.0 {
+                point.speed = Some((current - prev_distance) / seconds);
+            }
+        }
+        previous = Some((timestamp, position, point.distance));
+    }
+}
+
+/// Store the activity as a new file along with its records and laps
+fn store_activity(
+    data: &[u8],
+    tx: &Transaction,
+    mut activity: Activity,
+) -> Result<FileInfo, Error> {
+    let uuid = generate_uuid(data);
+    trace!("UUID hash of file: {}", uuid);
+    if find_file_by_uuid(tx, &uuid).is_ok() {
+        return Err(Error::DuplicateFileError(uuid));
+    }
+
+    // records need a timestamp so points without one are dropped
+    for lap in activity.laps.iter_mut() {
+        lap.points.retain(|p| p.timestamp.is_some());
+    }
+    activity.laps.retain(|l| !l.points.is_empty());
+    fill_distance_and_speed(&mut activity.laps);
+    let start = activity
+        .laps
+        .first()
+        .and_then(|l| l.start_time.or(l.points[0].timestamp))
+        .ok_or_else(|| {
+            Error::InvalidTrackFile(format!(
+                "{} file has no track points with a time",
+                activity.format.to_uppercase()
+            ))
+        })?;
+
+    let product = product_name(&activity.creator);
+    tx.execute(
+        "insert into files (type, device_manufacturer, device_product, device_serial_number,
+                            time_created, uuid)
+         values ('activity', ?1, ?2, 0, ?3, ?4)",
+        params![activity.format, product, start.to_rfc3339(), uuid],
+    )?;
+    let file_id = tx.last_insert_rowid() as u32;
+
+    // the file's elevation is the device's own, it also fills in the elevation until the
+    // elevation service replaces it
+    let mut record_stmt = tx.prepare_cached(
+        "insert into record_messages (position_lat, position_long, speed, distance, elevation,
+                                      device_elevation, heart_rate, timestamp, file_id)
+         values (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8)",
+    )?;
+    let mut lap_stmt = tx.prepare_cached(
+        "insert into lap_messages (start_position_lat, start_position_long, start_elevation,
+                                   end_position_lat, end_position_long, end_elevation,
+                                   average_speed, average_heart_rate, total_calories,
+                                   total_distance, start_time, timestamp, file_id)
+         values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+    )?;
+    let mut lap_start_distance = 0.0;
+    for lap in &activity.laps {
+        for point in &lap.points {
+            record_stmt.execute(params![
+                point.position.map(|(lat, _)| semicircles(lat)),
+                point.position.map(|(_, lon)| semicircles(lon)),
+                point.speed,
+                point.distance,
+                point.elevation,
+                point.heart_rate,
+                point.timestamp.map(|t| t.to_rfc3339()),
+                file_id
+            ])?;
+        }
+
+        // lap totals fall back to values derived from its points
+        let first = &lap.points[0];
+        let last = &lap.points[lap.points.len() - 1];
+        let start_time = lap.start_time.or(first.timestamp).unwrap_or(start);
+        let end_time = match lap.total_time {
+            Some(secs) => start_time + Duration::milliseconds((secs * 1000.0).round() as i64),
+            None => last.timestamp.unwrap_or(start_time),
+        };
+        let lap_end_distance = last.distance.unwrap_or(lap_start_distance);
+        let total_distance = lap
+            .total_distance
+            .unwrap_or(lap_end_distance - lap_start_distance);
+        lap_start_distance = lap_end_distance;
+        let seconds = (end_time - start_time).num_milliseconds() as f64 / 1000.0;
+        let average_speed = (seconds > 0.0).then(|| total_distance / seconds);
+        let heart_rates: Vec<i64> = lap.points.iter().filter_map(|p| p.heart_rate).collect();
+        let average_heart_rate = lap.average_heart_rate.or_else(|| {
+            (!heart_rates.is_empty())
+                .then(|| heart_rates.iter().sum::<i64>() / heart_rates.len() as i64)
+        });
+        let start_point = lap.points.iter().find(|p| p.position.is_some());
+        let end_point = lap.points.iter().rev().find(|p| p.position.is_some());
+        lap_stmt.execute(params![
+            start_point
+                .and_then(|p| p.position)
+                .map(|(lat, _)| semicircles(lat)),
+            start_point
+                .and_then(|p| p.position)
+                .map(|(_, lon)| semicircles(lon)),
+            start_point.and_then(|p| p.elevation),
+            end_point
+                .and_then(|p| p.position)
+                .map(|(lat, _)| semicircles(lat)),
+            end_point
+                .and_then(|p| p.position)
+                .map(|(_, lon)| semicircles(lon)),
+            end_point.and_then(|p| p.elevation),
+            average_speed,
+            average_heart_rate,
+            lap.total_calories,
+            total_distance,
+            start_time.to_rfc3339(),
+            end_time.to_rfc3339(),
+            file_id
+        ])?;
+    }
+    trace!(
+        "Stored {} laps from {} file",
+        activity.laps.len(),
+        activity.format
+    );
+
+    Ok(FileInfo {
+        id: Some(file_id),
+        manufacturer: activity.format.to_string(),
+        product,
+        serial_number: 0,
+        timestamp: start.with_timezone(&Local),
+        timezone_offset: None,
+        uuid,
+    })
+}
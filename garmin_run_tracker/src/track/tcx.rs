@@ -0,0 +1,71 @@
+//! Read the laps and trackpoints of TCX activities
+use super::{
+    child_text, parse_child, parse_descendant, parse_time, store_activity, Activity, Lap,
+    TrackPoint,
+};
+use crate::{Error, FileInfo};
+use roxmltree::{Document, Node};
+use rusqlite::Transaction;
+
+/// Import the first activity of a TCX file into the local database
+pub fn import_tcx_data(data: &[u8], tx: &Transaction) -> Result<FileInfo, Error> {
+    let text = std::str::from_utf8(data)
+        .map_err(|e| Error::InvalidTrackFile(format!("TCX file is not valid UTF-8: {}", e)))?;
+    let doc = Document::parse(text)
+        .map_err(|e| Error::InvalidTrackFile(format!("Could not parse TCX file: {}", e)))?;
+    let root = doc.root_element();
+    let activity = root
+        .descendants()
+        .find(|n| n.has_tag_name("Activity"))
+        .ok_or_else(|| Error::InvalidTrackFile("TCX file has no Activity element".to_string()))?;
+
+    let laps = activity
+        .children()
+        .filter(|n| n.has_tag_name("Lap"))
+        .map(|lap| Lap {
+            points: lap
+                .descendants()
+                .filter(|n| n.has_tag_name("Trackpoint"))
+                .map(track_point)
+                .collect(),
+            start_time: lap.attribute("StartTime").and_then(parse_time),
+            total_time: parse_child(lap, "TotalTimeSeconds"),
+            total_distance: parse_child(lap, "DistanceMeters"),
+            total_calories: parse_child(lap, "Calories"),
+            average_heart_rate: value(lap, "AverageHeartRateBpm"),
+        })
+        .collect();
+    let creator = activity
+        .children()
+        .find(|n| n.has_tag_name("Creator"))
+        .and_then(|n| child_text(n, "Name"))
+        .unwrap_or_default();
+    let activity = Activity {
+        format: "tcx",
+        creator: creator.to_string(),
+        laps,
+    };
+    store_activity(data, tx, activity)
+}
+
+/// Heart rates are wrapped in an element with a single Value child
+fn value(node: Node, name: &str) -> Option<i64> {
+    node.children()
+        .find(|n| n.has_tag_name(name))
+        .and_then(|n| parse_child(n, "Value"))
+}
+
+fn track_point(node: Node) -> TrackPoint {
+    let position = node.children().find(|n| n.has_tag_name("Position"));
+    let lat = position.and_then(|n| parse_child(n, "LatitudeDegrees"));
+    let lon = position.and_then(|n| parse_child(n, "LongitudeDegrees"));
+    TrackPoint {
+        timestamp: child_text(node, "Time").and_then(parse_time),
+        position: lat.zip(lon),
+        elevation: parse_child(node, "AltitudeMeters"),
+        heart_rate: value(node, "HeartRateBpm"),
+        distance: parse_child(node, "DistanceMeters"),
+        // the ActivityExtension stores speed as TPX/Speed
+        speed: parse_descendant(node, "Speed"),
+    }
+}
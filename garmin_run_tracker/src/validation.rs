@@ -0,0 +1,156 @@
+//! Sanity checks for imported data, e.g. to catch corrupt files before they skew statistics
+use crate::gps::Location;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+/// Limits above which record data is considered implausible
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationThresholds {
+    /// Heart rate in beats per minute
+    pub max_heart_rate: f64,
+    /// Speed in meters per second, the default is a little faster than a world record sprint
+    pub max_speed: f64,
+    /// Elevation change between consecutive records in meters per second
+    pub max_elevation_rate: f64,
+}
+
+impl Default for ValidationThresholds {
+    fn default() -> Self {
+        ValidationThresholds {
+            max_heart_rate: 230.0,
+            max_speed: 12.5,
+            max_elevation_rate: 100.0,
+        }
+    }
+}
+
+/// Check the records of a file against the thresholds and describe each kind of problem found,
+/// an empty list means the data looks plausible
+pub fn check_records(
+    conn: &Connection,
+    file_id: Option<u32>,
+    thresholds: &ValidationThresholds,
+) -> Result<Vec<String>, Error> {
+    let mut issues = Vec::new();
+    let limits = [
+        ("heart_rate", "heart rate", "bpm", thresholds.max_heart_rate),
+        ("speed", "speed", "m/s", thresholds.max_speed),
+    ];
+    for (column, name, units, limit) in limits {
+        let (count, max): (i64, Option<f64>) = conn.query_row(
+            &format!(
+                "select count(*), max({c}) from record_messages where file_id = ? and {c} > ?",
+                c = column
+            ),
+            params![file_id, limit],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        if count > 0 {
+            issues.push(format!(
+                "{} records with {} above {} {} (max {:.1})",
+                count,
+                name,
+                limit,
+                units,
+                max.unwrap_or(limit)
+            ));
+        }
+    }
+
+    // elevation jumps are measured between consecutive records with elevation data, the
+    // service's elevation isn't filled in yet when a file is checked during its import so the
+    // device's altitude is used without it
+    let mut stmt = conn.prepare(
+        "select timestamp, coalesce(elevation, device_elevation) as elevation
+         from record_messages
+         where file_id = ? and coalesce(elevation, device_elevation) is not null
+         order by timestamp",
+    )?;
+    let elevation = stmt
+        .query_map(params![file_id], |r| {
+            Ok((r.get::<usize, DateTime<Utc>>(0)?, r.get::<usize, f64>(1)?))
+        })?
+        .collect::<Result<Vec<(DateTime<Utc>, f64)>, rusqlite::Error>>()?;
+    let rates: Vec<f64> = elevation
+        .windows(2)
+        .map(|w| {
+            let secs = (w[1].0 - w[0].0).num_milliseconds() as f64 / 1000.0;
+            // records sharing a timestamp are treated as a one second step
+            (w[1].1 - w[0].1).abs() / secs.max(1.0)
+        })
+        .filter(|rate| *rate > thresholds.max_elevation_rate)
+        .collect();
+    if !rates.is_empty() {
+        issues.push(format!(
+            "{} elevation changes faster than {} m/s (max {:.1})",
+            rates.len(),
+            thresholds.max_elevation_rate,
+            rates.iter().cloned().fold(0.0, f64::max)
+        ));
+    }
+
+    Ok(issues)
+}
+
+/// Remove the position of records whose coordinates are clearly wrong, i.e. exactly (0, 0) or a
+/// jump from the surrounding points faster than `max_speed` meters per second. A point is only
+/// treated as a jump when moving to the next point is also too fast, so a single spike is
+/// dropped while a genuine gap in recording (e.g. a paused watch) is kept. The rest of the
+/// record is left untouched. Returns the number of positions removed.
+pub fn filter_gps_jumps(
+    conn: &Connection,
+    file_id: Option<u32>,
+    max_speed: f64,
+) -> Result<usize, Error> {
+    let mut stmt = conn.prepare(
+        "select id, position_lat, position_long, timestamp from record_messages
+         where file_id = ? and position_lat is not null and position_long is not null
+         order by timestamp",
+    )?;
+    let points = stmt
+        .query_map(params![file_id], |r| {
+            Ok((
+                r.get::<usize, i64>(0)?,
+                r.get::<usize, i32>(1)?,
+                r.get::<usize, i32>(2)?,
+                r.get::<usize, DateTime<Utc>>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+    // records sharing a timestamp are treated as a one second step
+    let speed = |a: &(i64, i32, i32, DateTime<Utc>), b: &(i64, i32, i32, DateTime<Utc>)| {
+        let secs = (b.3 - a.3).num_milliseconds() as f64 / 1000.0;
+        Location::from_fit_coordinates(a.1, a.2)
+            .distance_to(&Location::from_fit_coordinates(b.1, b.2))
+            / secs.max(1.0)
+    };
+
+    let mut removed = Vec::new();
+    let mut previous = None;
+    for (idx, point) in points.iter().enumerate() {
+        let null_island = point.1 == 0 && point.2 == 0;
+        let from_previous = previous.is_none_or(|prev| speed(prev, point) > max_speed);
+        // without an accepted point before it a spike right after this point would reject it
+        // too, so look one point further ahead
+        let to_next = points[idx + 1..]
+            .iter()
+            .take(if previous.is_none() { 2 } else { 1 })
+            .all(|next| speed(point, next) > max_speed);
+        if null_island || (from_previous && to_next && points.len() > 1) {
+            removed.push(point.0);
+        } else {
+            previous = Some(point);
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "update record_messages set position_lat = null, position_long = null where id = ?",
+    )?;
+    for id in &removed {
+        stmt.execute(params![id])?;
+    }
+    Ok(removed.len())
+}
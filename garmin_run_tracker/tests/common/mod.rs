@@ -0,0 +1,260 @@
+//! Shared helpers to build synthetic FIT files and in-memory databases for the integration
+//! tests, the unit tests use the copy in src/test_support.rs
+#![allow(dead_code)]
+use garmin_run_tracker::{import_fit_data, open_in_memory_db_connection, FileInfo};
+use rusqlite::Connection;
+
+/// Start time of the synthetic runs, 2021-06-01T12:00:00Z
+pub const START: u32 = 1_622_548_800;
+
+/// Seconds between the UNIX epoch and the FIT epoch (1989-12-31T00:00:00Z)
+pub const FIT_EPOCH_OFFSET: u32 = 631_065_600;
+
+// FIT base type identifiers
+pub const ENUM: u8 = 0x00;
+pub const UINT8: u8 = 0x02;
+pub const UINT16: u8 = 0x84;
+pub const SINT32: u8 = 0x85;
+pub const UINT32: u8 = 0x86;
+pub const UINT32Z: u8 = 0x8C;
+
+// FIT global message numbers
+pub const FILE_ID: u16 = 0;
+pub const SESSION: u16 = 18;
+pub const LAP: u16 = 19;
+pub const RECORD: u16 = 20;
+pub const EVENT: u16 = 21;
+pub const ACTIVITY: u16 = 34;
+
+static CRC_TABLE: [u16; 16] = [
+    0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800, 0xB401,
+    0x5000, 0x9C01, 0x8801, 0x4400,
+];
+
+/// Compute the FIT CRC-16 of a byte slice
+pub fn fit_crc(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        let tmp = CRC_TABLE[(crc & 0xF) as usize];
+        crc = (crc >> 4) & 0x0FFF;
+        crc = crc ^ tmp ^ CRC_TABLE[(byte & 0xF) as usize];
+        let tmp = CRC_TABLE[(crc & 0xF) as usize];
+        crc = (crc >> 4) & 0x0FFF;
+        crc ^ tmp ^ CRC_TABLE[((byte >> 4) & 0xF) as usize]
+    })
+}
+
+/// Convert a UNIX timestamp into a FIT timestamp
+pub fn fit_time(unix: u32) -> u32 {
+    unix - FIT_EPOCH_OFFSET
+}
+
+/// Convert degrees into the semicircle units used by FIT coordinates
+pub fn semicircles(degrees: f64) -> i32 {
+    (degrees * 2147483648.0 / 180.0).round() as i32
+}
+
+/// A single field value to write into a data message, the variant determines the base type
+#[derive(Clone, Copy, Debug)]
+pub enum Field {
+    Enum(u8, u8),
+    UInt8(u8, u8),
+    UInt16(u8, u16),
+    SInt32(u8, i32),
+    UInt32(u8, u32),
+    UInt32z(u8, u32),
+}
+
+impl Field {
+    fn number(&self) -> u8 {
+        match *self {
+            Field::Enum(n, _)
+            | Field::UInt8(n, _)
+            | Field::UInt16(n, _)
+            | Field::SInt32(n, _)
+            | Field::UInt32(n, _)
+            | Field::UInt32z(n, _) => n,
+        }
+    }
+
+    fn base_type(&self) -> u8 {
+        match self {
+            Field::Enum(..) => ENUM,
+            Field::UInt8(..) => UINT8,
+            Field::UInt16(..) => UINT16,
+            Field::SInt32(..) => SINT32,
+            Field::UInt32(..) => UINT32,
+            Field::UInt32z(..) => UINT32Z,
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match *self {
+            Field::Enum(_, v) | Field::UInt8(_, v) => vec![v],
+            Field::UInt16(_, v) => v.to_le_bytes().to_vec(),
+            Field::SInt32(_, v) => v.to_le_bytes().to_vec(),
+            Field::UInt32(_, v) | Field::UInt32z(_, v) => v.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+/// Builds a FIT byte stream one message at a time, every message gets its own definition using
+/// local message type 0 which keeps the builder simple at the cost of a slightly larger file.
+#[derive(Debug, Default)]
+pub struct FitFileBuilder {
+    records: Vec<u8>,
+}
+
+impl FitFileBuilder {
+    pub fn new() -> Self {
+        FitFileBuilder::default()
+    }
+
+    /// Append a definition and data message pair for the global message number
+    pub fn message(mut self, global: u16, fields: &[Field]) -> Self {
+        // definition message: header, reserved, little endian, global number, field count
+        self.records.extend_from_slice(&[0x40, 0, 0]);
+        self.records.extend_from_slice(&global.to_le_bytes());
+        self.records.push(fields.len() as u8);
+        for field in fields {
+            let size = field.bytes().len() as u8;
+            self.records
+                .extend_from_slice(&[field.number(), size, field.base_type()]);
+        }
+        // data message
+        self.records.push(0x00);
+        for field in fields {
+            self.records.extend(field.bytes());
+        }
+        self
+    }
+
+    /// Append a file_id message for an activity file
+    pub fn file_id(self, serial_number: u32, time_created: u32) -> Self {
+        self.message(
+            FILE_ID,
+            &[
+                Field::Enum(0, 4),   // type: activity
+                Field::UInt16(1, 1), // manufacturer: garmin
+                Field::UInt16(2, 1), // product
+                Field::UInt32z(3, serial_number),
+                Field::UInt32(4, fit_time(time_created)),
+            ],
+        )
+    }
+
+    /// Append a record message, distance is in meters and speed in meters per second
+    pub fn record(
+        self,
+        timestamp: u32,
+        position: (f64, f64),
+        distance: f64,
+        speed: f64,
+        heart_rate: u8,
+    ) -> Self {
+        self.message(
+            RECORD,
+            &[
+                Field::UInt32(253, fit_time(timestamp)),
+                Field::SInt32(0, semicircles(position.0)),
+                Field::SInt32(1, semicircles(position.1)),
+                Field::UInt8(3, heart_rate),
+                Field::UInt32(5, (distance * 100.0).round() as u32),
+                Field::UInt16(6, (speed * 1000.0).round() as u16),
+            ],
+        )
+    }
+
+    /// Append a lap message spanning the start and end timestamps
+    pub fn lap(
+        self,
+        start_time: u32,
+        timestamp: u32,
+        total_distance: f64,
+        avg_speed: f64,
+        total_calories: u16,
+    ) -> Self {
+        self.message(
+            LAP,
+            &[
+                Field::UInt32(253, fit_time(timestamp)),
+                Field::UInt32(2, fit_time(start_time)),
+                Field::UInt32(9, (total_distance * 100.0).round() as u32),
+                Field::UInt16(11, total_calories),
+                Field::UInt16(13, (avg_speed * 1000.0).round() as u16),
+            ],
+        )
+    }
+
+    /// Append a session message spanning the start and end timestamps
+    pub fn session(self, start_time: u32, timestamp: u32) -> Self {
+        self.message(
+            SESSION,
+            &[
+                Field::UInt32(253, fit_time(timestamp)),
+                Field::UInt32(2, fit_time(start_time)),
+            ],
+        )
+    }
+
+    /// Append an event message using the raw FIT event and event_type enum values
+    pub fn event(self, timestamp: u32, event: u8, event_type: u8) -> Self {
+        self.message(
+            EVENT,
+            &[
+                Field::UInt32(253, fit_time(timestamp)),
+                Field::Enum(0, event),
+                Field::Enum(1, event_type),
+            ],
+        )
+    }
+
+    /// Append an activity message with the local time offset from UTC in seconds
+    pub fn activity(self, timestamp: u32, utc_offset: i32) -> Self {
+        self.message(
+            ACTIVITY,
+            &[
+                Field::UInt32(253, fit_time(timestamp)),
+                Field::UInt32(5, (fit_time(timestamp) as i64 + utc_offset as i64) as u32),
+            ],
+        )
+    }
+
+    /// Wrap the messages with a FIT header and trailing CRC
+    pub fn build(self) -> Vec<u8> {
+        let mut data = vec![14, 0x10];
+        data.extend_from_slice(&2132u16.to_le_bytes()); // profile version
+        data.extend_from_slice(&(self.records.len() as u32).to_le_bytes());
+        data.extend_from_slice(b".FIT");
+        let header_crc = fit_crc(&data);
+        data.extend_from_slice(&header_crc.to_le_bytes());
+        data.extend(self.records);
+        let crc = fit_crc(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+}
+
+/// Build a short run with three records and a single lap starting at the given time
+pub fn simple_run(start: u32) -> Vec<u8> {
+    FitFileBuilder::new()
+        .file_id(1234, start)
+        .record(start, (39.46, -80.14), 0.0, 3.0, 120)
+        .record(start + 10, (39.4601, -80.14), 30.0, 3.0, 130)
+        .record(start + 20, (39.4602, -80.14), 60.0, 3.0, 140)
+        .lap(start, start + 20, 60.0, 3.0, 12)
+        .activity(start + 20, -4 * 3600)
+        .build()
+}
+
+/// Open an in-memory database with the application schema
+pub fn memory_db() -> Connection {
+    open_in_memory_db_connection().expect("failed to open in-memory database")
+}
+
+/// Import a FIT file into the database in its own transaction
+pub fn import(conn: &mut Connection, data: &[u8]) -> FileInfo {
+    let tx = conn.transaction().expect("failed to start a transaction");
+    let info = import_fit_data(&mut &data[..], &tx).expect("failed to import the FIT file");
+    tx.commit().expect("failed to commit the import");
+    info
+}
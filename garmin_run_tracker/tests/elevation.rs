@@ -0,0 +1,134 @@
+mod common;
+
+use chrono::NaiveDate;
+use common::{import, memory_db, simple_run, START};
+use garmin_run_tracker::gps::Location;
+use garmin_run_tracker::services::elevation::StaticElevationSource;
+use garmin_run_tracker::services::{
+    update_elevation_data, update_missing_elevation_data, ElevationDataSource,
+};
+use rusqlite::{params, Connection};
+
+/// Source that only covers locations south of a latitude, like a DTM with limited coverage
+struct PartialSource {
+    max_latitude: f32,
+}
+
+impl ElevationDataSource for PartialSource {
+    fn request_elevation_data(
+        &self,
+        locations: &mut [Location],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for location in locations.iter_mut() {
+            if location.latitude() < self.max_latitude {
+                location.set_elevation(Some(100.0));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record_elevations(conn: &Connection) -> Vec<Option<f64>> {
+    let mut stmt = conn
+        .prepare("select elevation from record_messages order by timestamp")
+        .unwrap();
+    let elevations = stmt
+        .query_map(params![], |r| r.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    elevations
+}
+
+#[test]
+fn update_elevation_data_sets_record_elevation() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    let info = import(&mut conn, &data);
+    assert_eq!(record_elevations(&conn), vec![None, None, None]);
+
+    let tx = conn.transaction().unwrap();
+    update_elevation_data(&tx, &StaticElevationSource::new(250.0), info.id(), false).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(record_elevations(&conn), vec![Some(250.0); 3]);
+
+    // missing values only are filled without overwrite so nothing changes here
+    let tx = conn.transaction().unwrap();
+    update_elevation_data(&tx, &StaticElevationSource::new(10.0), None, false).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(record_elevations(&conn), vec![Some(250.0); 3]);
+}
+
+#[test]
+fn update_elevation_data_overwrite_clears_pending_flag() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    let info = import(&mut conn, &data);
+    conn.execute("update files set elevation_pending = 1", params![])
+        .unwrap();
+
+    let src = StaticElevationSource::with_slope(0.0, 1000.0, 0.0);
+    let tx = conn.transaction().unwrap();
+    update_elevation_data(&tx, &src, info.id(), true).unwrap();
+    tx.commit().unwrap();
+
+    let expected: Vec<Option<f64>> = [39.46, 39.4601, 39.4602]
+        .iter()
+        .map(|lat| Some(src.elevation_at(&Location::new(*lat, -80.14)) as f64))
+        .collect();
+    let actual = record_elevations(&conn);
+    for (a, e) in actual.iter().zip(&expected) {
+        assert!((a.unwrap() - e.unwrap()).abs() < 0.01, "{:?} != {:?}", a, e);
+    }
+    let pending: bool = conn
+        .query_row("select elevation_pending from files", params![], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert!(!pending);
+}
+
+#[test]
+fn update_missing_elevation_data_respects_date_range() {
+    let mut conn = memory_db();
+    import(&mut conn, &simple_run(START));
+    let src = StaticElevationSource::new(250.0);
+
+    // the run was recorded on 2021-06-01 so it's outside of both ranges
+    let date = |d| NaiveDate::from_ymd_opt(2021, 6, d).unwrap();
+    let tx = conn.transaction().unwrap();
+    update_missing_elevation_data(&tx, &src, Some(date(2)), None).unwrap();
+    update_missing_elevation_data(&tx, &src, None, Some(date(1))).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(record_elevations(&conn), vec![None, None, None]);
+
+    let tx = conn.transaction().unwrap();
+    update_missing_elevation_data(&tx, &src, Some(date(1)), Some(date(2))).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(record_elevations(&conn), vec![Some(250.0); 3]);
+}
+
+#[test]
+fn update_elevation_report_counts_filled_points() {
+    let mut conn = memory_db();
+    let info = import(&mut conn, &simple_run(START));
+
+    // only the first of the three records is covered by the source
+    let src = PartialSource {
+        max_latitude: 39.46005,
+    };
+    let tx = conn.transaction().unwrap();
+    let report = update_elevation_data(&tx, &src, info.id(), false).unwrap();
+    tx.commit().unwrap();
+    let coverage = report.file(info.id().unwrap()).unwrap();
+    assert_eq!((coverage.filled(), coverage.points()), (1, 3));
+    assert!((coverage.fraction().unwrap() - 1.0 / 3.0).abs() < 1e-9);
+    assert_eq!(report.total(), *coverage);
+
+    // only the remaining gaps are requested when fixing missing data
+    let tx = conn.transaction().unwrap();
+    let report = update_missing_elevation_data(&tx, &src, None, None).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(report.total().filled(), 0);
+    assert_eq!(report.total().points(), 2);
+}
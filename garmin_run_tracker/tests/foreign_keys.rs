@@ -0,0 +1,45 @@
+mod common;
+
+use common::{import, memory_db, simple_run, START};
+use rusqlite::{params, Connection};
+
+fn count(conn: &Connection, table: &str) -> i64 {
+    conn.query_row(&format!("select count(*) from {}", table), params![], |r| {
+        r.get(0)
+    })
+    .unwrap()
+}
+
+#[test]
+fn deleting_a_file_removes_its_messages() {
+    let mut conn = memory_db();
+    for start in [START, START + 3600] {
+        let data = simple_run(start);
+        import(&mut conn, &data);
+    }
+    assert_eq!(count(&conn, "record_messages"), 6);
+    assert_eq!(count(&conn, "lap_messages"), 2);
+
+    conn.execute("delete from files where id = 1", params![])
+        .unwrap();
+    assert_eq!(count(&conn, "record_messages"), 3);
+    assert_eq!(count(&conn, "lap_messages"), 1);
+    let orphans: i64 = conn
+        .query_row(
+            "select count(*) from record_messages where file_id = 1",
+            params![],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(orphans, 0);
+}
+
+#[test]
+fn messages_require_an_existing_file() {
+    let conn = memory_db();
+    let result = conn.execute(
+        "insert into record_messages (timestamp, file_id) values ('2021-06-01T12:00:00Z', 42)",
+        params![],
+    );
+    assert!(result.is_err());
+}
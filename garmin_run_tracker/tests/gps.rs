@@ -0,0 +1,147 @@
+mod common;
+
+use common::semicircles;
+use garmin_run_tracker::gps::{
+    downsample, encode_coordinates, estimate_utc_offset, privacy_trim, simplify, Location,
+};
+
+fn loc(latitude: f64, longitude: f64) -> Location {
+    Location::from_fit_coordinates(semicircles(latitude), semicircles(longitude))
+}
+
+#[test]
+fn distance_to_segment_uses_closest_point() {
+    let start = loc(39.46, -80.14);
+    let end = loc(39.47, -80.14);
+    // ~100m east of the middle of the segment
+    let point = loc(39.465, -80.13884);
+    let dist = point.distance_to_segment(&start, &end);
+    assert!((dist - 100.0).abs() < 1.0, "{}", dist);
+    // beyond the end of the segment the distance is to the end point
+    let point = loc(39.48, -80.14);
+    let dist = point.distance_to_segment(&start, &end);
+    assert!((dist - point.distance_to(&end)).abs() < 1.0, "{}", dist);
+}
+
+#[test]
+fn simplify_drops_points_along_a_straight_line() {
+    let trace: Vec<Location> = (0..=10)
+        .map(|i| loc(39.46 + i as f64 * 0.001, -80.14))
+        .collect();
+    let simplified = simplify(&trace, 5.0);
+    assert_eq!(simplified.len(), 2);
+    assert_eq!(simplified[0].latitude(), trace[0].latitude());
+    assert_eq!(simplified[1].latitude(), trace[10].latitude());
+}
+
+#[test]
+fn simplify_keeps_corners() {
+    let trace = vec![
+        loc(39.46, -80.14),
+        loc(39.465, -80.14),
+        loc(39.47, -80.14),
+        loc(39.47, -80.135),
+        loc(39.47, -80.13),
+    ];
+    let simplified = simplify(&trace, 5.0);
+    let lats: Vec<f32> = simplified.iter().map(|l| l.latitude()).collect();
+    assert_eq!(
+        lats,
+        vec![
+            trace[0].latitude(),
+            trace[2].latitude(),
+            trace[4].latitude()
+        ]
+    );
+    assert_eq!(simplified[1].longitude(), trace[2].longitude());
+}
+
+#[test]
+fn downsample_keeps_evenly_spaced_points_and_the_ends() {
+    let trace: Vec<Location> = (0..=10)
+        .map(|i| loc(39.46 + i as f64 * 0.001, -80.14))
+        .collect();
+    assert_eq!(downsample(&trace, 20).len(), 11);
+    let lats: Vec<f32> = downsample(&trace, 3).iter().map(|l| l.latitude()).collect();
+    assert_eq!(
+        lats,
+        vec![
+            trace[0].latitude(),
+            trace[5].latitude(),
+            trace[10].latitude()
+        ]
+    );
+    assert_eq!(downsample(&trace, 4).len(), 4);
+}
+
+#[test]
+fn estimate_utc_offset_uses_nautical_zones() {
+    assert_eq!(estimate_utc_offset(-80.14), -5 * 3600);
+    assert_eq!(estimate_utc_offset(2.35), 0);
+    assert_eq!(estimate_utc_offset(151.2), 10 * 3600);
+    assert_eq!(estimate_utc_offset(-179.9), -12 * 3600);
+}
+
+#[test]
+fn privacy_trim_hides_the_ends_and_keeps_the_middle() {
+    // a loop that starts and finishes at the same spot and passes by it half way, points are
+    // roughly 111m apart
+    let trace: Vec<Location> = [0.0, 0.001, 0.002, 0.003, 0.0005, 0.003, 0.002, 0.001, 0.0]
+        .iter()
+        .map(|offset| loc(39.46 + offset, -80.14))
+        .collect();
+    assert_eq!(privacy_trim(&trace, 50.0), 1..8);
+    // the point passing back through the zone is kept so the trace stays contiguous
+    assert_eq!(privacy_trim(&trace, 150.0), 2..7);
+    assert_eq!(privacy_trim(&trace, 500.0), 0..0);
+    assert_eq!(privacy_trim(&[], 50.0), 0..0);
+}
+
+/// Decode a Google Encoded Polyline back into (latitude, longitude) pairs
+fn decode_polyline(polyline: &str, precision: i32) -> Vec<(f64, f64)> {
+    let factor = 10f64.powi(precision);
+    let mut values = Vec::new();
+    let (mut result, mut shift) = (0i64, 0);
+    for byte in polyline.bytes() {
+        let chunk = (byte - 63) as i64;
+        result |= (chunk & 0x1f) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            values.push(if result & 1 == 1 {
+                !(result >> 1)
+            } else {
+                result >> 1
+            });
+            result = 0;
+            shift = 0;
+        }
+    }
+    let (mut lat, mut lon) = (0, 0);
+    values
+        .chunks(2)
+        .map(|delta| {
+            lat += delta[0];
+            lon += delta[1];
+            (lat as f64 / factor, lon as f64 / factor)
+        })
+        .collect()
+}
+
+#[test]
+fn encode_coordinates_round_trips_at_both_precisions() {
+    let trace = vec![
+        Location::new(39.46012, -80.14046),
+        Location::new(1.234567, -2.345678),
+        Location::new(-33.85678, 151.2153),
+    ];
+    for (precision, tolerance) in [(5, 1e-5), (6, 1e-6)] {
+        let encoded = encode_coordinates(&trace, precision as u32).unwrap();
+        let decoded = decode_polyline(&encoded, precision);
+        assert_eq!(decoded.len(), trace.len());
+        for (loc, (lat, lon)) in trace.iter().zip(decoded) {
+            assert!((loc.latitude() as f64 - lat).abs() <= tolerance);
+            assert!((loc.longitude() as f64 - lon).abs() <= tolerance);
+        }
+    }
+    assert!(encode_coordinates(&trace, 7).is_err());
+}
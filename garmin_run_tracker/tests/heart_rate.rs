@@ -0,0 +1,49 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use garmin_run_tracker::heart_rate_recovery;
+
+/// Samples starting at a fixed time from (seconds offset, heart rate) pairs
+fn series(samples: &[(i64, Option<f64>)]) -> Vec<(DateTime<Utc>, Option<f64>)> {
+    let start = Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap();
+    samples
+        .iter()
+        .map(|(secs, hr)| (start + Duration::seconds(*secs), *hr))
+        .collect()
+}
+
+#[test]
+fn heart_rate_recovery_measures_the_minute_after_the_last_peak() {
+    // the peak is held until 20s so the minute runs until 80s
+    let samples = series(&[
+        (0, Some(150.0)),
+        (10, Some(180.0)),
+        (20, Some(180.0)),
+        (50, None),
+        (70, Some(160.0)),
+        (80, Some(150.0)),
+        (90, Some(140.0)),
+    ]);
+    let recovery = heart_rate_recovery(&samples).unwrap();
+    assert_eq!(recovery.peak(), 180.0);
+    assert_eq!(recovery.recovered(), 150.0);
+    assert_eq!(recovery.delta(), 30.0);
+}
+
+#[test]
+fn heart_rate_recovery_interpolates_between_samples() {
+    let samples = series(&[(0, Some(170.0)), (50, Some(150.0)), (70, Some(130.0))]);
+    let recovery = heart_rate_recovery(&samples).unwrap();
+    assert!((recovery.recovered() - 140.0).abs() < 1e-9);
+    assert!((recovery.delta() - 30.0).abs() < 1e-9);
+}
+
+#[test]
+fn heart_rate_recovery_needs_a_full_minute_of_nearby_samples() {
+    // the series ends before the minute is up
+    assert!(heart_rate_recovery(&series(&[(0, Some(170.0)), (59, Some(150.0))])).is_none());
+    // the heart rate dropped out around the end of the minute
+    let samples = series(&[(0, Some(170.0)), (40, Some(150.0)), (75, Some(130.0))]);
+    assert!(heart_rate_recovery(&samples).is_none());
+    // no heart rate at all
+    assert!(heart_rate_recovery(&series(&[(0, None), (60, None)])).is_none());
+    assert!(heart_rate_recovery(&[]).is_none());
+}
@@ -0,0 +1,495 @@
+mod common;
+
+use common::{
+    fit_time, import, memory_db, simple_run, Field, FitFileBuilder, EVENT, RECORD, START,
+};
+use garmin_run_tracker::validation::{check_records, filter_gps_jumps, ValidationThresholds};
+use garmin_run_tracker::{import_fit_activity, import_fit_data, Error, StoredFields};
+use rusqlite::params;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+
+#[test]
+fn import_stores_file_lap_and_record_messages() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    let info = import(&mut conn, &data);
+
+    assert_eq!(info.id(), Some(1));
+    assert_eq!(info.manufacturer(), "garmin");
+    assert_eq!(info.serial_number(), 1234);
+    assert_eq!(info.timestamp().timestamp(), START as i64);
+
+    let (nfiles, uuid): (i64, String) = conn
+        .query_row("select count(*), uuid from files", params![], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })
+        .unwrap();
+    assert_eq!(nfiles, 1);
+    assert_eq!(uuid, info.uuid());
+
+    let mut stmt = conn
+        .prepare(
+            "select distance, speed, heart_rate, file_id from record_messages order by timestamp",
+        )
+        .unwrap();
+    let records: Vec<(f64, f64, i64, i64)> = stmt
+        .query_map(params![], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        records,
+        vec![(0.0, 3.0, 120, 1), (30.0, 3.0, 130, 1), (60.0, 3.0, 140, 1)]
+    );
+
+    let (distance, speed, calories): (f64, f64, i64) = conn
+        .query_row(
+            "select total_distance, average_speed, total_calories from lap_messages",
+            params![],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!((distance, speed, calories), (60.0, 3.0, 12));
+}
+
+#[test]
+fn import_rejects_duplicate_files() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    import(&mut conn, &data);
+
+    let tx = conn.transaction().unwrap();
+    match import_fit_data(&mut data.as_slice(), &tx) {
+        Err(Error::DuplicateFileError(_)) => {}
+        other => panic!("expected a duplicate file error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn import_requires_file_id_message() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new().activity(START, 0).build();
+    let tx = conn.transaction().unwrap();
+    match import_fit_data(&mut data.as_slice(), &tx) {
+        Err(Error::FileIdMessageNotFound(_)) => {}
+        other => panic!("expected a missing file_id error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn import_stores_activity_timezone_offset() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    let info = import(&mut conn, &data);
+
+    assert_eq!(info.timezone_offset(), Some(-4 * 3600));
+    assert_eq!(
+        info.activity_timestamp().format("%H:%M").to_string(),
+        "08:00"
+    );
+    let offset: i32 = conn
+        .query_row("select timezone_offset from files", params![], |r| r.get(0))
+        .unwrap();
+    assert_eq!(offset, -4 * 3600);
+}
+
+#[test]
+fn import_stores_unmapped_fields() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .message(
+            RECORD,
+            &[
+                Field::UInt32(253, fit_time(START)),
+                Field::UInt16(2, 3000), // altitude: (100m + 500) * 5, decoded as enhanced_altitude
+                Field::UInt8(4, 88),    // cadence
+            ],
+        )
+        .build();
+    import(&mut conn, &data);
+
+    let (kind, index, value, units): (String, i64, f64, String) = conn
+        .query_row(
+            "select message_type, message_index, value, units from developer_fields
+             where field_name = 'cadence'",
+            params![],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        (kind.as_str(), index, value, units.as_str()),
+        ("record", 1, 88.0, "rpm")
+    );
+
+    // the device's altitude has its own column and leaves the elevation for the service
+    let (device_elevation, elevation): (f64, Option<f64>) = conn
+        .query_row(
+            "select device_elevation, elevation from record_messages",
+            params![],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((device_elevation, elevation), (100.0, None));
+
+    // mapped fields are never duplicated into the generic table
+    let nmapped: i64 = conn
+        .query_row(
+            "select count(*) from developer_fields
+             where field_name in ('timestamp', 'serial_number', 'enhanced_altitude')",
+            params![],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(nmapped, 0);
+}
+
+#[test]
+fn import_stores_only_the_listed_unmapped_fields() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .message(
+            RECORD,
+            &[
+                Field::UInt32(253, fit_time(START)),
+                Field::UInt8(4, 88),   // cadence
+                Field::UInt8(53, 128), // fractional_cadence
+                Field::UInt8(200, 7),  // not in the FIT profile
+            ],
+        )
+        .build();
+    let stored_fields = StoredFields::new(HashMap::from([(
+        "record".to_string(),
+        vec!["cadence".to_string(), "unknown_field_200".to_string()],
+    )]));
+    let tx = conn.transaction().unwrap();
+    import_fit_activity(&mut data.as_slice(), &tx, None, &stored_fields).unwrap();
+    tx.commit().unwrap();
+
+    let mut stmt = conn
+        .prepare("select field_name from developer_fields where message_type = 'record'")
+        .unwrap();
+    let mut fields = stmt
+        .query_map(params![], |r| r.get::<usize, String>(0))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    fields.sort();
+    assert_eq!(fields, ["cadence", "unknown_field_200"]);
+
+    // the core columns are always stored
+    let n: i64 = conn
+        .query_row("select count(*) from record_messages", params![], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(n, 1);
+}
+
+#[test]
+fn import_rejects_non_fit_data() {
+    let mut conn = memory_db();
+    let tx = conn.transaction().unwrap();
+    let data = b"just some plain text, not a FIT file".to_vec();
+    match import_fit_data(&mut data.as_slice(), &tx) {
+        Err(Error::InvalidFitFile(_)) => {}
+        other => panic!("expected an invalid FIT file error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn import_rejects_corrupted_files() {
+    let mut conn = memory_db();
+    let mut data = simple_run(START);
+    let idx = data.len() / 2;
+    data[idx] ^= 0xFF;
+    let tx = conn.transaction().unwrap();
+    match import_fit_data(&mut data.as_slice(), &tx) {
+        Err(Error::InvalidFitFile(msg)) => assert!(msg.contains("CRC"), "{}", msg),
+        other => panic!("expected an invalid FIT file error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn import_stores_running_dynamics() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .message(
+            RECORD,
+            &[
+                Field::UInt32(253, fit_time(START)),
+                Field::UInt16(39, 950),   // vertical_oscillation: 95.0 mm
+                Field::UInt16(41, 2500),  // stance_time: 250.0 ms
+                Field::UInt16(85, 12000), // step_length: 1200.0 mm
+            ],
+        )
+        .record(START + 1, (39.46, -80.14), 0.0, 3.0, 120)
+        .build();
+    import(&mut conn, &data);
+
+    let mut stmt = conn
+        .prepare(
+            "select vertical_oscillation, stance_time, step_length from record_messages
+             order by timestamp",
+        )
+        .unwrap();
+    let rows: Vec<(Option<f64>, Option<f64>, Option<f64>)> = stmt
+        .query_map(params![], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![(Some(95.0), Some(250.0), Some(1200.0)), (None, None, None)]
+    );
+}
+
+#[test]
+fn import_stores_timer_events() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .event(START, 0, 0) // timer start
+        .record(START, (39.46, -80.14), 0.0, 3.0, 120)
+        .event(START + 5, 0, 1) // timer stop (auto-pause)
+        .event(START + 5, 5, 3) // power down marker, not stored
+        .event(START + 15, 0, 0)
+        .record(START + 20, (39.4602, -80.14), 60.0, 3.0, 140)
+        .event(START + 20, 0, 4) // timer stop_all
+        // event_type is optional, such an event is skipped instead of failing the import
+        .message(
+            EVENT,
+            &[Field::UInt32(253, fit_time(START + 20)), Field::Enum(0, 0)],
+        )
+        .build();
+    import(&mut conn, &data);
+
+    let mut stmt = conn
+        .prepare("select event, event_type from events order by timestamp, id")
+        .unwrap();
+    let events: Vec<(String, String)> = stmt
+        .query_map(params![], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let events: Vec<(&str, &str)> = events
+        .iter()
+        .map(|(e, t)| (e.as_str(), t.as_str()))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            ("timer", "start"),
+            ("timer", "stop"),
+            ("timer", "start"),
+            ("timer", "stop_all")
+        ]
+    );
+}
+
+#[test]
+fn check_records_flags_implausible_heart_rate_and_speed() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .record(START, (39.46, -80.14), 0.0, 3.0, 120)
+        .record(START + 10, (39.4601, -80.14), 30.0, 3.0, 250)
+        .record(START + 20, (39.4602, -80.14), 260.0, 23.0, 140)
+        .build();
+    let info = import(&mut conn, &data);
+
+    let thresholds = ValidationThresholds::default();
+    let issues = check_records(&conn, info.id(), &thresholds).unwrap();
+    assert_eq!(
+        issues,
+        vec![
+            "1 records with heart rate above 230 bpm (max 250.0)",
+            "1 records with speed above 12.5 m/s (max 23.0)",
+        ]
+    );
+
+    let lenient = ValidationThresholds {
+        max_heart_rate: 255.0,
+        max_speed: 30.0,
+        ..thresholds
+    };
+    assert!(check_records(&conn, info.id(), &lenient)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn check_records_flags_device_altitude_spikes() {
+    let mut conn = memory_db();
+    // altitude is stored as (meters + 500) * 5
+    let record = |builder: FitFileBuilder, offset: u32, altitude: u16| {
+        builder.message(
+            RECORD,
+            &[
+                Field::UInt32(253, fit_time(START + offset)),
+                Field::UInt16(2, (altitude + 500) * 5),
+            ],
+        )
+    };
+    let mut builder = FitFileBuilder::new().file_id(1234, START);
+    for (offset, altitude) in [(0, 100), (10, 104), (20, 1600), (30, 104)] {
+        builder = record(builder, offset, altitude);
+    }
+    let data = builder.build();
+    let tx = conn.transaction().unwrap();
+    let info = import_fit_data(&mut data.as_slice(), &tx).unwrap();
+
+    // checked before the elevation service fills in the elevation, as during an import
+    let issues = check_records(&tx, info.id(), &ValidationThresholds::default()).unwrap();
+    assert_eq!(
+        issues,
+        vec!["2 elevation changes faster than 100 m/s (max 149.6)"]
+    );
+}
+
+#[test]
+fn filter_gps_jumps_removes_spikes_and_null_island() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .record(START, (39.46, -80.14), 0.0, 3.0, 120)
+        .record(START + 10, (39.4601, -80.14), 30.0, 3.0, 130)
+        .record(START + 20, (39.56, -80.14), 60.0, 3.0, 140)
+        .record(START + 30, (39.4603, -80.14), 90.0, 3.0, 140)
+        .record(START + 40, (0.0, 0.0), 120.0, 3.0, 140)
+        .record(START + 50, (39.4605, -80.14), 150.0, 3.0, 140)
+        .build();
+    let info = import(&mut conn, &data);
+
+    assert_eq!(filter_gps_jumps(&conn, info.id(), 12.5).unwrap(), 2);
+    let mut stmt = conn
+        .prepare(
+            "select position_lat is not null, heart_rate from record_messages
+             where file_id = ? order by timestamp",
+        )
+        .unwrap();
+    let rows: Vec<(bool, i64)> = stmt
+        .query_map(params![info.id()], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            (true, 120),
+            (true, 130),
+            (false, 140),
+            (true, 140),
+            (false, 140),
+            (true, 140)
+        ]
+    );
+}
+
+#[test]
+fn import_fit_activity_keeps_only_the_selected_session() {
+    let mut conn = memory_db();
+    let data = FitFileBuilder::new()
+        .file_id(1234, START)
+        .record(START, (39.46, -80.14), 0.0, 3.0, 120)
+        .record(START + 10, (39.4601, -80.14), 30.0, 3.0, 130)
+        .lap(START, START + 10, 30.0, 3.0, 6)
+        .session(START, START + 10)
+        .record(START + 600, (39.47, -80.14), 0.0, 3.0, 120)
+        .record(START + 610, (39.4701, -80.14), 30.0, 3.0, 125)
+        .record(START + 620, (39.4702, -80.14), 60.0, 3.0, 130)
+        .lap(START + 600, START + 620, 60.0, 3.0, 12)
+        .session(START + 600, START + 620)
+        .build();
+
+    let tx = conn.transaction().unwrap();
+    let second =
+        import_fit_activity(&mut data.as_slice(), &tx, Some(1), &StoredFields::default()).unwrap();
+    tx.commit().unwrap();
+    // the file is dated by the start of the activity
+    assert_eq!(second.timestamp().timestamp(), START as i64 + 600);
+    let (records, laps): (i64, i64) = conn
+        .query_row(
+            "select (select count(*) from record_messages where file_id = ?1),
+                    (select count(*) from lap_messages where file_id = ?1)",
+            params![second.id()],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((records, laps), (3, 1));
+
+    // every activity and the whole file are distinct imports
+    let tx = conn.transaction().unwrap();
+    let first =
+        import_fit_activity(&mut data.as_slice(), &tx, Some(0), &StoredFields::default()).unwrap();
+    let whole = import_fit_data(&mut data.as_slice(), &tx).unwrap();
+    tx.commit().unwrap();
+    assert_ne!(first.uuid(), second.uuid());
+    assert_ne!(whole.uuid(), second.uuid());
+
+    let tx = conn.transaction().unwrap();
+    assert!(matches!(
+        import_fit_activity(&mut data.as_slice(), &tx, Some(1), &StoredFields::default()),
+        Err(Error::DuplicateFileError(_))
+    ));
+    assert!(matches!(
+        import_fit_activity(&mut data.as_slice(), &tx, Some(2), &StoredFields::default()),
+        Err(Error::Other(_))
+    ));
+}
+
+#[test]
+fn persisted_path_expands_template_placeholders() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    let info = import(&mut conn, &data);
+
+    let name = OsStr::new("ABC123.FIT");
+    let path = info
+        .persisted_path("{manufacturer}-{product}-{serial}/{name}", Some(name))
+        .unwrap();
+    assert_eq!(
+        path,
+        Path::new(&format!("garmin-{}-1234/ABC123.FIT", info.product()))
+    );
+    let path = info
+        .persisted_path("{year}/{month}/{date}-{uuid}.fit", None)
+        .unwrap();
+    assert_eq!(
+        path,
+        Path::new(&format!("2021/06/2021-06-01-{}.fit", info.uuid()))
+    );
+    assert_eq!(
+        info.persisted_path("{name}", None).unwrap(),
+        Path::new(&format!("{}.fit", info.uuid()))
+    );
+
+    for template in ["{nope}.fit", "{date", "../{uuid}.fit", "/tmp/{uuid}.fit"] {
+        assert!(matches!(
+            info.persisted_path(template, Some(name)),
+            Err(Error::InvalidConfigurationValue(_))
+        ));
+    }
+}
+
+#[test]
+fn export_path_adds_extension_and_sanitizes_values() {
+    let mut conn = memory_db();
+    let data = simple_run(START);
+    let info = import(&mut conn, &data);
+
+    assert_eq!(
+        info.export_path("runs/{date}-{uuid}", "gpx").unwrap(),
+        Path::new(&format!("runs/2021-06-01-{}.gpx", info.uuid()))
+    );
+    assert!(matches!(
+        info.export_path("../{uuid}", "gpx"),
+        Err(Error::InvalidConfigurationValue(_))
+    ));
+}
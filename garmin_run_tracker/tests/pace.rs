@@ -0,0 +1,78 @@
+use garmin_run_tracker::{
+    format_pace, pace_from_speed, DistanceUnit, ElevationUnit, RestLapThresholds, UnitSystem,
+};
+
+#[test]
+fn pace_from_speed_converts_units() {
+    // 3 m/s is 8:56 per mile and 5:33 per kilometer
+    let pace = pace_from_speed(3.0, DistanceUnit::Mile).unwrap();
+    assert_eq!(pace.num_milliseconds(), 536_448);
+    let pace = pace_from_speed(3.0, DistanceUnit::Kilometer).unwrap();
+    assert_eq!(pace.num_milliseconds(), 333_333);
+}
+
+#[test]
+fn pace_from_speed_rejects_stopped_and_invalid_speeds() {
+    assert!(pace_from_speed(0.0, DistanceUnit::Mile).is_none());
+    assert!(pace_from_speed(-0.0, DistanceUnit::Mile).is_none());
+    assert!(pace_from_speed(-2.5, DistanceUnit::Mile).is_none());
+    assert!(pace_from_speed(f64::NAN, DistanceUnit::Mile).is_none());
+}
+
+#[test]
+fn pace_from_speed_rejects_tiny_speeds() {
+    assert!(pace_from_speed(f64::MIN_POSITIVE, DistanceUnit::Mile).is_none());
+    assert!(pace_from_speed(1e-9, DistanceUnit::Kilometer).is_none());
+    assert!(pace_from_speed(0.01, DistanceUnit::Mile).is_none());
+    // an hour per kilometer is still a valid pace
+    let pace = pace_from_speed(1000.0 / 3600.0, DistanceUnit::Kilometer).unwrap();
+    assert_eq!(pace.num_minutes(), 60);
+}
+
+#[test]
+fn unit_systems_convert_elevation() {
+    let feet = UnitSystem::Imperial.elevation_unit();
+    assert!((feet.from_meters(100.0) - 328.084).abs() < 0.001);
+    assert_eq!(feet.abbreviation(), "ft");
+    assert_eq!(UnitSystem::Metric.elevation_unit(), ElevationUnit::Meter);
+    assert_eq!(UnitSystem::Metric.distance_unit(), DistanceUnit::Kilometer);
+    assert_eq!(UnitSystem::default(), UnitSystem::Imperial);
+}
+
+#[test]
+fn format_pace_rounds_to_the_second() {
+    // 8:56.448 per mile and 5:33.333 per kilometer
+    assert_eq!(format_pace(Some(3.0), DistanceUnit::Mile), "8:56");
+    assert_eq!(format_pace(Some(3.0), DistanceUnit::Kilometer), "5:33");
+    // 7:59.6 per mile rounds up into the next minute instead of showing 7:60
+    assert_eq!(
+        format_pace(Some(1609.344 / 479.6), DistanceUnit::Mile),
+        "8:00"
+    );
+}
+
+#[test]
+fn format_pace_handles_stopped_and_walking_speeds() {
+    assert_eq!(format_pace(Some(0.0), DistanceUnit::Mile), "--:--");
+    assert_eq!(format_pace(None, DistanceUnit::Mile), "--:--");
+    assert_eq!(format_pace(Some(f64::NAN), DistanceUnit::Mile), "--:--");
+    assert_eq!(
+        format_pace(Some(f64::INFINITY), DistanceUnit::Mile),
+        "--:--"
+    );
+    // a slow 1 m/s walk is 26:49 per mile, a crawl slower than an hour per mile has no pace
+    assert_eq!(format_pace(Some(1.0), DistanceUnit::Mile), "26:49");
+    assert_eq!(format_pace(Some(0.4), DistanceUnit::Mile), "--:--");
+}
+
+#[test]
+fn rest_laps_are_short_in_distance_or_time() {
+    let thresholds = RestLapThresholds::default();
+    // a standing rest covers almost no ground however long it lasts
+    assert!(thresholds.is_rest(Some(3.0), 90.0));
+    // an accidental double press of the lap button
+    assert!(thresholds.is_rest(Some(60.0), 2.0));
+    assert!(thresholds.is_rest(None, 5.0));
+    assert!(!thresholds.is_rest(Some(400.0), 95.0));
+    assert!(!thresholds.is_rest(None, 95.0));
+}
@@ -0,0 +1,64 @@
+use garmin_run_tracker::gps::Location;
+use garmin_run_tracker::services::visualization::route::Marker;
+use garmin_run_tracker::services::{new_route_visualization_handler, RouteDrawingService};
+
+fn route_drawer(yaml: &str) -> Box<dyn RouteDrawingService> {
+    new_route_visualization_handler(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+}
+
+fn traces() -> (Vec<Location>, Vec<Location>) {
+    (
+        vec![Location::new(39.46, -80.14), Location::new(39.47, -80.13)],
+        vec![Location::new(39.45, -80.15), Location::new(39.48, -80.12)],
+    )
+}
+
+#[test]
+fn mapbox_preview_url_has_every_trace_and_marker_with_the_token_masked() {
+    let drawer = route_drawer(
+        "handler: mapbox
+configuration:
+  access_token: pk.secret
+",
+    );
+    let (first, second) = traces();
+    let markers = vec![
+        Marker::new(first[0], "S".to_string()),
+        Marker::new(first[1], "F".to_string()),
+    ];
+    let url = drawer.preview_url(&[&first, &second], &markers).unwrap();
+    assert!(url.starts_with("https://api.mapbox.com/styles/v1/mapbox/streets-v11/static/"));
+    assert!(url.contains("pin-l-s%2Bf07272%28-80.14%2C39.46%29"));
+    assert!(url.contains("pin-l-f%2Bf07272%28-80.13%2C39.47%29"));
+    // each trace is its own path overlay of a precision 5 polyline
+    assert!(url.contains("path-5+f44-0.75(_%60zoF%7EichNo%7D%40o%7D%40)"));
+    assert!(url.contains("path-5+f44-0.75(oaxoFnhehNozDozD)"));
+    assert!(url.ends_with("/auto/1280x1280?access_token=****"));
+    assert!(!url.contains("pk.secret"));
+}
+
+#[test]
+fn openmaptiles_preview_url_has_every_trace_with_the_key_masked() {
+    let drawer = route_drawer(
+        "handler: openmaptiles
+configuration:
+  base_url: http://localhost:8080
+  style: osm-bright
+  coordinate_precision: 4
+  api_key: secret
+",
+    );
+    let (first, second) = traces();
+    let markers = vec![Marker::new(first[0], "S".to_string())];
+    let url = drawer.preview_url(&[&first, &second], &markers).unwrap();
+    // the map covers the bounding box of both traces
+    assert!(url.starts_with(
+        "http://localhost:8080/styles/osm-bright/static/-80.15,39.45,-80.12,39.48/1800x1200.png?"
+    ));
+    assert!(url.contains("&path=-80.1400%2C39.4600%7C-80.1300%2C39.4700&"));
+    assert!(url.contains("&path=-80.1500%2C39.4500%7C-80.1200%2C39.4800&"));
+    // one path for each trace, OpenMapTiles doesn't draw the markers
+    assert_eq!(url.matches("path=").count(), 2);
+    assert!(url.ends_with("&api_key=****"));
+    assert!(!url.contains("secret"));
+}
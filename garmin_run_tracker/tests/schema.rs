@@ -0,0 +1,94 @@
+mod common;
+
+use common::memory_db;
+use garmin_run_tracker::{backup_database, latest_schema_version, schema_version};
+use rusqlite::{params, Connection};
+
+/// Return the detail column of each step in the query plan
+fn query_plan(conn: &Connection, sql: &str) -> Vec<String> {
+    let mut stmt = conn
+        .prepare(&format!("explain query plan {}", sql))
+        .unwrap();
+    stmt.query_map(params![], |r| r.get(3))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn record_messages_by_file_use_index() {
+    let conn = memory_db();
+    let plan = query_plan(
+        &conn,
+        "select distance, speed from record_messages where file_id = 1 order by timestamp",
+    );
+    assert!(
+        plan.iter()
+            .any(|s| s.contains("USING INDEX record_messages_file_id_timestamp")),
+        "unexpected query plan: {:?}",
+        plan
+    );
+    assert!(
+        !plan.iter().any(|s| s.contains("TEMP B-TREE")),
+        "{:?}",
+        plan
+    );
+}
+
+#[test]
+fn lap_messages_by_file_use_index() {
+    let conn = memory_db();
+    let plan = query_plan(
+        &conn,
+        "select total_distance from lap_messages where file_id = 1 order by start_time",
+    );
+    assert!(
+        plan.iter()
+            .any(|s| s.contains("USING INDEX lap_messages_file_id_start_time")),
+        "unexpected query plan: {:?}",
+        plan
+    );
+    assert!(
+        !plan.iter().any(|s| s.contains("TEMP B-TREE")),
+        "{:?}",
+        plan
+    );
+}
+
+#[test]
+fn files_uuid_is_unique() {
+    let conn = memory_db();
+    let insert = "insert into files (type, device_serial_number, time_created, uuid)
+                  values ('activity', 1, '2021-06-01T12:00:00Z', 'abc')";
+    conn.execute(insert, params![]).unwrap();
+    assert!(conn.execute(insert, params![]).is_err());
+
+    let plan = query_plan(&conn, "select id from files where uuid = 'abc'");
+    assert!(
+        plan.iter().any(|s| s.contains("files_uuid")),
+        "unexpected query plan: {:?}",
+        plan
+    );
+}
+
+#[test]
+fn backup_copies_data_and_schema_version() {
+    let conn = memory_db();
+    conn.execute(
+        "insert into files (type, device_serial_number, time_created, uuid)
+         values ('activity', 1234, '2021-06-01T12:00:00+00:00', 'abc')",
+        params![],
+    )
+    .unwrap();
+    let path = std::env::temp_dir().join(format!("grt-backup-{}.db", std::process::id()));
+    backup_database(&conn, &path).unwrap();
+
+    let backup = Connection::open(&path).unwrap();
+    assert_eq!(schema_version(&backup).unwrap(), latest_schema_version());
+    let uuid: String = backup
+        .query_row("select uuid from files", params![], |r| r.get(0))
+        .unwrap();
+    assert_eq!(uuid, "abc");
+    drop(backup);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,86 @@
+// every test here imports a TCX file
+#![cfg(feature = "tcx")]
+
+mod common;
+
+use common::memory_db;
+use rusqlite::params;
+
+#[test]
+fn tcx_laps_and_trackpoints_are_imported() {
+    use garmin_run_tracker::{import_tcx_data, Error};
+
+    let tcx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2">
+  <Activities>
+    <Activity Sport="Running">
+      <Id>2021-06-01T12:00:00Z</Id>
+      <Lap StartTime="2021-06-01T12:00:00Z">
+        <TotalTimeSeconds>20</TotalTimeSeconds>
+        <DistanceMeters>60</DistanceMeters>
+        <Calories>12</Calories>
+        <AverageHeartRateBpm><Value>130</Value></AverageHeartRateBpm>
+        <Track>
+          <Trackpoint>
+            <Time>2021-06-01T12:00:00Z</Time>
+            <Position><LatitudeDegrees>39.46</LatitudeDegrees><LongitudeDegrees>-80.14</LongitudeDegrees></Position>
+            <AltitudeMeters>300.5</AltitudeMeters>
+            <DistanceMeters>0</DistanceMeters>
+            <HeartRateBpm><Value>120</Value></HeartRateBpm>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2021-06-01T12:00:20Z</Time>
+            <Position><LatitudeDegrees>39.4602</LatitudeDegrees><LongitudeDegrees>-80.14</LongitudeDegrees></Position>
+            <AltitudeMeters>301.5</AltitudeMeters>
+            <DistanceMeters>60</DistanceMeters>
+            <HeartRateBpm><Value>140</Value></HeartRateBpm>
+          </Trackpoint>
+        </Track>
+      </Lap>
+      <Creator><Name>Forerunner 305</Name></Creator>
+    </Activity>
+  </Activities>
+</TrainingCenterDatabase>"#;
+    let mut conn = memory_db();
+    let tx = conn.transaction().unwrap();
+    let info = import_tcx_data(tcx.as_bytes(), &tx).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(info.manufacturer(), "tcx");
+    assert_eq!(info.product(), "forerunner_305");
+
+    let rows: Vec<(f64, f64, Option<f64>, i64)> = {
+        let mut stmt = conn
+            .prepare(
+                "select distance, elevation, speed, heart_rate from record_messages
+                 order by timestamp",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(params![], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows
+    };
+    assert_eq!(
+        rows,
+        vec![(0.0, 300.5, None, 120), (60.0, 301.5, Some(3.0), 140)]
+    );
+    let lap: (f64, f64, i64, i64) = conn
+        .query_row(
+            "select total_distance, average_speed, total_calories, average_heart_rate
+             from lap_messages",
+            params![],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap();
+    assert_eq!(lap, (60.0, 3.0, 12, 130));
+
+    let tx = conn.transaction().unwrap();
+    assert!(matches!(
+        import_tcx_data(b"<TrainingCenterDatabase/>", &tx),
+        Err(Error::InvalidTrackFile(_))
+    ));
+}
@@ -0,0 +1,111 @@
+use garmin_run_tracker::{init_database, open_db_connection_at, DbWriter};
+use rusqlite::{params, Connection, TransactionBehavior};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Create a database file in the temp directory that several connections can share
+fn temp_db(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("grt-{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut conn = open_db_connection_at(&path).unwrap();
+    init_database(&mut conn).unwrap();
+    path
+}
+
+fn insert_history(tx: &rusqlite::Transaction) -> Result<(), garmin_run_tracker::Error> {
+    tx.execute(
+        "insert into import_history (started_at) values ('2021-06-01T12:00:00+00:00')",
+        params![],
+    )?;
+    Ok(())
+}
+
+#[test]
+fn writes_from_several_threads_are_all_committed() {
+    let path = temp_db("writer-threads");
+    let writer = Arc::new(DbWriter::new(open_db_connection_at(&path).unwrap()));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let writer = Arc::clone(&writer);
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    writer.write(insert_history).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let conn = Arc::try_unwrap(writer).ok().unwrap().into_inner();
+    let count: i64 = conn
+        .query_row("select count(*) from import_history", params![], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(count, 100);
+    drop(conn);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn busy_writes_are_retried() {
+    let path = temp_db("writer-busy");
+    // without a busy timeout the writer sees SQLITE_BUSY right away and has to retry
+    let conn = Connection::open(&path).unwrap();
+    conn.busy_timeout(Duration::ZERO).unwrap();
+    let writer = DbWriter::new(conn);
+
+    let mut other = Connection::open(&path).unwrap();
+    let holder = thread::spawn(move || {
+        let tx = other
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .unwrap();
+        thread::sleep(Duration::from_millis(250));
+        tx.commit().unwrap();
+    });
+    thread::sleep(Duration::from_millis(50));
+    writer.write(insert_history).unwrap();
+    holder.join().unwrap();
+
+    let conn = writer.into_inner();
+    let count: i64 = conn
+        .query_row("select count(*) from import_history", params![], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(count, 1);
+    drop(conn);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn connections_use_the_write_ahead_log() {
+    let path = temp_db("writer-wal");
+    let writer = DbWriter::new(open_db_connection_at(&path).unwrap());
+    writer.write(insert_history).unwrap();
+    let conn = writer.into_inner();
+    let mode: String = conn
+        .query_row("pragma journal_mode", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(mode, "wal");
+    // a second connection can read while the first one has an open write transaction
+    conn.execute_batch("begin immediate").unwrap();
+    conn.execute(
+        "insert into import_history (started_at) values ('2021-06-02T12:00:00+00:00')",
+        params![],
+    )
+    .unwrap();
+    let reader = open_db_connection_at(&path).unwrap();
+    let count: u32 = reader
+        .query_row("select count(*) from import_history", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+    conn.execute_batch("commit").unwrap();
+    drop(reader);
+    drop(conn);
+    std::fs::remove_file(&path).unwrap();
+}
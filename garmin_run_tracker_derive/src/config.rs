@@ -8,6 +8,26 @@ pub fn expand_derive_from_service_config(
 ) -> Result<TokenStream, Vec<syn::Error>> {
     let name = &input.ident;
     let setters = config_setters(&input.data);
+    let fallback = match extra_field(&input.data) {
+        // unmapped parameters are collected as strings instead of being reported
+        Some(extra) => quote! {
+            _ => {
+                if let Some(values) = config.get_parameter_as_strings(key) {
+                    for value in values? {
+                        base.#extra.push((key.to_string(), value));
+                    }
+                }
+            }
+        },
+        None => quote! {
+            _ => log::warn!(
+                "unknown configuration parameter for {}: {}={}",
+                stringify!(#name),
+                key,
+                config.display_parameter(key)
+            ),
+        },
+    };
     let expanded = quote! {
         // The generated impl
         impl FromServiceConfig for #name {
@@ -16,12 +36,7 @@ pub fn expand_derive_from_service_config(
                 for key in config.parameters() {
                     match key.as_ref() {
                         #setters
-                        _ => log::warn!(
-                            "unknown configuration parameter for {}: {}={:?}",
-                            stringify!(#name),
-                            key,
-                            config.get_parameter(key)
-                        ),
+                        #fallback
                     }
                 }
                 Ok(base)
@@ -30,10 +45,12 @@ pub fn expand_derive_from_service_config(
     };
 
     // Hand the output tokens back to the compiler.
-    Ok(TokenStream::from(expanded))
+    Ok(expanded)
 }
 
-/// Generate a setter method for each field that isn't annotated with #[service_config(skip)]
+/// Generate a setter method for each field that isn't annotated with #[service_config(skip)] or
+/// #[service_config(extra)], fields annotated with #[service_config(parse)] are parsed from a
+/// string with their FromStr implementation
 fn config_setters(data: &Data) -> TokenStream {
     match *data {
         Data::Struct(ref data) => match data.fields {
@@ -42,7 +59,7 @@ fn config_setters(data: &Data) -> TokenStream {
                     .named
                     .iter()
                     .filter_map(|f| {
-                        if skip_field(&f) {
+                        if skip_field(f) || has_attribute(f, "extra") {
                             None
                         } else {
                             Some(generate_setter(f))
@@ -64,25 +81,48 @@ fn config_setters(data: &Data) -> TokenStream {
 }
 
 fn skip_field(field: &Field) -> bool {
-    for attr in &field.attrs {
-        let attr_str = format!("{}", quote!(#attr));
-        if &attr_str == "#[service_config(skip)]" {
-            return true;
-        }
+    has_attribute(field, "skip")
+}
+
+fn has_attribute(field: &Field, name: &str) -> bool {
+    let expected = format!("#[service_config({})]", name);
+    field
+        .attrs
+        .iter()
+        .any(|attr| format!("{}", quote!(#attr)) == expected)
+}
+
+/// Find the field annotated with #[service_config(extra)], it must be a Vec<(String, String)>
+/// and receives every parameter that doesn't match another field
+fn extra_field(data: &Data) -> Option<&Ident> {
+    match *data {
+        Data::Struct(ref data) => data
+            .fields
+            .iter()
+            .find(|f| has_attribute(f, "extra"))
+            .and_then(|f| f.ident.as_ref()),
+        _ => None,
     }
-    false
 }
 
 fn generate_setter(field: &Field) -> TokenStream {
     let name = field.ident.as_ref().unwrap();
     let key = format!("{}", &name);
-    let (get_fn, cast) = get_param_fn_ident(&field.ty);
-
-    // generate assignment tokens w/wo casting type
-    let assignment = if let Some(cast) = cast {
-        quote_spanned! { field.span() =>  base.#name = val? as #cast }
+    let (get_fn, conversion) = if has_attribute(field, "parse") {
+        // any type implementing FromStr, e.g. enumerations given by name
+        (
+            format_ident!("{}", "get_parameter_parsed"),
+            Conversion::None,
+        )
     } else {
-        quote_spanned! { field.span() => base.#name = val? }
+        get_param_fn_ident(&field.ty)
+    };
+
+    // generate assignment tokens w/wo converting the type
+    let assignment = match conversion {
+        Conversion::Cast(cast) => quote_spanned! { field.span() =>  base.#name = val? as #cast },
+        Conversion::Into => quote_spanned! { field.span() => base.#name = val?.into() },
+        Conversion::None => quote_spanned! { field.span() => base.#name = val? },
     };
 
     // wrap assignment op with function to fetch value from config
@@ -95,18 +135,39 @@ fn generate_setter(field: &Field) -> TokenStream {
     }
 }
 
-fn get_param_fn_ident(ty: &Type) -> (Ident, Option<&Type>) {
+/// How the value returned by the config getter is converted into the field's type
+enum Conversion<'a> {
+    None,
+    Cast(&'a Type),
+    Into,
+}
+
+fn get_param_fn_ident(ty: &Type) -> (Ident, Conversion<'_>) {
     let type_str = format!("{}", ty.to_token_stream());
-    let cast = Some(ty);
+    let cast = Conversion::Cast(ty);
     match type_str.as_ref() {
-        "String" => (format_ident!("{}", "get_parameter_as_string"), None),
-        "f32" | "f64" => (format_ident!("{}", "get_parameter_as_f64"), cast),
+        "String" => (
+            format_ident!("{}", "get_parameter_as_string"),
+            Conversion::None,
+        ),
+        "Secret" => (
+            format_ident!("{}", "get_parameter_as_string"),
+            Conversion::Into,
+        ),
+        // no cast is needed when the type matches what the getter returns
+        "f64" => (
+            format_ident!("{}", "get_parameter_as_f64"),
+            Conversion::None,
+        ),
+        "f32" => (format_ident!("{}", "get_parameter_as_f64"), cast),
         "u8" | "u16" | "u32" | "u64" | "usize" => {
             (format_ident!("{}", "get_parameter_as_i64"), cast)
         }
-        "i8" | "i16" | "i32" | "i64" | "isize" => {
-            (format_ident!("{}", "get_parameter_as_i64"), cast)
-        }
+        "i64" => (
+            format_ident!("{}", "get_parameter_as_i64"),
+            Conversion::None,
+        ),
+        "i8" | "i16" | "i32" | "isize" => (format_ident!("{}", "get_parameter_as_i64"), cast),
         _ => unimplemented!("Macro doesn't support type {}", type_str),
     }
 }